@@ -0,0 +1,94 @@
+/** @jsxImportSource @emotion/react */
+import { Pane, Button } from '../components/design'
+import * as sentMessages from '../protocol/sent-messages'
+import type { ConfigurationSnapshotSet } from '../protocol/data'
+import type {
+  CalibrationOffer,
+  CalibrationRecord,
+  ClientId,
+} from '../protocol/recieved-messages'
+
+/**
+ * Lists calibrations stored on the server, newest first. Each one can be
+ * applied again to connected clients of the same name, which is how
+ * calibrations are rolled back. Also shows calibrations offered to clients
+ * which just connected.
+ * @param props
+ * @returns
+ */
+export function CalibrationHistoryPane({
+  records,
+  offers,
+  serverState,
+  sendMessage,
+}: {
+  records: readonly CalibrationRecord[]
+  offers: readonly CalibrationOffer[]
+  serverState: ConfigurationSnapshotSet
+  sendMessage: sentMessages.SendMessage
+}) {
+  const apply = (clientId: ClientId, recordId: number) =>
+    void sendMessage({ type: 'ApplyStoredCalibration', clientId, recordId })
+  const clientsNamed = (name: string) =>
+    Object.entries(serverState.clients)
+      .filter(([, client]) => name && client.name === name)
+      .map(([id]) => +id)
+
+  return (
+    <Pane title="Calibration history" id="calibration-history">
+      {offers.map((offer) => (
+        <div
+          key={offer.id}
+          css={{ display: 'flex', gap: 6, alignItems: 'center' }}
+        >
+          Client {offer.id} ({offer.record.name}) has a calibration from{' '}
+          {new Date(offer.record.time).toLocaleString()}
+          <Button
+            type="button"
+            onClick={() => apply(offer.id, offer.record.id)}
+          >
+            Apply
+          </Button>
+        </div>
+      ))}
+      {records.length === 0 ? 'No calibrations stored yet' : null}
+      {records
+        .slice()
+        .reverse()
+        .map((record) => (
+          <div
+            key={record.id}
+            css={{
+              display: 'flex',
+              gap: 6,
+              alignItems: 'center',
+              flexWrap: 'wrap',
+              paddingBlock: 4,
+            }}
+          >
+            <span>{describe(record)}</span>
+            {clientsNamed(record.name).map((clientId) => (
+              <Button
+                type="button"
+                key={clientId}
+                onClick={() => apply(clientId, record.id)}
+              >
+                Apply to {clientId}
+              </Button>
+            ))}
+          </div>
+        ))}
+    </Pane>
+  )
+}
+
+function describe(record: CalibrationRecord) {
+  const { result } = record
+  const time = new Date(record.time).toLocaleString()
+  const quality = result
+    ? `, ${result.verdict}, ${result.quality.rms_position_error_mm.toFixed(1)} mm`
+    : ''
+  return `${time}: ${record.name || '?'} against ${
+    record.referenceName || '?'
+  } (${record.source}${quality})`
+}
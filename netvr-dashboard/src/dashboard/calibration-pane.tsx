@@ -7,6 +7,13 @@ import type {
 import { Button, Input, Pane, Select } from '../components/design'
 import * as sentMessages from '../protocol/sent-messages'
 import { MergedData } from './merge-data'
+import type {
+  CalibrationFinished,
+  CalibrationProgress,
+  CalibrationState,
+  ContinuousCalibrationStep,
+  MarkerCaptured,
+} from '../protocol/recieved-messages'
 import { JSONView } from '../components/json-view'
 import { useLocalStorage } from '../utils'
 import { useDropzone } from 'react-dropzone'
@@ -21,10 +28,19 @@ export function CalibrationPane({
   sendMessage,
   serverState,
   mergedData,
+  lastCalibration,
+  calibrationState,
 }: {
   sendMessage: sentMessages.SendMessage
   serverState: ConfigurationSnapshotSet
   mergedData: MergedData
+  lastCalibration:
+    | CalibrationProgress
+    | CalibrationFinished
+    | ContinuousCalibrationStep
+    | MarkerCaptured
+    | null
+  calibrationState: CalibrationState | null
 }) {
   const target = useDeviceSelect({ serverState })
   const reference = useDeviceSelect({
@@ -92,7 +108,22 @@ export function CalibrationPane({
       })
     },
   })
-  const hijack = useRef(false)
+  const mode = useRef<
+    'calibration' | 'hijack' | 'continuous' | 'batch' | 'markers'
+  >('calibration')
+  const [markerCount, setMarkerCount] = useState(3)
+  const [stopOnCoverage, setStopOnCoverage] = useLocalStorage(
+    'stop-on-coverage',
+    'true' as 'true' | 'false',
+    isBooleanString,
+  )
+  const [gravityAligned, setGravityAligned] = useLocalStorage(
+    'gravity-aligned',
+    'false' as 'true' | 'false',
+    isBooleanString,
+  )
+  const solver: sentMessages.CalibrationSolver =
+    gravityAligned === 'true' ? 'GravityAligned' : 'Full'
   return (
     <div {...dropzone.getRootProps()}>
       <Pane title="Calibration" id="calibration">
@@ -117,32 +148,71 @@ export function CalibrationPane({
             }
             const num = (v: unknown) => +str(v)
 
-            if (hijack.current) {
+            const devices = {
+              targetId: num(formData.get('targetId')),
+              targetSubactionPath: str(formData.get('targetSubactionPath')),
+              referenceId: num(formData.get('referenceId')),
+              referenceSubactionPath: str(
+                formData.get('referenceSubactionPath'),
+              ),
+            }
+            if (mode.current === 'hijack') {
+              sendMessage({ type: 'StartHijack', ...devices })
+            } else if (mode.current === 'batch') {
               sendMessage({
-                type: 'StartHijack',
-                targetId: num(formData.get('targetId')),
-                targetSubactionPath: str(formData.get('targetSubactionPath')),
-                referenceId: num(formData.get('referenceId')),
-                referenceSubactionPath: str(
-                  formData.get('referenceSubactionPath'),
+                type: 'StartBatchCalibration',
+                referenceId: devices.referenceId,
+                referenceSubactionPath: devices.referenceSubactionPath,
+                targets: batchTargets(
+                  serverState,
+                  devices.referenceId,
+                  devices.targetSubactionPath,
                 ),
+                conf: {
+                  sample_count: 500,
+                  sample_interval_nanos: 1000 * 1000 * 20,
+                  stop_on_coverage: stopOnCoverage === 'true',
+                  solver,
+                },
+              })
+            } else if (mode.current === 'markers') {
+              sendMessage({
+                type: 'StartMarkerCalibration',
+                referenceId: devices.referenceId,
+                referenceSubactionPath: devices.referenceSubactionPath,
+                targets: batchTargets(
+                  serverState,
+                  devices.referenceId,
+                  devices.targetSubactionPath,
+                ).map(({ clientId, subactionPath }) => ({
+                  clientId,
+                  subactionPath,
+                })),
+                pointCount: markerCount,
+              })
+            } else if (mode.current === 'continuous') {
+              sendMessage({
+                type: 'StartContinuousCalibration',
+                ...devices,
+                conf: {
+                  sample_count: 1500,
+                  sample_interval_nanos: 1000 * 1000 * 20,
+                  solver,
+                },
               })
             } else {
               sendMessage({
                 type: 'StartCalibration',
-                targetId: num(formData.get('targetId')),
-                targetSubactionPath: str(formData.get('targetSubactionPath')),
-                referenceId: num(formData.get('referenceId')),
-                referenceSubactionPath: str(
-                  formData.get('referenceSubactionPath'),
-                ),
+                ...devices,
                 conf: {
                   sample_count: 500,
                   sample_interval_nanos: 1000 * 1000 * 20,
+                  stop_on_coverage: stopOnCoverage === 'true',
+                  solver,
                 },
               })
             }
-            hijack.current = false
+            mode.current = 'calibration'
 
             const msg = 'Calibration triggered ' + JSON.stringify(data)
             setMessage(msg)
@@ -168,6 +238,26 @@ export function CalibrationPane({
             />{' '}
             Enable keyboard shortcuts
           </label>
+          <label css={{ userSelect: 'none' }}>
+            <Input
+              type="checkbox"
+              checked={stopOnCoverage === 'true'}
+              onChange={(evt) =>
+                setStopOnCoverage(evt.currentTarget.checked + '')
+              }
+            />{' '}
+            Stop when devices were rotated enough
+          </label>
+          <label css={{ userSelect: 'none' }}>
+            <Input
+              type="checkbox"
+              checked={gravityAligned === 'true'}
+              onChange={(evt) =>
+                setGravityAligned(evt.currentTarget.checked + '')
+              }
+            />{' '}
+            Only solve yaw, devices agree on which way is up
+          </label>
           <DeviceSelect
             serverState={serverState}
             data={target}
@@ -207,25 +297,187 @@ export function CalibrationPane({
           </Button>
           <Button
             onClick={() => {
-              hijack.current = true
+              mode.current = 'hijack'
             }}
             type="submit"
           >
             Data collection
           </Button>
+          <Button
+            onClick={() => {
+              mode.current = 'batch'
+            }}
+            type="submit"
+          >
+            Calibrate all clients against reference
+          </Button>
+          <div css={{ display: 'flex', alignItems: 'center', gap: 4 }}>
+            <Button
+              onClick={() => {
+                mode.current = 'markers'
+              }}
+              type="submit"
+            >
+              Calibrate all clients by markers
+            </Button>
+            <label>
+              Markers{' '}
+              <Input
+                type="number"
+                min={2}
+                value={markerCount}
+                onChange={(evt) => setMarkerCount(+evt.currentTarget.value)}
+              />
+            </label>
+            <Button
+              onClick={() => sendMessage({ type: 'CaptureMarker' })}
+              type="button"
+            >
+              Capture marker
+            </Button>
+          </div>
+          <Button
+            onClick={() => {
+              mode.current = 'continuous'
+            }}
+            type="submit"
+          >
+            Continuous calibration
+          </Button>
           <Button
             onClick={() => sendMessage({ type: 'FinishCalibration' })}
             type="button"
           >
-            Data collection finish
+            Finish data collection or continuous calibration
+          </Button>
+          <Button
+            onClick={() => sendMessage({ type: 'CancelCalibration' })}
+            type="button"
+          >
+            Cancel calibration
           </Button>
           {message}
         </form>
+        {calibrationState ? (
+          <CalibrationStateLine status={calibrationState} />
+        ) : null}
+        {lastCalibration?.type === 'CalibrationProgress' ? (
+          <CalibrationProgressLine progress={lastCalibration} />
+        ) : lastCalibration?.type === 'ContinuousCalibrationStep' ? (
+          <ContinuousCalibrationLine step={lastCalibration} />
+        ) : lastCalibration?.type === 'MarkerCaptured' ? (
+          <div>
+            Captured {lastCalibration.captured}/{lastCalibration.pointCount}{' '}
+            markers
+          </div>
+        ) : lastCalibration ? (
+          <CalibrationOutcome calibration={lastCalibration} />
+        ) : null}
       </Pane>
     </div>
   )
 }
 
+function CalibrationStateLine({ status }: { status: CalibrationState }) {
+  const { state } = status
+  return (
+    <div
+      css={{ color: state.type === 'Failed' ? 'var(--base-8)' : undefined }}
+    >
+      {status.kind} calibration of {status.clients.join(', ')} in room{' '}
+      {status.room}: {state.type}
+      {state.type === 'Failed' ? ` (${state.reason})` : null}
+    </div>
+  )
+}
+
+function CalibrationProgressLine({
+  progress,
+}: {
+  progress: CalibrationProgress
+}) {
+  const { coverage } = progress
+  return (
+    <div>
+      Collecting: {progress.targetSamples}/{progress.sampleCount} target,{' '}
+      {progress.referenceSamples}/{progress.sampleCount} reference,{' '}
+      {coverage.rotation_deltas} rotations, axis condition{' '}
+      {coverage.axis_condition > 1000
+        ? '∞'
+        : coverage.axis_condition.toFixed(1)}
+      {coverage.sufficient ? ' (enough)' : ' (keep rotating)'}
+    </div>
+  )
+}
+
+function ContinuousCalibrationLine({
+  step,
+}: {
+  step: ContinuousCalibrationStep
+}) {
+  const mm = (v: number | null) => (v === null ? '?' : v.toFixed(1) + ' mm')
+  if (!step.result) {
+    return (
+      <div css={{ color: 'var(--base-8)' }}>
+        Continuous calibration: {step.error}
+      </div>
+    )
+  }
+  return (
+    <div>
+      Continuous calibration: {step.result.verdict}, current error{' '}
+      {mm(step.currentErrorMm)}, new estimate {mm(step.estimateErrorMm)}
+      {step.applied ? ' (corrected)' : ' (kept)'}
+    </div>
+  )
+}
+
+function CalibrationOutcome({
+  calibration,
+}: {
+  calibration: CalibrationFinished
+}) {
+  const { result, error } = calibration
+  if (!result) {
+    return (
+      <div css={{ color: 'var(--base-8)' }}>Calibration failed: {error}</div>
+    )
+  }
+  const { quality } = result
+  return (
+    <div
+      css={{
+        color: result.verdict === 'Good' ? undefined : 'var(--base-8)',
+      }}
+    >
+      Last calibration: {result.verdict}, {quality.inliers}/{result.pairs}{' '}
+      pairs, error {quality.rms_position_error_mm.toFixed(1)} mm /{' '}
+      {quality.rms_angle_error_deg.toFixed(2)}°, axis condition{' '}
+      {quality.axis_condition.toFixed(1)}
+    </div>
+  )
+}
+
+/**
+ * Every other client which has a device is calibrated directly against the
+ * reference. The target's device is used where the client has it.
+ */
+function batchTargets(
+  serverState: ConfigurationSnapshotSet,
+  referenceId: number,
+  subactionPath: string,
+) {
+  return Object.entries(serverState.clients)
+    .filter(([id, client]) => isSelectableClient(id, client, referenceId))
+    .map(([id, client]) => ({
+      clientId: +id,
+      subactionPath: client.user_paths.includes(subactionPath)
+        ? subactionPath
+        : client.user_paths[0],
+      parentId: null,
+    }))
+}
+
 function isSelectableClient(
   clientId: number | string,
   client: RemoteConfigurationSnapshot | null,
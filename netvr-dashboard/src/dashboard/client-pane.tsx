@@ -3,7 +3,7 @@ import { ReactNode, useState } from 'react'
 import { Pane, Button, Input } from '../components/design'
 import { RemoteConfigurationSnapshot, StateSnapshot } from '../protocol/data'
 import * as sentMessages from '../protocol/sent-messages'
-import { ClientId } from '../protocol/recieved-messages'
+import { ClientId, ConnectionQuality } from '../protocol/recieved-messages'
 
 /**
  * Shows information about a client which is connected to the server.
@@ -17,15 +17,20 @@ export function ClientPane({
   clientId,
   sendMessage,
   stateSnapshot,
+  quality,
 }: {
   client: RemoteConfigurationSnapshot
   clientId: ClientId
   sendMessage: sentMessages.SendMessage
   stateSnapshot: StateSnapshot | null
+  quality: ConnectionQuality | null
 }) {
   function resetCalibration() {
     sendMessage({ type: 'ResetCalibration', clientId })
   }
+  function revoke() {
+    sendMessage({ type: 'RevokeClient', clientId })
+  }
 
   return (
     <Pane id={'client-' + clientId} title={`Client ${clientId}`}>
@@ -45,10 +50,16 @@ export function ClientPane({
               sendMessage({ type: 'SetName', name, clientId })
             }}
           />
-          <Button type="button" onClick={resetCalibration}>
-            Reset
-          </Button>
+          <div css={{ display: 'flex', gap: 6 }}>
+            <Button type="button" onClick={resetCalibration}>
+              Reset
+            </Button>
+            <Button type="button" onClick={revoke}>
+              Revoke
+            </Button>
+          </div>
         </div>
+        {quality ? <QualityLine quality={quality} /> : null}
         {client.user_paths?.map((userPath) => {
           return (
             <Device
@@ -64,6 +75,28 @@ export function ClientPane({
   )
 }
 
+function QualityLine({ quality }: { quality: ConnectionQuality }) {
+  const loss = quality.sentPackets
+    ? (quality.lostPackets / quality.sentPackets) * 100
+    : 0
+  const bad = quality.rttMs > 50 || loss > 2 || quality.snapshotJitterMs > 20
+  return (
+    <div
+      css={{
+        paddingInline: 8,
+        color: bad ? 'var(--base-8)' : undefined,
+      }}
+    >
+      RTT {quality.rttMs.toFixed(1)} ms, jitter{' '}
+      {quality.snapshotJitterMs.toFixed(1)} ms, loss {loss.toFixed(1)} %
+      {quality.datagramDrops ? `, ${quality.datagramDrops} dropped` : null}
+      {quality.clockOffsetMs !== null && quality.clockUncertaintyMs !== null
+        ? `, clock ${quality.clockOffsetMs.toFixed(1)} ± ${quality.clockUncertaintyMs.toFixed(1)} ms`
+        : null}
+    </div>
+  )
+}
+
 function NameInput({
   remoteValue,
   submit,
@@ -19,7 +19,24 @@ import { useLocalStorage } from '../utils'
 import { QuickActionsPane } from './quick-actions-pane'
 import { ClientPane } from './client-pane'
 import { SendMessage } from '../protocol/sent-messages'
-import { DashboardMessageDown, DatagramUp } from '../protocol/recieved-messages'
+import {
+  CalibrationFinished,
+  CalibrationOffer,
+  CalibrationProgress,
+  CalibrationRecord,
+  CalibrationState,
+  MarkerCaptured,
+  ClientId,
+  ConnectionQuality,
+  ContinuousCalibrationStep,
+  DashboardMessageDown,
+  DatagramUp,
+  ReplayStatus,
+  Rooms,
+} from '../protocol/recieved-messages'
+import { RoomsPane } from './rooms-pane'
+import { CalibrationHistoryPane } from './calibration-history-pane'
+import { ReplayPane } from './replay-pane'
 import { DatagramState, mergeData } from './merge-data'
 
 enableMapSet()
@@ -119,6 +136,27 @@ function DashboardInner() {
 
   const [configurationSnapshot, setConfigurationSnapshot] =
     useState<ConfigurationSnapshotSet | null>(null)
+  const [rooms, setRooms] = useState<Rooms | null>(null)
+  const [replay, setReplay] = useState<ReplayStatus | null>(null)
+  const [calibration, setCalibration] = useState<
+    | CalibrationProgress
+    | CalibrationFinished
+    | ContinuousCalibrationStep
+    | MarkerCaptured
+    | null
+  >(null)
+  const [calibrationState, setCalibrationState] =
+    useState<CalibrationState | null>(null)
+  const [calibrationHistory, setCalibrationHistory] = useState<
+    readonly CalibrationRecord[]
+  >([])
+  const [calibrationOffers, setCalibrationOffers] = useState<
+    readonly CalibrationOffer[]
+  >([])
+  const [revoked, setRevoked] = useState<readonly string[]>([])
+  const [quality, setQuality] = useState<{
+    [id: ClientId]: ConnectionQuality
+  }>({})
   const [datagramData, dispatchDatagram] = useReducer(datagramReducer, {})
   const mergedData = useMemo(
     () => mergeData(datagramData, configurationSnapshot),
@@ -144,6 +182,40 @@ function DashboardInner() {
             dispatchDatagram({ now: Date.now(), datagram: msg })
           } else if (msg.type === 'ConfigurationSnapshotChanged') {
             setConfigurationSnapshot(msg.value)
+          } else if (msg.type === 'RevokedClients') {
+            setRevoked(msg.names)
+          } else if (msg.type === 'Rooms') {
+            setRooms(msg)
+          } else if (msg.type === 'ReplayStatus') {
+            setReplay(msg)
+          } else if (msg.type === 'ConnectionQuality') {
+            setQuality((prev) => ({ ...prev, [msg.id]: msg }))
+          } else if (
+            msg.type === 'CalibrationProgress' ||
+            msg.type === 'CalibrationFinished' ||
+            msg.type === 'ContinuousCalibrationStep' ||
+            msg.type === 'MarkerCaptured'
+          ) {
+            setCalibration(msg)
+          } else if (msg.type === 'CalibrationState') {
+            setCalibrationState(msg)
+          } else if (msg.type === 'CalibrationHistory') {
+            setCalibrationHistory(msg.records)
+          } else if (msg.type === 'CalibrationStored') {
+            setCalibrationHistory((prev) => [...prev, msg.record])
+            setCalibrationOffers((prev) =>
+              prev.filter((offer) => offer.record.name !== msg.record.name),
+            )
+          } else if (msg.type === 'CalibrationOffer') {
+            setCalibrationOffers((prev) => [
+              ...prev.filter((offer) => offer.id !== msg.id),
+              msg,
+            ])
+          } else if (msg.type === 'ConnectionClosed') {
+            setQuality(({ [msg.id]: _, ...rest }) => rest)
+            setCalibrationOffers((prev) =>
+              prev.filter((offer) => offer.id !== msg.id),
+            )
           }
         }}
       />
@@ -160,15 +232,30 @@ function DashboardInner() {
             <ThemeSelector />
             <QuickActionsPane
               sendMessage={sendMessage}
+              revoked={revoked}
               closeSocket={() => void socket.close()}
             />
+            {rooms ? (
+              <RoomsPane rooms={rooms} sendMessage={sendMessage} />
+            ) : null}
+            {replay ? (
+              <ReplayPane status={replay} sendMessage={sendMessage} />
+            ) : null}
             <ErrorBoundary>
               <CalibrationPane
                 sendMessage={sendMessage}
                 serverState={configurationSnapshot}
                 mergedData={mergedData}
+                lastCalibration={calibration}
+                calibrationState={calibrationState}
               />
             </ErrorBoundary>
+            <CalibrationHistoryPane
+              records={calibrationHistory}
+              offers={calibrationOffers}
+              serverState={configurationSnapshot}
+              sendMessage={sendMessage}
+            />
             <StatePane data={mergedData} />
 
             {Object.entries(configurationSnapshot.clients).map(
@@ -182,6 +269,7 @@ function DashboardInner() {
                     clientId={clientId}
                     sendMessage={sendMessage}
                     stateSnapshot={datagramData[clientId]?.snapshot ?? null}
+                    quality={quality[clientId] ?? null}
                   />
                 )
               },
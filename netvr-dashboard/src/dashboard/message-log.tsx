@@ -29,7 +29,10 @@ function logReducer(
   if (action.type !== 'text') {
     return state
   }
-  if (action.parsed.type !== 'DatagramUp') {
+  if (
+    action.parsed.type !== 'DatagramUp' &&
+    action.parsed.type !== 'ConnectionQuality'
+  ) {
     return {
       ...state,
       events: state.events.concat({
@@ -11,8 +11,9 @@ import * as sentMessages from '../protocol/sent-messages'
 export function QuickActionsPane(props: {
   sendMessage: sentMessages.SendMessage
   closeSocket: () => void
+  revoked: readonly string[]
 }) {
-  const { sendMessage, closeSocket } = props
+  const { sendMessage, closeSocket, revoked } = props
 
   return (
     <Pane title="Quick actions" id="quick-actions">
@@ -45,7 +46,24 @@ export function QuickActionsPane(props: {
         >
           Disconnect all clients
         </Button>
+        <Button
+          type="button"
+          onClick={() => void sendMessage({ type: 'ListClients' })}
+        >
+          List admitted clients
+        </Button>
       </div>
+      {revoked.map((name) => (
+        <div key={name} css={{ display: 'flex', gap: 6, alignItems: 'center' }}>
+          Revoked {name}
+          <Button
+            type="button"
+            onClick={() => void sendMessage({ type: 'UnrevokeClient', name })}
+          >
+            Allow again
+          </Button>
+        </div>
+      ))}
     </Pane>
   )
 }
@@ -0,0 +1,72 @@
+/** @jsxImportSource @emotion/react */
+import { Pane, Button, Input } from '../components/design'
+import * as sentMessages from '../protocol/sent-messages'
+import { ReplayStatus } from '../protocol/recieved-messages'
+
+const speeds = [0.25, 0.5, 1, 2, 4]
+
+function formatNanos(nanos: number) {
+  const seconds = Math.floor(nanos / 1e9)
+  return `${Math.floor(seconds / 60)}:${(seconds % 60)
+    .toString()
+    .padStart(2, '0')}`
+}
+
+/**
+ * Controls for the recorded session when server runs in replay mode.
+ * @param props
+ * @returns
+ */
+export function ReplayPane({
+  status,
+  sendMessage,
+}: {
+  status: ReplayStatus
+  sendMessage: sentMessages.SendMessage
+}) {
+  return (
+    <Pane title="Replay" id="replay">
+      <div
+        css={{ display: 'flex', gap: 6, alignItems: 'center', flexWrap: 'wrap' }}
+      >
+        <Button
+          type="button"
+          onClick={() =>
+            void sendMessage({
+              type: status.playing ? 'ReplayPause' : 'ReplayPlay',
+            })
+          }
+        >
+          {status.playing ? 'Pause' : 'Play'}
+        </Button>
+        <span>
+          {formatNanos(status.positionNanos)} /{' '}
+          {formatNanos(status.durationNanos)}
+        </span>
+        {speeds.map((speed) => (
+          <Button
+            type="button"
+            key={speed}
+            disabled={speed === status.speed}
+            onClick={() => void sendMessage({ type: 'ReplaySetSpeed', speed })}
+          >
+            {speed}×
+          </Button>
+        ))}
+      </div>
+      <Input
+        type="range"
+        css={{ width: '100%' }}
+        min={0}
+        max={status.durationNanos}
+        value={status.positionNanos}
+        onChange={(evt) =>
+          void sendMessage({
+            type: 'ReplaySeek',
+            positionNanos: Number(evt.currentTarget.value),
+          })
+        }
+      />
+    </Pane>
+  )
+}
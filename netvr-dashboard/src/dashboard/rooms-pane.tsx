@@ -0,0 +1,100 @@
+/** @jsxImportSource @emotion/react */
+import { useState } from 'react'
+import { Pane, Button, Input } from '../components/design'
+import * as sentMessages from '../protocol/sent-messages'
+import { Rooms } from '../protocol/recieved-messages'
+
+/**
+ * Lists rooms on the server, allows choosing which one is shown in the
+ * dashboard, moving clients between them and changing how often their clients
+ * receive state.
+ * @param props
+ * @returns
+ */
+export function RoomsPane({
+  rooms,
+  sendMessage,
+}: {
+  rooms: Rooms
+  sendMessage: sentMessages.SendMessage
+}) {
+  const [target, setTarget] = useState('')
+
+  return (
+    <Pane title="Rooms" id="rooms">
+      <div
+        css={{ display: 'flex', gap: 6, marginBlockEnd: 8, flexWrap: 'wrap' }}
+      >
+        <Button
+          type="button"
+          disabled={rooms.watching === null}
+          onClick={() => void sendMessage({ type: 'WatchRoom', room: null })}
+        >
+          Watch all rooms
+        </Button>
+        <label css={{ display: 'flex', gap: 4, alignItems: 'center' }}>
+          Move to:
+          <Input
+            value={target}
+            placeholder="room name"
+            onChange={(evt) => void setTarget(evt.currentTarget.value)}
+          />
+        </label>
+      </div>
+      {rooms.rooms.map((room) => (
+        <div
+          key={room.name}
+          css={{
+            display: 'flex',
+            gap: 6,
+            alignItems: 'center',
+            flexWrap: 'wrap',
+            paddingBlock: 4,
+          }}
+        >
+          <Button
+            type="button"
+            disabled={rooms.watching === room.name}
+            onClick={() =>
+              void sendMessage({ type: 'WatchRoom', room: room.name })
+            }
+          >
+            Watch
+          </Button>
+          <span>
+            {room.name} ({room.clients.length} clients)
+          </span>
+          <label css={{ display: 'flex', gap: 4, alignItems: 'center' }}>
+            State Hz:
+            <Input
+              type="number"
+              min={1}
+              max={240}
+              defaultValue={room.stateRate}
+              key={room.stateRate}
+              css={{ width: 60 }}
+              onBlur={(evt) => {
+                const rate = evt.currentTarget.valueAsNumber
+                if (Number.isFinite(rate) && rate !== room.stateRate) {
+                  sendMessage({ type: 'SetStateRate', room: room.name, rate })
+                }
+              }}
+            />
+          </label>
+          {room.clients.map((clientId) => (
+            <Button
+              type="button"
+              key={clientId}
+              disabled={!target || target === room.name}
+              onClick={() =>
+                void sendMessage({ type: 'MoveClient', clientId, room: target })
+              }
+            >
+              Move {clientId}
+            </Button>
+          ))}
+        </div>
+      ))}
+    </Pane>
+  )
+}
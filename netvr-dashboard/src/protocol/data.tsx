@@ -58,4 +58,6 @@ export type StateSnapshot = {
   }[]
   view: Pose
   required_configuration: number
+  /** Capture time in sender's clock, zero if unknown */
+  nanos: number
 }
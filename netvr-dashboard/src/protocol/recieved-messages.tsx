@@ -1,4 +1,4 @@
-import type { ConfigurationSnapshotSet, StateSnapshot } from './data'
+import type { ConfigurationSnapshotSet, Pose, StateSnapshot } from './data'
 
 /**
  * Corresponds with SocketAddr in Rust.
@@ -50,6 +50,217 @@ export type ConfigurationSnapshotChanged = {
   value: ConfigurationSnapshotSet
 }
 
+/**
+ * Answer to ListClients. Contains clients which passed the handshake.
+ */
+export type AdmittedClients = {
+  type: 'AdmittedClients'
+  clients: { id: ClientId; addr: SocketAddr; room: string }[]
+}
+
+/**
+ * Names of clients which may not join. Sent on Init and on change.
+ */
+export type RevokedClients = {
+  type: 'RevokedClients'
+  names: string[]
+}
+
+/**
+ * Rooms matching what the dashboard watches. Sent on Init and whenever rooms
+ * change. watching is null if all rooms are shown.
+ */
+export type Rooms = {
+  type: 'Rooms'
+  rooms: { name: string; clients: ClientId[]; stateRate: number }[]
+  watching: string | null
+}
+
+/**
+ * Link quality of a client. Sent every second for each connected client.
+ */
+export type ConnectionQuality = {
+  type: 'ConnectionQuality'
+  id: ClientId
+  rttMs: number
+  /** Congestion window in bytes */
+  cwnd: number
+  sentPackets: number
+  lostPackets: number
+  /** Outgoing datagrams dropped because the send buffer was full */
+  datagramDrops: number
+  /** Smoothed time between state snapshots from the client */
+  snapshotIntervalMs: number
+  /** Variation of the time between state snapshots */
+  snapshotJitterMs: number
+  /** Client clock minus server clock, null before the first pong */
+  clockOffsetMs: number | null
+  /** How far the true offset can be from clockOffsetMs */
+  clockUncertaintyMs: number | null
+}
+
+/**
+ * Progress of session replay. Only sent when server runs with --replay.
+ */
+export type ReplayStatus = {
+  type: 'ReplayStatus'
+  playing: boolean
+  positionNanos: number
+  durationNanos: number
+  speed: number
+}
+
+/**
+ * Whether a calibration can be trusted. Unreliable ones are not applied.
+ */
+export type CalibrationVerdict =
+  | 'Good'
+  | 'Noisy'
+  | 'PoorCoverage'
+  | 'Unreliable'
+
+/**
+ * Result of a calibration along with how well it fits the samples.
+ */
+export type CalibrationResult = {
+  translation: { x: number; y: number; z: number }
+  rotation: { x: number; y: number; z: number; w: number }
+  pairs: number
+  quality: {
+    inliers: number
+    inlier_ratio: number
+    rms_position_error_mm: number
+    rms_angle_error_deg: number
+    /** Large if the devices were only rotated around one axis */
+    axis_condition: number
+  }
+  verdict: CalibrationVerdict
+}
+
+/**
+ * Sent twice a second while calibration samples are being collected.
+ */
+export type CalibrationProgress = {
+  type: 'CalibrationProgress'
+  target: ClientId
+  reference: ClientId
+  targetSamples: number
+  referenceSamples: number
+  sampleCount: number
+  coverage: {
+    pairs: number
+    /** Pairs rotated far enough from each other, in the worse half */
+    rotation_deltas: number
+    /** Large if the devices were only rotated around one axis */
+    axis_condition: number
+    /** Enough has been collected to stop early */
+    sufficient: boolean
+  }
+}
+
+/**
+ * Sent when a calibration finishes. Result is null if it could not be
+ * computed, error says why.
+ */
+export type CalibrationFinished = {
+  type: 'CalibrationFinished'
+  target: ClientId
+  reference: ClientId
+  result: CalibrationResult | null
+  error: string | null
+}
+
+/**
+ * Sent every time continuous calibration solves its window. Errors of the
+ * current calibration and of the new estimate are measured on the same samples.
+ */
+export type ContinuousCalibrationStep = {
+  type: 'ContinuousCalibrationStep'
+  target: ClientId
+  reference: ClientId
+  result: CalibrationResult | null
+  error: string | null
+  currentErrorMm: number | null
+  estimateErrorMm: number | null
+  /** Whether a correction was sent to the target */
+  applied: boolean
+}
+
+/**
+ * Marker calibration captured positions of all devices on a marker.
+ */
+export type MarkerCaptured = {
+  type: 'MarkerCaptured'
+  captured: number
+  pointCount: number
+}
+
+/**
+ * Calibration in a room moved to another phase. Clients are all which take
+ * part, the reference first. Failed works as idle, next calibration can start.
+ */
+export type CalibrationState = {
+  type: 'CalibrationState'
+  room: string
+  kind: string
+  clients: readonly ClientId[]
+  state:
+    | { type: 'Idle' }
+    | { type: 'Triggering' }
+    | { type: 'Collecting' }
+    | { type: 'Solving' }
+    | { type: 'Applying' }
+    | { type: 'Failed'; reason: string }
+}
+
+/**
+ * Calibration applied to a client, kept on the server by client name.
+ */
+export type CalibrationRecord = {
+  id: number
+  name: string
+  referenceName: string
+  /** ISO 8601 */
+  time: string
+  source:
+    | 'Samples'
+    | 'Reapply'
+    | 'Continuous'
+    | 'Batch'
+    | 'Markers'
+    | 'Rollback'
+  /** Server space pose sent to the client */
+  pose: Pose
+  /** Null for rollbacks, which only copy the pose */
+  result: CalibrationResult | null
+}
+
+/**
+ * All calibrations stored on the server, oldest first. Sent on Init.
+ */
+export type CalibrationHistory = {
+  type: 'CalibrationHistory'
+  records: CalibrationRecord[]
+}
+
+/**
+ * Sent when a calibration is applied and stored.
+ */
+export type CalibrationStored = {
+  type: 'CalibrationStored'
+  record: CalibrationRecord
+}
+
+/**
+ * Sent when a client connects which has a good stored calibration, unless
+ * the server applies it automatically.
+ */
+export type CalibrationOffer = {
+  type: 'CalibrationOffer'
+  id: ClientId
+  record: CalibrationRecord
+}
+
 /**
  * Message sent from server to dashboard.
  */
@@ -59,3 +270,16 @@ export type DashboardMessageDown =
   | ConnectionClosed
   | FullyConnected
   | ConnectionEstablished
+  | AdmittedClients
+  | RevokedClients
+  | Rooms
+  | ReplayStatus
+  | ConnectionQuality
+  | CalibrationProgress
+  | CalibrationFinished
+  | ContinuousCalibrationStep
+  | MarkerCaptured
+  | CalibrationState
+  | CalibrationHistory
+  | CalibrationStored
+  | CalibrationOffer
@@ -5,6 +5,12 @@ import type { ClientId } from './recieved-messages'
  */
 export type SendMessage = (message: DashboardMessageUp) => void
 
+/**
+ * Full solves rotation around all axes, GravityAligned only around the
+ * vertical one.
+ */
+export type CalibrationSolver = 'Full' | 'GravityAligned'
+
 /**
  * Messages that dashboard can send to server.
  */
@@ -17,6 +23,9 @@ export type DashboardMessageUp =
         | 'CalibrateByHeadsetPosition'
         | 'ResetAllCalibrations'
         | 'ForceDisconnectAll'
+        | 'ListClients'
+        | 'ReplayPlay'
+        | 'ReplayPause'
     }
   | { type: 'ResetCalibration'; clientId: ClientId }
   | {
@@ -29,6 +38,9 @@ export type DashboardMessageUp =
       conf: {
         sample_count: number
         sample_interval_nanos: number
+        /** Stop before sample_count once samples cover enough rotations */
+        stop_on_coverage?: boolean
+        solver?: CalibrationSolver
       }
     }
   | {
@@ -38,7 +50,56 @@ export type DashboardMessageUp =
       referenceId: ClientId
       referenceSubactionPath: string
     }
+  | {
+      /** Keeps correcting drift until FinishCalibration */
+      type: 'StartContinuousCalibration'
+      targetId: ClientId
+      targetSubactionPath: string
+      referenceId: ClientId
+      referenceSubactionPath: string
+
+      /** sample_count is the size of the sliding window */
+      conf: {
+        sample_count: number
+        sample_interval_nanos: number
+        solver?: CalibrationSolver
+      }
+    }
+  | {
+      /** Calibrates all targets at once */
+      type: 'StartBatchCalibration'
+      referenceId: ClientId
+      referenceSubactionPath: string
+      targets: {
+        clientId: ClientId
+        subactionPath: string
+        /** Target is solved against this client, the reference if null */
+        parentId: ClientId | null
+      }[]
+
+      conf: {
+        sample_count: number
+        sample_interval_nanos: number
+        stop_on_coverage?: boolean
+        solver?: CalibrationSolver
+      }
+    }
+  | {
+      /**
+       * Calibrates all targets by devices placed on pointCount shared
+       * markers, CaptureMarker is sent for each of them
+       */
+      type: 'StartMarkerCalibration'
+      referenceId: ClientId
+      referenceSubactionPath: string
+      targets: { clientId: ClientId; subactionPath: string }[]
+      pointCount: number
+    }
+  /** All devices of marker calibration are on the next marker */
+  | { type: 'CaptureMarker' }
   | { type: 'FinishCalibration' }
+  /** Stops running calibrations without applying anything */
+  | { type: 'CancelCalibration' }
   | {
       type: 'ReapplyCalibration'
       targetId: ClientId
@@ -47,9 +108,24 @@ export type DashboardMessageUp =
       referenceSubactionPath: string
       data: any
     }
+  | {
+      /** Used for rollback and for accepting offered calibrations */
+      type: 'ApplyStoredCalibration'
+      clientId: ClientId
+      recordId: number
+    }
   | {
       type: 'TriggerHapticImpulse'
       clientId: ClientId
       subactionPath: string
     }
   | { type: 'SetName'; name: string; clientId: number }
+  /** Disconnects the client and refuses clients with its name */
+  | { type: 'RevokeClient'; clientId: ClientId }
+  /** Lets clients with the name join again */
+  | { type: 'UnrevokeClient'; name: string }
+  | { type: 'WatchRoom'; room: string | null }
+  | { type: 'MoveClient'; clientId: ClientId; room: string }
+  | { type: 'SetStateRate'; room: string; rate: number }
+  | { type: 'ReplaySeek'; positionNanos: number }
+  | { type: 'ReplaySetSpeed'; speed: number }
@@ -2,13 +2,14 @@
 #![allow(dead_code)]
 #![allow(unreachable_code)]
 
-use std::cmp::min;
-
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use nalgebra::{
     Const, Dyn, Matrix3, OMatrix, Quaternion, Rotation3, RowVector3, UnitQuaternion, Vector3,
 };
-use netvr_data::{net::CalibrationSample, Vec3};
+use netvr_data::{
+    net::{CalibrationSample, CalibrationSolver, ClockEstimate},
+    Vec3,
+};
 
 use crate::input::*;
 
@@ -111,7 +112,32 @@ fn DeltaRotationSamples(s1: SamplePairF64, s2: SamplePairF64) -> Option<DSample>
     }
 }
 
-fn CalibrateRotation(samples: &[SamplePairF64]) -> Rotation3<f64> {
+fn RotationDeltas(samples: &[SamplePairF64]) -> Vec<DSample> {
+    let mut deltas = vec![];
+    for i in 0..samples.len() {
+        for j in 0..i {
+            let delta = DeltaRotationSamples(samples[i], samples[j]);
+            if let Some(delta) = delta {
+                deltas.push(delta);
+            }
+        }
+    }
+    deltas
+}
+
+/// How evenly the rotation axes cover all directions. One if they do
+/// perfectly, large if they are mostly around one axis.
+fn AxisCondition(deltas: &[DSample]) -> f64 {
+    let mut axes = OMatrix::<f64, Dyn, Const<3>>::zeros(deltas.len());
+    for (i, delta) in deltas.iter().enumerate() {
+        axes.set_row(i, &delta.reference);
+    }
+    let eigenvalues = (axes.transpose() * &axes).symmetric_eigenvalues();
+    (eigenvalues.max() / eigenvalues.min().max(f64::EPSILON)).sqrt()
+}
+
+/// Returns the rotation along with condition number of the rotation axes
+fn CalibrateRotation(samples: &[SamplePairF64]) -> Result<(Rotation3<f64>, f64)> {
     // std::vector<DSample> deltas;
     // for (size_t i = 0; i < samples.size(); i++)
     // {
@@ -123,14 +149,9 @@ fn CalibrateRotation(samples: &[SamplePairF64]) -> Rotation3<f64> {
     //     }
     // }
 
-    let mut deltas = vec![];
-    for i in 0..samples.len() {
-        for j in 0..i {
-            let delta = DeltaRotationSamples(samples[i], samples[j]);
-            if let Some(delta) = delta {
-                deltas.push(delta);
-            }
-        }
+    let deltas = RotationDeltas(samples);
+    if deltas.is_empty() {
+        bail!("Devices were not rotated enough");
     }
 
     // Kabsch algorithm
@@ -156,6 +177,7 @@ fn CalibrateRotation(samples: &[SamplePairF64]) -> Rotation3<f64> {
         targetPoints.set_row(i, &delta.target);
         targetCentroid += deltas[i].target;
     }
+    let axis_condition = AxisCondition(&deltas);
     // refCentroid /= (double)deltas.size();
     // targetCentroid /= (double)deltas.size();
     refCentroid /= deltas.len() as f64;
@@ -200,7 +222,46 @@ fn CalibrateRotation(samples: &[SamplePairF64]) -> Rotation3<f64> {
 
     // let (roll, pitch, yaw) = Rotation3::from_matrix(&rot).euler_angles();
     // Vector3::new(yaw, pitch, roll) // I think?
-    Rotation3::from_matrix(&rot)
+    Ok((Rotation3::from_matrix(&rot), axis_condition))
+}
+
+/// Yaw which best rotates target directions onto reference directions. Rotation
+/// around the vertical axis does not change the vertical components, so only
+/// the horizontal ones matter.
+fn best_yaw(pairs: impl Iterator<Item = (Vector3<f64>, Vector3<f64>)>) -> UnitQuaternion<f64> {
+    let (mut cos, mut sin) = (0., 0.);
+    for (t, r) in pairs {
+        cos += r.x * t.x + r.z * t.z;
+        sin += r.x * t.z - r.z * t.x;
+    }
+    UnitQuaternion::from_axis_angle(&Vector3::y_axis(), sin.atan2(cos))
+}
+
+/// Rotation axes closer to vertical than this on average do not determine
+/// the yaw
+const MIN_HORIZONTAL_AXIS: f64 = 0.1;
+
+/// Like CalibrateRotation, but only solves rotation around the vertical axis.
+/// Axes are not centered, the rotation maps them onto each other directly.
+fn calibrate_yaw(samples: &[SamplePairF64]) -> Result<(Rotation3<f64>, f64)> {
+    let deltas = RotationDeltas(samples);
+    if deltas.is_empty() {
+        bail!("Devices were not rotated enough");
+    }
+    let horizontal = deltas
+        .iter()
+        .map(|delta| delta.target[0].hypot(delta.target[2]))
+        .sum::<f64>()
+        / deltas.len() as f64;
+    if horizontal < MIN_HORIZONTAL_AXIS {
+        bail!("Devices were only rotated around the vertical axis");
+    }
+    let yaw = best_yaw(
+        deltas
+            .iter()
+            .map(|delta| (delta.target.transpose(), delta.reference.transpose())),
+    );
+    Ok((yaw.to_rotation_matrix(), AxisCondition(&deltas)))
 }
 
 fn CalibrateTranslation(samples: &[SamplePairF64]) -> Result<RowVector3<f64>> {
@@ -254,12 +315,6 @@ fn CalibrateTranslation(samples: &[SamplePairF64]) -> Result<RowVector3<f64>> {
         }
     }
 
-    println!("samples len: {}", samples.len());
-    println!("delta len: {}", deltas.len());
-    println!("constants len: {}", constants.len());
-    println!("coefficients len: {}", coefficients.len());
-    // println!("coefficients: {:#?}", coefficients);
-    // println!("{:#?}", constants);
     // Eigen::Vector3d trans = coefficients.bdcSvd(Eigen::ComputeThinU |
     // Eigen::ComputeThinV).solve(constants);
     // auto transcm = trans * 100.0;
@@ -271,35 +326,154 @@ fn CalibrateTranslation(samples: &[SamplePairF64]) -> Result<RowVector3<f64>> {
     Ok(RowVector3::new(trans[0], trans[1], trans[2]))
 }
 
+/// XR_SPACE_LOCATION_{ORIENTATION,POSITION}_VALID_BIT
+const VALID_FLAGS: u64 = 0b11;
+
+/// Samples without valid position and orientation are useless
+pub fn is_valid(sample: &CalibrationSample) -> bool {
+    sample.flags & VALID_FLAGS == VALID_FLAGS
+}
+
+/// Sample time in server clock
+fn sample_time(sample: &CalibrationSample, clock: Option<ClockEstimate>) -> i64 {
+    clock.map_or(sample.nanos, |clock| clock.to_server(sample.nanos))
+}
+
+/// Pose between samples a and b, t is from 0 to 1
+fn interpolate_sample(a: &CalibrationSample, b: &CalibrationSample, t: f64) -> SampleF64 {
+    let position =
+        convert_vector(a.pose.position.clone()).lerp(&convert_vector(b.pose.position.clone()), t);
+    let (qa, qb) = (
+        convert_quaternion(a.pose.orientation.clone()),
+        convert_quaternion(b.pose.orientation.clone()),
+    );
+    // samples half a turn apart are not interpolated, the nearer one is used
+    let rotation = qa
+        .try_slerp(&qb, t, f64::EPSILON)
+        .unwrap_or(if t < 0.5 { qa } else { qb });
+    SampleF64 {
+        pose: PoseF64 {
+            position,
+            rotation: rotation.to_rotation_matrix().into(),
+        },
+    }
+}
+
+/// Pairs samples taken at the same time as configured by input.pairing.
+/// Samples with invalid pose are skipped.
 fn match_samples(input: &CalibrationInput) -> Vec<SamplePairF64> {
+    if input.pairing == SamplePairing::Order {
+        return input
+            .reference
+            .iter()
+            .zip(&input.target)
+            .filter(|(reference, target)| is_valid(reference) && is_valid(target))
+            .map(|(reference, target)| SamplePairF64 {
+                reference: reference.clone().into(),
+                target: target.clone().into(),
+            })
+            .collect();
+    }
+
+    let reference = input
+        .reference
+        .iter()
+        .filter(|sample| is_valid(sample))
+        .map(|sample| (sample_time(sample, input.reference_clock), sample))
+        .collect::<Vec<_>>();
+    if reference.len() < 2 {
+        return vec![];
+    }
+    // typical time between samples, reference samples are not looked for
+    // further away than that
+    let interval =
+        (reference[reference.len() - 1].0 - reference[0].0) / (reference.len() as i64 - 1);
+
     let mut matches = vec![];
-    for i in 0..min(input.reference.len(), input.target.len()) {
+    for target in input.target.iter().filter(|sample| is_valid(sample)) {
+        let time = sample_time(target, input.target_clock);
+        let reference = match input.pairing {
+            SamplePairing::Interpolate => interpolated_reference(&reference, time, interval),
+            _ => nearest_reference(&reference, time, interval),
+        };
+        let Some(reference) = reference else { continue; };
         matches.push(SamplePairF64 {
-            reference: input.reference[i].clone().into(),
-            target: input.target[i].clone().into(),
+            reference,
+            target: target.clone().into(),
         });
     }
     matches
 }
 
-/// Compute the calibration from the pairs of samples.
-/// Port of the original C++ code from OpenVR Space Calibrator.
-pub fn calibrate(samples: &CalibrationInput) -> Result<CalibrationResult> {
-    // Notes from original code:
-    // - it applies rotation right when it determines it
-    // - it collects SampleCount for each phase
-    // - it waits at least 50ms (0.05s) between samples
+/// Reference sample closest to time, None if it is more than half of the
+/// interval away
+fn nearest_reference(
+    reference: &[(i64, &CalibrationSample)],
+    time: i64,
+    interval: i64,
+) -> Option<SampleF64> {
+    let next = reference.partition_point(|(reference_time, _)| *reference_time <= time);
+    let around = &reference[next.saturating_sub(1)..(next + 1).min(reference.len())];
+    let (reference_time, sample) = around
+        .iter()
+        .min_by_key(|(reference_time, _)| (reference_time - time).abs())?;
+    if (reference_time - time).abs() * 2 > interval {
+        return None;
+    }
+    Some((*sample).clone().into())
+}
 
-    let matches = match_samples(samples);
+/// Reference pose interpolated between samples around time, None if there
+/// are no samples on one side or they are too far apart
+fn interpolated_reference(
+    reference: &[(i64, &CalibrationSample)],
+    time: i64,
+    interval: i64,
+) -> Option<SampleF64> {
+    let next = reference.partition_point(|(reference_time, _)| *reference_time <= time);
+    let (before_time, before) = reference.get(next.checked_sub(1)?)?;
+    if *before_time == time {
+        return Some((*before).clone().into());
+    }
+    let (after_time, after) = reference.get(next)?;
+    if after_time - before_time > interval * 2 {
+        return None;
+    }
+    let t = (time - before_time) as f64 / (after_time - before_time) as f64;
+    Some(interpolate_sample(before, after, t))
+}
 
-    let rot_samples = matches.len() / 2;
-    let eigen_samples = matches
-        .iter()
-        .take(rot_samples)
-        .copied()
-        .collect::<Vec<SamplePairF64>>();
+/// Fewer pairs than this can not give a meaningful result
+const MIN_PAIRS: usize = 6;
+/// How many times outliers are rejected and the result recomputed
+const MAX_ITERATIONS: usize = 5;
+/// Pairs within these errors are never rejected as outliers
+const MIN_POSITION_LIMIT_MM: f64 = 5.;
+const MIN_ANGLE_LIMIT_DEG: f64 = 1.;
+/// Errors of a good calibration
+const GOOD_POSITION_ERROR_MM: f64 = 10.;
+const GOOD_ANGLE_ERROR_DEG: f64 = 2.;
+/// Larger condition means that rotations were mostly around one axis
+const MAX_AXIS_CONDITION: f64 = 10.;
+/// Smaller share of inliers means that the samples do not describe rigidly
+/// attached devices
+const MIN_INLIER_RATIO: f64 = 0.5;
+
+/// Transform from target space to reference space
+struct Solution {
+    rotation: Rotation3<f64>,
+    translation: Vector3<f64>,
+    axis_condition: f64,
+}
 
-    let rotation = CalibrateRotation(&eigen_samples);
+/// Solves rotation from the first half of the pairs and translation from the
+/// second half
+fn solve(matches: &[SamplePairF64], solver: CalibrationSolver) -> Result<Solution> {
+    let rot_samples = matches.len() / 2;
+    let (rotation, axis_condition) = match solver {
+        CalibrationSolver::Full => CalibrateRotation(&matches[..rot_samples])?,
+        CalibrationSolver::GravityAligned => calibrate_yaw(&matches[..rot_samples])?,
+    };
 
     let eigen_samples = matches
         .iter()
@@ -316,10 +490,290 @@ pub fn calibrate(samples: &CalibrationInput) -> Result<CalibrationResult> {
         .collect::<Vec<SamplePairF64>>();
 
     let translation = CalibrateTranslation(&eigen_samples)?;
-    println!("Solved position: {:?}", translation);
-    let rotation = UnitQuaternion::from_matrix(&rotation.into()).cast::<f32>();
-    println!("Solved rotation: {:?}", rotation.euler_angles());
+    Ok(Solution {
+        rotation,
+        translation: translation.transpose(),
+        axis_condition,
+    })
+}
+
+/// Position (mm) and angle (degrees) by which each of the pairs deviates from
+/// the devices being rigidly attached. The offset between the devices is
+/// averaged from the fit pairs.
+fn residuals(
+    solution: &Solution,
+    fit: &[SamplePairF64],
+    pairs: &[SamplePairF64],
+) -> Vec<(f64, f64)> {
+    let offset = |pair: &SamplePairF64| {
+        let reference = &pair.reference.pose;
+        let inverse = reference.rotation.transpose();
+        let position = solution.rotation * pair.target.pose.position + solution.translation;
+        let rotation = solution.rotation * pair.target.pose.rotation;
+        (
+            inverse * (position - reference.position),
+            UnitQuaternion::from_matrix(&(inverse * rotation)),
+        )
+    };
+    let offsets = fit.iter().map(offset).collect::<Vec<_>>();
+    let position = offsets
+        .iter()
+        .map(|(position, _)| position)
+        .sum::<Vector3<f64>>()
+        / offsets.len() as f64;
+    // q and -q are the same rotation, so flip them to one side before averaging
+    let first = offsets[0].1.coords;
+    let orientation = UnitQuaternion::new_normalize(Quaternion::from_vector(
+        offsets
+            .iter()
+            .map(|(_, q)| {
+                if q.coords.dot(&first) < 0. {
+                    -q.coords
+                } else {
+                    q.coords
+                }
+            })
+            .sum(),
+    ));
+    pairs
+        .iter()
+        .map(|pair| {
+            let (position_offset, orientation_offset) = offset(pair);
+            (
+                (position_offset - position).norm() * 1000.,
+                orientation_offset.angle_to(&orientation).to_degrees(),
+            )
+        })
+        .collect()
+}
+
+/// Values above this are outliers. Uses median absolute deviation, which is
+/// not affected by the outliers themselves.
+fn outlier_limit(values: impl Iterator<Item = f64>, floor: f64) -> f64 {
+    let median = |mut values: Vec<f64>| {
+        values.sort_by(f64::total_cmp);
+        values[values.len() / 2]
+    };
+    let values = values.collect::<Vec<_>>();
+    let center = median(values.clone());
+    let deviation = median(values.iter().map(|v| (v - center).abs()).collect());
+    (center + 3. * 1.4826 * deviation).max(floor)
+}
+
+fn root_mean_square(values: impl Iterator<Item = f64>) -> f64 {
+    let (sum, count) = values.fold((0., 0), |(sum, count), v| (sum + v * v, count + 1));
+    (sum / count as f64).sqrt()
+}
+
+fn verdict(quality: &CalibrationQuality) -> CalibrationVerdict {
+    if quality.inlier_ratio < MIN_INLIER_RATIO {
+        CalibrationVerdict::Unreliable
+    } else if quality.axis_condition > MAX_AXIS_CONDITION {
+        CalibrationVerdict::PoorCoverage
+    } else if quality.rms_position_error_mm > GOOD_POSITION_ERROR_MM
+        || quality.rms_angle_error_deg > GOOD_ANGLE_ERROR_DEG
+    {
+        CalibrationVerdict::Noisy
+    } else {
+        CalibrationVerdict::Good
+    }
+}
+
+/// Rotation deltas each half of the pairs needs for early stop
+const SUFFICIENT_ROTATION_DELTAS: usize = 100;
+
+/// Computes how well the samples collected so far cover rotations. Each half of
+/// the pairs is used for a different part of the solve, so the worse half is
+/// reported.
+pub fn coverage(samples: &CalibrationInput) -> CalibrationCoverage {
+    let matches = match_samples(samples);
+    let (first, second) = matches.split_at(matches.len() / 2);
+    let (first, second) = (RotationDeltas(first), RotationDeltas(second));
+    let rotation_deltas = first.len().min(second.len());
+    let axis_condition = if rotation_deltas == 0 {
+        f64::MAX
+    } else {
+        AxisCondition(&first).max(AxisCondition(&second))
+    };
+    CalibrationCoverage {
+        pairs: matches.len(),
+        rotation_deltas,
+        axis_condition,
+        sufficient: matches.len() >= MIN_PAIRS
+            && rotation_deltas >= SUFFICIENT_ROTATION_DELTAS
+            && axis_condition <= MAX_AXIS_CONDITION,
+    }
+}
+
+/// Compute the calibration from the pairs of samples.
+/// Port of the original C++ code from OpenVR Space Calibrator, extended by
+/// repeatedly rejecting pairs which disagree with the result and solving
+/// again without them. With CalibrationSolver::GravityAligned only yaw and
+/// translation are solved.
+pub fn calibrate(samples: &CalibrationInput) -> Result<CalibrationResult> {
+    // Notes from original code:
+    // - it applies rotation right when it determines it
+    // - it collects SampleCount for each phase
+    // - it waits at least 50ms (0.05s) between samples
+
+    let matches = match_samples(samples);
+    if matches.len() < MIN_PAIRS {
+        bail!(
+            "Only {} sample pairs, at least {} are needed",
+            matches.len(),
+            MIN_PAIRS
+        );
+    }
+
+    let mut inliers = (0..matches.len()).collect::<Vec<_>>();
+    let mut fit = matches.clone();
+    let mut solution = solve(&fit, samples.solver)?;
+    for _ in 0..MAX_ITERATIONS {
+        let residuals = residuals(&solution, &fit, &matches);
+        let position_limit = outlier_limit(residuals.iter().map(|r| r.0), MIN_POSITION_LIMIT_MM);
+        let angle_limit = outlier_limit(residuals.iter().map(|r| r.1), MIN_ANGLE_LIMIT_DEG);
+        let next = (0..matches.len())
+            .filter(|&i| residuals[i].0 <= position_limit && residuals[i].1 <= angle_limit)
+            .collect::<Vec<_>>();
+        if next == inliers || next.len() < MIN_PAIRS {
+            break;
+        }
+        let next_fit = next.iter().map(|&i| matches[i]).collect::<Vec<_>>();
+        let Ok(next_solution) = solve(&next_fit, samples.solver) else { break; };
+        (inliers, fit, solution) = (next, next_fit, next_solution);
+    }
+
+    let residuals = residuals(&solution, &fit, &fit);
+    let quality = CalibrationQuality {
+        inliers: inliers.len(),
+        inlier_ratio: inliers.len() as f64 / matches.len() as f64,
+        rms_position_error_mm: root_mean_square(residuals.iter().map(|r| r.0)),
+        rms_angle_error_deg: root_mean_square(residuals.iter().map(|r| r.1)),
+        axis_condition: solution.axis_condition,
+    };
+    let rotation = UnitQuaternion::from_matrix(&solution.rotation.into()).cast::<f32>();
+
+    Ok(CalibrationResult {
+        rotation: netvr_data::Quaternion {
+            x: rotation.i,
+            y: rotation.j,
+            z: rotation.k,
+            w: rotation.w,
+        },
+        translation: Vec3 {
+            x: solution.translation[0] as f32,
+            y: solution.translation[1] as f32,
+            z: solution.translation[2] as f32,
+        },
+        pairs: matches.len(),
+        verdict: verdict(&quality),
+        quality,
+    })
+}
+
+/// Root mean square position error (mm) of a given calibration on the samples,
+/// ignoring outliers. None if there are not enough pairs. Allows comparing
+/// calibrations on the same data.
+pub fn position_error(
+    samples: &CalibrationInput,
+    translation: Vec3,
+    rotation: netvr_data::Quaternion,
+) -> Option<f64> {
+    let matches = match_samples(samples);
+    if matches.len() < MIN_PAIRS {
+        return None;
+    }
+    let solution = Solution {
+        rotation: convert_quaternion(rotation).to_rotation_matrix(),
+        translation: convert_vector(translation),
+        axis_condition: 0.,
+    };
+    let residuals = residuals(&solution, &matches, &matches);
+    let limit = outlier_limit(residuals.iter().map(|r| r.0), MIN_POSITION_LIMIT_MM);
+    Some(root_mean_square(
+        residuals.iter().map(|r| r.0).filter(|&r| r <= limit),
+    ))
+}
+
+/// At least this many marker points are needed to tell the yaw
+pub const MIN_MARKERS: usize = 2;
+/// Marker points closer to each other than this do not determine the yaw
+const MIN_MARKER_SPREAD_M: f64 = 0.1;
+/// Marker points further apart than this give precise yaw
+const GOOD_MARKER_SPREAD_M: f64 = 0.5;
+/// Devices placed on a marker by hand are rarely closer than this
+const GOOD_MARKER_ERROR_MM: f64 = 20.;
+/// Larger error means that points were swapped or not touched
+const MAX_MARKER_ERROR_MM: f64 = 100.;
+
+/// Computes yaw and translation which map target points onto reference points,
+/// for example positions of controllers placed on the same physical markers.
+/// Both devices are assumed to agree on which way is up, so that two points
+/// are enough. Position error is the distance of the mapped target points
+/// from reference points, angle error is the yaw uncertainty it causes.
+pub fn calibrate_markers(target: &[Vec3], reference: &[Vec3]) -> Result<CalibrationResult> {
+    if target.len() != reference.len() {
+        bail!(
+            "Got {} target and {} reference marker points",
+            target.len(),
+            reference.len()
+        );
+    }
+    if target.len() < MIN_MARKERS {
+        bail!(
+            "Only {} marker points, at least {} are needed",
+            target.len(),
+            MIN_MARKERS
+        );
+    }
+    let target: Vec<_> = target.iter().cloned().map(convert_vector).collect();
+    let reference: Vec<_> = reference.iter().cloned().map(convert_vector).collect();
+    let spread = target
+        .iter()
+        .flat_map(|a| target.iter().map(move |b| (a - b).xz().norm()))
+        .fold(0., f64::max);
+    if spread < MIN_MARKER_SPREAD_M {
+        bail!(
+            "Marker points are only {:.0} mm apart, at least {:.0} mm are needed",
+            spread * 1000.,
+            MIN_MARKER_SPREAD_M * 1000.
+        );
+    }
+
+    let center =
+        |points: &[Vector3<f64>]| points.iter().sum::<Vector3<f64>>() / points.len() as f64;
+    let (target_center, reference_center) = (center(&target), center(&reference));
+    let rotation = best_yaw(
+        target
+            .iter()
+            .zip(&reference)
+            .map(|(t, r)| (t - target_center, r - reference_center)),
+    );
+    let translation = reference_center - rotation * target_center;
+
+    let errors = target
+        .iter()
+        .zip(&reference)
+        .map(|(t, r)| (rotation * t + translation - r).norm() * 1000.);
+    let rms_position_error_mm = root_mean_square(errors);
+    let quality = CalibrationQuality {
+        inliers: target.len(),
+        inlier_ratio: 1.,
+        rms_position_error_mm,
+        rms_angle_error_deg: (rms_position_error_mm / 1000. / spread).atan().to_degrees(),
+        axis_condition: 1.,
+    };
+    let verdict = if rms_position_error_mm > MAX_MARKER_ERROR_MM {
+        CalibrationVerdict::Unreliable
+    } else if spread < GOOD_MARKER_SPREAD_M {
+        CalibrationVerdict::PoorCoverage
+    } else if rms_position_error_mm > GOOD_MARKER_ERROR_MM {
+        CalibrationVerdict::Noisy
+    } else {
+        CalibrationVerdict::Good
+    };
 
+    let rotation = rotation.cast::<f32>();
     Ok(CalibrationResult {
         rotation: netvr_data::Quaternion {
             x: rotation.i,
@@ -328,10 +782,13 @@ pub fn calibrate(samples: &CalibrationInput) -> Result<CalibrationResult> {
             w: rotation.w,
         },
         translation: Vec3 {
-            x: translation[0] as f32,
-            y: translation[1] as f32,
-            z: translation[2] as f32,
+            x: translation.x as f32,
+            y: translation.y as f32,
+            z: translation.z as f32,
         },
+        pairs: target.len(),
+        verdict,
+        quality,
     })
 }
 
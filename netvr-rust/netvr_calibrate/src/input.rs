@@ -1,11 +1,77 @@
-use netvr_data::{net::CalibrationSample, Quaternion, Vec3};
+use netvr_data::{
+    net::{CalibrationSample, CalibrationSolver, ClockEstimate},
+    Quaternion, Vec3,
+};
 use serde::{Deserialize, Serialize};
 
 /// Calibration result
-#[derive(Serialize, Default, Clone, Debug)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct CalibrationResult {
     pub translation: Vec3,
     pub rotation: Quaternion,
+    /// Number of sample pairs the result was computed from
+    pub pairs: usize,
+    pub quality: CalibrationQuality,
+    pub verdict: CalibrationVerdict,
+}
+
+/// How well the result explains the samples. Errors are measured against
+/// devices being rigidly attached to each other.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct CalibrationQuality {
+    /// Pairs which were not rejected as outliers
+    pub inliers: usize,
+    pub inlier_ratio: f64,
+    /// Root mean square position error of inliers
+    pub rms_position_error_mm: f64,
+    /// Root mean square orientation error of inliers
+    pub rms_angle_error_deg: f64,
+    /// Ratio of the largest and smallest singular value of the rotation axes.
+    /// Large if the devices were only rotated around one axis.
+    pub axis_condition: f64,
+}
+
+/// How well the samples collected so far cover rotations around different
+/// axes, see coverage()
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct CalibrationCoverage {
+    pub pairs: usize,
+    /// Pairs of pairs rotated far enough from each other to tell the rotation
+    /// axis, in the worse half of the pairs
+    pub rotation_deltas: usize,
+    /// Condition number of the rotation axes in the worse half of the pairs,
+    /// see [CalibrationQuality::axis_condition]
+    pub axis_condition: f64,
+    /// Enough has been collected to stop early
+    pub sufficient: bool,
+}
+
+/// Whether the calibration can be trusted. Defaults to Unreliable, so that a
+/// result which was not judged is never applied.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CalibrationVerdict {
+    /// Small errors and rotations around enough different axes
+    Good,
+    /// Usable, but errors are larger than expected
+    Noisy,
+    /// Devices were not rotated around enough different axes, so the
+    /// translation is poorly determined
+    PoorCoverage,
+    /// Too many pairs disagree with the result, it should not be applied
+    #[default]
+    Unreliable,
+}
+
+/// How samples of the two devices are paired
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SamplePairing {
+    /// n-th target sample with n-th reference sample
+    #[default]
+    Order,
+    /// Each target sample with the reference sample closest in time
+    Nearest,
+    /// Each target sample with reference pose interpolated to its time
+    Interpolate,
 }
 
 /// Everything needed to compute a calibration
@@ -15,4 +81,14 @@ pub struct CalibrationInput {
     pub target_name: String,
     pub reference: Vec<CalibrationSample>,
     pub reference_name: String,
+    #[serde(default)]
+    pub pairing: SamplePairing,
+    /// Clocks of both clients relative to the server, used when pairing by
+    /// time. Device without one is assumed to use server time.
+    #[serde(default)]
+    pub target_clock: Option<ClockEstimate>,
+    #[serde(default)]
+    pub reference_clock: Option<ClockEstimate>,
+    #[serde(default)]
+    pub solver: CalibrationSolver,
 }
@@ -15,6 +15,9 @@ pub struct CalibrationResultCompat {
     pub translate: netvr_data::Vec3,
     pub rotate: netvr_data::Vec3,
     pub rotateq: netvr_data::Quaternion,
+    pub pairs: usize,
+    pub quality: input::CalibrationQuality,
+    pub verdict: input::CalibrationVerdict,
 }
 
 /// Compute calibration from samples. Only used on the web.
@@ -44,6 +47,9 @@ pub fn compute(samples: &str) -> String {
             netvr_data::Vec3 { x, y, z }
         },
         rotateq: result.rotation,
+        pairs: result.pairs,
+        quality: result.quality,
+        verdict: result.verdict,
     }) {
         Ok(result) => result,
         Err(e) => {
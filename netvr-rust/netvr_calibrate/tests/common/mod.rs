@@ -0,0 +1,102 @@
+//! Synthetic samples shared by the tests
+
+// not every test uses every helper
+#![allow(dead_code)]
+
+use nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
+use netvr_calibrate::CalibrationResult;
+use netvr_data::{net::CalibrationSample, Pose, Quaternion, Vec3};
+
+/// Deterministic random numbers, so that failures can be reproduced
+pub struct Random(pub u64);
+
+impl Random {
+    /// Uniform in [0, 1), xorshift64*
+    pub fn next(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Normally distributed with the given standard deviation
+    pub fn normal(&mut self, sigma: f64) -> f64 {
+        let (u, v) = (1. - self.next(), self.next());
+        sigma * (-2. * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos()
+    }
+
+    pub fn vector(&mut self, sigma: f64) -> Vector3<f64> {
+        Vector3::new(self.normal(sigma), self.normal(sigma), self.normal(sigma))
+    }
+
+    /// Translation and rotation vector with the given standard deviations
+    pub fn isometry(&mut self, (position, rotation): (f64, f64)) -> Isometry3<f64> {
+        Isometry3::new(self.vector(position), self.vector(rotation))
+    }
+}
+
+/// Target stage space in reference's server space
+pub fn stage() -> Isometry3<f64> {
+    Isometry3::from_parts(
+        Translation3::new(1.5, 0.2, -0.8),
+        UnitQuaternion::from_euler_angles(0., 2.1, 0.),
+    )
+}
+
+/// Where the target device is attached to the reference one
+pub fn offset() -> Isometry3<f64> {
+    Isometry3::new(Vector3::new(0.05, -0.02, 0.1), Vector3::new(0.3, 0.1, -0.2))
+}
+
+pub fn sample(pose: &Isometry3<f64>) -> CalibrationSample {
+    let (position, rotation) = (pose.translation.vector.cast::<f32>(), pose.rotation.cast());
+    CalibrationSample {
+        flags: 0b11,
+        pose: Pose {
+            position: Vec3 {
+                x: position.x,
+                y: position.y,
+                z: position.z,
+            },
+            orientation: Quaternion {
+                x: rotation.i,
+                y: rotation.j,
+                z: rotation.k,
+                w: rotation.w,
+            },
+        },
+        prev_flags: None,
+        prev_pose: None,
+        nanos: 0,
+        now_nanos: 0,
+    }
+}
+
+/// Sample taken at nanos of the device's clock
+pub fn sample_at(pose: &Isometry3<f64>, nanos: i64) -> CalibrationSample {
+    CalibrationSample {
+        nanos,
+        now_nanos: nanos,
+        ..sample(pose)
+    }
+}
+
+/// Samples of the reference and of the target attached to it at the given
+/// pose of the reference
+pub fn attached(world: &Isometry3<f64>) -> (Isometry3<f64>, Isometry3<f64>) {
+    (*world, stage().inverse() * world * offset())
+}
+
+/// Position (mm) and angle (degrees) error of the solved stage space
+pub fn error(result: &CalibrationResult) -> (f64, f64) {
+    let (t, q) = (&result.translation, &result.rotation);
+    let solved = Isometry3::from_parts(
+        Translation3::new(t.x, t.y, t.z).cast(),
+        UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(q.w, q.x, q.y, q.z)).cast(),
+    );
+    let error = solved.inverse() * stage();
+    (
+        error.translation.vector.norm() * 1000.,
+        error.rotation.angle().to_degrees(),
+    )
+}
@@ -0,0 +1,100 @@
+//! Coverage of rotations while samples of two devices held together are
+//! being collected, and stopping once it is good enough.
+
+use nalgebra::{Isometry3, UnitQuaternion, Vector3};
+use netvr_calibrate::{calibrate, coverage, CalibrationInput, CalibrationVerdict, SamplePairing};
+use netvr_data::net::CalibrationSolver;
+
+use common::{attached, error, sample, Random};
+
+mod common;
+
+/// Most samples a calibration collects before it gives up on coverage
+const SAMPLE_COUNT: usize = 200;
+/// Noise of tracking, standard deviation of position in meters and of
+/// rotation in radians
+const NOISE: (f64, f64) = (0.002, 0.01);
+
+/// Collects samples of the reference at given poses one by one until
+/// coverage is sufficient, returns None if it never is
+fn collect(
+    random: &mut Random,
+    mut pose: impl FnMut(&mut Random) -> Isometry3<f64>,
+) -> Option<CalibrationInput> {
+    let mut input = CalibrationInput {
+        target: vec![],
+        target_name: "target".to_owned(),
+        reference: vec![],
+        reference_name: "reference".to_owned(),
+        pairing: SamplePairing::Order,
+        target_clock: None,
+        reference_clock: None,
+        solver: CalibrationSolver::Full,
+    };
+    for _ in 0..SAMPLE_COUNT {
+        let (reference, target) = attached(&pose(random));
+        input
+            .reference
+            .push(sample(&(reference * random.isometry(NOISE))));
+        input
+            .target
+            .push(sample(&(target * random.isometry(NOISE))));
+        if coverage(&input).sufficient {
+            return Some(input);
+        }
+    }
+    None
+}
+
+/// Held in front of the user, turned randomly around every axis
+fn turned_around(random: &mut Random) -> Isometry3<f64> {
+    held_at(random.isometry((0., 1.5)).rotation)
+}
+
+/// Held in front of the user, turned randomly around the vertical axis only
+fn turned_around_vertical(random: &mut Random) -> Isometry3<f64> {
+    let yaw = random.normal(1.5);
+    held_at(UnitQuaternion::from_euler_angles(0., yaw, 0.))
+}
+
+fn held_at(rotation: UnitQuaternion<f64>) -> Isometry3<f64> {
+    Isometry3::from_parts(Vector3::new(0., 1.2, 0.).into(), rotation)
+}
+
+#[test]
+fn few_pairs_are_not_sufficient() {
+    let mut random = Random(0x9E37_79B9_7F4A_7C15);
+    let mut input = collect(&mut random, turned_around).expect("coverage should be sufficient");
+    input.reference.truncate(4);
+    input.target.truncate(4);
+    let coverage = coverage(&input);
+    assert_eq!(coverage.pairs, 4);
+    assert!(!coverage.sufficient);
+}
+
+#[test]
+fn rotating_around_one_axis_is_not_sufficient() {
+    let mut random = Random(0x9E37_79B9_7F4A_7C15);
+    assert!(collect(&mut random, turned_around_vertical).is_none());
+}
+
+#[test]
+fn collection_stops_early_with_good_result() {
+    let mut random = Random(0x9E37_79B9_7F4A_7C15);
+    for _ in 0..5 {
+        let input = collect(&mut random, turned_around).expect("coverage should be sufficient");
+        assert!(input.target.len() < SAMPLE_COUNT / 2);
+
+        let result = calibrate(&input).expect("calibration should succeed");
+        let (position, angle) = error(&result);
+        assert_eq!(result.verdict, CalibrationVerdict::Good);
+        // few pairs with noise, so only centimeter accuracy
+        assert!(
+            position < 25. && angle < 1.,
+            "stopped after {} pairs, off by {} mm, {}°",
+            input.target.len(),
+            position,
+            angle
+        );
+    }
+}
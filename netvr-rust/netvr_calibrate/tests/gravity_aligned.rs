@@ -0,0 +1,92 @@
+//! Compares the full and the gravity aligned solver on synthetic samples of
+//! two devices held together, with target stage space rotated only around the
+//! vertical axis as it is on real runtimes.
+
+use nalgebra::{Isometry3, Vector3};
+use netvr_calibrate::{calibrate, CalibrationInput, SamplePairing};
+use netvr_data::net::CalibrationSolver;
+
+use common::{attached, error, sample, Random};
+
+mod common;
+
+/// Samples of the reference and a target attached to it, noise is standard
+/// deviation of position in meters and of rotation in radians
+fn input(
+    random: &mut Random,
+    count: usize,
+    noise: (f64, f64),
+    solver: CalibrationSolver,
+) -> CalibrationInput {
+    let (mut reference, mut target) = (vec![], vec![]);
+    for _ in 0..count {
+        let world = Isometry3::new(Vector3::new(0., 1.2, 0.), Vector3::zeros())
+            * random.isometry((0.3, 1.5));
+        let (reference_pose, target_pose) = attached(&world);
+        reference.push(sample(&(reference_pose * random.isometry(noise))));
+        target.push(sample(&(target_pose * random.isometry(noise))));
+    }
+    CalibrationInput {
+        target,
+        target_name: "target".to_owned(),
+        reference,
+        reference_name: "reference".to_owned(),
+        pairing: SamplePairing::Order,
+        target_clock: None,
+        reference_clock: None,
+        solver,
+    }
+}
+
+/// Mean position (mm) and angle (degrees) error of the solved transform over
+/// several sample sets. Both solvers get the same samples.
+fn mean_error(solver: CalibrationSolver, count: usize, noise: (f64, f64)) -> (f64, f64) {
+    const RUNS: usize = 20;
+    let mut random = Random(0x9E37_79B9_7F4A_7C15);
+    let (mut position, mut angle) = (0., 0.);
+    for _ in 0..RUNS {
+        let result = calibrate(&input(&mut random, count, noise, solver))
+            .expect("calibration should succeed");
+        let error = error(&result);
+        position += error.0;
+        angle += error.1;
+    }
+    (position / RUNS as f64, angle / RUNS as f64)
+}
+
+#[test]
+fn gravity_aligned_recovers_exact_transform() {
+    let (position, angle) = mean_error(CalibrationSolver::GravityAligned, 20, (0., 0.));
+    assert!(
+        position < 0.5 && angle < 0.01,
+        "off by {} mm, {}°",
+        position,
+        angle
+    );
+}
+
+#[test]
+fn gravity_aligned_is_more_accurate_with_few_samples() {
+    let noise = (0.002, 0.5f64.to_radians());
+    let full = mean_error(CalibrationSolver::Full, 8, noise);
+    let gravity_aligned = mean_error(CalibrationSolver::GravityAligned, 8, noise);
+    assert!(
+        gravity_aligned.0 < full.0 && gravity_aligned.1 < full.1,
+        "off by {:?}, full solver off by {:?}",
+        gravity_aligned,
+        full
+    );
+}
+
+#[test]
+fn gravity_aligned_is_more_accurate_with_noisy_samples() {
+    let noise = (0.01, 3f64.to_radians());
+    let full = mean_error(CalibrationSolver::Full, 100, noise);
+    let gravity_aligned = mean_error(CalibrationSolver::GravityAligned, 100, noise);
+    assert!(
+        gravity_aligned.0 < full.0 && gravity_aligned.1 < full.1,
+        "off by {:?}, full solver off by {:?}",
+        gravity_aligned,
+        full
+    );
+}
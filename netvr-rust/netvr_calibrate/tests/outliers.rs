@@ -0,0 +1,101 @@
+//! Pairs where tracking glitched have to be rejected as outliers instead of
+//! pulling the result away, and they have to show in the reported quality.
+
+use nalgebra::{Isometry3, Vector3};
+use netvr_calibrate::{calibrate, CalibrationInput, CalibrationVerdict, SamplePairing};
+use netvr_data::net::CalibrationSolver;
+
+use common::{attached, error, sample, Random};
+
+mod common;
+
+const PAIRS: usize = 60;
+
+/// Pairs of the reference and a target attached to it with slight noise,
+/// the target jumps away from the reference in the given pairs
+fn input(random: &mut Random, glitched: &[usize]) -> CalibrationInput {
+    let (mut reference, mut target) = (vec![], vec![]);
+    for i in 0..PAIRS {
+        let world = Isometry3::new(Vector3::new(0., 1.2, 0.), Vector3::zeros())
+            * random.isometry((0.3, 1.5));
+        let (reference_pose, mut target_pose) = attached(&world);
+        if glitched.contains(&i) {
+            target_pose = random.isometry((0.5, 1.)) * target_pose;
+        }
+        let noise = (0.001, 0.2f64.to_radians());
+        reference.push(sample(&(reference_pose * random.isometry(noise))));
+        target.push(sample(&(target_pose * random.isometry(noise))));
+    }
+    CalibrationInput {
+        target,
+        target_name: "target".to_owned(),
+        reference,
+        reference_name: "reference".to_owned(),
+        pairing: SamplePairing::Order,
+        target_clock: None,
+        reference_clock: None,
+        solver: CalibrationSolver::Full,
+    }
+}
+
+/// Distinct pair indices, without the first which order pairing needs
+fn pick(random: &mut Random, count: usize) -> Vec<usize> {
+    let mut picked = vec![];
+    while picked.len() < count {
+        let i = 1 + (random.next() * (PAIRS - 1) as f64) as usize;
+        if !picked.contains(&i) {
+            picked.push(i);
+        }
+    }
+    picked
+}
+
+#[test]
+fn clean_pairs_are_all_inliers() {
+    let mut random = Random(0x9E37_79B9_7F4A_7C15);
+    let result = calibrate(&input(&mut random, &[])).expect("calibration should succeed");
+    assert_eq!(result.quality.inliers, PAIRS);
+    assert_eq!(result.verdict, CalibrationVerdict::Good);
+}
+
+#[test]
+fn glitched_pairs_are_rejected() {
+    let mut random = Random(0x9E37_79B9_7F4A_7C15);
+    for _ in 0..5 {
+        let glitched = pick(&mut random, 10);
+        let result = calibrate(&input(&mut random, &glitched)).expect("calibration should succeed");
+        let (position, angle) = error(&result);
+        assert_eq!(
+            result.quality.inliers,
+            PAIRS - glitched.len(),
+            "{:?}",
+            result.quality
+        );
+        assert!(
+            result.quality.rms_position_error_mm < 5. && result.quality.rms_angle_error_deg < 1.,
+            "{:?}",
+            result.quality
+        );
+        assert!(
+            position < 10. && angle < 0.5,
+            "off by {} mm, {}°",
+            position,
+            angle
+        );
+        assert_eq!(result.verdict, CalibrationVerdict::Good);
+    }
+}
+
+#[test]
+fn mostly_glitched_pairs_are_not_trusted() {
+    let mut random = Random(0x9E37_79B9_7F4A_7C15);
+    let glitched = pick(&mut random, 2 * PAIRS / 3);
+    let result = calibrate(&input(&mut random, &glitched)).expect("calibration should succeed");
+    // glitches are the majority, so they can not be told apart from the rest
+    assert!(
+        result.quality.rms_position_error_mm > 100.,
+        "{:?}",
+        result.quality
+    );
+    assert_ne!(result.verdict, CalibrationVerdict::Good);
+}
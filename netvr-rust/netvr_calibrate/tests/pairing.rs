@@ -0,0 +1,160 @@
+//! Pairs samples of two devices held together, which were taken at different
+//! times or on different clocks.
+
+use nalgebra::{Isometry3, Vector3};
+use netvr_calibrate::{
+    calibrate, CalibrationInput, CalibrationResult, CalibrationVerdict, SamplePairing,
+};
+use netvr_data::net::{CalibrationSample, CalibrationSolver, ClockEstimate};
+
+use common::{attached, error, sample, sample_at, Random};
+
+mod common;
+
+const MILLI: i64 = 1_000_000;
+/// Time between samples of one device
+const INTERVAL: i64 = 50 * MILLI;
+/// Target clock is this much ahead of the server's
+const TARGET_OFFSET: i64 = 3_000 * MILLI;
+
+/// Reference pose moving smoothly through rotations around all axes
+fn trajectory(nanos: i64) -> Isometry3<f64> {
+    let t = nanos as f64 / 1e9;
+    Isometry3::new(
+        Vector3::new(
+            0.3 * (0.7 * t).sin(),
+            1.2 + 0.2 * (1.1 * t).sin(),
+            0.3 * (0.9 * t).cos(),
+        ),
+        Vector3::new(
+            1.2 * (0.8 * t).sin(),
+            2. * (0.5 * t).sin(),
+            1.2 * (0.6 * t).cos(),
+        ),
+    )
+}
+
+/// Reference samples every interval for ten seconds and target samples
+/// delayed by lag, both on their own clocks
+fn timed_samples(lag: i64) -> (Vec<CalibrationSample>, Vec<CalibrationSample>) {
+    let (mut reference, mut target) = (vec![], vec![]);
+    for i in 0..200 {
+        let time = i * INTERVAL;
+        reference.push(sample_at(&attached(&trajectory(time)).0, time));
+        let time = time + lag;
+        target.push(sample_at(
+            &attached(&trajectory(time)).1,
+            time + TARGET_OFFSET,
+        ));
+    }
+    (reference, target)
+}
+
+fn input(
+    reference: Vec<CalibrationSample>,
+    target: Vec<CalibrationSample>,
+    pairing: SamplePairing,
+) -> CalibrationInput {
+    CalibrationInput {
+        target,
+        target_name: "target".to_owned(),
+        reference,
+        reference_name: "reference".to_owned(),
+        pairing,
+        target_clock: Some(ClockEstimate {
+            offset_nanos: TARGET_OFFSET,
+            uncertainty_nanos: MILLI,
+        }),
+        reference_clock: None,
+        solver: CalibrationSolver::Full,
+    }
+}
+
+fn solve(input: &CalibrationInput) -> (CalibrationResult, (f64, f64)) {
+    let result = calibrate(input).expect("calibration should succeed");
+    let error = error(&result);
+    (result, error)
+}
+
+#[test]
+fn nearest_pairing_survives_dropped_sample() {
+    let (reference, mut target) = timed_samples(0);
+    target.remove(20);
+
+    let (result, nearest) = solve(&input(
+        reference.clone(),
+        target.clone(),
+        SamplePairing::Nearest,
+    ));
+    assert_eq!(result.pairs, target.len());
+    assert!(nearest.0 < 0.5 && nearest.1 < 0.05, "off by {:?}", nearest);
+
+    // every pair after the dropped sample is one interval apart
+    let (_, order) = solve(&input(reference, target, SamplePairing::Order));
+    assert!(order.0 > 10. * nearest.0, "off by {:?}", order);
+}
+
+#[test]
+fn interpolated_pairing_is_more_accurate_between_samples() {
+    let (reference, target) = timed_samples(2 * INTERVAL / 5);
+    let (_, nearest) = solve(&input(
+        reference.clone(),
+        target.clone(),
+        SamplePairing::Nearest,
+    ));
+    let (result, interpolated) = solve(&input(reference, target, SamplePairing::Interpolate));
+    // last target sample is after all reference samples
+    assert_eq!(result.pairs, 199);
+    assert!(
+        interpolated.0 < nearest.0 && interpolated.1 < nearest.1,
+        "off by {:?}, nearest off by {:?}",
+        interpolated,
+        nearest
+    );
+    assert!(
+        interpolated.0 < 2. && interpolated.1 < 0.2,
+        "off by {:?}",
+        interpolated
+    );
+}
+
+#[test]
+fn samples_are_not_paired_across_clocks() {
+    let (reference, target) = timed_samples(0);
+    let mut input = input(reference, target, SamplePairing::Nearest);
+    input.target_clock = None;
+    // target samples are paired with reference samples three seconds older
+    let (result, error) = solve(&input);
+    assert!(error.0 > 10., "off by {:?}", error);
+    assert_ne!(result.verdict, CalibrationVerdict::Good);
+}
+
+#[test]
+fn invalid_samples_are_dropped() {
+    let mut random = Random(0x9E37_79B9_7F4A_7C15);
+    let (mut reference, mut target) = (vec![], vec![]);
+    for _ in 0..30 {
+        let world = Isometry3::new(Vector3::new(0., 1.2, 0.), Vector3::zeros())
+            * random.isometry((0.3, 1.5));
+        let (reference_pose, target_pose) = attached(&world);
+        reference.push(sample(&reference_pose));
+        target.push(sample(&target_pose));
+    }
+    // lost tracking, runtimes report garbage poses with the bits cleared
+    let garbage = sample(&Isometry3::new(Vector3::new(5., -3., 2.), Vector3::zeros()));
+    for i in [3, 11, 17, 25] {
+        target[i] = CalibrationSample {
+            flags: 0b01,
+            ..garbage.clone()
+        };
+    }
+    reference[8] = CalibrationSample {
+        flags: 0b10,
+        ..garbage
+    };
+
+    let (result, error) = solve(&input(reference, target, SamplePairing::Order));
+    assert_eq!(result.pairs, 25);
+    assert_eq!(result.quality.inliers, 25);
+    assert!(error.0 < 0.5 && error.1 < 0.05, "off by {:?}", error);
+}
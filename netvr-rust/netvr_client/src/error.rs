@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use netvr_data::bincode;
 use tokio::io;
 
@@ -10,6 +12,9 @@ pub enum Error {
     #[error("QUIC connection error")]
     Connection(#[from] quinn::ConnectionError),
 
+    #[error("failed to start QUIC connection")]
+    Connect(#[from] quinn::ConnectError),
+
     #[error("Bincode encode/decode error")]
     Bincode(#[from] bincode::Error),
 
@@ -19,6 +24,21 @@ pub enum Error {
     #[error("Framing Error")]
     FramingError(#[from] netvr_data::FramingError),
 
+    #[error("server rejected the connection: {0}")]
+    Rejected(String),
+
+    #[error("no server answered discovery")]
+    DiscoveryTimeout,
+
+    #[error("invalid server address: {0}")]
+    InvalidAddress(String),
+
+    #[error("server certificate {actual} does not match pinned {expected}")]
+    PinMismatch { expected: String, actual: String },
+
+    #[error("pin file {0:?} is corrupted")]
+    InvalidPinFile(PathBuf),
+
     #[error("unknown netvr connect error")]
     Unknown,
 }
@@ -1,64 +1,186 @@
 mod error;
+mod options;
 mod quinn_connect;
+mod trust;
 
 use std::{
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    time::Duration,
+    sync::Arc,
 };
 
-use error::Error;
+pub use error::Error;
 use netvr_data::{
     app::{AppDown, AppUp},
     bincode,
-    net::{self, CalibrationSample, ConfigurationDown, ConfigurationUp, Heartbeat},
+    net::{
+        self, CalibrationSample, Capabilities, ClientHello, ClientId, ConfigurationDown,
+        ConfigurationUp, Fingerprint, Heartbeat, ServerHello,
+    },
+    FramingError,
 };
 pub use netvr_data::{RecvFrames, SendFrames};
+pub use options::{ConnectOptions, RetryPolicy};
+pub use trust::reset_server_pin;
 use quinn::{Connection, Endpoint};
-use tokio::{net::UdpSocket, select};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    select,
+};
 
-use crate::quinn_connect::quinn_connect;
+use crate::{quinn_connect::quinn_connect, trust::FingerprintVerification};
 
 pub struct NetVRConnection {
     pub endpoint: Endpoint,
     pub connection: Connection,
+    /// Id assigned to this client by the server
+    pub client_id: ClientId,
+    /// Optional features supported by both sides
+    pub capabilities: Capabilities,
     pub heartbeat: RecvFrames<Heartbeat>,
     pub configuration_up: SendFrames<ConfigurationUp>,
     pub configuration_down: RecvFrames<ConfigurationDown>,
-    pub calibration_up: SendFrames<CalibrationSample>,
-    pub app_up_stream: SendFrames<AppUp>,
-    pub app_down_stream: RecvFrames<AppDown>,
+    /// Only present if Capabilities::CALIBRATION was negotiated
+    pub calibration_up: Option<SendFrames<CalibrationSample>>,
+    /// Only present if Capabilities::APP_OBJECTS was negotiated
+    pub app_up_stream: Option<SendFrames<AppUp>>,
+    /// Only present if Capabilities::APP_OBJECTS was negotiated
+    pub app_down_stream: Option<RecvFrames<AppDown>>,
 }
 
 /// Performs server discovery and returns a socket bound to correct address and
 /// port.
 pub async fn connect(log: fn(String) -> ()) -> Result<NetVRConnection, Error> {
+    connect_with_options(log, &ConnectOptions::default()).await
+}
+
+/// Connects to the server at given address without doing discovery.
+pub async fn connect_to(
+    log: fn(String) -> (),
+    addr: SocketAddr,
+) -> Result<NetVRConnection, Error> {
+    connect_with_options(
+        log,
+        &ConnectOptions {
+            server_address: Some(addr),
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+/// Connects to the server using either discovery or explicit address based on
+/// options.
+pub async fn connect_with_options(
+    log: fn(String) -> (),
+    options: &ConnectOptions,
+) -> Result<NetVRConnection, Error> {
+    let pin = match &options.pin_file {
+        Some(pin_file) => trust::load_pin(pin_file).await?,
+        None => None,
+    };
+    let (addr, advertised) = match options.server_address {
+        Some(addr) => (addr, None),
+        None => {
+            let (addr, advertised) = discover(log, options, pin).await?;
+            (addr, Some(advertised))
+        }
+    };
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let verifier = FingerprintVerification::new(pin.or(advertised));
+        match setup_connection(log, addr, options, verifier.clone()).await {
+            Ok(connection) => {
+                if let (Some(pin_file), None) = (&options.pin_file, pin) {
+                    if let Some(fingerprint) = verifier.seen() {
+                        log(format!(
+                            "Pinning server fingerprint {}",
+                            net::fingerprint_to_hex(&fingerprint)
+                        ));
+                        trust::store_pin(pin_file, &fingerprint).await?;
+                    }
+                }
+                return Ok(connection);
+            }
+            Err(Error::Rejected(reason)) => return Err(Error::Rejected(reason)),
+            Err(err @ Error::PinMismatch { .. }) => return Err(err),
+            Err(err) => {
+                if !options.retry.should_retry(attempt) {
+                    return Err(err);
+                }
+                log(format!("Connection attempt {} failed: {}", attempt, err));
+                tokio::time::sleep(options.retry.delay).await;
+            }
+        }
+    }
+}
+
+/// Parses server address as written by the user. Accepts ip:port, ip and
+/// hostname with or without port. Port defaults to net::DEFAULT_SERVER_PORT.
+pub async fn resolve_server_address(address: &str) -> Result<SocketAddr, Error> {
+    if let Ok(addr) = address.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = address.parse::<std::net::IpAddr>() {
+        return Ok(SocketAddr::new(ip, net::DEFAULT_SERVER_PORT));
+    }
+    let resolved = match lookup_host(address).await {
+        Ok(mut addrs) => addrs.next(),
+        Err(_) => lookup_host((address, net::DEFAULT_SERVER_PORT))
+            .await?
+            .next(),
+    };
+    resolved.ok_or_else(|| Error::InvalidAddress(address.to_owned()))
+}
+
+/// Broadcasts discovery requests until some server answers. Servers which do
+/// not match the pinned fingerprint are ignored.
+async fn discover(
+    log: fn(String) -> (),
+    options: &ConnectOptions,
+    pin: Option<Fingerprint>,
+) -> Result<(SocketAddr, Fingerprint), Error> {
     let socket: UdpSocket = UdpSocket::bind("0.0.0.0:0").await?;
     socket.set_broadcast(true)?;
     log(format!("Broadcasting as {:?}", socket.local_addr()?));
 
+    let mut attempt = 0;
     loop {
+        if attempt > 0 && !options.retry.should_retry(attempt) {
+            return Err(Error::DiscoveryTimeout);
+        }
+        attempt += 1;
         log("Sending request".to_string());
         socket
             .send_to(
                 "netvr".as_bytes(),
-                SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), 13161),
+                SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), options.discovery_port),
             )
             .await?;
 
-        let mut recv_buff: [u8; 10] = [0; 10];
+        let mut recv_buff: [u8; 64] = [0; 64];
 
         select! {
-            () = tokio::time::sleep(Duration::from_millis(500)) => {},
+            () = tokio::time::sleep(options.discovery_timeout) => {},
             rec = socket.recv_from(recv_buff.as_mut()) => {
                 let (n, addr) = rec?;
                 if let Ok(data) = bincode::deserialize::<net::DiscoveryResponse>(&recv_buff[..n]) {
                     if !data.validate_header() {
-                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        tokio::time::sleep(options.retry.delay).await;
+                    } else if matches!(pin, Some(pin) if pin != data.fingerprint) {
+                        log(format!(
+                            "Ignoring server {:?} with fingerprint {}, it does not match pinned one",
+                            addr,
+                            net::fingerprint_to_hex(&data.fingerprint)
+                        ));
+                        tokio::time::sleep(options.retry.delay).await;
                     } else {
-                        return setup_connection(log, addr).await;
+                        return Ok((addr, data.fingerprint));
                     }
                 } else {
-                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    log(format!("Ignoring invalid discovery response from {:?}", addr));
+                    tokio::time::sleep(options.retry.delay).await;
                 }
             },
         };
@@ -69,10 +191,33 @@ pub async fn connect(log: fn(String) -> ()) -> Result<NetVRConnection, Error> {
 async fn setup_connection(
     log: fn(String) -> (),
     addr: SocketAddr,
+    options: &ConnectOptions,
+    verifier: Arc<FingerprintVerification>,
 ) -> Result<NetVRConnection, Error> {
-    log(format!("Got valid response from {:?}", addr));
-    let (endpoint, connection) = quinn_connect(addr).await?;
+    log(format!("Connecting to {:?}", addr));
+    let (endpoint, connection) = match quinn_connect(addr, verifier.clone()).await {
+        Ok(connection) => connection,
+        Err(err) => {
+            verifier.check()?;
+            return Err(err);
+        }
+    };
     log("Connection established.".to_string());
+    let (client_id, capabilities) = handshake(
+        &connection,
+        ClientHello {
+            capabilities: options.capabilities,
+            join_token: options.join_token.clone(),
+            room: options.room.clone(),
+            name: options.name.clone(),
+            ..Default::default()
+        },
+    )
+    .await?;
+    log(format!(
+        "Handshake finished. Client id: {}, capabilities: {:?}",
+        client_id, capabilities
+    ));
     log("Accepting heartbeat channel.".to_string());
     let heartbeat = RecvFrames::open(&connection, b"heartbee").await?;
     log("Heartbeat channel opened.".to_string());
@@ -80,18 +225,31 @@ async fn setup_connection(
     log("Configuration up channel opened.".to_string());
     let configuration_down = RecvFrames::open(&connection, b"confetti").await?;
     log("Configuration down channel opened.".to_string());
-    let calibration_up = SendFrames::open(&connection, b"calibrat").await?;
-    log("Calibration up channel opened.".to_string());
-    let app_up_stream: SendFrames<AppUp> = SendFrames::open(&connection, b"app_up__").await?;
-    log("App up channel opened.".to_string());
-    let app_down_stream: RecvFrames<AppDown> = RecvFrames::open(&connection, b"app_down").await?;
-    log("App down channel opened.".to_string());
+    let calibration_up = if capabilities.contains(Capabilities::CALIBRATION) {
+        let stream = SendFrames::open(&connection, b"calibrat").await?;
+        log("Calibration up channel opened.".to_string());
+        Some(stream)
+    } else {
+        None
+    };
+    let (app_up_stream, app_down_stream) = if capabilities.contains(Capabilities::APP_OBJECTS) {
+        let app_up_stream: SendFrames<AppUp> = SendFrames::open(&connection, b"app_up__").await?;
+        log("App up channel opened.".to_string());
+        let app_down_stream: RecvFrames<AppDown> =
+            RecvFrames::open(&connection, b"app_down").await?;
+        log("App down channel opened.".to_string());
+        (Some(app_up_stream), Some(app_down_stream))
+    } else {
+        (None, None)
+    };
 
     log("Channels opened.".to_string());
 
     Ok(NetVRConnection {
         endpoint,
         connection,
+        client_id,
+        capabilities,
         heartbeat,
         configuration_up,
         configuration_down,
@@ -100,3 +258,31 @@ async fn setup_connection(
         app_down_stream,
     })
 }
+
+/// Tells the server which protocol version and features we support and waits
+/// for it to accept us.
+async fn handshake(
+    connection: &Connection,
+    hello: ClientHello,
+) -> Result<(ClientId, Capabilities), Error> {
+    let mut hello_up = SendFrames::open(connection, b"hello___").await?;
+    hello_up.write(&hello).await?;
+    let mut hello_down: RecvFrames<ServerHello> =
+        match RecvFrames::open(connection, b"welcome_").await {
+            Ok(stream) => stream,
+            Err(FramingError::InvalidSetupFrame(..)) => {
+                return Err(Error::Rejected(
+                    "server did not answer hello, it is probably outdated".to_string(),
+                ))
+            }
+            Err(err) => return Err(err.into()),
+        };
+    match hello_down.read().await? {
+        ServerHello::Accepted {
+            capabilities,
+            client_id,
+            ..
+        } => Ok((client_id, capabilities)),
+        ServerHello::Rejected { reason, .. } => Err(Error::Rejected(reason)),
+    }
+}
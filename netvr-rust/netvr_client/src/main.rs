@@ -1,13 +1,23 @@
 use std::time::Duration;
 
-use netvr_client::connect;
+use netvr_client::{connect, connect_to, resolve_server_address};
 use quinn::VarInt;
 
-/// Utility for verifying that the connection code still works.
+/// Utility for verifying that the connection code still works. Optionally takes
+/// server address as the first argument to skip discovery.
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Hello there! I'm looking for NetVR devices...");
-    let conn = connect(|text| println!("[discovery] {}", text)).await?;
+    let conn = match std::env::args().nth(1) {
+        Some(address) => {
+            let addr = resolve_server_address(&address).await?;
+            println!("Hello there! I'm connecting to {:?}...", addr);
+            connect_to(|text| println!("[connect] {}", text), addr).await?
+        }
+        None => {
+            println!("Hello there! I'm looking for NetVR devices...");
+            connect(|text| println!("[discovery] {}", text)).await?
+        }
+    };
     let connection = conn.connection;
     println!("  remote_address: {:?}", connection.remote_address());
     println!("  local: {:?}", connection.local_ip());
@@ -0,0 +1,73 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use netvr_data::net::{self, Capabilities};
+
+/// How many times and how often to retry finding or connecting to the server
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// None means retry forever
+    pub max_attempts: Option<u32>,
+    /// Wait between two attempts
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: None,
+            delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns true if another attempt should be made after `attempt` attempts
+    /// failed.
+    pub(crate) fn should_retry(&self, attempt: u32) -> bool {
+        match self.max_attempts {
+            Some(max_attempts) => attempt < max_attempts,
+            None => true,
+        }
+    }
+}
+
+/// Options for connecting to the server
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    /// Connect directly to this address instead of using broadcast discovery
+    pub server_address: Option<SocketAddr>,
+    /// UDP port discovery requests are broadcast to
+    pub discovery_port: u16,
+    /// How long to wait for discovery response before sending another request
+    pub discovery_timeout: Duration,
+    /// Applies to both discovery requests and connection attempts
+    pub retry: RetryPolicy,
+    /// Optional protocol features this client wants to use
+    pub capabilities: Capabilities,
+    /// File in which fingerprint of the server is pinned on first connection.
+    /// Servers with different certificate are refused afterwards. If None any
+    /// server is trusted, which is vulnerable to impersonation.
+    pub pin_file: Option<PathBuf>,
+    /// Secret required by servers which do not admit everyone
+    pub join_token: Option<String>,
+    /// Room to join, server puts the client to default room if None
+    pub room: Option<String>,
+    /// Name announced in the hello, servers refuse names which were revoked
+    pub name: Option<String>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            server_address: None,
+            discovery_port: net::DEFAULT_DISCOVERY_PORT,
+            discovery_timeout: Duration::from_millis(500),
+            retry: RetryPolicy::default(),
+            capabilities: Capabilities::all(),
+            pin_file: None,
+            join_token: None,
+            room: None,
+            name: None,
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use netvr_data::net::{self, Fingerprint};
+
+use crate::error::Error;
+
+/// Reads pinned server fingerprint. Returns None if nothing was pinned yet.
+pub(crate) async fn load_pin(pin_file: &Path) -> Result<Option<Fingerprint>, Error> {
+    match tokio::fs::read_to_string(pin_file).await {
+        Ok(text) => match net::fingerprint_from_hex(&text) {
+            Some(fingerprint) => Ok(Some(fingerprint)),
+            None => Err(Error::InvalidPinFile(pin_file.to_owned())),
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Stores fingerprint so that next connections only accept the same server.
+pub(crate) async fn store_pin(pin_file: &Path, fingerprint: &Fingerprint) -> Result<(), Error> {
+    if let Some(parent) = pin_file.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(pin_file, net::fingerprint_to_hex(fingerprint)).await?;
+    Ok(())
+}
+
+/// Forgets pinned server so that the next connection pins whichever server it
+/// reaches. Use when the server identity was intentionally regenerated.
+pub async fn reset_server_pin(pin_file: impl AsRef<Path>) -> Result<(), Error> {
+    match tokio::fs::remove_file(pin_file).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Certificate verifier which only checks the certificate fingerprint. The
+/// server certificate is self-signed so there is no chain to verify.
+///
+/// If no fingerprint is expected any certificate is accepted, which is
+/// vulnerable to MITM attacks. Fingerprint of the presented certificate is
+/// remembered either way so that it can be pinned after connecting.
+pub(crate) struct FingerprintVerification {
+    expected: Option<Fingerprint>,
+    seen: Mutex<Option<Fingerprint>>,
+}
+
+impl FingerprintVerification {
+    pub(crate) fn new(expected: Option<Fingerprint>) -> Arc<Self> {
+        Arc::new(Self {
+            expected,
+            seen: Mutex::new(None),
+        })
+    }
+
+    /// Fingerprint of the last certificate presented by the server.
+    pub(crate) fn seen(&self) -> Option<Fingerprint> {
+        *self.seen.lock().unwrap()
+    }
+
+    /// Returns error if the server presented different certificate than
+    /// expected.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        match (self.expected, self.seen()) {
+            (Some(expected), Some(actual)) if expected != actual => Err(Error::PinMismatch {
+                expected: net::fingerprint_to_hex(&expected),
+                actual: net::fingerprint_to_hex(&actual),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl rustls::client::ServerCertVerifier for FingerprintVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let fingerprint = net::fingerprint_of(&end_entity.0);
+        self.seen.lock().unwrap().replace(fingerprint);
+        match self.expected {
+            Some(expected) if expected != fingerprint => Err(rustls::Error::General(
+                "server certificate does not match pinned fingerprint".to_string(),
+            )),
+            _ => Ok(rustls::client::ServerCertVerified::assertion()),
+        }
+    }
+}
@@ -0,0 +1,737 @@
+//! Compact encoding of state snapshots sent from the server to clients.
+//!
+//! Positions are quantized to half millimeters, orientations are packed into
+//! 32 bits using the smallest three components and each set is encoded as a
+//! delta against the last set the client acknowledged. Clients which did not
+//! change since then are left out entirely. Sets which do not fit into one
+//! datagram are split into parts.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    f32::consts::FRAC_1_SQRT_2,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bincode,
+    net::{ClientId, Controller, DatagramDown, RemoteStateSnapshotSet, StateSnapshot},
+    Pose, Quaternion, Vec3,
+};
+
+/// Quantization steps per meter
+const POSITION_SCALE: f32 = 2000.;
+/// Bits used for each of the three smallest quaternion components
+const ORIENTATION_BITS: u32 = 10;
+const ORIENTATION_MAX: u32 = (1 << ORIENTATION_BITS) - 1;
+/// How many sent sets are remembered to be used as a baseline
+const HISTORY_LENGTH: usize = 32;
+
+/// Pose with quantized position and compressed orientation
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct CompactPose {
+    pub position: [i32; 3],
+    pub orientation: u32,
+}
+
+/// Pose stored as a position offset from the pose in the baseline
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct CompactPoseDelta {
+    pub position: [i16; 3],
+    pub orientation: u32,
+}
+
+/// Controller with compact pose
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct CompactController {
+    pub interaction_profile: u8,
+    pub user_path: u8,
+    pub pose: CompactPose,
+}
+
+/// State snapshot with compact poses
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct CompactState {
+    pub controllers: Vec<CompactController>,
+    pub view: CompactPose,
+    pub required_configuration: u32,
+    pub nanos: i64,
+}
+
+/// State snapshot relative to the baseline. Only used if the client has the
+/// same controllers as in the baseline.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct CompactStateDelta {
+    pub controllers: Vec<CompactPoseDelta>,
+    pub view: CompactPoseDelta,
+    pub required_configuration: u32,
+    pub nanos: i64,
+}
+
+/// Change of one client's state since the baseline
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum CompactEntry {
+    Full(CompactState),
+    Delta(CompactStateDelta),
+    Removed,
+}
+
+/// Compact version of RemoteStateSnapshotSet
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct CompactStateSet {
+    /// Same as RemoteStateSnapshotSet::order
+    pub order: usize,
+    /// Order of the set this one is relative to, zero if it is not relative
+    /// to anything
+    pub baseline: usize,
+    /// Number of datagrams the set was split into. It is only applied once
+    /// all of them arrive.
+    pub parts: u32,
+    /// Clients not listed did not change since the baseline
+    pub clients: Vec<(ClientId, CompactEntry)>,
+}
+
+impl CompactStateSet {
+    /// Splits the set into parts whose DatagramDown fits into max_size bytes.
+    /// Entries too big to fit on their own get a part to themselves.
+    pub fn split(self, max_size: usize) -> Vec<CompactStateSet> {
+        let empty = CompactStateSet {
+            order: self.order,
+            baseline: self.baseline,
+            parts: 0,
+            clients: vec![],
+        };
+        let header = serialized_len(&DatagramDown::State(empty.clone()));
+        let mut parts: Vec<CompactStateSet> = vec![];
+        let mut size = header;
+        for entry in self.clients {
+            let entry_size = serialized_len(&entry);
+            match parts.last_mut() {
+                Some(part) if size + entry_size <= max_size => part.clients.push(entry),
+                _ => {
+                    parts.push(CompactStateSet {
+                        clients: vec![entry],
+                        ..empty.clone()
+                    });
+                    size = header;
+                }
+            }
+            size += entry_size;
+        }
+        if parts.is_empty() {
+            // nothing changed, but the client still learns the new order
+            parts.push(empty);
+        }
+        let count = parts.len() as u32;
+        for part in &mut parts {
+            part.parts = count;
+        }
+        parts
+    }
+}
+
+fn serialized_len<T: Serialize>(value: &T) -> usize {
+    bincode::serialized_size(value).map_or(0, |size| size as usize)
+}
+
+type CompactStates = HashMap<ClientId, CompactState>;
+
+/// Recently sent or received sets by order
+#[derive(Default)]
+struct History(VecDeque<(usize, CompactStates)>);
+
+impl History {
+    fn get(&self, order: usize) -> Option<&CompactStates> {
+        if order == 0 {
+            return None;
+        }
+        self.0
+            .iter()
+            .find(|(o, _)| *o == order)
+            .map(|(_, states)| states)
+    }
+
+    fn remember(&mut self, order: usize, states: CompactStates) {
+        self.0.retain(|(o, _)| *o != order);
+        if self.0.len() >= HISTORY_LENGTH {
+            self.0.pop_front();
+        }
+        self.0.push_back((order, states));
+    }
+}
+
+/// Encodes sets sent to one client. Keeps track of what was sent so that
+/// following sets can be encoded against the one the client acknowledged.
+#[derive(Default)]
+pub struct StateEncoder {
+    history: History,
+    acknowledged: usize,
+}
+
+impl StateEncoder {
+    /// Call with the order of the latest set the client applied
+    pub fn acknowledge(&mut self, order: usize) {
+        self.acknowledged = order;
+    }
+
+    /// Encodes the set relative to the acknowledged one if it is still known
+    pub fn encode(&mut self, set: &RemoteStateSnapshotSet) -> CompactStateSet {
+        let states: CompactStates = set
+            .clients
+            .iter()
+            .map(|(id, state)| (*id, state.into()))
+            .collect();
+        let empty = CompactStates::new();
+        let (baseline, base) = match self.history.get(self.acknowledged) {
+            Some(base) => (self.acknowledged, base),
+            None => (0, &empty),
+        };
+
+        let mut clients: Vec<_> = states
+            .iter()
+            .filter_map(|(id, state)| {
+                let entry = match base.get(id) {
+                    Some(base) if base == state => return None,
+                    Some(base) => match state.delta_from(base) {
+                        Some(delta) => CompactEntry::Delta(delta),
+                        None => CompactEntry::Full(state.clone()),
+                    },
+                    None => CompactEntry::Full(state.clone()),
+                };
+                Some((*id, entry))
+            })
+            .collect();
+        clients.extend(
+            base.keys()
+                .filter(|id| !states.contains_key(id))
+                .map(|id| (*id, CompactEntry::Removed)),
+        );
+
+        self.history.remember(set.order, states);
+        CompactStateSet {
+            order: set.order,
+            baseline,
+            parts: 1,
+            clients,
+        }
+    }
+}
+
+/// Decodes sets received from the server
+#[derive(Default)]
+pub struct StateDecoder {
+    history: History,
+    /// Set whose parts are still arriving
+    incomplete: Option<IncompleteSet>,
+}
+
+struct IncompleteSet {
+    order: usize,
+    received: u32,
+    states: CompactStates,
+}
+
+impl StateDecoder {
+    /// Returns None if the set is relative to one which is no longer known or
+    /// if some of its parts did not arrive yet. Does not check the order, that
+    /// is up to the caller.
+    pub fn decode(&mut self, set: CompactStateSet) -> Option<RemoteStateSnapshotSet> {
+        let base = match set.baseline {
+            0 => CompactStates::new(),
+            baseline => self.history.get(baseline)?.clone(),
+        };
+        let (mut states, received) = match self.incomplete.take() {
+            Some(incomplete) if incomplete.order == set.order => {
+                (incomplete.states, incomplete.received + 1)
+            }
+            _ => (base.clone(), 1),
+        };
+        for (id, entry) in set.clients {
+            match entry {
+                CompactEntry::Full(state) => {
+                    states.insert(id, state);
+                }
+                CompactEntry::Delta(delta) => {
+                    states.insert(id, delta.apply_to(base.get(&id)?)?);
+                }
+                CompactEntry::Removed => {
+                    states.remove(&id);
+                }
+            }
+        }
+        if received < set.parts {
+            self.incomplete = Some(IncompleteSet {
+                order: set.order,
+                received,
+                states,
+            });
+            return None;
+        }
+
+        let clients = states
+            .iter()
+            .map(|(id, state)| (*id, state.into()))
+            .collect();
+        self.history.remember(set.order, states);
+        Some(RemoteStateSnapshotSet {
+            order: set.order,
+            clients,
+        })
+    }
+}
+
+impl CompactState {
+    fn delta_from(&self, base: &CompactState) -> Option<CompactStateDelta> {
+        if self.controllers.len() != base.controllers.len() {
+            return None;
+        }
+        let controllers = self
+            .controllers
+            .iter()
+            .zip(&base.controllers)
+            .map(|(controller, base)| {
+                if controller.interaction_profile != base.interaction_profile
+                    || controller.user_path != base.user_path
+                {
+                    return None;
+                }
+                controller.pose.delta_from(&base.pose)
+            })
+            .collect::<Option<_>>()?;
+        Some(CompactStateDelta {
+            controllers,
+            view: self.view.delta_from(&base.view)?,
+            required_configuration: self.required_configuration,
+            nanos: self.nanos,
+        })
+    }
+}
+
+impl CompactStateDelta {
+    fn apply_to(&self, base: &CompactState) -> Option<CompactState> {
+        if self.controllers.len() != base.controllers.len() {
+            return None;
+        }
+        let controllers = self
+            .controllers
+            .iter()
+            .zip(&base.controllers)
+            .map(|(delta, base)| CompactController {
+                interaction_profile: base.interaction_profile,
+                user_path: base.user_path,
+                pose: delta.apply_to(&base.pose),
+            })
+            .collect();
+        Some(CompactState {
+            controllers,
+            view: self.view.apply_to(&base.view),
+            required_configuration: self.required_configuration,
+            nanos: self.nanos,
+        })
+    }
+}
+
+impl CompactPose {
+    /// None if the position moved too far to fit into the delta
+    fn delta_from(&self, base: &CompactPose) -> Option<CompactPoseDelta> {
+        let [x, y, z] = [0, 1, 2]
+            .map(|i| i16::try_from(i64::from(self.position[i]) - i64::from(base.position[i])).ok());
+        Some(CompactPoseDelta {
+            position: [x?, y?, z?],
+            orientation: self.orientation,
+        })
+    }
+}
+
+impl CompactPoseDelta {
+    fn apply_to(&self, base: &CompactPose) -> CompactPose {
+        CompactPose {
+            position: [0, 1, 2].map(|i| base.position[i].wrapping_add(self.position[i].into())),
+            orientation: self.orientation,
+        }
+    }
+}
+
+impl From<&Pose> for CompactPose {
+    fn from(pose: &Pose) -> Self {
+        let Vec3 { x, y, z } = pose.position;
+        Self {
+            position: [x, y, z].map(|v| (v * POSITION_SCALE).round() as i32),
+            orientation: compress_orientation(&pose.orientation),
+        }
+    }
+}
+
+impl From<&CompactPose> for Pose {
+    fn from(pose: &CompactPose) -> Self {
+        let [x, y, z] = pose.position.map(|v| v as f32 / POSITION_SCALE);
+        Self {
+            position: Vec3 { x, y, z },
+            orientation: decompress_orientation(pose.orientation),
+        }
+    }
+}
+
+impl From<&StateSnapshot> for CompactState {
+    fn from(state: &StateSnapshot) -> Self {
+        Self {
+            controllers: state
+                .controllers
+                .iter()
+                .map(|controller| CompactController {
+                    interaction_profile: controller.interaction_profile,
+                    user_path: controller.user_path,
+                    pose: (&controller.pose).into(),
+                })
+                .collect(),
+            view: (&state.view).into(),
+            required_configuration: state.required_configuration,
+            nanos: state.nanos,
+        }
+    }
+}
+
+impl From<&CompactState> for StateSnapshot {
+    fn from(state: &CompactState) -> Self {
+        Self {
+            controllers: state
+                .controllers
+                .iter()
+                .map(|controller| Controller {
+                    interaction_profile: controller.interaction_profile,
+                    user_path: controller.user_path,
+                    pose: (&controller.pose).into(),
+                })
+                .collect(),
+            view: (&state.view).into(),
+            required_configuration: state.required_configuration,
+            nanos: state.nanos,
+        }
+    }
+}
+
+/// Packs index of the largest component in two top bits followed by the
+/// other three components. The largest one is recomputed from them.
+fn compress_orientation(q: &Quaternion) -> u32 {
+    let components = [q.x, q.y, q.z, q.w];
+    let norm = components.iter().map(|c| c * c).sum::<f32>().sqrt();
+    if !norm.is_normal() {
+        return compress_orientation(&Quaternion::default());
+    }
+    let largest = (0..4)
+        .max_by(|a, b| components[*a].abs().total_cmp(&components[*b].abs()))
+        .unwrap_or(3);
+    // q and -q are the same rotation, make the dropped component positive
+    let scale = components[largest].signum() / norm;
+    let mut bits = largest as u32;
+    for (i, c) in components.iter().enumerate() {
+        if i != largest {
+            let normalized = (c * scale / FRAC_1_SQRT_2 + 1.) / 2.;
+            let quantized = (normalized * ORIENTATION_MAX as f32).round() as u32;
+            bits = bits << ORIENTATION_BITS | quantized.min(ORIENTATION_MAX);
+        }
+    }
+    bits
+}
+
+fn decompress_orientation(bits: u32) -> Quaternion {
+    let largest = (bits >> (3 * ORIENTATION_BITS)) as usize;
+    let mut components = [0.; 4];
+    let mut shift = 3 * ORIENTATION_BITS;
+    for (i, c) in components.iter_mut().enumerate() {
+        if i != largest {
+            shift -= ORIENTATION_BITS;
+            let quantized = (bits >> shift) & ORIENTATION_MAX;
+            *c = (quantized as f32 / ORIENTATION_MAX as f32 * 2. - 1.) * FRAC_1_SQRT_2;
+        }
+    }
+    let rest: f32 = components.iter().map(|c| c * c).sum();
+    components[largest] = (1. - rest).max(0.).sqrt();
+    let [x, y, z, w] = components;
+    Quaternion { x, y, z, w }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quaternion(x: f32, y: f32, z: f32, w: f32) -> Quaternion {
+        let norm = (x * x + y * y + z * z + w * w).sqrt();
+        Quaternion {
+            x: x / norm,
+            y: y / norm,
+            z: z / norm,
+            w: w / norm,
+        }
+    }
+
+    /// q and -q are the same rotation, so only the angle between them matters
+    fn assert_same_rotation(a: &Quaternion, b: &Quaternion) {
+        let dot = a.x * b.x + a.y * b.y + a.z * b.z + a.w * b.w;
+        assert!(dot.abs() > 0.9999, "{:?} != {:?}", a, b);
+    }
+
+    fn assert_same_pose(a: &Pose, b: &Pose) {
+        let (a_position, b_position) = (&a.position, &b.position);
+        for (a, b) in [
+            (a_position.x, b_position.x),
+            (a_position.y, b_position.y),
+            (a_position.z, b_position.z),
+        ] {
+            assert!((a - b).abs() <= 0.5 / POSITION_SCALE, "{} != {}", a, b);
+        }
+        assert_same_rotation(&a.orientation, &b.orientation);
+    }
+
+    fn assert_same_set(a: &RemoteStateSnapshotSet, b: &RemoteStateSnapshotSet) {
+        assert_eq!(a.order, b.order);
+        let mut ids: Vec<_> = a.clients.keys().collect();
+        ids.sort();
+        let mut other: Vec<_> = b.clients.keys().collect();
+        other.sort();
+        assert_eq!(ids, other);
+        for id in ids {
+            let (a, b) = (&a.clients[id], &b.clients[id]);
+            assert_eq!(a.controllers.len(), b.controllers.len());
+            for (a, b) in a.controllers.iter().zip(&b.controllers) {
+                assert_same_pose(&a.pose, &b.pose);
+            }
+            assert_same_pose(&a.view, &b.view);
+            assert_eq!(a.nanos, b.nanos);
+        }
+    }
+
+    fn state(x: f32, angle: f32) -> StateSnapshot {
+        let (sin, cos) = (angle / 2.).sin_cos();
+        StateSnapshot {
+            controllers: vec![Controller {
+                interaction_profile: 1,
+                user_path: 2,
+                pose: Pose {
+                    position: Vec3 { x, y: 1., z: -x },
+                    orientation: quaternion(sin, 0., 0., cos),
+                },
+            }],
+            view: Pose {
+                position: Vec3 { x, y: 1.6, z: 0. },
+                orientation: quaternion(0., sin, 0., cos),
+            },
+            required_configuration: 1,
+            nanos: (x * 1e9) as i64,
+        }
+    }
+
+    fn set(order: usize, states: &[(ClientId, StateSnapshot)]) -> RemoteStateSnapshotSet {
+        RemoteStateSnapshotSet {
+            order,
+            clients: states.iter().cloned().collect(),
+        }
+    }
+
+    #[test]
+    fn orientation_round_trips() {
+        let orientations = [
+            quaternion(0., 0., 0., 1.),
+            quaternion(0., 0., 0., -1.),
+            quaternion(1., 0., 0., 0.),
+            quaternion(0., -1., 0., 0.),
+            quaternion(0.999, 0.01, -0.02, 0.03),
+            quaternion(0.01, -0.02, 0.03, -0.999),
+            quaternion(0.3, -0.5, 0.1, -0.8),
+            quaternion(0.5, 0.5, -0.5, -0.5),
+            quaternion(0.7, 0.7, 0.1, 0.1),
+        ];
+        for orientation in orientations {
+            let decompressed = decompress_orientation(compress_orientation(&orientation));
+            assert_same_rotation(&orientation, &decompressed);
+        }
+    }
+
+    #[test]
+    fn invalid_orientation_becomes_identity() {
+        let zero = Quaternion {
+            x: 0.,
+            y: 0.,
+            z: 0.,
+            w: 0.,
+        };
+        let decompressed = decompress_orientation(compress_orientation(&zero));
+        assert_same_rotation(&decompressed, &Quaternion::default());
+    }
+
+    #[test]
+    fn position_is_quantized_to_half_step() {
+        let step = 1. / POSITION_SCALE;
+        for value in [
+            0.,
+            step / 2. - 1e-6,
+            -step / 2. + 1e-6,
+            step,
+            -0.74 * step,
+            1000.,
+            -1000.,
+        ] {
+            let pose = Pose {
+                position: Vec3 {
+                    x: value,
+                    y: -value,
+                    z: value,
+                },
+                ..Default::default()
+            };
+            assert_same_pose(&pose, &Pose::from(&CompactPose::from(&pose)));
+        }
+    }
+
+    #[test]
+    fn far_positions_saturate() {
+        let far = |x| Pose {
+            position: Vec3 { x, y: 0., z: 0. },
+            ..Default::default()
+        };
+        assert_eq!(CompactPose::from(&far(1e7)).position[0], i32::MAX);
+        assert_eq!(CompactPose::from(&far(-1e7)).position[0], i32::MIN);
+    }
+
+    #[test]
+    fn delta_against_acknowledged_set() {
+        let (mut encoder, mut decoder) = (StateEncoder::default(), StateDecoder::default());
+        let first = set(
+            1,
+            &[
+                (1, state(0.1, 0.2)),
+                (2, state(0.5, 1.)),
+                (3, state(2., 0.)),
+            ],
+        );
+        let encoded = encoder.encode(&first);
+        assert_eq!(encoded.baseline, 0);
+        assert_same_set(&first, &decoder.decode(encoded).unwrap());
+
+        // not acknowledged yet, so it can not be a delta
+        let second = set(
+            2,
+            &[
+                (1, state(0.2, 0.3)),
+                (2, state(0.5, 1.)),
+                (3, state(2., 0.)),
+            ],
+        );
+        assert_eq!(encoder.encode(&second).baseline, 0);
+
+        encoder.acknowledge(1);
+        let third = set(
+            3,
+            &[
+                (1, state(0.2, 0.3)),
+                (2, state(0.5, 1.)),
+                (4, state(1., 0.)),
+            ],
+        );
+        let encoded = encoder.encode(&third);
+        assert_eq!(encoded.baseline, 1);
+        let mut entries: Vec<_> = encoded
+            .clients
+            .iter()
+            .map(|(id, entry)| match entry {
+                CompactEntry::Full(_) => (*id, "full"),
+                CompactEntry::Delta(_) => (*id, "delta"),
+                CompactEntry::Removed => (*id, "removed"),
+            })
+            .collect();
+        entries.sort();
+        // 2 did not change since the baseline
+        assert_eq!(entries, [(1, "delta"), (3, "removed"), (4, "full")]);
+        assert_same_set(&third, &decoder.decode(encoded).unwrap());
+    }
+
+    #[test]
+    fn large_move_is_sent_in_full() {
+        let mut encoder = StateEncoder::default();
+        encoder.encode(&set(1, &[(1, state(0., 0.))]));
+        encoder.acknowledge(1);
+        let encoded = encoder.encode(&set(2, &[(1, state(20., 0.))]));
+        assert!(matches!(encoded.clients[..], [(1, CompactEntry::Full(_))]));
+    }
+
+    #[test]
+    fn split_set_is_applied_once_complete() {
+        let states: Vec<_> = (1..=20).map(|id| (id, state(id as f32, 0.))).collect();
+        let (mut encoder, mut decoder) = (StateEncoder::default(), StateDecoder::default());
+        let first = set(1, &states);
+        let parts = encoder.encode(&first).split(200);
+        assert!(parts.len() > 2, "only {} parts", parts.len());
+        assert!(parts.iter().all(|part| part.parts == parts.len() as u32));
+        let count = parts.len();
+        for (i, part) in parts.into_iter().enumerate() {
+            let decoded = decoder.decode(part);
+            if i + 1 < count {
+                assert!(decoded.is_none());
+            } else {
+                assert_same_set(&first, &decoded.unwrap());
+            }
+        }
+
+        // second part is lost, so the set is never applied
+        encoder.acknowledge(1);
+        let moved: Vec<_> = (1..=20)
+            .map(|id| (id, state(id as f32 + 0.5, 1.)))
+            .collect();
+        let mut parts = encoder.encode(&set(2, &moved)).split(200);
+        assert!(parts.len() > 2, "only {} parts", parts.len());
+        parts.remove(1);
+        for part in parts {
+            assert!(decoder.decode(part).is_none());
+        }
+
+        // baseline is still the last applied set
+        let third = set(3, &states[2..]);
+        let parts = encoder.encode(&third).split(200);
+        assert!(parts.iter().all(|part| part.baseline == 1));
+        let decoded: Vec<_> = parts
+            .into_iter()
+            .filter_map(|part| decoder.decode(part))
+            .collect();
+        assert_eq!(decoded.len(), 1);
+        assert_same_set(&third, &decoded[0]);
+    }
+
+    #[test]
+    fn empty_change_still_sends_order() {
+        let mut encoder = StateEncoder::default();
+        encoder.encode(&set(1, &[(1, state(0., 0.))]));
+        encoder.acknowledge(1);
+        let parts = encoder.encode(&set(2, &[(1, state(0., 0.))])).split(200);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].order, 2);
+        assert!(parts[0].clients.is_empty());
+    }
+
+    #[test]
+    fn missing_baseline_is_not_decoded() {
+        let (mut encoder, mut decoder) = (StateEncoder::default(), StateDecoder::default());
+        // decoder never receives the first set
+        encoder.encode(&set(1, &[(1, state(0., 0.))]));
+        encoder.acknowledge(1);
+        let encoded = encoder.encode(&set(2, &[(1, state(0.1, 0.))]));
+        assert_eq!(encoded.baseline, 1);
+        assert!(decoder.decode(encoded).is_none());
+    }
+
+    #[test]
+    fn forgotten_baseline_is_not_decoded() {
+        let (mut encoder, mut decoder) = (StateEncoder::default(), StateDecoder::default());
+        decoder.decode(encoder.encode(&set(1, &[(1, state(0., 0.))])));
+        encoder.acknowledge(1);
+        let delta = encoder.encode(&set(2, &[(1, state(0.1, 0.))]));
+        assert_eq!(delta.baseline, 1);
+
+        // history only keeps the latest sets
+        let mut other = StateEncoder::default();
+        for order in 10..(10 + HISTORY_LENGTH) {
+            let encoded = other.encode(&set(order, &[(1, state(order as f32, 0.))]));
+            decoder.decode(encoded).unwrap();
+        }
+        assert!(decoder.decode(delta).is_none());
+    }
+}
@@ -89,4 +89,10 @@ impl<T: serde::ser::Serialize> SendFrames<T> {
         self.inner.write_all(&data).await?;
         Ok(())
     }
+
+    /// Closes the stream and waits until the peer received all the data.
+    pub async fn finish(&mut self) -> Result<(), FramingError> {
+        self.inner.finish().await?;
+        Ok(())
+    }
 }
@@ -1,4 +1,5 @@
 pub mod app;
+pub mod compact;
 mod framing;
 pub mod handle_serializer;
 
@@ -1,27 +1,178 @@
-use std::{collections::HashMap, fmt::Debug};
+use std::{collections::HashMap, fmt::Debug, ops::BitOr};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{app, Pose};
+use crate::{app, compact::CompactStateSet, Pose};
+
+/// Version of the wire protocol. Has to be bumped every time the layout of any
+/// type sent over the network changes. Client and server must use the same one.
+pub const PROTOCOL_VERSION: u32 = 11;
+
+/// UDP port the server listens on for QUIC connections unless it is taken.
+/// Used when connecting to explicit address which does not specify a port.
+pub const DEFAULT_SERVER_PORT: u16 = 13162;
+
+/// UDP port on which the server answers discovery broadcasts
+pub const DEFAULT_DISCOVERY_PORT: u16 = 13161;
+
+/// TCP port of the dashboard, same number as discovery but different protocol
+pub const DEFAULT_DASHBOARD_PORT: u16 = 13161;
+
+/// Room clients join unless they ask for a different one
+pub const DEFAULT_ROOM: &str = "default";
+
+/// Set of optional protocol features. Streams and message variants belonging
+/// to a feature are only used if both sides advertise it.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Synchronized objects (app_up/app_down streams and app datagrams)
+    pub const APP_OBJECTS: Self = Self(1 << 0);
+    /// Calibration sample collection (calibrat stream)
+    pub const CALIBRATION: Self = Self(1 << 1);
+
+    /// No optional features
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// All optional features known to this build
+    pub const fn all() -> Self {
+        Self(Self::APP_OBJECTS.0 | Self::CALIBRATION.0)
+    }
+
+    /// Checks if all features from other are present in self
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Features supported by both sets
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// First frame sent by the client on the hello stream. New fields must only
+/// ever be appended so that older servers can still read the version.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientHello {
+    pub protocol_version: u32,
+    pub capabilities: Capabilities,
+    /// Shared secret required by servers which do not admit everyone
+    pub join_token: Option<String>,
+    /// Room to join, DEFAULT_ROOM if not specified
+    pub room: Option<String>,
+    /// Name the client goes by, lets the server refuse revoked clients before
+    /// admitting them. Chosen by the client, so it does not prove identity.
+    pub name: Option<String>,
+}
+
+impl Default for ClientHello {
+    fn default() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::all(),
+            join_token: None,
+            room: None,
+            name: None,
+        }
+    }
+}
+
+/// Answer to ClientHello. Layout of this type must never change so that
+/// incompatible clients can still read why they were rejected.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ServerHello {
+    Accepted {
+        protocol_version: u32,
+        /// Features both sides support, these are the ones to be used.
+        capabilities: Capabilities,
+        client_id: ClientId,
+    },
+    Rejected {
+        protocol_version: u32,
+        reason: String,
+    },
+}
+
+/// Application error codes used when closing the QUIC connection
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CloseCode {
+    Normal = 0,
+    IncompatibleProtocol = 1,
+    MissingHello = 2,
+    /// Join token was missing or wrong
+    Unauthorized = 3,
+    /// Operator removed the client from the session
+    Revoked = 4,
+}
+
+impl CloseCode {
+    /// Raw code to be passed to quinn
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+}
+
+/// SHA-256 hash of server's certificate in DER format
+pub type Fingerprint = [u8; 32];
+
+/// Computes fingerprint of DER encoded certificate
+#[cfg(not(target_arch = "wasm32"))]
+pub fn fingerprint_of(cert_der: &[u8]) -> Fingerprint {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert_der);
+    let mut fingerprint = Fingerprint::default();
+    fingerprint.copy_from_slice(digest.as_ref());
+    fingerprint
+}
+
+/// Formats fingerprint as lowercase hex string
+pub fn fingerprint_to_hex(fingerprint: &Fingerprint) -> String {
+    fingerprint.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses fingerprint formatted by fingerprint_to_hex
+pub fn fingerprint_from_hex(text: &str) -> Option<Fingerprint> {
+    let text = text.trim();
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+    let mut fingerprint = Fingerprint::default();
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(fingerprint)
+}
 
 /// Response from discovery server
 #[derive(Serialize, Deserialize)]
 pub struct DiscoveryResponse {
     header: [u8; 5],
+    /// Fingerprint of the certificate the server will present
+    pub fingerprint: Fingerprint,
 }
 
 impl DiscoveryResponse {
-    pub fn validate_header(&self) -> bool {
-        self.header == [b'n', b'e', b't', b'v', b'r']
-    }
-}
-
-impl Default for DiscoveryResponse {
-    fn default() -> Self {
+    pub fn new(fingerprint: Fingerprint) -> Self {
         Self {
             header: [b'n', b'e', b't', b'v', b'r'],
+            fingerprint,
         }
     }
+
+    pub fn validate_header(&self) -> bool {
+        self.header == [b'n', b'e', b't', b'v', b'r']
+    }
 }
 
 /// Space to be used for calibration sample collection
@@ -40,6 +191,9 @@ pub enum ConfigurationDown {
     RequestSample(String, BaseSpace),
     StopCalibration,
     ChangeName(String),
+    /// Sent when the client is put into a room, either on connect or when
+    /// moved by the dashboard
+    JoinedRoom(String),
 }
 
 /// Controller data
@@ -56,6 +210,10 @@ pub struct StateSnapshot {
     pub controllers: Vec<Controller>,
     pub view: Pose,
     pub required_configuration: u32,
+    /// When the poses were captured, in sender's XrTime nanoseconds. Zero if
+    /// unknown.
+    #[serde(default)]
+    pub nanos: i64,
 }
 
 /// Type of OpenXR acction but serializable
@@ -141,6 +299,23 @@ pub struct CalibrationSample {
 pub struct CalibrationConfiguration {
     pub sample_count: usize,
     pub sample_interval_nanos: i64,
+    /// Server stops collecting before sample_count once the samples cover
+    /// enough rotations. Clients can ignore it.
+    #[serde(default)]
+    pub stop_on_coverage: bool,
+    #[serde(default)]
+    pub solver: CalibrationSolver,
+}
+
+/// What the calibration solves for. Clients can ignore it.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationSolver {
+    /// Rotation around all axes and translation
+    #[default]
+    Full,
+    /// Rotation around the vertical axis and translation. Stage spaces of all
+    /// runtimes agree on which way is up, so pitch and roll only add error.
+    GravityAligned,
 }
 
 /// Id of a client
@@ -159,26 +334,48 @@ pub struct RemoteStateSnapshotSet {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum DatagramDown {
     App(app::Snapshot),
-    State(RemoteStateSnapshotSet),
+    /// Decoded into RemoteStateSnapshotSet by compact::StateDecoder
+    State(CompactStateSet),
 }
 
 /// Whati s sent from client to server over unrealiable channel
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum DatagramUp {
-    State(StateSnapshot),
+    /// Local state and order of the latest DatagramDown::State applied by the
+    /// client, zero if none
+    State(StateSnapshot, usize),
     App(app::AppDatagramUp),
+    /// Answer to Heartbeat: its server_nanos and the client's clock at the
+    /// time it arrived
+    Pong {
+        server_nanos: i64,
+        client_nanos: i64,
+    },
 }
 
-/// Sent periodically to check that connection is still alive
+/// Sent periodically to check that connection is still alive. Clients answer
+/// with DatagramUp::Pong so that the server can estimate their clock.
 /// ... this was triumph, I'm making a note here: huge success
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Heartbeat {
-    _buf: [u8; 5],
+    /// Server time when the heartbeat was sent, nanoseconds since UNIX epoch
+    pub server_nanos: i64,
+    /// How the server currently sees the client's clock
+    pub clock: Option<ClockEstimate>,
 }
-impl Default for Heartbeat {
-    fn default() -> Self {
-        Self {
-            _buf: [b'h', b'e', b'l', b'l', b'o'],
-        }
+
+/// Relation between a client's clock and the server's
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ClockEstimate {
+    /// Client time minus server time
+    pub offset_nanos: i64,
+    /// How far the true offset can be from offset_nanos
+    pub uncertainty_nanos: i64,
+}
+
+impl ClockEstimate {
+    /// Converts time read from the client's clock to server time
+    pub fn to_server(&self, client_nanos: i64) -> i64 {
+        client_nanos - self.offset_nanos
     }
 }
@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use netvr_data::Pose;
 use serde::{Deserialize, Serialize};
@@ -11,6 +11,20 @@ pub(crate) struct Config {
     pub name: String,
     #[serde(default)]
     pub server_space_pose: Pose,
+    /// Connect to this server instead of using discovery. Useful on networks
+    /// which block broadcast.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_address: Option<String>,
+    /// Forget pinned server fingerprint on next connection. Set this when the
+    /// server identity was regenerated on purpose. Cleared after use.
+    #[serde(default)]
+    pub reset_server_pin: bool,
+    /// Secret required by servers started with a join token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join_token: Option<String>,
+    /// Room to join, updated when the dashboard moves this client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
 
     #[serde(skip)]
     data_directory: String,
@@ -39,6 +53,11 @@ impl Config {
         }
     }
 
+    /// File in which the fingerprint of trusted server is stored
+    pub fn server_pin_file(&self) -> PathBuf {
+        Path::new(&self.data_directory).join("server_pin.txt")
+    }
+
     pub async fn write(&self) {
         let config = serde_json::to_string(&self).unwrap();
         if let Err(err) =
@@ -62,11 +62,26 @@ pub(crate) fn read_remote_devices(
             .remote_merged
             .read()
             .map_err(|err| anyhow!("{:?}", err))?;
+        let remote_history = session
+            .remote_history
+            .read()
+            .map_err(|err| anyhow!("{:?}", err))?;
+        let display_time = session.predicted_display_time.as_nanos();
 
         for (client_id, client) in remote_merged.clients.iter() {
+            // only use smoothed state if it was captured with the same
+            // configuration as the merged one
+            let smoothed = remote_history
+                .get(client_id)
+                .filter(|_| display_time > 0)
+                .and_then(|history| history.sample(display_time))
+                .filter(|state| {
+                    state.required_configuration == client.state.required_configuration
+                });
+            let state = smoothed.as_ref().unwrap_or(&client.state);
             let mut i = 0;
             {
-                let device = client.state.view.clone();
+                let device = state.view.clone();
                 i += 1;
                 devices.devices.push(RemoteDevice {
                     id: client_id * 100 + i,
@@ -76,7 +91,7 @@ pub(crate) fn read_remote_devices(
                     interaction_profile: "generic_hmd".to_owned(),
                 });
             }
-            for device in client.state.controllers.iter() {
+            for device in state.controllers.iter() {
                 let Some(interaction_profile) = client
                     .configuration
                     .interaction_profiles
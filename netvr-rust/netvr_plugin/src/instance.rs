@@ -7,7 +7,8 @@ use std::{
 use anyhow::{anyhow, Result};
 use netvr_data::{
     app,
-    net::{self, ConfigurationSnapshotSet, RemoteStateSnapshotSet},
+    compact::StateDecoder,
+    net::{self, ClientId, ConfigurationSnapshotSet, RemoteStateSnapshotSet},
     Pose, RemoteSnapshot,
 };
 use tokio::sync::watch;
@@ -20,7 +21,9 @@ use xr_layer::{
     XrDebug,
 };
 
-use crate::{local_configuration::LocalConfigurationSnapshot, xr_wrap::Trace};
+use crate::{
+    interpolation::RemoteHistory, local_configuration::LocalConfigurationSnapshot, xr_wrap::Trace,
+};
 
 /// This struct has 1-1 correspondence with each session the application creates
 /// It is used to hold the underlying session from runtime and extra data
@@ -45,6 +48,10 @@ pub(crate) struct Session {
     /// This contains data that is received from the server and is made
     /// available to the application.
     pub(crate) remote_state: Arc<RwLock<RemoteStateSnapshotSet>>,
+    /// Decodes state sets of the current room, reset when the room changes
+    pub(crate) state_decoder: Arc<RwLock<StateDecoder>>,
+    /// Recent states of each remote client used to smooth their poses
+    pub(crate) remote_history: Arc<RwLock<HashMap<ClientId, RemoteHistory>>>,
     pub(crate) remote_configuration: Arc<RwLock<ConfigurationSnapshotSet>>,
 
     pub(crate) remote_app_state: Arc<RwLock<app::Snapshot>>,
@@ -89,6 +96,8 @@ impl Session {
             started_session: AtomicBool::new(false),
 
             remote_state: Arc::default(),
+            state_decoder: Arc::default(),
+            remote_history: Arc::default(),
             remote_configuration: Arc::default(),
             remote_merged: Arc::default(),
 
@@ -0,0 +1,332 @@
+//! Smooths out remote devices. States of remote clients arrive in bursts and
+//! some of them get lost, so instead of showing the newest one they are shown
+//! slightly delayed and interpolated to the time of the displayed frame. If
+//! newer data is late, poses are extrapolated for a short while.
+
+use std::collections::VecDeque;
+
+use nalgebra::{UnitQuaternion, Vector3};
+use netvr_data::{
+    net::{Controller, StateSnapshot},
+    Pose, Quaternion, Vec3,
+};
+
+/// How long states are remembered
+const HISTORY_NANOS: i64 = 1_000_000_000;
+/// Remote state is shown this far in the past so that there usually is a newer
+/// state to interpolate towards
+const DELAY_NANOS: i64 = 50_000_000;
+/// For how long poses keep moving when no new state arrives
+const MAX_EXTRAPOLATION_NANOS: i64 = 100_000_000;
+
+struct Sample {
+    /// Local XrTime when the state arrived
+    received: i64,
+    state: StateSnapshot,
+}
+
+/// Recent states of one remote client
+#[derive(Default)]
+pub(crate) struct RemoteHistory {
+    samples: VecDeque<Sample>,
+}
+
+impl RemoteHistory {
+    /// Call whenever a state set arrives. Repeated states and states without
+    /// capture time are ignored.
+    pub(crate) fn push(&mut self, state: &StateSnapshot, received: i64) {
+        let newest = self.samples.back().map_or(0, |sample| sample.state.nanos);
+        if state.nanos <= newest {
+            return;
+        }
+        self.samples.push_back(Sample {
+            received,
+            state: state.clone(),
+        });
+        while let Some(oldest) = self.samples.front() {
+            if oldest.state.nanos >= state.nanos - HISTORY_NANOS {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// State as it should be shown in a frame displayed at given local
+    /// XrTime. None if there is no state with capture time.
+    pub(crate) fn sample(&self, display_time: i64) -> Option<StateSnapshot> {
+        // Clocks of the two clients are unrelated. Their difference plus the
+        // lowest latency seen recently maps remote times to local ones.
+        let offset = self
+            .samples
+            .iter()
+            .map(|sample| sample.received - sample.state.nanos)
+            .min()?;
+        let target = display_time - offset - DELAY_NANOS;
+        let next = self
+            .samples
+            .iter()
+            .position(|sample| sample.state.nanos > target);
+        let (a, b) = match next {
+            Some(0) => return Some(self.samples[0].state.clone()),
+            Some(next) => (&self.samples[next - 1], &self.samples[next]),
+            None => {
+                let newest = self.samples.back()?;
+                let target = target.min(newest.state.nanos + MAX_EXTRAPOLATION_NANOS);
+                return Some(match self.samples.iter().nth_back(1) {
+                    Some(previous) => interpolate(&previous.state, &newest.state, target),
+                    None => newest.state.clone(),
+                });
+            }
+        };
+        Some(interpolate(&a.state, &b.state, target))
+    }
+}
+
+/// Interpolates between a and b, or extrapolates if target is after b.
+/// Devices which are not in both states are taken from b.
+fn interpolate(a: &StateSnapshot, b: &StateSnapshot, target: i64) -> StateSnapshot {
+    let t = (target - a.nanos) as f32 / (b.nanos - a.nanos) as f32;
+    let controllers = b
+        .controllers
+        .iter()
+        .map(|controller| {
+            let previous = a.controllers.iter().find(|previous| {
+                previous.user_path == controller.user_path
+                    && previous.interaction_profile == controller.interaction_profile
+            });
+            Controller {
+                pose: match previous {
+                    Some(previous) => lerp_pose(&previous.pose, &controller.pose, t),
+                    None => controller.pose.clone(),
+                },
+                ..controller.clone()
+            }
+        })
+        .collect();
+    StateSnapshot {
+        controllers,
+        view: lerp_pose(&a.view, &b.view, t),
+        required_configuration: b.required_configuration,
+        nanos: target,
+    }
+}
+
+/// Same as slerp for t in 0..1, continues with the same velocity after that
+fn lerp_pose(a: &Pose, b: &Pose, t: f32) -> Pose {
+    let position = |v: &Vec3| Vector3::new(v.x, v.y, v.z);
+    let orientation = |q: &Quaternion| {
+        UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(q.w, q.x, q.y, q.z))
+    };
+    let (pa, pb) = (position(&a.position), position(&b.position));
+    let (qa, qb) = (orientation(&a.orientation), orientation(&b.orientation));
+
+    let p = pa + (pb - pa) * t;
+    let q = (qb * qa.inverse()).powf(t) * qa;
+    Pose {
+        position: Vec3 {
+            x: p.x,
+            y: p.y,
+            z: p.z,
+        },
+        orientation: Quaternion {
+            x: q.i,
+            y: q.j,
+            z: q.k,
+            w: q.w,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic random numbers, so that failures can be reproduced
+    struct Random(u64);
+
+    impl Random {
+        /// Uniform in [0, 1), xorshift64*
+        fn unit(&mut self) -> f32 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 40) as f32 / (1u64 << 24) as f32
+        }
+
+        /// Uniform in [min, max)
+        fn range(&mut self, min: f32, max: f32) -> f32 {
+            min + (max - min) * self.unit()
+        }
+
+        /// Uniform in [min, max)
+        fn nanos(&mut self, min: i64, max: i64) -> i64 {
+            min + ((max - min) as f32 * self.unit()) as i64
+        }
+    }
+
+    /// Pose at x meters along the x axis turned by yaw radians
+    fn pose(x: f32, yaw: f32) -> Pose {
+        let q = UnitQuaternion::from_euler_angles(0., yaw, 0.);
+        Pose {
+            position: Vec3 { x, y: 1.5, z: 0. },
+            orientation: Quaternion {
+                x: q.i,
+                y: q.j,
+                z: q.k,
+                w: q.w,
+            },
+        }
+    }
+
+    /// Head and right hand both at the same pose
+    fn state(nanos: i64, x: f32, yaw: f32) -> StateSnapshot {
+        StateSnapshot {
+            controllers: vec![Controller {
+                interaction_profile: 1,
+                user_path: 2,
+                pose: pose(x, yaw),
+            }],
+            view: pose(x, yaw),
+            required_configuration: 0,
+            nanos,
+        }
+    }
+
+    fn yaw(pose: &Pose) -> f32 {
+        let q = &pose.orientation;
+        let q = UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(q.w, q.x, q.y, q.z));
+        q.euler_angles().1
+    }
+
+    fn assert_pose(actual: &Pose, x: f32, yaw_radians: f32) {
+        assert!(
+            (actual.position.x - x).abs() < 1e-4 && (yaw(actual) - yaw_radians).abs() < 1e-4,
+            "expected x {} yaw {}, got {:?} yaw {}",
+            x,
+            yaw_radians,
+            actual.position,
+            yaw(actual)
+        );
+    }
+
+    #[test]
+    fn interpolates_between_two_states() {
+        let mut random = Random(0x1d0c_5a7e_0000_0015);
+        for _ in 0..100 {
+            let offset = random.nanos(-1_000_000_000_000, 1_000_000_000_000);
+            let latency = random.nanos(1_000_000, 40_000_000);
+            let a = state(
+                random.nanos(1, 1_000_000_000_000),
+                random.range(-2., 2.),
+                random.range(-1., 1.),
+            );
+            let b = state(
+                a.nanos + random.nanos(5_000_000, 30_000_000),
+                random.range(-2., 2.),
+                random.range(-1., 1.),
+            );
+            let mut history = RemoteHistory::default();
+            history.push(&a, a.nanos + offset + latency);
+            history.push(&b, b.nanos + offset + latency);
+
+            let t = random.unit();
+            let target = a.nanos + ((b.nanos - a.nanos) as f32 * t) as i64;
+            let t = (target - a.nanos) as f32 / (b.nanos - a.nanos) as f32;
+            let shown = history
+                .sample(target + offset + latency + DELAY_NANOS)
+                .unwrap();
+
+            assert_eq!(shown.nanos, target);
+            let x = a.view.position.x + (b.view.position.x - a.view.position.x) * t;
+            let yaw = yaw(&a.view) + (yaw(&b.view) - yaw(&a.view)) * t;
+            assert_pose(&shown.view, x, yaw);
+            assert_pose(&shown.controllers[0].pose, x, yaw);
+        }
+    }
+
+    #[test]
+    fn extrapolation_is_clamped() {
+        let mut random = Random(0x1d0c_5a7e_0001_0015);
+        for _ in 0..100 {
+            let latency = random.nanos(1_000_000, 40_000_000);
+            let interval = random.nanos(5_000_000, 30_000_000);
+            let velocity = random.range(-2., 2.) * 1e-9;
+            let spin = random.range(-1., 1.) * 1e-9;
+            let mut history = RemoteHistory::default();
+            for nanos in [interval, 2 * interval] {
+                let moved = state(nanos, velocity * nanos as f32, spin * nanos as f32);
+                history.push(&moved, nanos + latency);
+            }
+            let newest = 2 * interval;
+
+            // continues with the same velocity for a while
+            let ahead = random.nanos(0, MAX_EXTRAPOLATION_NANOS);
+            let shown = history
+                .sample(newest + ahead + latency + DELAY_NANOS)
+                .unwrap();
+            let nanos = (newest + ahead) as f32;
+            assert_eq!(shown.nanos, newest + ahead);
+            assert_pose(&shown.view, velocity * nanos, spin * nanos);
+
+            // then stops
+            let late = random.nanos(MAX_EXTRAPOLATION_NANOS, 10 * MAX_EXTRAPOLATION_NANOS);
+            let shown = history
+                .sample(newest + late + latency + DELAY_NANOS)
+                .unwrap();
+            let nanos = (newest + MAX_EXTRAPOLATION_NANOS) as f32;
+            assert_eq!(shown.nanos, newest + MAX_EXTRAPOLATION_NANOS);
+            assert_pose(&shown.view, velocity * nanos, spin * nanos);
+        }
+    }
+
+    #[test]
+    fn old_and_untimed_states_are_ignored() {
+        let mut history = RemoteHistory::default();
+        history.push(&state(0, 1., 0.), 10);
+        assert!(history.sample(1_000_000_000).is_none());
+
+        history.push(&state(100_000_000, 1., 0.), 100_000_000);
+        history.push(&state(200_000_000, 2., 0.), 200_000_000);
+        // arrived late, repeated, and without capture time
+        history.push(&state(150_000_000, 9., 0.), 250_000_000);
+        history.push(&state(200_000_000, 9., 0.), 250_000_000);
+        history.push(&state(0, 9., 0.), 250_000_000);
+
+        let shown = history.sample(150_000_000 + DELAY_NANOS).unwrap();
+        assert_pose(&shown.view, 1.5, 0.);
+        let shown = history.sample(200_000_000 + DELAY_NANOS).unwrap();
+        assert_pose(&shown.view, 2., 0.);
+    }
+
+    #[test]
+    fn offset_uses_lowest_latency() {
+        let mut random = Random(0x1d0c_5a7e_0002_0015);
+        for _ in 0..20 {
+            let offset = random.nanos(-1_000_000_000_000, 1_000_000_000_000);
+            let min_latency = random.nanos(1_000_000, 20_000_000);
+            let fastest = random.nanos(0, 50) as usize;
+            let mut history = RemoteHistory::default();
+            let mut states = vec![];
+            let mut nanos = random.nanos(1, 1_000_000_000);
+            for i in 0..50 {
+                nanos += random.nanos(5_000_000, 15_000_000);
+                let moved = state(nanos, i as f32, 0.);
+                // bursts and lost packets only ever add latency
+                let latency = if i == fastest {
+                    min_latency
+                } else {
+                    min_latency + random.nanos(1_000_000, 60_000_000)
+                };
+                history.push(&moved, nanos + offset + latency);
+                states.push(moved);
+            }
+
+            let expected = &states[random.nanos(0, 49) as usize];
+            let shown = history
+                .sample(expected.nanos + offset + min_latency + DELAY_NANOS)
+                .unwrap();
+            assert_eq!(shown.nanos, expected.nanos);
+            assert_pose(&shown.view, expected.view.position.x, 0.);
+        }
+    }
+}
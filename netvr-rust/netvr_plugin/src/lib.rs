@@ -17,6 +17,7 @@ mod bincode_abi;
 mod config;
 mod implementation;
 mod instance;
+mod interpolation;
 mod local_configuration;
 mod net_client;
 mod overrides;
@@ -1,12 +1,19 @@
-use std::{collections::HashSet, ptr, time::Duration};
+use std::{
+    collections::HashSet,
+    future::{pending, Future},
+    ptr,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
+use netvr_client::ConnectOptions;
 use netvr_data::{
     app::{AppDatagramUp::SetPose, AppDown, AppUp},
     bincode,
+    compact::StateDecoder,
     net::{
         self, ActionType, BaseSpace, CalibrationConfiguration, CalibrationSample, ConfigurationUp,
-        DatagramDown, DatagramUp, StateSnapshot,
+        DatagramDown, DatagramUp, Heartbeat, StateSnapshot,
     },
     Pose, RecvFrames, SendFrames,
 };
@@ -45,7 +52,7 @@ pub(crate) async fn run_net_client(
     data_directory: String,
 ) -> Result<()> {
     LogTrace::str("connecting to netvr server...");
-    let config = Config::load(data_directory).await;
+    let mut config = Config::load(data_directory).await;
     set_local_configuration_name(instance_handle, session_handle, config.name.clone())?;
     set_space_server_pose(
         instance_handle,
@@ -53,8 +60,29 @@ pub(crate) async fn run_net_client(
         config.server_space_pose.clone(),
     )?;
 
-    let connection =
-        netvr_client::connect(|text| LogTrace::string(format!("[conn] {}", text))).await?;
+    if config.reset_server_pin {
+        LogTrace::str("Resetting pinned server fingerprint");
+        netvr_client::reset_server_pin(config.server_pin_file()).await?;
+        config.reset_server_pin = false;
+        config.write().await;
+    }
+
+    let options = ConnectOptions {
+        server_address: match &config.server_address {
+            Some(address) => Some(netvr_client::resolve_server_address(address).await?),
+            None => None,
+        },
+        pin_file: Some(config.server_pin_file()),
+        join_token: config.join_token.clone(),
+        room: config.room.clone(),
+        name: Some(config.name.clone()).filter(|name| !name.is_empty()),
+        ..Default::default()
+    };
+    let connection = netvr_client::connect_with_options(
+        |text| LogTrace::string(format!("[conn] {}", text)),
+        &options,
+    )
+    .await?;
     let remote_address = connection.connection.remote_address().to_string();
     with_layer(instance_handle, |instance| {
         let session = instance
@@ -80,6 +108,11 @@ pub(crate) async fn run_net_client(
 
     // alright, so we are connected let's send info about local devices...
 
+    let heartbeat = run_heartbeat(
+        connection.heartbeat,
+        connection.connection.clone(),
+        instance_handle,
+    );
     let transmit_conf =
         run_transmit_configuration(connection.configuration_up, instance_handle, session_handle);
     let transmit_snap = run_transmit_snapshots(
@@ -99,20 +132,29 @@ pub(crate) async fn run_net_client(
         calibration_trigger.0,
         config,
     );
-    let calibration_sender = spawn(run_calibration_sender(
-        instance_handle,
-        session_handle,
-        connection.calibration_up,
-        calibration_trigger.1,
-    ));
-    let send_app = run_send_app(
-        instance_handle,
-        session_handle,
-        connection.app_up_stream,
-        connection.connection.clone(),
+    let calibration_sender = optional(connection.calibration_up.map(|calibration_up| {
+        spawn(run_calibration_sender(
+            instance_handle,
+            session_handle,
+            calibration_up,
+            calibration_trigger.1,
+        ))
+    }));
+    let send_app = optional(connection.app_up_stream.map(|app_up_stream| {
+        run_send_app(
+            instance_handle,
+            session_handle,
+            app_up_stream,
+            connection.connection.clone(),
+        )
+    }));
+    let recv_app = optional(
+        connection
+            .app_down_stream
+            .map(|app_down_stream| run_recv_app(instance_handle, session_handle, app_down_stream)),
     );
-    let recv_app = run_recv_app(instance_handle, session_handle, connection.app_down_stream);
     select! {
+        value = heartbeat => value,
         value = transmit_conf => value,
         value = transmit_snap => value,
         value = receive => value,
@@ -123,6 +165,15 @@ pub(crate) async fn run_net_client(
     }
 }
 
+/// Runs the future if the stream it needs was negotiated, otherwise never
+/// finishes.
+async fn optional<F: Future>(future: Option<F>) -> F::Output {
+    match future {
+        Some(future) => future.await,
+        None => pending().await,
+    }
+}
+
 async fn run_recv_app(
     instance_handle: sys::Instance,
     session_handle: sys::Session,
@@ -263,6 +314,27 @@ async fn run_transmit_configuration(
     }
 }
 
+/// Answers heartbeats so that the server can estimate our clock
+async fn run_heartbeat(
+    mut heartbeat: RecvFrames<Heartbeat>,
+    connection: quinn::Connection,
+    instance_handle: sys::Instance,
+) -> Result<()> {
+    let mut clock = None;
+    loop {
+        let message = heartbeat.read().await?;
+        let datagram = DatagramUp::Pong {
+            server_nanos: message.server_nanos,
+            client_nanos: now(instance_handle)?.as_nanos(),
+        };
+        connection.send_datagram(bincode::serialize(&datagram)?.into())?;
+        if message.clock != clock {
+            clock = message.clock;
+            LogTrace::string(format!("Clock estimated by server: {:?}", clock));
+        }
+    }
+}
+
 async fn run_receive_datagrams(
     connection: quinn::Connection,
     instance_handle: sys::Instance,
@@ -290,13 +362,32 @@ async fn run_receive_datagrams(
                     Ok(())
                 }
                 DatagramDown::State(value) => {
+                    // baseline is gone or parts are missing, newer set will follow
+                    let decoded = session
+                        .state_decoder
+                        .write()
+                        .map_err(map_err!("Failed to acquire write lock on state_decoder"))?
+                        .decode(value);
+                    let Some(value) = decoded else { return Ok(()); };
                     {
                         let mut remote_state = session
                             .remote_state
                             .write()
                             .map_err(map_err!("Failed to acquire write lock on remote_state"))?;
-                        if value.order > remote_state.order {
-                            remote_state.clone_from(&value);
+                        if value.order <= remote_state.order {
+                            return Ok(());
+                        }
+                        remote_state.clone_from(&value);
+                    }
+                    {
+                        let received = instance.instance.now()?.as_nanos();
+                        let mut remote_history = session
+                            .remote_history
+                            .write()
+                            .map_err(map_err!("Failed to acquire write lock on remote_history"))?;
+                        remote_history.retain(|id, _| value.clients.contains_key(id));
+                        for (id, state) in &value.clients {
+                            remote_history.entry(*id).or_default().push(state, received);
                         }
                     }
                     session.update_merged()?;
@@ -533,10 +624,39 @@ async fn run_receive_configuration(
                 set_local_configuration_name(instance_handle, session_handle, config.name.clone())?;
                 config.write().await;
             }
+            net::ConfigurationDown::JoinedRoom(room) => {
+                reset_remote_state(instance_handle, session_handle)?;
+                // remember room assigned by the dashboard for reconnects
+                if config.room.as_ref() != Some(&room) {
+                    config.room = Some(room);
+                    config.write().await;
+                }
+            }
         };
     }
 }
 
+/// State orders and baselines of the previous room mean nothing in the new one
+fn reset_remote_state(instance_handle: sys::Instance, session_handle: sys::Session) -> Result<()> {
+    with_layer(instance_handle, |instance| {
+        let session = instance
+            .sessions
+            .get(&session_handle)
+            .ok_or(anyhow!("Failed to read session from instance"))?;
+        let mut decoder = session
+            .state_decoder
+            .write()
+            .map_err(map_err!("Failed to acquire write lock on state_decoder"))?;
+        *decoder = StateDecoder::default();
+        let mut remote_state = session
+            .remote_state
+            .write()
+            .map_err(map_err!("Failed to acquire write lock on remote_state"))?;
+        remote_state.order = 0;
+        Ok(())
+    })
+}
+
 fn set_space_server_pose(
     instance_handle: sys::Instance,
     session_handle: sys::Session,
@@ -573,13 +693,33 @@ async fn run_transmit_snapshots(
     let mut interval = tokio::time::interval(Duration::from_micros(11_111));
     loop {
         if let Some(value) = collect_state(instance_handle, session_handle)? {
-            connection.send_datagram(bincode::serialize(&DatagramUp::State(value))?.into())?;
+            let acknowledged = applied_state_order(instance_handle, session_handle)?;
+            let datagram = DatagramUp::State(value, acknowledged);
+            connection.send_datagram(bincode::serialize(&datagram)?.into())?;
         }
 
         interval.tick().await;
     }
 }
 
+/// Order of the latest remote state set applied to the session
+fn applied_state_order(
+    instance_handle: sys::Instance,
+    session_handle: sys::Session,
+) -> Result<usize> {
+    with_layer(instance_handle, |instance| {
+        let session = instance
+            .sessions
+            .get(&session_handle)
+            .ok_or(anyhow!("Failed to get session data"))?;
+        let remote_state = session
+            .remote_state
+            .read()
+            .map_err(map_err!("Failed to acquire read lock on remote_state"))?;
+        Ok(remote_state.order)
+    })
+}
+
 /// Collects the state of the local devices. None means that the state could not
 /// be collected and that it should be tried again. Errors are non-recoverable
 /// and the connection loop should be ended.
@@ -639,6 +779,7 @@ fn collect_state_impl(instance: &Instance, session: &Session) -> Option<StateSna
         controllers,
         view: view_location.pose.into(),
         required_configuration: conf.version,
+        nanos: time.as_nanos(),
     })
 }
 
@@ -1,19 +1,33 @@
+use std::{
+    future::pending,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
 use anyhow::{anyhow, Result};
 use netvr_data::{
     app::{AppDown, AppUp},
     bincode,
-    net::{CalibrationSample, ClientId, ConfigurationDown, ConfigurationUp, DatagramUp, Heartbeat},
+    net::{
+        self, CalibrationSample, Capabilities, ClientHello, ClientId, CloseCode, ConfigurationDown,
+        ConfigurationUp, DatagramUp, Heartbeat, ServerHello, PROTOCOL_VERSION,
+    },
     FramingError, RecvFrames, SendFrames,
 };
-use quinn::{Connecting, Connection};
-use tokio::sync::{broadcast, mpsc};
+use quinn::{Connecting, Connection, VarInt};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+};
 use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, trace, warn};
 
 use crate::{
-    app::{AppChannel, AppServerMessage},
-    calibration_protocol::{CalibrationProtocolMessage, CalibrationSender},
+    auth::Auth,
     client::Client,
+    clock,
     dashboard::DashboardMessage,
+    metrics::{self, ClientMetrics},
     server::Server,
 };
 
@@ -23,29 +37,18 @@ pub(crate) async fn accept_connection(
     server: Server,
     ws: broadcast::Sender<DashboardMessage>,
     id: ClientId,
-    calibration_sender: CalibrationSender,
-    app_channel: AppChannel,
+    auth: Auth,
 ) {
     let token = CancellationToken::new();
 
     // Create client struct
 
-    match run_connection(
-        connecting,
-        token.clone(),
-        id,
-        ws,
-        server.clone(),
-        calibration_sender,
-        app_channel,
-    )
-    .await
-    {
+    match run_connection(connecting, token.clone(), id, ws, server.clone(), auth).await {
         Ok(()) => {
-            println!("Connection finished ok");
+            info!("Connection finished ok");
         }
         Err(e) => {
-            println!("Connection finished with error: {:?}", e);
+            warn!("Connection finished with error: {:?}", e);
         }
     }
     token.cancel();
@@ -59,30 +62,62 @@ async fn run_connection(
     id: ClientId,
     ws: broadcast::Sender<DashboardMessage>,
     server: Server,
-    calibration_sender: CalibrationSender,
-    app_channel: AppChannel,
+    auth: Auth,
 ) -> Result<()> {
-    // Accept connection and open channels
+    // Accept connection, agree on protocol and check that client may join
     let connection = connecting.await?;
+    let (capabilities, room) = match run_handshake(&connection, id, &auth).await {
+        Ok(accepted) => accepted,
+        Err((code, reason)) => {
+            let message = format!(
+                "Rejected client {} ({:?}): {}",
+                id,
+                connection.remote_address(),
+                reason
+            );
+            info!("{}", message);
+            let _ = ws.send(DashboardMessage::Info { message });
+            connection.close(VarInt::from_u32(code.code()), reason.as_bytes());
+            return Ok(());
+        }
+    };
+
+    // Open channels
     let heartbeat_channel = SendFrames::open(&connection, b"heartbee").await?;
     let configuration_up_stream = RecvFrames::open(&connection, b"configur").await?;
     let configuration_down_stream = SendFrames::open(&connection, b"confetti").await?;
-    let calibration_up_stream: RecvFrames<CalibrationSample> =
-        RecvFrames::open(&connection, b"calibrat").await?;
-    let app_up_stream: RecvFrames<AppUp> = RecvFrames::open(&connection, b"app_up__").await?;
-    let app_down_stream: SendFrames<AppDown> = SendFrames::open(&connection, b"app_down").await?;
+    let calibration_up_stream: Option<RecvFrames<CalibrationSample>> =
+        if capabilities.contains(Capabilities::CALIBRATION) {
+            Some(RecvFrames::open(&connection, b"calibrat").await?)
+        } else {
+            None
+        };
+    let app_streams: Option<(RecvFrames<AppUp>, SendFrames<AppDown>)> =
+        if capabilities.contains(Capabilities::APP_OBJECTS) {
+            Some((
+                RecvFrames::open(&connection, b"app_up__").await?,
+                SendFrames::open(&connection, b"app_down").await?,
+            ))
+        } else {
+            None
+        };
 
     // Setup client
     let configuration_down_queue = mpsc::unbounded_channel();
     let app_down_queue = mpsc::unbounded_channel();
+    let room = server
+        .room(room.as_deref().unwrap_or(net::DEFAULT_ROOM))
+        .await;
     let client = Client::new(
         ws.clone(),
         token.clone(),
-        server.clone(),
+        room,
         id,
+        capabilities,
         configuration_down_queue.0.clone(),
         app_down_queue.0.clone(),
         connection.clone(),
+        server.recorder().clone(),
     );
     server.add_client(client.clone()).await?;
     let configuration_down_queue = configuration_down_queue.1;
@@ -93,85 +128,182 @@ async fn run_connection(
         id: client.id(),
         addr: connection.remote_address(),
     });
-    println!("Connection established: {:?}", connection.remote_address());
+    info!("Connection established");
 
     // Start sending heartbeat
-    let task_heartbeat = run_heartbeat(heartbeat_channel);
+    let task_heartbeat = run_heartbeat(heartbeat_channel, client.clone());
 
     // Start receiving configuration messages
-    let task_conf_up =
-        run_configuration_up(configuration_up_stream, client.clone(), server.clone());
+    let task_conf_up = run_configuration_up(configuration_up_stream, client.clone(), auth);
 
     // Start receiving datagrams
-    let task_datagram = run_datagram_up(
-        connection.clone(),
-        client.clone(),
-        server.clone(),
-        app_channel.clone(),
-    );
+    let task_datagram = run_datagram_up(connection.clone(), client.clone());
 
     // Start receiving and sending app messages
-    let task_app_up = run_app_message_up(app_up_stream, app_channel, client.id());
-    let task_app_down = run_app_message_down(app_down_stream, app_down_queue);
+    let (app_up_stream, app_down_stream) = app_streams.unzip();
+    let task_app_up = async {
+        match app_up_stream {
+            Some(stream) => run_app_message_up(stream, client.clone()).await,
+            None => pending().await,
+        }
+    };
+    let task_app_down = async {
+        match app_down_stream {
+            Some(stream) => {
+                run_app_message_down(stream, app_down_queue, client.metrics().clone()).await
+            }
+            None => pending().await,
+        }
+    };
 
     // Start sending configurations
-    let task_conf_listen_change = run_configuration_listen_change(client.clone(), server.clone());
-    let task_conf_down =
-        run_configuration_down(configuration_down_stream, configuration_down_queue);
-    let task_calibration_up =
-        run_calibration_up(client.id(), calibration_up_stream, calibration_sender);
+    let task_conf_listen_change = run_configuration_listen_change(client.clone());
+    let task_conf_down = run_configuration_down(
+        configuration_down_stream,
+        configuration_down_queue,
+        client.metrics().clone(),
+    );
+    let task_calibration_up = async {
+        match calibration_up_stream {
+            Some(stream) => run_calibration_up(stream, client.clone()).await,
+            None => pending().await,
+        }
+    };
+
+    // Start reporting link quality
+    let task_quality = run_quality_report(client.clone(), ws.clone());
 
     let _ = ws.send(DashboardMessage::FullyConnected { id: client.id() });
-    println!("Fully connected: {:?}", connection.remote_address());
+    info!("Fully connected");
 
     // Wait for some task to finish
     tokio::select! {
         _ = client.cancelled() => {
-            println!("Cancelled");
+            debug!("Cancelled");
         },
         _ = connection.closed() => {
-            println!("Closed");
+            debug!("Closed");
         },
         _ = task_conf_up => {
-            println!("Configuration closed");
+            debug!("Configuration closed");
         },
         res = task_datagram => {
-            println!("Datagram closed: {:?}", res);
+            debug!("Datagram closed: {:?}", res);
         },
         _ = task_heartbeat => {
-            println!("Heartbeat ended");
+            debug!("Heartbeat ended");
         },
         res = task_app_up => {
-            println!("App up ended: {:?}", res);
+            debug!("App up ended: {:?}", res);
         },
         res = task_app_down => {
-            println!("App down ended: {:?}", res);
+            debug!("App down ended: {:?}", res);
         },
         res = task_conf_listen_change => {
-            println!("Configuration listen change ended: {:?}", res);
+            debug!("Configuration listen change ended: {:?}", res);
         },
         res = task_conf_down => {
-            println!("Configuration down ended: {:?}", res);
+            debug!("Configuration down ended: {:?}", res);
         },
         res = task_calibration_up => {
-            println!("Calibration up ended: {:?}", res);
+            debug!("Calibration up ended: {:?}", res);
+        },
+        _ = task_quality => {
+            debug!("Quality report ended");
         }
     }
 
     // Report to dashboard and console
     ws.send(DashboardMessage::ConnectionClosed { id: client.id() })?;
-    println!("Connection closed: {:?}", connection.remote_address());
+    info!("Connection closed");
     Ok(())
 }
 
-async fn run_heartbeat(mut heartbeat: SendFrames<Heartbeat>) {
+/// Reads client's hello and answers it. Returns capabilities to be used for the
+/// rest of the connection along with requested room, or the reason why the
+/// client was rejected.
+async fn run_handshake(
+    connection: &Connection,
+    id: ClientId,
+    auth: &Auth,
+) -> std::result::Result<(Capabilities, Option<String>), (CloseCode, String)> {
+    let mut hello_up: RecvFrames<ClientHello> = RecvFrames::open(connection, b"hello___")
+        .await
+        .map_err(|err| {
+            (
+                CloseCode::MissingHello,
+                format!(
+                    "client did not send hello, it is probably outdated ({})",
+                    err
+                ),
+            )
+        })?;
+    let mut hello_down: SendFrames<ServerHello> =
+        SendFrames::open(connection, b"welcome_")
+            .await
+            .map_err(|err| (CloseCode::MissingHello, format!("{}", err)))?;
+
+    let result = match hello_up.read().await {
+        Ok(hello) => check_hello(&hello, auth).map(|capabilities| (capabilities, hello.room)),
+        Err(err) => Err((
+            CloseCode::IncompatibleProtocol,
+            format!("failed to decode hello: {}", err),
+        )),
+    };
+    let reply = match &result {
+        Ok((capabilities, _)) => ServerHello::Accepted {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: *capabilities,
+            client_id: id,
+        },
+        Err((_, reason)) => ServerHello::Rejected {
+            protocol_version: PROTOCOL_VERSION,
+            reason: reason.clone(),
+        },
+    };
+    if let Err(err) = hello_down.write(&reply).await {
+        result?;
+        return Err((CloseCode::MissingHello, format!("{}", err)));
+    }
+    if result.is_err() {
+        // make sure that the client gets to read the reason before we close
+        let _ = tokio::time::timeout(Duration::from_secs(1), hello_down.finish()).await;
+    }
+    result
+}
+
+/// Decides whether the client can be accepted based on its hello.
+fn check_hello(
+    hello: &ClientHello,
+    auth: &Auth,
+) -> std::result::Result<Capabilities, (CloseCode, String)> {
+    if hello.protocol_version != PROTOCOL_VERSION {
+        return Err((
+            CloseCode::IncompatibleProtocol,
+            format!(
+                "client uses protocol version {}, but server requires {}",
+                hello.protocol_version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+    auth.check(hello)?;
+    Ok(hello.capabilities.intersection(Capabilities::all()))
+}
+
+/// Sends heartbeats along with current clock estimate. Client's answers are
+/// handled in run_datagram_up.
+async fn run_heartbeat(mut heartbeat: SendFrames<Heartbeat>, client: Client) {
     loop {
-        match heartbeat.write(&Heartbeat::default()).await {
+        let message = Heartbeat {
+            server_nanos: clock::now_nanos(),
+            clock: client.clock(),
+        };
+        match heartbeat.write(&message).await {
             Ok(v) => {
-                println!("Sent heartbeat {:?}", v);
+                trace!("Sent heartbeat {:?}", v);
             }
             Err(e) => {
-                println!("Error sending heartbeat: {:?}", e);
+                warn!("Error sending heartbeat: {:?}", e);
             }
         };
         tokio::time::sleep(tokio::time::Duration::from_secs(1)).await
@@ -181,17 +313,19 @@ async fn run_heartbeat(mut heartbeat: SendFrames<Heartbeat>) {
 async fn run_configuration_up(
     mut configuration_up: RecvFrames<ConfigurationUp>,
     client: Client,
-    server: Server,
+    auth: Auth,
 ) {
     loop {
         match configuration_up.read().await {
             Ok(message) => {
                 if let ConfigurationUp::ConfigurationSnapshot(snapshot) = &message {
-                    server
-                        .apply_configuration(client.id(), snapshot.clone())
-                        .await;
+                    if auth.is_revoked(&snapshot.name) {
+                        info!("Client named {} was revoked, disconnecting", snapshot.name);
+                        client.close(CloseCode::Revoked, "client was removed from this session");
+                        break;
+                    }
                 }
-                client.handle_configuration_up(message).await;
+                client.handle_configuration_up(message).await
             }
             Err(e) => match e {
                 FramingError::ReadExactError(_)
@@ -199,24 +333,17 @@ async fn run_configuration_up(
                 | FramingError::ConnectionError(quinn::ConnectionError::ApplicationClosed(_))
                 | FramingError::ConnectionError(quinn::ConnectionError::TimedOut) => break,
                 _ => {
-                    println!("Error reading configuration: {:?}", e);
+                    warn!("Error reading configuration: {:?}", e);
                 }
             },
         }
     }
 }
 
-async fn run_app_message_up(
-    mut connection: RecvFrames<AppUp>,
-    app_channel: AppChannel,
-    client_id: ClientId,
-) -> Result<()> {
+async fn run_app_message_up(mut connection: RecvFrames<AppUp>, client: Client) -> Result<()> {
     loop {
         match connection.read().await {
-            Ok(message) => {
-                println!("Received app message: {:?}", message);
-                app_channel.send(AppServerMessage::AppUp(client_id, message))?;
-            }
+            Ok(message) => client.handle_app_up(message)?,
             Err(e) => match e {
                 FramingError::ReadExactError(_)
                 | FramingError::ConnectionError(quinn::ConnectionError::ConnectionClosed(_))
@@ -234,36 +361,43 @@ async fn run_app_message_up(
 async fn run_app_message_down(
     mut connection: SendFrames<AppDown>,
     mut channel: mpsc::UnboundedReceiver<AppDown>,
+    metrics: Arc<ClientMetrics>,
 ) -> Result<()> {
     loop {
         let Some(message) = channel.recv().await else { break; };
+        metrics.app_down_queue.fetch_sub(1, Ordering::Relaxed);
         connection.write(&message).await?;
     }
     Ok(())
 }
 
-async fn run_datagram_up(
-    connection: Connection,
-    client: Client,
-    server: Server,
-    app_channel: AppChannel,
-) -> Result<()> {
+async fn run_datagram_up(connection: Connection, client: Client) -> Result<()> {
     loop {
         match connection.read_datagram().await {
-            Ok(bytes) => match bincode::deserialize::<DatagramUp>(&bytes) {
-                Ok(message) => match message {
-                    DatagramUp::State(message) => {
-                        server.apply_snapshot(client.id(), message.clone()).await;
-                        client.handle_recv_snapshot(message).await?;
+            Ok(bytes) => {
+                metrics::inc(&client.metrics().datagrams_up);
+                match bincode::deserialize::<DatagramUp>(&bytes) {
+                    Ok(message) => match message {
+                        DatagramUp::State(message, acknowledged) => {
+                            client.metrics().snapshot_arrived();
+                            client.handle_recv_snapshot(message, acknowledged).await?;
+                        }
+                        DatagramUp::App(message) => {
+                            client.handle_app_datagram(message)?;
+                        }
+                        DatagramUp::Pong {
+                            server_nanos,
+                            client_nanos,
+                        } => {
+                            client.handle_pong(server_nanos, client_nanos);
+                        }
+                    },
+                    Err(e) => {
+                        metrics::inc(&client.metrics().datagram_decode_failures);
+                        warn!("Failed to decode snapshot: {:?}", e);
                     }
-                    DatagramUp::App(message) => {
-                        app_channel.send(AppServerMessage::Datagram(client.id(), message))?;
-                    }
-                },
-                Err(e) => {
-                    println!("Failed to decode snapshot: {:?}", e);
                 }
-            },
+            }
             Err(e) => match e {
                 quinn::ConnectionError::ConnectionClosed(_)
                 | quinn::ConnectionError::ApplicationClosed(_)
@@ -277,41 +411,75 @@ async fn run_datagram_up(
     Ok(())
 }
 
-async fn run_configuration_listen_change(client: Client, server: Server) -> Result<()> {
-    let mut conf = server.latest_configuration().await;
+/// Sends configurations of clients in the same room, switching to a different
+/// room when the client is moved.
+async fn run_configuration_listen_change(client: Client) -> Result<()> {
+    let mut room = client.subscribe_room();
     loop {
-        let val = conf.borrow().to_owned();
-        client.send_configuration_down(ConfigurationDown::Snapshot(val))?;
+        let current = room.borrow_and_update().clone();
+        client.send_configuration_down(ConfigurationDown::JoinedRoom(current.name().to_owned()))?;
+        let mut conf = current.latest_configuration().await;
+        loop {
+            let val = conf.borrow_and_update().to_owned();
+            client.send_configuration_down(ConfigurationDown::Snapshot(val))?;
+
+            select! {
+                res = conf.changed() => res?,
+                res = room.changed() => {
+                    res?;
+                    break;
+                },
+            }
+        }
+    }
+}
 
-        conf.changed().await?;
+/// Periodically sends connection statistics of the client to the dashboard
+async fn run_quality_report(client: Client, ws: broadcast::Sender<DashboardMessage>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let Some(stats) = client.connection_stats() else { return; };
+        let metrics = client.metrics();
+        let (snapshot_interval, snapshot_jitter) = metrics.snapshot_timing();
+        let clock = client.clock();
+        let _ = ws.send(DashboardMessage::ConnectionQuality {
+            id: client.id(),
+            rtt_ms: stats.path.rtt.as_secs_f64() * 1000.,
+            cwnd: stats.path.cwnd,
+            sent_packets: stats.path.sent_packets,
+            lost_packets: stats.path.lost_packets,
+            datagram_drops: metrics.datagram_drops.load(Ordering::Relaxed),
+            snapshot_interval_ms: snapshot_interval * 1000.,
+            snapshot_jitter_ms: snapshot_jitter * 1000.,
+            clock_offset_ms: clock.map(|clock| clock.offset_nanos as f64 / 1e6),
+            clock_uncertainty_ms: clock.map(|clock| clock.uncertainty_nanos as f64 / 1e6),
+        });
     }
 }
 
 async fn run_configuration_down(
     mut connection: SendFrames<ConfigurationDown>,
     mut channel: mpsc::UnboundedReceiver<ConfigurationDown>,
+    metrics: Arc<ClientMetrics>,
 ) -> Result<()> {
     loop {
         let Some(val) = channel.recv().await else { break; };
-        println!("Sending configuration: <snip>");
+        metrics
+            .configuration_down_queue
+            .fetch_sub(1, Ordering::Relaxed);
+        trace!("Sending configuration: <snip>");
         connection.write(&val).await?;
     }
     Ok(())
 }
 
 async fn run_calibration_up(
-    client_id: ClientId,
     mut connection: RecvFrames<CalibrationSample>,
-    channel: CalibrationSender,
+    client: Client,
 ) -> Result<()> {
     loop {
         let val = connection.read().await?;
-        println!("Forwarding calibration: {:?}", val);
-        channel
-            .send(CalibrationProtocolMessage::Sample {
-                client: client_id,
-                sample: val,
-            })
-            .map_err(|err| anyhow!("Failed to forward calibration message: {:?}", err))?;
+        client.handle_calibration_sample(val)?;
     }
 }
@@ -1,12 +1,13 @@
 use anyhow::Result;
 use netvr_data::{
     app::{AppDatagramUp, AppDown, AppUp, Snapshot},
-    net::{ClientId, DatagramDown::App},
+    net::{Capabilities, ClientId, DatagramDown::App},
     Pose,
 };
 use tokio::{select, sync::mpsc, time::Interval};
+use tracing::{debug, warn};
 
-use crate::server::Server;
+use crate::room::Room;
 
 #[derive(Clone, Debug)]
 struct AppObject {
@@ -19,7 +20,7 @@ pub(crate) struct AppServer {
     channel: mpsc::UnboundedReceiver<AppServerMessage>,
     initial_state: Vec<AppObject>,
     state: Vec<AppObject>,
-    server: Server,
+    room: Room,
 }
 
 enum UpMessage {
@@ -42,19 +43,14 @@ pub(crate) enum AppServerMessage {
 pub(crate) type AppChannel = mpsc::UnboundedSender<AppServerMessage>;
 
 impl AppServer {
-    /// Prepare everything for running the synchronized object system
-    pub(crate) fn start(server: Server) -> (Self, AppChannel) {
-        let channel = mpsc::unbounded_channel::<AppServerMessage>();
-
-        (
-            Self {
-                channel: channel.1,
-                initial_state: Default::default(),
-                state: Default::default(),
-                server,
-            },
-            channel.0,
-        )
+    /// Prepare everything for running the synchronized object system of a room
+    pub(crate) fn new(channel: mpsc::UnboundedReceiver<AppServerMessage>, room: Room) -> Self {
+        Self {
+            channel,
+            initial_state: Default::default(),
+            state: Default::default(),
+            room,
+        }
     }
 
     async fn recv_flat(&mut self, interval: &mut Interval) -> Result<UpMessage> {
@@ -90,14 +86,14 @@ impl AppServer {
                         if entry.owner == client_id {
                             entry.pose = pose;
                         } else {
-                            println!(
+                            debug!(
                                 "Received pose update for object {} from unauthorized client {}. \
                                  This is probably okay.",
                                 object_id, client_id
                             );
                         }
                     } else {
-                        println!("Received pose update for unknown object {}", object_id);
+                        warn!("Received pose update for unknown object {}", object_id);
                     }
                 }
                 UpMessage::Init(snapshot) => {
@@ -115,15 +111,15 @@ impl AppServer {
                 UpMessage::Grab(client_id, object_id) => {
                     if let Some(ref mut entry) = self.state.get_mut(object_id as usize) {
                         if entry.owner != client_id {
-                            if let Some(client) = self.server.get_client(entry.owner).await {
+                            if let Some(client) = self.room.get_client(entry.owner).await {
                                 if let Err(e) = client.send_app_down(AppDown::Release(object_id)) {
-                                    println!("Failed to send release to client: {}", e);
+                                    warn!("Failed to send release to client: {}", e);
                                 }
                             }
                             entry.owner = client_id;
                         }
                     } else {
-                        println!("Received grab for unknown object {}", object_id);
+                        warn!("Received grab for unknown object {}", object_id);
                     }
                 }
                 UpMessage::IntervalLapsed => {
@@ -131,11 +127,14 @@ impl AppServer {
                     for object in self.state.iter() {
                         snapshot.objects.push(object.pose.to_owned());
                     }
-                    let clients = self.server.get_clients().await;
+                    let clients = self.room.get_clients().await;
                     let message = App(snapshot.clone());
                     for (client_id, client) in clients.iter() {
+                        if !client.capabilities().contains(Capabilities::APP_OBJECTS) {
+                            continue;
+                        }
                         if let Err(e) = client.send_datagram(&message) {
-                            println!("Failed to send app datagram to client {}: {}", client_id, e)
+                            warn!("Failed to send app datagram to client {}: {}", client_id, e)
                         }
                     }
                 }
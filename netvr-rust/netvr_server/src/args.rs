@@ -0,0 +1,196 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use netvr_server::{LogFormat, ServerOptions};
+
+/// NetVR server. Discovers and connects headsets, serves the dashboard.
+///
+/// Options given on the command line override those from the config file.
+#[derive(Parser, Debug)]
+#[command(about)]
+pub(crate) struct Args {
+    /// TOML file with server options, keys are the same as long flags with
+    /// underscores, e.g. server_addr = "0.0.0.0:13160"
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// UDP address for QUIC connections
+    #[arg(long)]
+    pub server_addr: Option<SocketAddr>,
+
+    /// UDP address on which discovery requests are answered
+    #[arg(long)]
+    pub discovery_addr: Option<SocketAddr>,
+
+    /// Multicast group the discovery socket joins
+    #[arg(long)]
+    pub multicast_group: Option<Ipv4Addr>,
+
+    /// Interface used for joining the multicast group
+    #[arg(long)]
+    pub multicast_interface: Option<Ipv4Addr>,
+
+    /// TCP address of the dashboard
+    #[arg(long)]
+    pub dashboard_addr: Option<SocketAddr>,
+
+    /// Directory with the built dashboard
+    #[arg(long)]
+    pub dashboard_assets: Option<PathBuf>,
+
+    /// Where files uploaded through the dashboard are stored
+    #[arg(long)]
+    pub upload_directory: Option<PathBuf>,
+
+    /// Where data of each finished calibration is written
+    #[arg(long)]
+    pub calibration_directory: Option<PathBuf>,
+
+    /// Where the server keypair is persisted between restarts
+    #[arg(long)]
+    pub identity_directory: Option<PathBuf>,
+
+    /// Where applied calibrations are kept by client name
+    #[arg(long)]
+    pub calibration_store_directory: Option<PathBuf>,
+
+    /// Do not answer discovery requests, clients have to know the address
+    #[arg(long)]
+    pub no_discovery: bool,
+
+    /// Do not join the multicast group
+    #[arg(long)]
+    pub no_multicast: bool,
+
+    /// Do not serve the dashboard
+    #[arg(long)]
+    pub no_dashboard: bool,
+
+    /// Reject file uploads through the dashboard
+    #[arg(long)]
+    pub no_uploads: bool,
+
+    /// Do not write calibration data to files
+    #[arg(long)]
+    pub no_calibration_files: bool,
+
+    /// Keep history of applied calibrations only until restart
+    #[arg(long)]
+    pub no_calibration_store: bool,
+
+    /// Send clients their last good calibration when they connect
+    #[arg(long)]
+    pub auto_apply_calibration: bool,
+
+    /// Only admit clients which present this token
+    #[arg(long, conflicts_with = "join_token_file")]
+    pub join_token: Option<String>,
+
+    /// Read join token from the first line of this file
+    #[arg(long)]
+    pub join_token_file: Option<PathBuf>,
+
+    /// Record everything clients send into this session file
+    #[arg(long, conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+
+    /// Play back session file as virtual clients, controlled from dashboard
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+
+    /// How many times per second each client is sent state of the others
+    #[arg(long)]
+    pub state_rate: Option<f64>,
+
+    /// Which events are logged, eg. "debug" or "info,netvr_server::app=trace"
+    #[arg(long)]
+    pub log: Option<String>,
+
+    /// Format of log lines
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Append logs to this file instead of printing them
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+}
+
+impl Args {
+    /// Loads the config file, if any, and applies command line options on top
+    pub(crate) async fn options(&self) -> Result<ServerOptions> {
+        let mut options = match &self.config {
+            Some(path) => ServerOptions::load(path).await?,
+            None => ServerOptions::default(),
+        };
+        set(&mut options.server_addr, &self.server_addr);
+        set(&mut options.discovery_addr, &self.discovery_addr);
+        set(&mut options.multicast_group, &self.multicast_group);
+        set(&mut options.multicast_interface, &self.multicast_interface);
+        set(&mut options.dashboard_addr, &self.dashboard_addr);
+        set(&mut options.upload_directory, &self.upload_directory);
+        set(
+            &mut options.calibration_directory,
+            &self.calibration_directory,
+        );
+        set(&mut options.identity_directory, &self.identity_directory);
+        set(
+            &mut options.calibration_store_directory,
+            &self.calibration_store_directory,
+        );
+        set(&mut options.state_rate, &self.state_rate);
+        set(&mut options.log.filter, &self.log);
+        set(&mut options.log.format, &self.log_format);
+        if self.log_file.is_some() {
+            options.log.file = self.log_file.clone();
+        }
+        if self.dashboard_assets.is_some() {
+            options.dashboard_assets = self.dashboard_assets.clone();
+        }
+        options.enable_discovery &= !self.no_discovery;
+        options.enable_multicast &= !self.no_multicast;
+        options.enable_dashboard &= !self.no_dashboard;
+        options.enable_uploads &= !self.no_uploads;
+        options.enable_calibration_files &= !self.no_calibration_files;
+        options.enable_calibration_store &= !self.no_calibration_store;
+        options.auto_apply_calibration |= self.auto_apply_calibration;
+        if let Some(token) = self.join_token().await? {
+            options.join_token = Some(token);
+        }
+        if self.record.is_some() {
+            options.record = self.record.clone();
+        }
+        if self.replay.is_some() {
+            options.replay = self.replay.clone();
+        }
+        if options.record.is_some() && options.replay.is_some() {
+            return Err(anyhow!("Cannot record and replay at the same time"));
+        }
+        Ok(options)
+    }
+
+    /// Returns join token from whichever source was specified
+    async fn join_token(&self) -> Result<Option<String>> {
+        if let Some(token) = &self.join_token {
+            return Ok(Some(token.clone()));
+        }
+        let Some(path) = &self.join_token_file else { return Ok(None); };
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read join token from {:?}", path))?;
+        match text.lines().next().map(str::trim) {
+            Some(token) if !token.is_empty() => Ok(Some(token.to_owned())),
+            _ => Err(anyhow!("Join token file {:?} is empty", path)),
+        }
+    }
+}
+
+/// Overrides option from config file if it was given on the command line
+fn set<T: Clone>(option: &mut T, arg: &Option<T>) {
+    if let Some(value) = arg {
+        *option = value.clone();
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use netvr_data::net::{ClientHello, CloseCode};
+
+/// Decides which clients are allowed to join the session.
+#[derive(Clone)]
+pub(crate) struct Auth {
+    join_token: Option<Arc<str>>,
+    /// Names of clients which may not rejoin
+    revoked: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl Auth {
+    /// If join_token is None, everyone except revoked clients is admitted.
+    pub(crate) fn new(join_token: Option<String>) -> Self {
+        Self {
+            join_token: join_token.map(Into::into),
+            revoked: Arc::default(),
+        }
+    }
+
+    /// Whether clients have to present a join token
+    pub(crate) fn requires_token(&self) -> bool {
+        self.join_token.is_some()
+    }
+
+    /// Returns the reason why the client is not admitted, if any.
+    pub(crate) fn check(
+        &self,
+        hello: &ClientHello,
+    ) -> std::result::Result<(), (CloseCode, String)> {
+        if let Some(expected) = &self.join_token {
+            let Some(token) = &hello.join_token else {
+                return Err((CloseCode::Unauthorized, "join token required".to_string()));
+            };
+            if ring::constant_time::verify_slices_are_equal(token.as_bytes(), expected.as_bytes())
+                .is_err()
+            {
+                return Err((CloseCode::Unauthorized, "invalid join token".to_string()));
+            }
+        }
+        if let Some(name) = &hello.name {
+            if self.is_revoked(name) {
+                let reason = "client was removed from this session".to_string();
+                return Err((CloseCode::Revoked, reason));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether client announcing this name has to be disconnected. Checked on
+    /// the name from the hello before admission and again on every
+    /// configuration the client sends, because the name can change later.
+    pub(crate) fn is_revoked(&self, name: &str) -> bool {
+        !name.is_empty() && self.revoked.lock().unwrap().contains(name)
+    }
+
+    /// Refuses clients with given name until unrevoked or restart
+    pub(crate) fn revoke(&self, name: String) {
+        self.revoked.lock().unwrap().insert(name);
+    }
+
+    /// Returns whether the name was revoked
+    pub(crate) fn unrevoke(&self, name: &str) -> bool {
+        self.revoked.lock().unwrap().remove(name)
+    }
+
+    /// Sorted names of revoked clients
+    pub(crate) fn revoked(&self) -> Vec<String> {
+        self.revoked.lock().unwrap().iter().cloned().collect()
+    }
+}
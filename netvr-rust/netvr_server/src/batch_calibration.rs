@@ -0,0 +1,290 @@
+//! Calibrates several clients at once. Every target is linked either to the
+//! batch reference or to another target, so the links form a tree. All
+//! clients sample at the same time, the reference in its server space and
+//! targets in their stage spaces. Each link is solved on its own and the
+//! transforms are chained from the reference outwards, so that every target
+//! ends up in the reference's server space.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use netvr_calibrate::CalibrationInput;
+use netvr_data::net::{
+    BaseSpace, CalibrationConfiguration, CalibrationSample, CalibrationSolver, ClientId,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+};
+use tracing::{info, warn};
+
+use crate::{
+    calibration_protocol::{
+        any_disconnected, apply_result, sample_pairing, save_calibration_data, server_space_pose,
+        solve, stop_calibration, supports_calibration, trigger,
+        CalibrationProtocolMessage::{self, *},
+        COLLECT_TIMEOUT, PROGRESS_INTERVAL,
+    },
+    calibration_state::{CalibrationState, CalibrationStatus},
+    calibration_store::CalibrationSource,
+    client::Client,
+    continuous_calibration::Transform,
+    dashboard::DashboardMessage,
+    room::Room,
+};
+
+/// One client calibrated in a batch
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BatchTarget {
+    pub client_id: ClientId,
+    pub subaction_path: String,
+    /// Client whose samples this one is solved against, the batch reference
+    /// if None
+    pub parent_id: Option<ClientId>,
+}
+
+/// Target solved against parent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Link {
+    target: ClientId,
+    parent: ClientId,
+}
+
+/// Calibrates all targets at once. Returns whether every one of them was
+/// applied.
+pub(crate) async fn run_batch(
+    recv: &mut mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
+    client_reference: (ClientId, String),
+    targets: Vec<BatchTarget>,
+    room: Room,
+    tx: broadcast::Sender<DashboardMessage>,
+    conf: CalibrationConfiguration,
+    output: Option<&Path>,
+) -> bool {
+    let reference_id = client_reference.0;
+    let ids = std::iter::once(reference_id)
+        .chain(targets.iter().map(|target| target.client_id))
+        .collect();
+    let status = CalibrationStatus::new(tx.clone(), &room, "batch", ids);
+    status.set(CalibrationState::Triggering);
+    let links = match order_links(reference_id, &targets) {
+        Ok(links) => links,
+        Err(err) => return status.fail(err.to_string()),
+    };
+
+    let devices = std::iter::once(client_reference)
+        .chain(targets.into_iter().map(|t| (t.client_id, t.subaction_path)));
+    let mut clients = HashMap::new();
+    let mut paths = vec![];
+    for (id, path) in devices {
+        let Some(client) = room.get_client(id).await else {
+            return status.fail(format!("Client {} not found", id));
+        };
+        clients.insert(id, client);
+        paths.push((id, path));
+    }
+    if !supports_calibration(&clients.values().collect::<Vec<_>>()) {
+        return status.fail("Not all clients support calibration");
+    }
+    let mut triggered = vec![];
+    for (id, path) in paths {
+        let space = if id == reference_id {
+            BaseSpace::Server
+        } else {
+            BaseSpace::Stage
+        };
+        if !trigger(&clients[&id], path, conf, space) {
+            stop_calibration(&room, &triggered).await;
+            return status.fail(format!("Failed to trigger calibration of {}", id));
+        }
+        triggered.push(id);
+    }
+    info!(
+        "Batch calibration of {} targets against {} started",
+        links.len(),
+        reference_id
+    );
+
+    status.set(CalibrationState::Collecting);
+    let collected = collect_batch(conf, &links, &clients, recv, &tx).await;
+    stop_calibration(&room, &triggered).await;
+    let samples = match collected {
+        Ok(v) => v,
+        Err(err) => return status.fail(err.to_string()),
+    };
+
+    status.set(CalibrationState::Solving);
+    // maps stage spaces of solved clients to reference's server space
+    let mut chained = HashMap::from([(reference_id, Transform::identity())]);
+    let mut solved = vec![];
+    for link in &links {
+        let input = link_input(*link, conf.solver, &clients, &samples);
+        let prefix = format!("calibration-data-{}-{}", link.target, link.parent);
+        save_calibration_data(output, &prefix, &input);
+        let Some(parent) = chained.get(&link.parent).copied() else {
+            let message = format!(
+                "Client {} was not calibrated, because calibration of {} failed",
+                link.target, link.parent
+            );
+            warn!("{}", message);
+            let _ = tx.send(DashboardMessage::Info { message });
+            continue;
+        };
+        let data = match solve(&tx, &input, link.target, link.parent) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("Calibration of {:?} failed: {}", link, err);
+                continue;
+            }
+        };
+        let transform = parent.chain(&Transform::from(&data));
+        chained.insert(link.target, transform);
+        let pose = server_space_pose(transform.translation(), transform.rotation());
+        solved.push((*link, pose, data, input));
+    }
+
+    status.set(CalibrationState::Applying);
+    let mut applied = 0;
+    for (link, pose, data, input) in solved {
+        let (target, parent) = (&clients[&link.target], &clients[&link.parent]);
+        if apply_result(target, parent, CalibrationSource::Batch, pose, data, input).await {
+            applied += 1;
+        }
+    }
+    info!(
+        "Batch calibration applied to {} of {} targets",
+        applied,
+        links.len()
+    );
+    if applied < links.len() {
+        return status.fail(format!("Applied to {} of {} targets", applied, links.len()));
+    }
+    status.set(CalibrationState::Idle);
+    true
+}
+
+/// Orders links so that every parent comes before its children. Fails unless
+/// the targets form a tree rooted in the reference.
+fn order_links(reference: ClientId, targets: &[BatchTarget]) -> Result<Vec<Link>> {
+    if targets.is_empty() {
+        return Err(anyhow!("Batch calibration has no targets"));
+    }
+    let mut parents = HashMap::new();
+    for target in targets {
+        if target.client_id == reference {
+            return Err(anyhow!("Client {} is both reference and target", reference));
+        }
+        let parent = target.parent_id.unwrap_or(reference);
+        if parents.insert(target.client_id, parent).is_some() {
+            return Err(anyhow!("Client {} is in the batch twice", target.client_id));
+        }
+    }
+    let mut links = vec![];
+    let mut placed = HashSet::from([reference]);
+    while links.len() < targets.len() {
+        let before = links.len();
+        for target in targets {
+            let parent = parents[&target.client_id];
+            if !placed.contains(&target.client_id) && placed.contains(&parent) {
+                placed.insert(target.client_id);
+                links.push(Link {
+                    target: target.client_id,
+                    parent,
+                });
+            }
+        }
+        if links.len() == before {
+            return Err(anyhow!(
+                "Some targets are not linked to the reference {}",
+                reference
+            ));
+        }
+    }
+    Ok(links)
+}
+
+/// Samples of one link collected so far
+fn link_input(
+    link: Link,
+    solver: CalibrationSolver,
+    clients: &HashMap<ClientId, Client>,
+    samples: &HashMap<ClientId, Vec<CalibrationSample>>,
+) -> CalibrationInput {
+    let (target, parent) = (&clients[&link.target], &clients[&link.parent]);
+    CalibrationInput {
+        target: samples[&link.target].clone(),
+        target_name: target.name(),
+        reference: samples[&link.parent].clone(),
+        reference_name: parent.name(),
+        pairing: sample_pairing(target.clock(), parent.clock()),
+        target_clock: target.clock(),
+        reference_clock: parent.clock(),
+        solver,
+    }
+}
+
+/// Collects samples until every client has conf.sample_count, or all links
+/// cover enough rotations if conf.stop_on_coverage is set. Reports progress
+/// of each link to the dashboard.
+async fn collect_batch(
+    conf: CalibrationConfiguration,
+    links: &[Link],
+    clients: &HashMap<ClientId, Client>,
+    recv: &mut mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
+    tx: &broadcast::Sender<DashboardMessage>,
+) -> Result<HashMap<ClientId, Vec<CalibrationSample>>> {
+    let mut samples: HashMap<_, Vec<_>> = clients.keys().map(|id| (*id, vec![])).collect();
+    let time_start = std::time::Instant::now();
+    let mut progress = tokio::time::interval(PROGRESS_INTERVAL);
+    loop {
+        let message = select! {
+            message = recv.recv() => message,
+            id = any_disconnected(clients.values()) => {
+                Err(anyhow!("Client {} disconnected", id))?
+            }
+            _ = progress.tick() => {
+                if time_start.elapsed() > COLLECT_TIMEOUT {
+                    Err(anyhow!("Timed out waiting for samples"))?
+                }
+                let mut sufficient = true;
+                for link in links {
+                    let input = link_input(*link, conf.solver, clients, &samples);
+                    let coverage = netvr_calibrate::coverage(&input);
+                    sufficient &= coverage.sufficient;
+                    let _ = tx.send(DashboardMessage::CalibrationProgress {
+                        target: link.target,
+                        reference: link.parent,
+                        target_samples: input.target.len(),
+                        reference_samples: input.reference.len(),
+                        sample_count: conf.sample_count,
+                        coverage,
+                    });
+                }
+                if conf.stop_on_coverage && sufficient {
+                    info!("Samples of all links cover enough rotations, stopping early");
+                    break;
+                }
+                continue;
+            }
+        };
+        match message {
+            None | Some(FinishCalibration) => break,
+            Some(Cancel) => Err(anyhow!("Cancelled"))?,
+            Some(Sample { client, sample }) => {
+                if let Some(client_samples) = samples.get_mut(&client) {
+                    client_samples.push(sample);
+                }
+                if samples.values().all(|s| s.len() >= conf.sample_count) {
+                    break;
+                }
+            }
+            Some(message) => warn!("Ignoring {:?} during batch calibration", message),
+        }
+    }
+    Ok(samples)
+}
@@ -1,18 +1,26 @@
 use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
-use netvr_calibrate::{invert_quaternion, rotate_vector, CalibrationInput};
+use futures_util::future::select_all;
+use netvr_calibrate::{
+    invert_quaternion, rotate_vector, CalibrationInput, CalibrationResult, CalibrationVerdict,
+    SamplePairing,
+};
 use netvr_data::{
     net::{
-        BaseSpace, CalibrationConfiguration, CalibrationSample, ClientId,
+        BaseSpace, CalibrationConfiguration, CalibrationSample, CalibrationSolver, Capabilities,
+        ClientId, ClockEstimate,
         ConfigurationDown::{
             RequestSample, SetServerSpacePose, StopCalibration, TriggerCalibration,
         },
     },
-    Pose, Vec3,
+    Pose, Quaternion, Vec3,
 };
 use std::io::Write;
 use std::{
+    collections::HashMap,
     fs::File,
+    path::{Path, PathBuf},
+    sync::Arc,
     time::{Duration, SystemTime},
     vec,
 };
@@ -20,13 +28,32 @@ use tokio::{
     select,
     sync::{broadcast, mpsc},
 };
+use tracing::{debug, error, info, trace, warn};
 
 use self::CalibrationProtocolMessage::*;
-use crate::{client::Client, dashboard::DashboardMessage, server::Server};
+use crate::{
+    batch_calibration::{run_batch, BatchTarget},
+    calibration_state::{CalibrationState, CalibrationStatus},
+    calibration_store::CalibrationSource,
+    client::Client,
+    continuous_calibration::run_continuous,
+    dashboard::DashboardMessage,
+    marker_calibration::{run_markers, MarkerTarget},
+    metrics::Metrics,
+    room::Room,
+};
+
+/// How often coverage of collected samples is computed and reported
+pub(crate) const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+/// Collection fails if it does not finish in time
+pub(crate) const COLLECT_TIMEOUT: Duration = Duration::from_secs(60);
 
 /// Calibration protocol subsystem data
 pub(crate) struct CalibrationProtocol {
     recv: mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
+    /// Directory where collected data is written, disabled if None
+    output: Option<PathBuf>,
+    metrics: Arc<Metrics>,
 }
 
 /// Channel for passing messages instructing what to do in the calibration subsystem
@@ -44,7 +71,31 @@ pub(crate) enum CalibrationProtocolMessage {
         client_target: (ClientId, String),
         client_reference: (ClientId, String),
     },
+    /// Keeps correcting drift of target until FinishCalibration
+    Continuous {
+        client_target: (ClientId, String),
+        client_reference: (ClientId, String),
+        conf: CalibrationConfiguration,
+    },
+    /// Calibrates several targets at once, each against the reference or
+    /// another target
+    Batch {
+        client_reference: (ClientId, String),
+        targets: Vec<BatchTarget>,
+        conf: CalibrationConfiguration,
+    },
+    /// Calibrates targets by devices placed on point_count shared markers,
+    /// one CaptureMarker for each
+    Markers {
+        client_reference: (ClientId, String),
+        targets: Vec<MarkerTarget>,
+        point_count: usize,
+    },
+    /// All devices of marker calibration are on the next marker
+    CaptureMarker,
     FinishCalibration,
+    /// Stops the running calibration without applying anything
+    Cancel,
     Sample {
         client: ClientId,
         sample: CalibrationSample,
@@ -58,29 +109,38 @@ pub(crate) enum CalibrationProtocolMessage {
 }
 
 impl CalibrationProtocol {
-    pub(crate) fn new() -> (Self, CalibrationSender) {
+    pub(crate) fn new(output: Option<PathBuf>, metrics: Arc<Metrics>) -> (Self, CalibrationSender) {
         let (sender, recv) = mpsc::unbounded_channel();
-        (Self { recv }, sender)
+        (
+            Self {
+                recv,
+                output,
+                metrics,
+            },
+            sender,
+        )
     }
 
     pub(crate) async fn run(
         self,
-        server: Server,
+        room: Room,
         tx: broadcast::Sender<DashboardMessage>,
     ) -> Result<()> {
-        run(self.recv, server, tx).await
+        run(self.recv, room, tx, self.output.as_deref(), &self.metrics).await
     }
 }
 
 /// Runs the calibration subsystem loop
 async fn run(
     mut recv: mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
-    server: Server,
+    room: Room,
     tx: broadcast::Sender<DashboardMessage>,
+    output: Option<&Path>,
+    metrics: &Metrics,
 ) -> Result<()> {
     loop {
         let Some(instruction) = recv.recv().await else { break; };
-        println!("Received calibration instruction {:?}", instruction);
+        trace!("Received calibration instruction {:?}", instruction);
         let (client_target, client_reference, conf) = match instruction {
             Begin {
                 client_target,
@@ -91,49 +151,100 @@ async fn run(
                 client_target,
                 client_reference,
             } => {
-                run_hijack(
+                let ok = run_hijack(
+                    &mut recv,
+                    client_target,
+                    client_reference,
+                    room.clone(),
+                    tx.clone(),
+                    output,
+                )
+                .await;
+                metrics.calibration_finished("hijack", ok);
+                continue;
+            }
+            Continuous {
+                client_target,
+                client_reference,
+                conf,
+            } => {
+                let ok = run_continuous(
                     &mut recv,
                     client_target,
                     client_reference,
-                    server.clone(),
+                    room.clone(),
                     tx.clone(),
+                    conf,
                 )
                 .await;
+                metrics.calibration_finished("continuous", ok);
+                continue;
+            }
+            Batch {
+                client_reference,
+                targets,
+                conf,
+            } => {
+                let ok = run_batch(
+                    &mut recv,
+                    client_reference,
+                    targets,
+                    room.clone(),
+                    tx.clone(),
+                    conf,
+                    output,
+                )
+                .await;
+                metrics.calibration_finished("batch", ok);
+                continue;
+            }
+            Markers {
+                client_reference,
+                targets,
+                point_count,
+            } => {
+                let ok = run_markers(
+                    &mut recv,
+                    client_reference,
+                    targets,
+                    point_count,
+                    room.clone(),
+                    tx.clone(),
+                )
+                .await;
+                metrics.calibration_finished("markers", ok);
                 continue;
             }
             Sample { .. } => continue,
-            FinishCalibration => continue,
+            FinishCalibration | Cancel | CaptureMarker => continue,
             Reapply {
                 client_target,
                 client_reference,
                 data,
             } => {
-                let Some(client_target) = server.get_client(client_target.0).await else { continue; };
-                let Some(client_reference) = server.get_client(client_reference.0).await else { continue; };
-                finish(tx.clone(), data, &client_target, &client_reference).await;
+                let ok = run_reapply(client_target.0, client_reference.0, data, &room, &tx).await;
+                metrics.calibration_finished("reapply", ok);
                 continue;
             }
             ByHeadset => {
-                let clients = server.get_clients().await;
-                match calibrate_by_headset(clients, &mut recv).await {
-                    Ok(_) => println!("Calibrated by headset"),
-                    Err(err) => println!("Failed to calibrate by headset: {:?}", err),
-                }
-
+                let ok = run_headset(&mut recv, &room, &tx).await;
+                metrics.calibration_finished("headset", ok);
                 continue;
             }
         };
-        run_calibration(
+        let ok = run_calibration(
             &mut recv,
             client_target,
             client_reference,
-            server.clone(),
+            room.clone(),
             tx.clone(),
             conf,
+            output,
         )
         .await;
+        metrics.calibration_finished("samples", ok);
     }
-    println!("Calibration protocol stopped");
+    info!("Calibration protocol stopped");
     Ok(())
 }
 
@@ -142,101 +253,119 @@ async fn run_calibration(
     recv: &mut mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
     client_target: (ClientId, String),
     client_reference: (ClientId, String),
-    server: Server,
+    room: Room,
     tx: broadcast::Sender<DashboardMessage>,
     conf: CalibrationConfiguration,
-) {
+    output: Option<&Path>,
+) -> bool {
     let client_target_id = client_target.0;
     let client_reference_id = client_reference.0;
     let client_target_path = client_target.1;
     let client_reference_path = client_reference.1;
+    let status = CalibrationStatus::new(
+        tx.clone(),
+        &room,
+        "samples",
+        vec![client_reference_id, client_target_id],
+    );
 
-    let Some(client_target) = server.get_client(client_target.0).await else { return ; };
-    let Some(client_reference) = server.get_client(client_reference.0).await else { return ; };
-    if let Err(err) = client_target.send_configuration_down(TriggerCalibration(
-        client_target_path,
-        conf,
-        BaseSpace::Stage,
-    )) {
-        println!(
-            "Failed to send trigger calibration to client {:?}: {:?}",
-            client_target_id, err
-        );
-        return;
-    }
-    if let Err(err) = client_reference.send_configuration_down(TriggerCalibration(
-        client_reference_path,
+    status.set(CalibrationState::Triggering);
+    let (client_target, client_reference) =
+        match get_pair(&room, client_target_id, client_reference_id).await {
+            Ok(pair) => pair,
+            Err(err) => return status.fail(err.to_string()),
+        };
+    if !trigger_calibration(
+        (&client_target, client_target_path),
+        (&client_reference, client_reference_path),
         conf,
-        BaseSpace::Server,
-    )) {
-        println!(
-            "Failed to send trigger calibration to client {:?}: {:?}",
-            client_reference_id, err
-        );
-        let _ = client_target.send_configuration_down(StopCalibration);
-        return;
+    ) {
+        return status.fail("Failed to trigger calibration");
     }
 
-    let samples_result = collect_samples(
-        conf.sample_count,
-        client_target_id,
-        client_reference_id,
-        recv,
-    )
-    .await;
-    // Send end to clients
-    for id in [client_target_id, client_reference_id] {
-        if let Some(client) = server.get_client(id).await {
-            if let Err(err) = client.send_configuration_down(StopCalibration) {
-                println!(
-                    "Failed to send stop calibration to client {:?}: {:?}",
-                    id, err
-                );
-            }
-        }
-    }
+    status.set(CalibrationState::Collecting);
+    let samples_result = collect_samples(conf, &client_target, &client_reference, recv, &tx).await;
+    stop_calibration(&room, &[client_target_id, client_reference_id]).await;
     let (samples_target, samples_reference) = match samples_result {
         Ok(v) => v,
-        Err(err) => {
-            println!("Calibration failed: {:?}", err);
-            return;
-        }
+        Err(err) => return status.fail(err.to_string()),
     };
 
     // Samples collected. Calibrate and apply
-    println!("samples_target: {:?}", samples_target.len());
-    println!("samples_reference: {:?}", samples_target.len());
-    let configuration = server.latest_configuration().await;
-    let configuration = configuration.borrow();
-    let calibration = CalibrationInput {
-        target: samples_target,
-        target_name: configuration
-            .clients
-            .get(&client_target_id)
-            .map(|v| v.name.clone())
-            .unwrap_or_default(),
-        reference: samples_reference,
-        reference_name: configuration
-            .clients
-            .get(&client_reference_id)
-            .map(|v| v.name.clone())
-            .unwrap_or_default(),
+    debug!("samples_target: {:?}", samples_target.len());
+    debug!("samples_reference: {:?}", samples_reference.len());
+    let configuration = room.latest_configuration().await;
+    let calibration = {
+        let configuration = configuration.borrow();
+        CalibrationInput {
+            target: samples_target,
+            target_name: configuration
+                .clients
+                .get(&client_target_id)
+                .map(|v| v.name.clone())
+                .unwrap_or_default(),
+            reference: samples_reference,
+            reference_name: configuration
+                .clients
+                .get(&client_reference_id)
+                .map(|v| v.name.clone())
+                .unwrap_or_default(),
+            pairing: sample_pairing(client_target.clock(), client_reference.clock()),
+            target_clock: client_target.clock(),
+            reference_clock: client_reference.clock(),
+            solver: conf.solver,
+        }
     };
-    match serde_json::to_string(&calibration) {
+    save_calibration_data(output, "calibration-data", &calibration);
+    finish(
+        &status,
+        calibration,
+        &client_target,
+        &client_reference,
+        CalibrationSource::Samples,
+    )
+    .await
+}
+
+/// Samples are paired by time once clocks of both clients are known, because
+/// each of them starts sampling at a slightly different moment
+pub(crate) fn sample_pairing(
+    target: Option<ClockEstimate>,
+    reference: Option<ClockEstimate>,
+) -> SamplePairing {
+    if target.is_some() && reference.is_some() {
+        SamplePairing::Interpolate
+    } else {
+        SamplePairing::Order
+    }
+}
+
+/// Writes collected calibration data to a file in the directory named by
+/// prefix and current time
+pub(crate) fn save_calibration_data(
+    output: Option<&Path>,
+    prefix: &str,
+    calibration: &CalibrationInput,
+) {
+    let Some(output) = output else { return; };
+    match serde_json::to_string(calibration) {
         Ok(data) => {
             let dt: DateTime<Utc> = SystemTime::now().into();
-            let fname = format!("calibration-data-{}.json", dt.format("%Y-%m-%dT%H-%M-%S"));
-            match File::create(fname.clone()) {
+            let fname = output.join(format!(
+                "{}-{}.json",
+                prefix,
+                dt.format("%Y-%m-%dT%H-%M-%S")
+            ));
+            match File::create(&fname) {
                 Ok(mut f) => match f.write_all(data.as_bytes()) {
-                    Ok(()) => println!("Calibration data written to file: {}", fname),
-                    Err(err) => println!("Failed to write calibration data to file: {:?}", err),
+                    Ok(()) => info!("Calibration data written to file: {:?}", fname),
+                    Err(err) => error!("Failed to write calibration data to file: {:?}", err),
                 },
-                Err(err) => println!("Failed to create calibration file: {:?}", err),
+                Err(err) => error!("Failed to create calibration file: {:?}", err),
             }
         }
-        Err(err) => println!("Failed to serialize calibration data: {:?}", err),
+        Err(err) => error!("Failed to serialize calibration data: {:?}", err),
     };
-    finish(tx.clone(), calibration, &client_target, &client_reference).await;
 }
 
 /// Does one pseudo-calibration for logging data about the connected devices
@@ -244,75 +373,55 @@ async fn run_hijack(
     recv: &mut mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
     client_target: (ClientId, String),
     client_reference: (ClientId, String),
-    server: Server,
+    room: Room,
     tx: broadcast::Sender<DashboardMessage>,
-) {
+    output: Option<&Path>,
+) -> bool {
     let conf = CalibrationConfiguration {
         sample_count: 50 * 3600,
         sample_interval_nanos: 20_000_000,
+        stop_on_coverage: false,
+        solver: CalibrationSolver::Full,
     };
 
     let client_target_id = client_target.0;
     let client_reference_id = client_reference.0;
     let client_target_path = client_target.1;
     let client_reference_path = client_reference.1;
+    let status = CalibrationStatus::new(
+        tx.clone(),
+        &room,
+        "hijack",
+        vec![client_reference_id, client_target_id],
+    );
 
-    let Some(client_target) = server.get_client(client_target.0).await else { return ; };
-    let Some(client_reference) = server.get_client(client_reference.0).await else { return ; };
-    if let Err(err) = client_target.send_configuration_down(TriggerCalibration(
-        client_target_path,
-        conf,
-        BaseSpace::Stage,
-    )) {
-        println!(
-            "Failed to send trigger calibration to client {:?}: {:?}",
-            client_target_id, err
-        );
-        return;
-    }
-    if let Err(err) = client_reference.send_configuration_down(TriggerCalibration(
-        client_reference_path,
+    status.set(CalibrationState::Triggering);
+    let (client_target, client_reference) =
+        match get_pair(&room, client_target_id, client_reference_id).await {
+            Ok(pair) => pair,
+            Err(err) => return status.fail(err.to_string()),
+        };
+    if !trigger_calibration(
+        (&client_target, client_target_path),
+        (&client_reference, client_reference_path),
         conf,
-        BaseSpace::Server,
-    )) {
-        println!(
-            "Failed to send trigger calibration to client {:?}: {:?}",
-            client_reference_id, err
-        );
-        let _ = client_target.send_configuration_down(StopCalibration);
-        return;
+    ) {
+        return status.fail("Failed to trigger calibration");
     }
 
-    let samples_result = collect_samples_hijack(
-        conf.sample_count,
-        client_target_id,
-        client_reference_id,
-        recv,
-    )
-    .await;
-    // Send end to clients
-    for id in [client_target_id, client_reference_id] {
-        if let Some(client) = server.get_client(id).await {
-            if let Err(err) = client.send_configuration_down(StopCalibration) {
-                println!(
-                    "Failed to send stop calibration to client {:?}: {:?}",
-                    id, err
-                );
-            }
-        }
-    }
+    status.set(CalibrationState::Collecting);
+    let samples_result =
+        collect_samples_hijack(conf.sample_count, &client_target, &client_reference, recv).await;
+    stop_calibration(&room, &[client_target_id, client_reference_id]).await;
     let (samples_target, samples_reference) = match samples_result {
         Ok(v) => v,
-        Err(err) => {
-            println!("Calibration failed: {:?}", err);
-            return;
-        }
+        Err(err) => return status.fail(err.to_string()),
     };
 
     // Samples collected. Calibrate and apply
-    println!("samples_target: {:?}", samples_target.len());
-    println!("samples_reference: {:?}", samples_target.len());
-    let configuration = server.latest_configuration().await;
+    debug!("samples_target: {:?}", samples_target.len());
+    debug!("samples_reference: {:?}", samples_reference.len());
+    let configuration = room.latest_configuration().await;
     let configuration = configuration.borrow();
     let calibration = CalibrationInput {
         target: samples_target,
@@ -327,21 +436,12 @@ async fn run_hijack(
             .get(&client_reference_id)
             .map(|v| v.name.clone())
             .unwrap_or_default(),
+        pairing: sample_pairing(client_target.clock(), client_reference.clock()),
+        target_clock: client_target.clock(),
+        reference_clock: client_reference.clock(),
+        solver: conf.solver,
     };
-    match serde_json::to_string(&calibration) {
-        Ok(data) => {
-            let dt: DateTime<Utc> = SystemTime::now().into();
-            let fname = format!("calibration-data-{}.json", dt.format("%Y-%m-%dT%H-%M-%S"));
-            match File::create(fname.clone()) {
-                Ok(mut f) => match f.write_all(data.as_bytes()) {
-                    Ok(()) => println!("Calibration data written to file: {}", fname),
-                    Err(err) => println!("Failed to write calibration data to file: {:?}", err),
-                },
-                Err(err) => println!("Failed to create calibration file: {:?}", err),
-            }
-        }
-        Err(err) => println!("Failed to serialize calibration data: {:?}", err),
-    };
+    save_calibration_data(output, "calibration-data", &calibration);
     let _ = tx.send(DashboardMessage::Info {
         message: format!(
             "Data collection finished: {:?}, {:?}",
@@ -349,120 +449,403 @@ async fn run_hijack(
             calibration.target.len()
         ),
     });
+    status.set(CalibrationState::Idle);
+    true
+}
+
+/// Computes calibration from samples collected earlier and applies it
+async fn run_reapply(
+    client_target_id: ClientId,
+    client_reference_id: ClientId,
+    data: CalibrationInput,
+    room: &Room,
+    tx: &broadcast::Sender<DashboardMessage>,
+) -> bool {
+    let status = CalibrationStatus::new(
+        tx.clone(),
+        room,
+        "reapply",
+        vec![client_reference_id, client_target_id],
+    );
+    let (client_target, client_reference) =
+        match get_pair(room, client_target_id, client_reference_id).await {
+            Ok(pair) => pair,
+            Err(err) => return status.fail(err.to_string()),
+        };
+    finish(
+        &status,
+        data,
+        &client_target,
+        &client_reference,
+        CalibrationSource::Reapply,
+    )
+    .await
 }
 
-async fn calibrate_by_headset(
-    clients: Vec<(u32, Client)>,
+/// Finds both clients of a calibration in the room
+pub(crate) async fn get_pair(
+    room: &Room,
+    target: ClientId,
+    reference: ClientId,
+) -> Result<(Client, Client)> {
+    let Some(client_target) = room.get_client(target).await else {
+        return Err(anyhow!("Target client {} not found", target));
+    };
+    let Some(client_reference) = room.get_client(reference).await else {
+        return Err(anyhow!("Reference client {} not found", reference));
+    };
+    Ok((client_target, client_reference))
+}
+
+/// Moves server space of every client which supports calibration under its
+/// headset, at floor level. Returns whether all of them were moved.
+async fn run_headset(
     recv: &mut mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
-) -> Result<()> {
-    for (client_id, client) in clients {
-        client
-            .send_configuration_down(RequestSample("/user/head".to_string(), BaseSpace::Stage))?;
-        let Some(sample) = select!(
-            sample = recv_sample(client_id, recv) => sample,
-            _ = tokio::time::sleep(Duration::from_secs(1)) => None,
-        ) else { continue; };
-        client.send_configuration_down(SetServerSpacePose(Pose {
+    room: &Room,
+    tx: &broadcast::Sender<DashboardMessage>,
+) -> bool {
+    let clients: Vec<_> = room
+        .get_clients()
+        .await
+        .into_iter()
+        .map(|(_, client)| client)
+        .filter(|client| supports_calibration(&[client]))
+        .collect();
+    let ids = clients.iter().map(|client| client.id()).collect();
+    let status = CalibrationStatus::new(tx.clone(), room, "headset", ids);
+    if clients.is_empty() {
+        return status.fail("No client supports calibration");
+    }
+
+    status.set(CalibrationState::Triggering);
+    for client in &clients {
+        let request = RequestSample("/user/head".to_string(), BaseSpace::Stage);
+        if let Err(err) = client.send_configuration_down(request) {
+            return status.fail(format!("Failed to request sample: {}", err));
+        }
+    }
+
+    status.set(CalibrationState::Collecting);
+    let mut samples = HashMap::new();
+    let timeout = tokio::time::sleep(Duration::from_secs(1));
+    tokio::pin!(timeout);
+    while samples.len() < clients.len() {
+        select! {
+            message = recv.recv() => match message {
+                Some(Sample { client, sample }) => {
+                    if clients.iter().any(|c| c.id() == client) {
+                        samples.insert(client, sample);
+                    }
+                }
+                Some(Cancel) | None => return status.fail("Cancelled"),
+                Some(message) => warn!("Ignoring {:?} during headset calibration", message),
+            },
+            id = any_disconnected(&clients) => {
+                return status.fail(format!("Client {} disconnected", id));
+            }
+            _ = &mut timeout => break,
+        }
+    }
+
+    status.set(CalibrationState::Applying);
+    for client in &clients {
+        let Some(sample) = samples.remove(&client.id()) else {
+            return status.fail(format!("No headset pose from client {}", client.id()));
+        };
+        let pose = Pose {
             position: Vec3 {
                 y: 0.0,
                 ..sample.pose.position
             },
             orientation: sample.pose.orientation,
-        }))?;
+        };
+        if let Err(err) = client.send_configuration_down(SetServerSpacePose(pose)) {
+            return status.fail(format!("Failed to send pose: {}", err));
+        }
     }
-    Ok(())
+    status.set(CalibrationState::Idle);
+    true
 }
 
-async fn recv_sample(
-    client_id: ClientId,
-    recv: &mut mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
-) -> Option<CalibrationSample> {
-    loop {
-        let message = recv.recv().await;
-        let Some(message) = message else { break; };
-        if let CalibrationProtocolMessage::Sample { client, sample } = message {
-            if client == client_id {
-                return Some(sample);
+/// Makes the target sample in its stage space and the reference in its
+/// server space. Returns false if either of them could not be triggered.
+pub(crate) fn trigger_calibration(
+    (client_target, target_path): (&Client, String),
+    (client_reference, reference_path): (&Client, String),
+    conf: CalibrationConfiguration,
+) -> bool {
+    if !supports_calibration(&[client_target, client_reference]) {
+        return false;
+    }
+    if !trigger(client_target, target_path, conf, BaseSpace::Stage) {
+        return false;
+    }
+    if !trigger(client_reference, reference_path, conf, BaseSpace::Server) {
+        let _ = client_target.send_configuration_down(StopCalibration);
+        return false;
+    }
+    true
+}
+
+/// Makes one client sample the device in given space. Returns whether the
+/// request was sent.
+pub(crate) fn trigger(
+    client: &Client,
+    path: String,
+    conf: CalibrationConfiguration,
+    space: BaseSpace,
+) -> bool {
+    if let Err(err) = client.send_configuration_down(TriggerCalibration(path, conf, space)) {
+        warn!(
+            "Failed to send trigger calibration to client {:?}: {:?}",
+            client.id(),
+            err
+        );
+        return false;
+    }
+    true
+}
+
+/// Tells clients which are still connected to stop sampling
+pub(crate) async fn stop_calibration(room: &Room, ids: &[ClientId]) {
+    for &id in ids {
+        if let Some(client) = room.get_client(id).await {
+            if let Err(err) = client.send_configuration_down(StopCalibration) {
+                warn!(
+                    "Failed to send stop calibration to client {:?}: {:?}",
+                    id, err
+                );
             }
-        };
+        }
     }
-    None
 }
 
+/// Checks that all clients negotiated the calibration stream
+pub(crate) fn supports_calibration(clients: &[&Client]) -> bool {
+    for client in clients {
+        if !client.capabilities().contains(Capabilities::CALIBRATION) {
+            warn!("Client {} does not support calibration", client.id());
+            return false;
+        }
+    }
+    true
+}
+
+/// Computes the calibration and sends the result to the target unless it is
+/// unreliable. Applied calibrations are recorded in the store. Returns whether
+/// it was applied.
 async fn finish(
-    tx: broadcast::Sender<DashboardMessage>,
+    status: &CalibrationStatus,
     input: CalibrationInput,
     target: &Client,
     reference: &Client,
-) {
-    let result = netvr_calibrate::calibrate(&input);
-    println!("Calibration result: {:?}", result);
-    let _ = tx.send(DashboardMessage::Info {
-        message: format!("Calibration finished: {:?}", result),
+    source: CalibrationSource,
+) -> bool {
+    status.set(CalibrationState::Solving);
+    let data = match solve(status.tx(), &input, target.id(), reference.id()) {
+        Ok(data) => data,
+        Err(err) => return status.fail(err.to_string()),
+    };
+    status.set(CalibrationState::Applying);
+    let pose = server_space_pose(data.translation.clone(), data.rotation.clone());
+    if !apply_result(target, reference, source, pose, data, input).await {
+        return status.fail("Failed to send calibration to the target");
+    }
+    status.set(CalibrationState::Idle);
+    true
+}
+
+/// Computes the calibration and reports it to the dashboard. Fails if it
+/// could not be computed or is unreliable.
+pub(crate) fn solve(
+    tx: &broadcast::Sender<DashboardMessage>,
+    input: &CalibrationInput,
+    target: ClientId,
+    reference: ClientId,
+) -> Result<CalibrationResult> {
+    check_result(tx, netvr_calibrate::calibrate(input), target, reference)
+}
+
+/// Reports computed calibration to the dashboard. Fails if it could not be
+/// computed or is unreliable.
+pub(crate) fn check_result(
+    tx: &broadcast::Sender<DashboardMessage>,
+    result: Result<CalibrationResult>,
+    target: ClientId,
+    reference: ClientId,
+) -> Result<CalibrationResult> {
+    info!("Calibration result: {:?}", result);
+    let _ = tx.send(DashboardMessage::CalibrationFinished {
+        target,
+        reference,
+        result: result.as_ref().ok().cloned(),
+        error: result.as_ref().err().map(|err| err.to_string()),
     });
-    let Ok(data) = result else { return; };
-    if let Err(res) = target.send_configuration_down(SetServerSpacePose(Pose {
+    let data = result?;
+    if data.verdict == CalibrationVerdict::Unreliable {
+        return Err(anyhow!("Calibration is unreliable, not applying it"));
+    }
+    Ok(data)
+}
+
+/// Sends the pose to the target and records it in the store. Returns whether
+/// it was sent.
+pub(crate) async fn apply_result(
+    target: &Client,
+    reference: &Client,
+    source: CalibrationSource,
+    pose: Pose,
+    data: CalibrationResult,
+    input: CalibrationInput,
+) -> bool {
+    if !apply_calibration(target, pose.clone()) {
+        return false;
+    }
+    target
+        .room()
+        .calibration_store()
+        .add(
+            target.name(),
+            reference.name(),
+            source,
+            pose,
+            Some(data),
+            Some(input),
+        )
+        .await;
+    true
+}
+
+/// Server space pose which maps the target's stage space to the reference's
+/// server space, as computed by netvr_calibrate
+pub(crate) fn server_space_pose(translation: Vec3, rotation: Quaternion) -> Pose {
+    Pose {
         position: rotate_vector(
             Vec3 {
-                x: -data.translation.x,
-                y: -data.translation.y,
-                z: -data.translation.z,
+                x: -translation.x,
+                y: -translation.y,
+                z: -translation.z,
             },
-            invert_quaternion(data.rotation.clone()),
+            invert_quaternion(rotation.clone()),
         ),
-        orientation: invert_quaternion(data.rotation),
-    })) {
-        println!("Failed to send stage pose to target: {:?}", res);
+        orientation: invert_quaternion(rotation),
     }
 }
 
+/// Sends the server space pose to the target. Returns whether it was sent.
+pub(crate) fn apply_calibration(target: &Client, pose: Pose) -> bool {
+    if let Err(res) = target.send_configuration_down(SetServerSpacePose(pose)) {
+        warn!("Failed to send stage pose to target: {:?}", res);
+        return false;
+    }
+    true
+}
+
+/// Collects samples until there are conf.sample_count from both clients, or
+/// they cover enough rotations if conf.stop_on_coverage is set. Reports
+/// progress to the dashboard.
 async fn collect_samples(
-    sample_count: usize,
-    client_target: ClientId,
-    client_reference: ClientId,
+    conf: CalibrationConfiguration,
+    client_target: &Client,
+    client_reference: &Client,
     recv: &mut mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
+    tx: &broadcast::Sender<DashboardMessage>,
 ) -> Result<(Vec<CalibrationSample>, Vec<CalibrationSample>)> {
-    let mut samples_target = vec![];
-    let mut samples_reference = vec![];
+    let mut input = CalibrationInput {
+        target: vec![],
+        target_name: String::new(),
+        reference: vec![],
+        reference_name: String::new(),
+        pairing: sample_pairing(client_target.clock(), client_reference.clock()),
+        target_clock: client_target.clock(),
+        reference_clock: client_reference.clock(),
+        solver: conf.solver,
+    };
     let time_start = std::time::Instant::now();
+    let mut progress = tokio::time::interval(PROGRESS_INTERVAL);
     loop {
-        let Some(sample) = recv.recv().await else { break; };
-        if let FinishCalibration = sample {
-            break;
+        let message = select! {
+            message = recv.recv() => message,
+            id = any_disconnected([client_target, client_reference]) => {
+                Err(anyhow!("Client {} disconnected", id))?
+            }
+            _ = progress.tick() => {
+                if time_start.elapsed() > COLLECT_TIMEOUT {
+                    Err(anyhow!("Timed out waiting for samples"))?
+                }
+                let coverage = netvr_calibrate::coverage(&input);
+                let _ = tx.send(DashboardMessage::CalibrationProgress {
+                    target: client_target.id(),
+                    reference: client_reference.id(),
+                    target_samples: input.target.len(),
+                    reference_samples: input.reference.len(),
+                    sample_count: conf.sample_count,
+                    coverage: coverage.clone(),
+                });
+                if conf.stop_on_coverage && coverage.sufficient {
+                    info!("Samples cover enough rotations, stopping early");
+                    break;
+                }
+                continue;
+            }
+        };
+        let Some(sample) = message else { break; };
+        match sample {
+            FinishCalibration => break,
+            Cancel => Err(anyhow!("Cancelled"))?,
+            _ => {}
         }
         let Sample { client, sample } = sample else { continue; };
-        if client == client_target {
-            samples_target.push(sample);
-        } else if client == client_reference {
-            samples_reference.push(sample);
+        if client == client_target.id() {
+            input.target.push(sample);
+        } else if client == client_reference.id() {
+            input.reference.push(sample);
         }
-        if samples_target.len() >= sample_count && samples_reference.len() >= sample_count {
+        if input.target.len() >= conf.sample_count && input.reference.len() >= conf.sample_count {
             break;
         }
-        if time_start.elapsed().as_secs() > 60 {
-            Err(anyhow!("Timed out waiting for samples"))?
-        }
     }
-    Ok((samples_target, samples_reference))
+    Ok((input.target, input.reference))
+}
+
+/// Resolves with id of the first of the clients which disconnects
+pub(crate) async fn any_disconnected<'a>(
+    clients: impl IntoIterator<Item = &'a Client>,
+) -> ClientId {
+    let disconnected = clients.into_iter().map(|client| {
+        Box::pin(async move {
+            client.cancelled().await;
+            client.id()
+        })
+    });
+    select_all(disconnected).await.0
 }
 
 async fn collect_samples_hijack(
     sample_count: usize,
-    client_target: ClientId,
-    client_reference: ClientId,
+    client_target: &Client,
+    client_reference: &Client,
     recv: &mut mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
 ) -> Result<(Vec<CalibrationSample>, Vec<CalibrationSample>)> {
     let mut samples_target = vec![];
     let mut samples_reference = vec![];
     loop {
-        let Some(sample) = recv.recv().await else { break; };
-        if let FinishCalibration = sample {
-            break;
+        let sample = select! {
+            sample = recv.recv() => sample,
+            id = any_disconnected([client_target, client_reference]) => {
+                Err(anyhow!("Client {} disconnected", id))?
+            }
+        };
+        let Some(sample) = sample else { break; };
+        match sample {
+            FinishCalibration => break,
+            Cancel => Err(anyhow!("Cancelled"))?,
+            _ => {}
         }
         let Sample { client, sample } = sample else { continue; };
-        if client == client_target {
+        if client == client_target.id() {
             samples_target.push(sample);
-        } else if client == client_reference {
+        } else if client == client_reference.id() {
             samples_reference.push(sample);
         }
     }
@@ -0,0 +1,78 @@
+//! Phases of a running calibration, published to dashboards on every change
+
+use netvr_data::net::ClientId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+use crate::{dashboard::DashboardMessage, room::Room};
+
+/// Phase of the calibration running in a room
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub(crate) enum CalibrationState {
+    /// Finished successfully, next calibration can start
+    Idle,
+    /// Clients are asked to start sampling
+    Triggering,
+    Collecting,
+    Solving,
+    /// Result is sent to the calibrated clients
+    Applying,
+    /// Cancelled, a client disconnected or the result was not usable. Next
+    /// calibration can start right away.
+    Failed {
+        reason: String,
+    },
+}
+
+/// Publishes state transitions of one calibration
+pub(crate) struct CalibrationStatus {
+    tx: broadcast::Sender<DashboardMessage>,
+    room: String,
+    kind: &'static str,
+    clients: Vec<ClientId>,
+}
+
+impl CalibrationStatus {
+    /// Kind is the same as in calibration metrics, clients are all clients
+    /// taking part with the reference first
+    pub(crate) fn new(
+        tx: broadcast::Sender<DashboardMessage>,
+        room: &Room,
+        kind: &'static str,
+        clients: Vec<ClientId>,
+    ) -> Self {
+        Self {
+            tx,
+            room: room.name().to_owned(),
+            kind,
+            clients,
+        }
+    }
+
+    pub(crate) fn tx(&self) -> &broadcast::Sender<DashboardMessage> {
+        &self.tx
+    }
+
+    pub(crate) fn set(&self, state: CalibrationState) {
+        debug!(
+            "Calibration {} of {:?} in room {}: {:?}",
+            self.kind, self.clients, self.room, state
+        );
+        let _ = self.tx.send(DashboardMessage::CalibrationState {
+            room: self.room.clone(),
+            kind: self.kind.to_owned(),
+            clients: self.clients.clone(),
+            state,
+        });
+    }
+
+    /// Moves to Failed and returns false, so that it can end a calibration
+    pub(crate) fn fail(&self, reason: impl Into<String>) -> bool {
+        let reason = reason.into();
+        warn!("Calibration {} failed: {}", self.kind, reason);
+        self.set(CalibrationState::Failed { reason });
+        false
+    }
+}
@@ -0,0 +1,237 @@
+//! Calibrations applied to clients, kept by client name so that they can be
+//! applied again when the client reconnects or rolled back from the
+//! dashboard. Each one is written to its own file together with the samples it
+//! was computed from.
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use netvr_calibrate::{CalibrationInput, CalibrationResult, CalibrationVerdict};
+use netvr_data::{net::ConfigurationDown, Pose};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::{client::Client, dashboard::DashboardMessage};
+
+/// How a calibration came to be
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CalibrationSource {
+    Samples,
+    Reapply,
+    Continuous,
+    /// Part of a batch, the pose may be chained through other targets
+    Batch,
+    /// Computed from devices placed on shared markers
+    Markers,
+    /// Copy of an earlier calibration chosen from the dashboard
+    Rollback,
+}
+
+/// Calibration applied to one client
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CalibrationRecord {
+    /// Unique within the store, also names the file
+    pub id: u64,
+    /// Name of the client which was calibrated
+    pub name: String,
+    /// Name of the client it was calibrated against
+    pub reference_name: String,
+    pub time: DateTime<Utc>,
+    pub source: CalibrationSource,
+    /// Server space pose sent to the client
+    pub pose: Pose,
+    /// None for rollbacks, which only copy the pose
+    pub result: Option<CalibrationResult>,
+}
+
+impl CalibrationRecord {
+    /// Good calibrations are offered to reconnecting clients. Rollbacks were
+    /// chosen by hand, so they count as good.
+    pub(crate) fn is_good(&self) -> bool {
+        match &self.result {
+            Some(result) => result.verdict == CalibrationVerdict::Good,
+            None => self.source == CalibrationSource::Rollback,
+        }
+    }
+}
+
+/// Contents of one file in the store
+#[derive(Serialize, Deserialize)]
+struct StoredCalibration {
+    record: CalibrationRecord,
+    /// Samples the calibration was computed from, if any
+    input: Option<CalibrationInput>,
+}
+
+struct InnerStore {
+    /// Records are only kept in memory if None
+    directory: Option<PathBuf>,
+    auto_apply: bool,
+    /// Sorted by id
+    records: Mutex<Vec<CalibrationRecord>>,
+    ws: broadcast::Sender<DashboardMessage>,
+}
+
+/// History of calibrations of all clients the server has seen
+#[derive(Clone)]
+pub(crate) struct CalibrationStore {
+    inner: Arc<InnerStore>,
+}
+
+impl CalibrationStore {
+    /// Loads all calibrations from the directory, creating it if needed. New
+    /// calibrations are announced to dashboards through ws. If auto_apply is
+    /// set, clients get their last good calibration when they connect.
+    pub(crate) async fn load(
+        directory: Option<PathBuf>,
+        auto_apply: bool,
+        ws: broadcast::Sender<DashboardMessage>,
+    ) -> Result<Self> {
+        let mut records = vec![];
+        if let Some(directory) = &directory {
+            tokio::fs::create_dir_all(directory)
+                .await
+                .with_context(|| format!("Failed to create {:?}", directory))?;
+            let mut entries = tokio::fs::read_dir(directory).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path.extension() != Some(OsStr::new("json")) {
+                    continue;
+                }
+                match read_record(&path).await {
+                    Ok(record) => records.push(record),
+                    Err(err) => warn!("Skipping calibration file {:?}: {:?}", path, err),
+                }
+            }
+            info!("Loaded {} calibrations from {:?}", records.len(), directory);
+        }
+        records.sort_by_key(|record| record.id);
+        Ok(Self {
+            inner: Arc::new(InnerStore {
+                directory,
+                auto_apply,
+                records: Mutex::new(records),
+                ws,
+            }),
+        })
+    }
+
+    /// Whether clients get their last good calibration when they connect
+    pub(crate) fn auto_apply(&self) -> bool {
+        self.inner.auto_apply
+    }
+
+    /// Records a calibration which was just applied to a client and writes
+    /// it to disk together with its samples
+    pub(crate) async fn add(
+        &self,
+        name: String,
+        reference_name: String,
+        source: CalibrationSource,
+        pose: Pose,
+        result: Option<CalibrationResult>,
+        input: Option<CalibrationInput>,
+    ) -> CalibrationRecord {
+        let time = Utc::now();
+        let record = {
+            let mut records = self.inner.records.lock().unwrap();
+            // ids follow time, but stay unique when two arrive within a millisecond
+            let id = records
+                .last()
+                .map_or(0, |last| last.id + 1)
+                .max(time.timestamp_millis() as u64);
+            let record = CalibrationRecord {
+                id,
+                name,
+                reference_name,
+                time,
+                source,
+                pose,
+                result,
+            };
+            records.push(record.clone());
+            record
+        };
+        if let Some(directory) = &self.inner.directory {
+            let path = directory.join(format!("{}.json", record.id));
+            let stored = StoredCalibration {
+                record: record.clone(),
+                input,
+            };
+            if let Err(err) = write_stored(&path, &stored).await {
+                warn!("Failed to write calibration {:?}: {:?}", path, err);
+            }
+        }
+        info!(
+            "Stored calibration {} of {:?} ({:?})",
+            record.id, record.name, record.source
+        );
+        let _ = self.inner.ws.send(DashboardMessage::CalibrationStored {
+            record: record.clone(),
+        });
+        record
+    }
+
+    /// All calibrations, oldest first
+    pub(crate) fn history(&self) -> Vec<CalibrationRecord> {
+        self.inner.records.lock().unwrap().clone()
+    }
+
+    pub(crate) fn get(&self, id: u64) -> Option<CalibrationRecord> {
+        let records = self.inner.records.lock().unwrap();
+        records.iter().find(|record| record.id == id).cloned()
+    }
+
+    /// The most recent good calibration of the client with this name
+    pub(crate) fn latest_good(&self, name: &str) -> Option<CalibrationRecord> {
+        let records = self.inner.records.lock().unwrap();
+        records
+            .iter()
+            .rev()
+            .find(|record| record.name == name && record.is_good())
+            .cloned()
+    }
+
+    /// Called when a client announces its name. Applies or offers its last
+    /// good calibration.
+    pub(crate) fn client_named(&self, client: &Client, name: &str) {
+        let Some(record) = self.latest_good(name) else { return; };
+        if !self.auto_apply() {
+            let _ = self.inner.ws.send(DashboardMessage::CalibrationOffer {
+                id: client.id(),
+                record,
+            });
+            return;
+        }
+        match client.send_configuration_down(ConfigurationDown::SetServerSpacePose(record.pose)) {
+            Ok(()) => {
+                let message = format!(
+                    "Applied stored calibration {} to client {} ({:?})",
+                    record.id,
+                    client.id(),
+                    name
+                );
+                info!("{}", message);
+                let _ = self.inner.ws.send(DashboardMessage::Info { message });
+            }
+            Err(err) => warn!("Failed to apply stored calibration: {:?}", err),
+        }
+    }
+}
+
+async fn read_record(path: &Path) -> Result<CalibrationRecord> {
+    let text = tokio::fs::read_to_string(path).await?;
+    Ok(serde_json::from_str::<StoredCalibration>(&text)?.record)
+}
+
+async fn write_stored(path: &Path, stored: &StoredCalibration) -> Result<()> {
+    tokio::fs::write(path, serde_json::to_string(stored)?).await?;
+    Ok(())
+}
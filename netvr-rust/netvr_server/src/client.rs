@@ -1,24 +1,53 @@
-use std::sync::Arc;
+use std::{
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, Mutex},
+};
 
-use anyhow::{Ok, Result};
+use anyhow::{anyhow, Ok, Result};
 use netvr_data::{
     app, bincode,
-    net::{ClientId, ConfigurationDown, ConfigurationUp, DatagramDown, StateSnapshot},
+    compact::StateEncoder,
+    net::{
+        CalibrationSample, Capabilities, ClientId, ClockEstimate, CloseCode, ConfigurationDown,
+        ConfigurationUp, DatagramDown, RemoteStateSnapshotSet, StateSnapshot,
+    },
+};
+use quinn::{Connection, VarInt};
+use quinn_proto::ConnectionStats;
+use tokio::{
+    spawn,
+    sync::{broadcast, mpsc, watch},
 };
-use quinn::Connection;
-use tokio::sync::{broadcast, mpsc};
 use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
 
-use crate::{dashboard::DashboardMessage, server::Server};
+use crate::{
+    app::AppServerMessage,
+    calibration_protocol::CalibrationProtocolMessage,
+    clock::{self, ClockEstimator},
+    dashboard::DashboardMessage,
+    metrics::{self, ClientMetrics},
+    recorder::{RecordedEventKind, Recorder},
+    room::Room,
+};
 
 struct InnerClient {
     id: ClientId,
+    capabilities: Capabilities,
     ws: broadcast::Sender<DashboardMessage>,
     token: CancellationToken,
-    server: Server,
+    room: watch::Sender<Room>,
     configuration_down_queue: mpsc::UnboundedSender<ConfigurationDown>,
     app_down_queue: mpsc::UnboundedSender<app::AppDown>,
-    connection: Connection,
+    /// None for virtual clients created by replay
+    connection: Option<Connection>,
+    recorder: Recorder,
+    metrics: Arc<ClientMetrics>,
+    /// Remembers state sets sent to the client to send deltas
+    state_encoder: Mutex<StateEncoder>,
+    clock: Mutex<ClockEstimator>,
+    /// From the latest configuration snapshot
+    name: Mutex<String>,
 }
 
 /// Represnets one connected client
@@ -32,40 +61,203 @@ impl Client {
     pub(crate) fn new(
         ws: broadcast::Sender<DashboardMessage>,
         token: CancellationToken,
-        server: Server,
+        room: Room,
         id: ClientId,
+        capabilities: Capabilities,
         configuration_down_queue: mpsc::UnboundedSender<ConfigurationDown>,
         app_down_queue: mpsc::UnboundedSender<app::AppDown>,
         connection: Connection,
+        recorder: Recorder,
     ) -> Self {
         Self {
             inner: Arc::new(InnerClient {
                 id,
+                capabilities,
                 ws,
                 token,
-                server,
+                room: watch::channel(room).0,
                 configuration_down_queue,
                 app_down_queue,
-                connection,
+                connection: Some(connection),
+                recorder,
+                metrics: Arc::default(),
+                state_encoder: Mutex::default(),
+                clock: Mutex::default(),
+                name: Mutex::default(),
             }),
         }
     }
 
+    /// Create a client which is not backed by a connection. Messages sent to
+    /// it are discarded.
+    pub(crate) fn new_virtual(
+        ws: broadcast::Sender<DashboardMessage>,
+        room: Room,
+        id: ClientId,
+        capabilities: Capabilities,
+    ) -> Self {
+        let (configuration_down_queue, mut configuration_down) = mpsc::unbounded_channel();
+        let (app_down_queue, mut app_down) = mpsc::unbounded_channel();
+        let metrics = Arc::new(ClientMetrics::default());
+        let queue = metrics.clone();
+        spawn(async move {
+            while configuration_down.recv().await.is_some() {
+                queue
+                    .configuration_down_queue
+                    .fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+        let queue = metrics.clone();
+        spawn(async move {
+            while app_down.recv().await.is_some() {
+                queue.app_down_queue.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+        Self {
+            inner: Arc::new(InnerClient {
+                id,
+                capabilities,
+                ws,
+                token: CancellationToken::new(),
+                room: watch::channel(room).0,
+                configuration_down_queue,
+                app_down_queue,
+                connection: None,
+                recorder: Recorder::disabled(),
+                metrics,
+                state_encoder: Mutex::default(),
+                clock: Mutex::default(),
+                name: Mutex::default(),
+            }),
+        }
+    }
+
+    fn record(&self, kind: RecordedEventKind) {
+        self.inner
+            .recorder
+            .record(self.id(), self.room().name(), kind);
+    }
+
+    /// Call when the client joins the server
+    pub(crate) fn handle_connected(&self) {
+        self.record(RecordedEventKind::Connected(self.capabilities()));
+    }
+
+    /// Call when the client leaves the server
+    pub(crate) fn handle_disconnected(&self) {
+        self.record(RecordedEventKind::Disconnected);
+    }
+
     /// Call on configuration message
     pub async fn handle_configuration_up(&self, message: ConfigurationUp) {
-        println!("Received configuration up {:?}", message);
+        debug!("Received configuration up {:?}", message);
+        self.record(RecordedEventKind::ConfigurationUp(message.clone()));
+        if let ConfigurationUp::ConfigurationSnapshot(snapshot) = message {
+            let name = snapshot.name.clone();
+            let previous = std::mem::replace(&mut *self.inner.name.lock().unwrap(), name.clone());
+            let room = self.room();
+            room.apply_configuration(self.id(), snapshot).await;
+            // replayed clients already got their calibration when recorded
+            if name != previous && !name.is_empty() && self.inner.connection.is_some() {
+                room.calibration_store().client_named(self, &name);
+            }
+        }
+    }
+
+    /// Call on synchronized object message
+    pub(crate) fn handle_app_up(&self, message: app::AppUp) -> Result<()> {
+        debug!("Received app message: {:?}", message);
+        self.record(RecordedEventKind::AppUp(message.clone()));
+        self.room()
+            .app_channel()
+            .send(AppServerMessage::AppUp(self.id(), message))?;
+        Ok(())
     }
 
-    /// Call on datagram
-    pub async fn handle_recv_snapshot(&self, message: StateSnapshot) -> Result<()> {
-        // println!("Received datagram {:?}", message);
+    /// Call on synchronized object datagram
+    pub(crate) fn handle_app_datagram(&self, message: app::AppDatagramUp) -> Result<()> {
+        self.record(RecordedEventKind::AppDatagramUp(message.clone()));
+        self.room()
+            .app_channel()
+            .send(AppServerMessage::Datagram(self.id(), message))?;
+        Ok(())
+    }
+
+    /// Call on calibration sample
+    pub(crate) fn handle_calibration_sample(&self, sample: CalibrationSample) -> Result<()> {
+        trace!("Forwarding calibration: {:?}", sample);
+        self.record(RecordedEventKind::CalibrationSample(sample.clone()));
+        self.room()
+            .calibration_sender()
+            .send(CalibrationProtocolMessage::Sample {
+                client: self.id(),
+                sample,
+            })
+            .map_err(|err| anyhow!("Failed to forward calibration message: {:?}", err))?;
+        Ok(())
+    }
+
+    /// Call on datagram. Acknowledged is the order of the latest state set the
+    /// client applied.
+    pub async fn handle_recv_snapshot(
+        &self,
+        message: StateSnapshot,
+        acknowledged: usize,
+    ) -> Result<()> {
+        self.record(RecordedEventKind::State(message.clone()));
+        self.room().apply_snapshot(self.id(), message.clone()).await;
         let _ = self.ws().send(DashboardMessage::DatagramUp {
             id: self.id(),
             message,
         });
-        let mut snapshots = self.inner.server.read_latest_snapshots().await;
+        self.inner
+            .state_encoder
+            .lock()
+            .unwrap()
+            .acknowledge(acknowledged);
+        Ok(())
+    }
+
+    /// Call when the client answers a heartbeat
+    pub(crate) fn handle_pong(&self, server_nanos: i64, client_nanos: i64) {
+        let received = clock::now_nanos();
+        let mut estimator = self.inner.clock.lock().unwrap();
+        estimator.pong(server_nanos, client_nanos, received);
+        trace!("Clock estimate: {:?}", estimator.estimate());
+    }
+
+    /// Name the client announced in its configuration, empty until then
+    pub(crate) fn name(&self) -> String {
+        self.inner.name.lock().unwrap().clone()
+    }
+
+    /// How the client's clock relates to the server's, None until it answers
+    /// a heartbeat
+    pub(crate) fn clock(&self) -> Option<ClockEstimate> {
+        self.inner.clock.lock().unwrap().estimate()
+    }
+
+    /// Sends state of other clients in the room, split into as many datagrams
+    /// as needed. Skipped if the client already moved out of the room.
+    pub(crate) fn send_state(
+        &self,
+        room: &Room,
+        mut snapshots: RemoteStateSnapshotSet,
+    ) -> Result<()> {
+        let Some(connection) = &self.inner.connection else { return Ok(()); };
+        let max_size = connection
+            .max_datagram_size()
+            .ok_or(anyhow!("Client does not accept datagrams"))?;
         snapshots.clients.remove(&self.id());
-        self.send_datagram(&DatagramDown::State(snapshots))?;
+        let mut encoder = self.inner.state_encoder.lock().unwrap();
+        if !self.room().ptr_eq(room) {
+            return Ok(());
+        }
+        let compact = encoder.encode(&snapshots);
+        drop(encoder);
+        for part in compact.split(max_size) {
+            self.send_datagram(&DatagramDown::State(part))?;
+        }
         Ok(())
     }
 
@@ -78,25 +270,40 @@ impl Client {
     /// Call when you want to send something to a client
     pub(crate) fn send_configuration_down(&self, message: ConfigurationDown) -> Result<()> {
         self.inner.configuration_down_queue.send(message)?;
+        self.inner
+            .metrics
+            .configuration_down_queue
+            .fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
     /// Call when you want to send something to a client
     pub(crate) fn send_app_down(&self, message: app::AppDown) -> Result<()> {
         self.inner.app_down_queue.send(message)?;
+        self.inner
+            .metrics
+            .app_down_queue
+            .fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
     /// Call when you want to send something to a client
     pub(crate) fn send_datagram(&self, datagram: &DatagramDown) -> Result<()> {
-        self.inner
-            .connection
-            .send_datagram(bincode::serialize(&datagram)?.into())?;
+        let Some(connection) = &self.inner.connection else { return Ok(()); };
+        let bytes = bincode::serialize(&datagram)?;
+        // quinn silently drops the oldest datagram if the new one does not fit
+        if connection.datagram_send_buffer_space() < bytes.len() {
+            metrics::inc(&self.inner.metrics.datagram_drops);
+        }
+        if let Err(err) = connection.send_datagram(bytes.into()) {
+            metrics::inc(&self.inner.metrics.datagram_drops);
+            Err(err)?;
+        }
+        metrics::inc(&self.inner.metrics.datagrams_down);
         Ok(())
     }
 
     /// Wait until the client is cancelled
-    #[allow(dead_code)]
     pub(crate) fn cancelled(&self) -> tokio_util::sync::WaitForCancellationFuture {
         self.inner.token.cancelled()
     }
@@ -118,4 +325,54 @@ impl Client {
     pub(crate) fn id(&self) -> ClientId {
         self.inner.id
     }
+
+    /// Counters exposed on /metrics
+    pub(crate) fn metrics(&self) -> &Arc<ClientMetrics> {
+        &self.inner.metrics
+    }
+
+    /// QUIC statistics, None for virtual clients
+    pub(crate) fn connection_stats(&self) -> Option<ConnectionStats> {
+        self.inner.connection.as_ref().map(Connection::stats)
+    }
+
+    /// Optional protocol features negotiated with this client
+    pub(crate) fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities
+    }
+
+    /// Room the client is currently in
+    pub(crate) fn room(&self) -> Room {
+        self.inner.room.borrow().clone()
+    }
+
+    /// Notifies when the client is moved to a different room
+    pub(crate) fn subscribe_room(&self) -> watch::Receiver<Room> {
+        self.inner.room.subscribe()
+    }
+
+    /// Only changes where the client thinks it is. Use Server::move_client to
+    /// actually move it. State is encoded from scratch, because orders of the
+    /// new room are unrelated to those the client acknowledged before.
+    pub(crate) fn set_room(&self, room: Room) {
+        let mut encoder = self.inner.state_encoder.lock().unwrap();
+        *encoder = StateEncoder::default();
+        self.inner.room.send_replace(room);
+    }
+
+    /// Address the client connected from, None for virtual clients
+    pub(crate) fn remote_address(&self) -> Option<SocketAddr> {
+        self.inner
+            .connection
+            .as_ref()
+            .map(|connection| connection.remote_address())
+    }
+
+    /// Closes the connection telling the client why
+    pub(crate) fn close(&self, code: CloseCode, reason: &str) {
+        if let Some(connection) = &self.inner.connection {
+            connection.close(VarInt::from_u32(code.code()), reason.as_bytes());
+        }
+        self.cancel();
+    }
 }
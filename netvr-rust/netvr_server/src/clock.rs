@@ -0,0 +1,140 @@
+//! Estimates how clocks of clients relate to the server's clock, similar to
+//! NTP. Every heartbeat carries server time, the client answers with its own
+//! time and the server notes when the answer arrived.
+
+use std::{collections::VecDeque, time::SystemTime};
+
+use netvr_data::net::ClockEstimate;
+
+/// How many recent measurements are considered
+const MEASUREMENTS: usize = 16;
+
+/// Server time in nanoseconds since UNIX epoch
+pub(crate) fn now_nanos() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as i64)
+        .unwrap_or_default()
+}
+
+/// Recent heartbeat round trips of one client
+#[derive(Default)]
+pub(crate) struct ClockEstimator {
+    /// Offset and round trip time of each measurement
+    measurements: VecDeque<(i64, i64)>,
+}
+
+impl ClockEstimator {
+    /// Call when a pong arrives at server time received
+    pub(crate) fn pong(&mut self, server_nanos: i64, client_nanos: i64, received: i64) {
+        let round_trip = received - server_nanos;
+        if round_trip < 0 {
+            return;
+        }
+        // the client read its clock somewhere in the middle of the round trip
+        let offset = client_nanos - (server_nanos + round_trip / 2);
+        self.measurements.push_back((offset, round_trip));
+        if self.measurements.len() > MEASUREMENTS {
+            self.measurements.pop_front();
+        }
+    }
+
+    /// Uses the measurement with the shortest round trip, because it was
+    /// delayed the least by queues along the way. None until the first pong.
+    pub(crate) fn estimate(&self) -> Option<ClockEstimate> {
+        let (offset, round_trip) = self
+            .measurements
+            .iter()
+            .min_by_key(|(_, round_trip)| *round_trip)?;
+        Some(ClockEstimate {
+            offset_nanos: *offset,
+            uncertainty_nanos: round_trip / 2,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic random numbers, so that failures can be reproduced
+    struct Random(u64);
+
+    impl Random {
+        /// Uniform in [0, max), xorshift64*
+        fn below(&mut self, max: i64) -> i64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) % max as u64) as i64
+        }
+    }
+
+    /// Client clock is this much ahead of the server's
+    const OFFSET: i64 = 5_000_000_000;
+    const MILLI: i64 = 1_000_000;
+
+    /// Heartbeat sent at server time sent which takes up and down nanoseconds
+    /// to reach the client and back
+    fn measure(estimator: &mut ClockEstimator, sent: i64, up: i64, down: i64) {
+        estimator.pong(sent, sent + up + OFFSET, sent + up + down);
+    }
+
+    #[test]
+    fn nothing_is_estimated_without_pongs() {
+        assert!(ClockEstimator::default().estimate().is_none());
+    }
+
+    #[test]
+    fn symmetric_delay_gives_exact_offset() {
+        let mut estimator = ClockEstimator::default();
+        measure(&mut estimator, 1_000 * MILLI, 20 * MILLI, 20 * MILLI);
+        let estimate = estimator.estimate().unwrap();
+        assert_eq!(estimate.offset_nanos, OFFSET);
+        assert_eq!(estimate.uncertainty_nanos, 20 * MILLI);
+    }
+
+    #[test]
+    fn offset_is_within_uncertainty_despite_jitter() {
+        let mut random = Random(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..20 {
+            let mut estimator = ClockEstimator::default();
+            let mut fastest = i64::MAX;
+            let mut delay = || MILLI + random.below(50 * MILLI);
+            for i in 0..MEASUREMENTS as i64 {
+                // queues delay each direction differently
+                let (up, down) = (delay(), delay());
+                fastest = fastest.min(up + down);
+                measure(&mut estimator, i * 1_000 * MILLI, up, down);
+            }
+            let estimate = estimator.estimate().unwrap();
+            assert_eq!(estimate.uncertainty_nanos, fastest / 2);
+            let error = (estimate.offset_nanos - OFFSET).abs();
+            assert!(
+                error <= estimate.uncertainty_nanos,
+                "off by {} ns, uncertainty {} ns",
+                error,
+                estimate.uncertainty_nanos
+            );
+        }
+    }
+
+    #[test]
+    fn pong_from_the_future_is_ignored() {
+        let mut estimator = ClockEstimator::default();
+        estimator.pong(2_000 * MILLI, OFFSET, 1_000 * MILLI);
+        assert!(estimator.estimate().is_none());
+    }
+
+    #[test]
+    fn old_measurements_are_forgotten() {
+        let mut estimator = ClockEstimator::default();
+        measure(&mut estimator, 0, MILLI, MILLI);
+        for i in 1..=MEASUREMENTS as i64 {
+            measure(&mut estimator, i * 1_000 * MILLI, 30 * MILLI, 10 * MILLI);
+        }
+        let estimate = estimator.estimate().unwrap();
+        assert_eq!(estimate.uncertainty_nanos, 20 * MILLI);
+        assert_eq!(estimate.offset_nanos, OFFSET + 10 * MILLI);
+    }
+}
@@ -0,0 +1,582 @@
+//! Continuous calibration which corrects drift between two clients while
+//! their devices stay co-located, for example a tracker strapped to a
+//! controller. Samples are kept in a sliding window which is solved on a
+//! schedule. Corrections are applied in small steps and only when they clearly
+//! improve on the current calibration.
+
+use std::time::Duration;
+
+use nalgebra::{Quaternion, UnitQuaternion, Vector3};
+use netvr_calibrate::{CalibrationInput, CalibrationResult, CalibrationVerdict};
+use netvr_data::{
+    net::{CalibrationConfiguration, ClientId},
+    Pose, Vec3,
+};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+};
+use tracing::{info, warn};
+
+use crate::{
+    calibration_protocol::{
+        any_disconnected, apply_calibration, get_pair, sample_pairing, server_space_pose,
+        stop_calibration, trigger_calibration,
+        CalibrationProtocolMessage::{self, *},
+    },
+    calibration_state::{CalibrationState, CalibrationStatus},
+    calibration_store::CalibrationSource,
+    dashboard::DashboardMessage,
+    room::Room,
+};
+
+/// How often the window is solved
+const SOLVE_INTERVAL: Duration = Duration::from_secs(10);
+/// A new estimate must have at most this share of the current error...
+const IMPROVEMENT_RATIO: f64 = 0.8;
+/// ...and be at least this much better, so that noise is not chased
+const MIN_IMPROVEMENT_MM: f64 = 1.;
+/// Share of the difference to a new estimate applied in one step
+const SMOOTHING: f32 = 0.5;
+/// Largest correction applied in one step
+const MAX_STEP_METERS: f32 = 0.02;
+const MAX_STEP_RADIANS: f32 = 0.035;
+
+/// Maps target's stage space to the space reference samples are in, its
+/// server space unless it is being calibrated itself
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Transform {
+    translation: Vector3<f32>,
+    rotation: UnitQuaternion<f32>,
+}
+
+impl From<&CalibrationResult> for Transform {
+    fn from(result: &CalibrationResult) -> Self {
+        let (t, q) = (&result.translation, &result.rotation);
+        Self {
+            translation: Vector3::new(t.x, t.y, t.z),
+            rotation: UnitQuaternion::new_normalize(Quaternion::new(q.w, q.x, q.y, q.z)),
+        }
+    }
+}
+
+impl Transform {
+    pub(crate) fn identity() -> Self {
+        Self {
+            translation: Vector3::zeros(),
+            rotation: UnitQuaternion::identity(),
+        }
+    }
+
+    /// Transform which applies inner first and self second
+    pub(crate) fn chain(&self, inner: &Transform) -> Transform {
+        Transform {
+            translation: self.rotation * inner.translation + self.translation,
+            rotation: self.rotation * inner.rotation,
+        }
+    }
+
+    pub(crate) fn translation(&self) -> Vec3 {
+        Vec3 {
+            x: self.translation.x,
+            y: self.translation.y,
+            z: self.translation.z,
+        }
+    }
+
+    pub(crate) fn rotation(&self) -> netvr_data::Quaternion {
+        netvr_data::Quaternion {
+            x: self.rotation.i,
+            y: self.rotation.j,
+            z: self.rotation.k,
+            w: self.rotation.w,
+        }
+    }
+
+    /// Moves part of the way towards other, but at most by MAX_STEP_*
+    fn step_towards(&self, other: &Transform) -> Transform {
+        let translation = other.translation - self.translation;
+        let rotation = other.rotation * self.rotation.inverse();
+        let mut fraction = SMOOTHING;
+        let distance = translation.norm();
+        if distance * fraction > MAX_STEP_METERS {
+            fraction = MAX_STEP_METERS / distance;
+        }
+        let angle = rotation.angle();
+        if angle * fraction > MAX_STEP_RADIANS {
+            fraction = MAX_STEP_RADIANS / angle;
+        }
+        Transform {
+            translation: self.translation + translation * fraction,
+            rotation: rotation.powf(fraction) * self.rotation,
+        }
+    }
+
+    fn position_error(&self, window: &CalibrationInput) -> Option<f64> {
+        netvr_calibrate::position_error(window, self.translation(), self.rotation())
+    }
+
+    /// Estimate moved to this transform, so that the stored result matches
+    /// what was applied. Position error is measured again on the window, the
+    /// rest of the quality is that of the estimate.
+    fn result(&self, estimate: &CalibrationResult, window: &CalibrationInput) -> CalibrationResult {
+        let mut result = CalibrationResult {
+            translation: self.translation(),
+            rotation: self.rotation(),
+            ..estimate.clone()
+        };
+        if let Some(error) = self.position_error(window) {
+            result.quality.rms_position_error_mm = error;
+        }
+        result
+    }
+}
+
+/// Keeps calibrating target against reference until FinishCalibration.
+/// conf.sample_count is the size of the sliding window. Stays in Collecting
+/// state while running, solved windows are reported as steps. Returns whether
+/// any correction was applied, false if it was cancelled or a client
+/// disconnected.
+pub(crate) async fn run_continuous(
+    recv: &mut mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
+    client_target: (ClientId, String),
+    client_reference: (ClientId, String),
+    room: Room,
+    tx: broadcast::Sender<DashboardMessage>,
+    conf: CalibrationConfiguration,
+) -> bool {
+    let status = CalibrationStatus::new(
+        tx.clone(),
+        &room,
+        "continuous",
+        vec![client_reference.0, client_target.0],
+    );
+    status.set(CalibrationState::Triggering);
+    let (target, reference) = match get_pair(&room, client_target.0, client_reference.0).await {
+        Ok(pair) => pair,
+        Err(err) => return status.fail(err.to_string()),
+    };
+    if !trigger_calibration(
+        (&target, client_target.1),
+        (&reference, client_reference.1),
+        conf,
+    ) {
+        return status.fail("Failed to trigger calibration");
+    }
+    status.set(CalibrationState::Collecting);
+    info!(
+        "Continuous calibration of {} against {} started",
+        target.id(),
+        reference.id()
+    );
+
+    let mut window = CalibrationInput {
+        target: vec![],
+        target_name: String::new(),
+        reference: vec![],
+        reference_name: String::new(),
+        pairing: sample_pairing(target.clock(), reference.clock()),
+        target_clock: target.clock(),
+        reference_clock: reference.clock(),
+        solver: conf.solver,
+    };
+    let mut current: Option<Transform> = None;
+    // only the last applied step is stored, once finished, with the window
+    let mut last_applied: Option<(Pose, Option<CalibrationResult>)> = None;
+    let mut solve = tokio::time::interval(SOLVE_INTERVAL);
+    // the first tick is immediate and the window is still empty
+    solve.tick().await;
+    let failure = loop {
+        let message = select! {
+            message = recv.recv() => message,
+            id = any_disconnected([&target, &reference]) => {
+                break Some(format!("Client {} disconnected", id));
+            }
+            _ = solve.tick() => {
+                window.target_name = target.name();
+                window.reference_name = reference.name();
+                window.target_clock = target.clock();
+                window.reference_clock = reference.clock();
+                window.pairing = sample_pairing(window.target_clock, window.reference_clock);
+                let step = solve_window(&window, current.as_ref());
+                let mut applied = false;
+                if let Some(next) = step.next {
+                    let pose = server_space_pose(next.translation(), next.rotation());
+                    applied = apply_calibration(&target, pose.clone());
+                    if applied {
+                        current = Some(next);
+                        let result = step
+                            .result
+                            .as_ref()
+                            .map(|estimate| next.result(estimate, &window));
+                        last_applied = Some((pose, result));
+                    }
+                }
+                let _ = tx.send(DashboardMessage::ContinuousCalibrationStep {
+                    target: target.id(),
+                    reference: reference.id(),
+                    result: step.result,
+                    error: step.error,
+                    current_error_mm: step.current_error_mm,
+                    estimate_error_mm: step.estimate_error_mm,
+                    applied,
+                });
+                continue;
+            }
+        };
+        match message {
+            None | Some(FinishCalibration) => break None,
+            Some(Cancel) => break Some("Cancelled".to_owned()),
+            Some(Sample { client, sample }) => {
+                let samples = if client == target.id() {
+                    &mut window.target
+                } else if client == reference.id() {
+                    &mut window.reference
+                } else {
+                    continue;
+                };
+                samples.push(sample);
+                // the same number is dropped from both, so that pairs by
+                // order stay paired
+                let longest = window.target.len().max(window.reference.len());
+                let excess = longest.saturating_sub(conf.sample_count);
+                for samples in [&mut window.target, &mut window.reference] {
+                    samples.drain(..excess.min(samples.len()));
+                }
+            }
+            Some(message) => warn!("Ignoring {:?} during continuous calibration", message),
+        }
+    };
+
+    stop_calibration(&room, &[target.id(), reference.id()]).await;
+    if let Some((pose, result)) = last_applied {
+        room.calibration_store()
+            .add(
+                target.name(),
+                reference.name(),
+                CalibrationSource::Continuous,
+                pose,
+                result,
+                Some(window),
+            )
+            .await;
+    }
+    info!(
+        "Continuous calibration of {} against {} stopped",
+        target.id(),
+        reference.id()
+    );
+    if let Some(reason) = failure {
+        return status.fail(reason);
+    }
+    status.set(CalibrationState::Idle);
+    current.is_some()
+}
+
+/// Outcome of solving the window once
+struct SolveStep {
+    result: Option<CalibrationResult>,
+    error: Option<String>,
+    current_error_mm: Option<f64>,
+    estimate_error_mm: Option<f64>,
+    /// Transform to apply, None keeps the current one
+    next: Option<Transform>,
+}
+
+/// Solves the window and decides whether to move towards the estimate. The
+/// first estimate is applied as a whole if it is good. Later estimates must
+/// fit the window clearly better than the current calibration, both measured
+/// on the same samples.
+fn solve_window(window: &CalibrationInput, current: Option<&Transform>) -> SolveStep {
+    let estimate = match netvr_calibrate::calibrate(window) {
+        Ok(estimate) => estimate,
+        Err(err) => {
+            return SolveStep {
+                result: None,
+                error: Some(err.to_string()),
+                current_error_mm: current.and_then(|current| current.position_error(window)),
+                estimate_error_mm: None,
+                next: None,
+            }
+        }
+    };
+    let transform = Transform::from(&estimate);
+    let current_error_mm = current.and_then(|current| current.position_error(window));
+    let estimate_error_mm = transform.position_error(window);
+    let next = match (current, current_error_mm, estimate_error_mm) {
+        (None, _, _) => (estimate.verdict == CalibrationVerdict::Good).then_some(transform),
+        (Some(current), Some(current_error), Some(estimate_error))
+            if matches!(
+                estimate.verdict,
+                CalibrationVerdict::Good | CalibrationVerdict::Noisy
+            ) && estimate_error < current_error * IMPROVEMENT_RATIO
+                && current_error - estimate_error >= MIN_IMPROVEMENT_MM =>
+        {
+            Some(current.step_towards(&transform))
+        }
+        _ => None,
+    };
+    SolveStep {
+        result: Some(estimate),
+        error: None,
+        current_error_mm,
+        estimate_error_mm,
+        next,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Isometry3, Translation3};
+    use netvr_calibrate::SamplePairing;
+    use netvr_data::net::{CalibrationSample, CalibrationSolver};
+
+    use super::*;
+
+    /// Deterministic random numbers, so that failures can be reproduced
+    struct Random(u64);
+
+    impl Random {
+        /// Uniform in [0, 1), xorshift64*
+        fn next(&mut self) -> f64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+        }
+
+        /// Normally distributed with the given standard deviation
+        fn normal(&mut self, sigma: f64) -> f64 {
+            let (u, v) = (1. - self.next(), self.next());
+            sigma * (-2. * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos()
+        }
+
+        fn vector(&mut self, sigma: f64) -> Vector3<f64> {
+            Vector3::new(self.normal(sigma), self.normal(sigma), self.normal(sigma))
+        }
+
+        /// Translation and rotation vector with the given standard deviations
+        fn isometry(&mut self, (position, rotation): (f64, f64)) -> Isometry3<f64> {
+            Isometry3::new(self.vector(position), self.vector(rotation))
+        }
+    }
+
+    /// Target stage space in reference's server space
+    fn stage() -> Isometry3<f64> {
+        Isometry3::from_parts(
+            Translation3::new(1.5, 0.2, -0.8),
+            UnitQuaternion::from_euler_angles(0., 2.1, 0.),
+        )
+    }
+
+    fn transform(isometry: &Isometry3<f64>) -> Transform {
+        Transform {
+            translation: isometry.translation.vector.cast(),
+            rotation: isometry.rotation.cast(),
+        }
+    }
+
+    fn sample(pose: &Isometry3<f64>) -> CalibrationSample {
+        let (position, rotation) = (pose.translation.vector.cast::<f32>(), pose.rotation);
+        CalibrationSample {
+            flags: 0b11,
+            pose: Pose {
+                position: Vec3 {
+                    x: position.x,
+                    y: position.y,
+                    z: position.z,
+                },
+                orientation: netvr_data::Quaternion {
+                    x: rotation.i as f32,
+                    y: rotation.j as f32,
+                    z: rotation.k as f32,
+                    w: rotation.w as f32,
+                },
+            },
+            prev_flags: None,
+            prev_pose: None,
+            nanos: 0,
+            now_nanos: 0,
+        }
+    }
+
+    /// Window of a target held together with the reference, noise is standard
+    /// deviation of position in meters and of rotation in radians
+    fn window(random: &mut Random, noise: (f64, f64)) -> CalibrationInput {
+        let offset = Isometry3::new(Vector3::new(0.05, -0.02, 0.1), Vector3::new(0.3, 0.1, -0.2));
+        let (mut reference, mut target) = (vec![], vec![]);
+        for _ in 0..100 {
+            let world = Isometry3::new(Vector3::new(0., 1.2, 0.), Vector3::zeros())
+                * random.isometry((0.3, 1.5));
+            let attached = stage().inverse() * world * offset;
+            reference.push(sample(&(world * random.isometry(noise))));
+            target.push(sample(&(attached * random.isometry(noise))));
+        }
+        CalibrationInput {
+            target,
+            target_name: "target".to_owned(),
+            reference,
+            reference_name: "reference".to_owned(),
+            pairing: SamplePairing::Order,
+            target_clock: None,
+            reference_clock: None,
+            solver: CalibrationSolver::Full,
+        }
+    }
+
+    /// Distance in meters and angle in radians between two transforms
+    fn difference(a: &Transform, b: &Transform) -> (f32, f32) {
+        (
+            (a.translation - b.translation).norm(),
+            a.rotation.angle_to(&b.rotation),
+        )
+    }
+
+    const PRECISE: (f64, f64) = (0.0005, 0.001);
+
+    #[test]
+    fn first_estimate_is_applied_whole_only_if_good() {
+        let mut random = Random(0x9E37_79B9_7F4A_7C15);
+        let step = solve_window(&window(&mut random, PRECISE), None);
+        let next = step.next.expect("good estimate should be applied");
+        let (distance, angle) = difference(&next, &transform(&stage()));
+        assert!(
+            distance < 0.002 && angle < 0.002,
+            "off by {} m, {} rad",
+            distance,
+            angle
+        );
+
+        let step = solve_window(&window(&mut random, (0.03, 0.05)), None);
+        let verdict = step.result.as_ref().map(|result| result.verdict);
+        assert_ne!(verdict, Some(CalibrationVerdict::Good));
+        assert!(step.next.is_none(), "{:?} should not be applied", verdict);
+    }
+
+    #[test]
+    fn drift_is_corrected_by_limited_step() {
+        let mut random = Random(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..5 {
+            let drifted = transform(&(stage() * random.isometry((0.05, 0.))));
+            let step = solve_window(&window(&mut random, PRECISE), Some(&drifted));
+            let next = step.next.expect("drift should be corrected");
+            let (moved, _) = difference(&drifted, &next);
+            let (before, _) = difference(&drifted, &transform(&stage()));
+            let (after, _) = difference(&next, &transform(&stage()));
+            assert!(
+                after < before,
+                "moved away, {} m before, {} m after",
+                before,
+                after
+            );
+            assert!(moved <= MAX_STEP_METERS * 1.001, "moved by {} m", moved);
+        }
+    }
+
+    #[test]
+    fn small_improvement_is_not_applied() {
+        let mut random = Random(0x9E37_79B9_7F4A_7C15);
+        let window = window(&mut random, PRECISE);
+        let step = solve_window(&window, None);
+        let applied = step.next.unwrap();
+        // a fraction of a millimeter is below MIN_IMPROVEMENT_MM
+        let close = Transform {
+            translation: applied.translation + Vector3::new(0.0005, 0., 0.),
+            ..applied
+        };
+        let step = solve_window(&window, Some(&close));
+        let (current, estimate) = (
+            step.current_error_mm.unwrap(),
+            step.estimate_error_mm.unwrap(),
+        );
+        assert!(
+            current - estimate < MIN_IMPROVEMENT_MM,
+            "{} vs {} mm",
+            current,
+            estimate
+        );
+        assert!(step.next.is_none());
+    }
+
+    #[test]
+    fn improvement_within_noise_is_not_applied() {
+        let mut random = Random(0x9E37_79B9_7F4A_7C15);
+        let window = window(&mut random, (0.015, 0.01));
+        let step = solve_window(&window, None);
+        let estimate = Transform::from(step.result.as_ref().unwrap());
+        // better by more than MIN_IMPROVEMENT_MM, but not by IMPROVEMENT_RATIO
+        let close = Transform {
+            translation: estimate.translation + Vector3::new(0.015, 0., 0.),
+            ..estimate
+        };
+        let step = solve_window(&window, Some(&close));
+        let (current, estimate) = (
+            step.current_error_mm.unwrap(),
+            step.estimate_error_mm.unwrap(),
+        );
+        assert!(
+            current - estimate >= MIN_IMPROVEMENT_MM,
+            "{} vs {} mm",
+            current,
+            estimate
+        );
+        assert!(
+            estimate >= current * IMPROVEMENT_RATIO,
+            "{} vs {} mm",
+            current,
+            estimate
+        );
+        assert!(step.next.is_none());
+    }
+
+    #[test]
+    fn step_moves_part_of_the_way() {
+        let mut random = Random(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..100 {
+            let from = transform(&random.isometry((1., 1.)));
+            let to = Transform {
+                translation: from.translation + random.vector(0.005).cast(),
+                rotation: UnitQuaternion::new(random.vector(0.01).cast()) * from.rotation,
+            };
+            let step = from.step_towards(&to);
+            let (distance, angle) = difference(&from, &to);
+            let (moved, turned) = difference(&from, &step);
+            assert!(
+                (moved - distance * SMOOTHING).abs() < 1e-5,
+                "{} of {}",
+                moved,
+                distance
+            );
+            assert!(
+                (turned - angle * SMOOTHING).abs() < 1e-4,
+                "{} of {}",
+                turned,
+                angle
+            );
+        }
+    }
+
+    #[test]
+    fn step_is_clamped() {
+        let mut random = Random(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..100 {
+            let from = transform(&random.isometry((1., 1.)));
+            let to = transform(&random.isometry((1., 1.)));
+            let step = from.step_towards(&to);
+            let (distance, angle) = difference(&from, &to);
+            let (moved, turned) = difference(&from, &step);
+            // both are scaled by the same fraction, so one limit is reached
+            assert!(moved <= MAX_STEP_METERS * 1.001, "moved by {} m", moved);
+            assert!(
+                turned <= MAX_STEP_RADIANS * 1.001,
+                "turned by {} rad",
+                turned
+            );
+            let fraction = (moved / distance).max(turned / angle);
+            assert!(fraction < SMOOTHING, "moved {} of the way", fraction);
+            let limited =
+                (moved - MAX_STEP_METERS).abs() < 1e-4 || (turned - MAX_STEP_RADIANS).abs() < 1e-4;
+            assert!(limited, "moved by {} m, turned by {} rad", moved, turned);
+        }
+    }
+}
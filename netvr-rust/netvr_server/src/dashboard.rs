@@ -1,4 +1,4 @@
-use std::{env, net::SocketAddr};
+use std::{env, future::Future, net::SocketAddr, sync::atomic::Ordering};
 
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
@@ -6,15 +6,16 @@ use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use netvr_calibrate::CalibrationInput;
+use netvr_calibrate::{CalibrationCoverage, CalibrationInput, CalibrationResult};
 use netvr_data::{
     net::{
-        CalibrationConfiguration, ClientId, ConfigurationDown, ConfigurationSnapshotSet,
+        CalibrationConfiguration, ClientId, CloseCode, ConfigurationDown, ConfigurationSnapshotSet,
         StateSnapshot,
     },
     serde::{Deserialize, Serialize},
 };
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{debug, info, info_span, warn, Instrument};
 use warp::{
     http::{self, Response},
     ws::{Message, WebSocket},
@@ -22,11 +23,23 @@ use warp::{
 };
 
 use crate::{
+    auth::Auth,
+    batch_calibration::BatchTarget,
     calibration_protocol::{
-        CalibrationProtocolMessage::{Begin, ByHeadset, FinishCalibration, Hijack, Reapply},
-        CalibrationSender,
+        apply_calibration,
+        CalibrationProtocolMessage::{
+            Batch, Begin, ByHeadset, Cancel, CaptureMarker, Continuous, FinishCalibration, Hijack,
+            Markers, Reapply,
+        },
     },
-    server::Server,
+    calibration_state::CalibrationState,
+    calibration_store::{CalibrationRecord, CalibrationSource},
+    client::Client,
+    marker_calibration::MarkerTarget,
+    replay::{ReplayCommand, ReplaySender},
+    room::Room,
+    server::{RoomFilter, Server},
+    ServerOptions,
 };
 
 /// All the messages that could be sent to the dashboard
@@ -59,6 +72,147 @@ pub(crate) enum DashboardMessage {
     Info {
         message: String,
     },
+    /// Samples collected so far by a running calibration
+    #[serde(rename_all = "camelCase")]
+    CalibrationProgress {
+        target: ClientId,
+        reference: ClientId,
+        target_samples: usize,
+        reference_samples: usize,
+        sample_count: usize,
+        coverage: CalibrationCoverage,
+    },
+    /// Outcome of a calibration, result is None if it could not be computed
+    #[serde(rename_all = "camelCase")]
+    CalibrationFinished {
+        target: ClientId,
+        reference: ClientId,
+        result: Option<CalibrationResult>,
+        error: Option<String>,
+    },
+    /// Continuous calibration solved its window. Errors of the current
+    /// calibration and of the new estimate are measured on the same samples.
+    #[serde(rename_all = "camelCase")]
+    ContinuousCalibrationStep {
+        target: ClientId,
+        reference: ClientId,
+        result: Option<CalibrationResult>,
+        error: Option<String>,
+        current_error_mm: Option<f64>,
+        estimate_error_mm: Option<f64>,
+        /// Whether a correction was sent to the target
+        applied: bool,
+    },
+    /// Marker calibration captured positions of all devices on a marker
+    #[serde(rename_all = "camelCase")]
+    MarkerCaptured {
+        captured: usize,
+        point_count: usize,
+    },
+    /// Calibration in a room moved to another phase. Clients are all which
+    /// take part, the reference first.
+    #[serde(rename_all = "camelCase")]
+    CalibrationState {
+        room: String,
+        kind: String,
+        clients: Vec<ClientId>,
+        state: CalibrationState,
+    },
+    /// All calibrations in the store, sent on Init
+    #[serde(rename_all = "camelCase")]
+    CalibrationHistory {
+        records: Vec<CalibrationRecord>,
+    },
+    /// Calibration was applied to a client and recorded in the store
+    #[serde(rename_all = "camelCase")]
+    CalibrationStored {
+        record: CalibrationRecord,
+    },
+    /// Client connected which has a good calibration in the store. Sent
+    /// instead of applying it unless auto_apply_calibration is set.
+    #[serde(rename_all = "camelCase")]
+    CalibrationOffer {
+        id: ClientId,
+        record: CalibrationRecord,
+    },
+    #[serde(rename_all = "camelCase")]
+    AdmittedClients {
+        clients: Vec<AdmittedClient>,
+    },
+    /// Names of clients which may not join. Sent on Init and on change.
+    RevokedClients {
+        names: Vec<String>,
+    },
+    /// Rooms matching the watched filter and their members
+    #[serde(rename_all = "camelCase")]
+    Rooms {
+        rooms: Vec<RoomInfo>,
+        watching: Option<String>,
+    },
+    /// Link quality of a client, sent every second
+    #[serde(rename_all = "camelCase")]
+    ConnectionQuality {
+        id: ClientId,
+        rtt_ms: f64,
+        /// Congestion window in bytes
+        cwnd: u64,
+        sent_packets: u64,
+        lost_packets: u64,
+        /// Outgoing datagrams dropped because the send buffer was full
+        datagram_drops: u64,
+        /// Smoothed time between state snapshots from the client
+        snapshot_interval_ms: f64,
+        /// Variation of the time between state snapshots
+        snapshot_jitter_ms: f64,
+        /// Client clock minus server clock, None until the client answers a
+        /// heartbeat
+        clock_offset_ms: Option<f64>,
+        /// How far the true offset can be from clock_offset_ms
+        clock_uncertainty_ms: Option<f64>,
+    },
+    /// Progress of session replay, only sent in replay mode
+    #[serde(rename_all = "camelCase")]
+    ReplayStatus {
+        playing: bool,
+        position_nanos: u64,
+        duration_nanos: u64,
+        speed: f64,
+    },
+}
+
+impl DashboardMessage {
+    /// Client the message is about, if any. Used for filtering by room.
+    fn client_id(&self) -> Option<ClientId> {
+        match self {
+            DashboardMessage::ConnectionEstablished { id, .. }
+            | DashboardMessage::FullyConnected { id }
+            | DashboardMessage::ConnectionClosed { id }
+            | DashboardMessage::DatagramUp { id, .. }
+            | DashboardMessage::ConnectionQuality { id, .. }
+            | DashboardMessage::CalibrationOffer { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+/// Client which passed the handshake and is part of the session
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AdmittedClient {
+    id: ClientId,
+    /// None for virtual clients created by replay
+    addr: Option<SocketAddr>,
+    room: String,
+}
+
+/// Summary of one room
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RoomInfo {
+    name: String,
+    clients: Vec<ClientId>,
+    /// How many times per second clients receive state snapshots
+    state_rate: f64,
 }
 
 /// All the messages that could be received from the dashboard
@@ -95,8 +249,41 @@ pub(crate) enum DashboardMessageRecv {
         reference_id: ClientId,
         reference_subaction_path: String,
     },
+    /// Keeps correcting drift between co-located devices until
+    /// FinishCalibration, conf.sample_count is the size of the window
+    #[serde(rename_all = "camelCase")]
+    StartContinuousCalibration {
+        target_id: ClientId,
+        target_subaction_path: String,
+        reference_id: ClientId,
+        reference_subaction_path: String,
+
+        conf: CalibrationConfiguration,
+    },
+    /// Calibrates all targets at once, each against the reference or the
+    /// target given as its parent
+    #[serde(rename_all = "camelCase")]
+    StartBatchCalibration {
+        reference_id: ClientId,
+        reference_subaction_path: String,
+        targets: Vec<BatchTarget>,
+
+        conf: CalibrationConfiguration,
+    },
+    /// Calibrates targets by devices placed on point_count shared markers.
+    /// CaptureMarker is sent when the devices are on the next marker.
+    #[serde(rename_all = "camelCase")]
+    StartMarkerCalibration {
+        reference_id: ClientId,
+        reference_subaction_path: String,
+        targets: Vec<MarkerTarget>,
+        point_count: usize,
+    },
+    CaptureMarker,
     #[serde(rename_all = "camelCase")]
     FinishCalibration,
+    /// Stops running calibrations without applying anything
+    CancelCalibration,
     #[serde(rename_all = "camelCase")]
     ReapplyCalibration {
         target_id: ClientId,
@@ -105,6 +292,13 @@ pub(crate) enum DashboardMessageRecv {
         reference_subaction_path: String,
         data: CalibrationInput,
     },
+    /// Sends the pose of a calibration from the store to the client, used
+    /// for rolling back and for accepting offers
+    #[serde(rename_all = "camelCase")]
+    ApplyStoredCalibration {
+        client_id: ClientId,
+        record_id: u64,
+    },
     #[serde(rename_all = "camelCase")]
     SetName {
         name: String,
@@ -112,6 +306,44 @@ pub(crate) enum DashboardMessageRecv {
     },
     ForceDisconnectAll,
     ResetObjects,
+    ListClients,
+    /// Disconnects the client and refuses clients with its name until
+    /// UnrevokeClient or server restart. Names are chosen by the clients, so
+    /// this is not a security boundary: a client can come back under another
+    /// name. Change the join token to keep a device out for good.
+    #[serde(rename_all = "camelCase")]
+    RevokeClient {
+        client_id: ClientId,
+    },
+    /// Lets clients with the name join again
+    UnrevokeClient {
+        name: String,
+    },
+    /// Only show and control clients in given room. None means all rooms.
+    #[serde(rename_all = "camelCase")]
+    WatchRoom {
+        room: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    MoveClient {
+        client_id: ClientId,
+        room: String,
+    },
+    /// Changes how many times per second clients in the room receive state
+    SetStateRate {
+        room: String,
+        rate: f64,
+    },
+    ReplayPlay,
+    ReplayPause,
+    #[serde(rename_all = "camelCase")]
+    ReplaySeek {
+        position_nanos: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    ReplaySetSpeed {
+        speed: f64,
+    },
 }
 
 /// Forward messages to the dashboard
@@ -119,6 +351,8 @@ async fn dashboard_send(
     mut ws: SplitSink<WebSocket, warp::ws::Message>,
     mut receiver: broadcast::Receiver<DashboardMessage>,
     mut reply: mpsc::UnboundedReceiver<DashboardMessage>,
+    server: Server,
+    filter: watch::Receiver<RoomFilter>,
 ) {
     loop {
         let msg = tokio::select! {
@@ -126,10 +360,11 @@ async fn dashboard_send(
                 Ok(msg) => msg,
                 Err(err) => match err {
                     broadcast::error::RecvError::Closed => {
-                        println!("dashboard connection closed");
+                        debug!("dashboard connection closed");
                         break;
                     }
                     broadcast::error::RecvError::Lagged(n) => {
+                        server.metrics().dashboard_lagged.fetch_add(n, Ordering::Relaxed);
                         let _ = ws
                             .send(Message::text(format!("failed to forward {} messages", n)))
                             .await;
@@ -142,6 +377,9 @@ async fn dashboard_send(
                 None => { continue }
             },
         };
+        if !is_watched(&msg, &server, &filter).await {
+            continue;
+        }
 
         match ws
             .send(if let DashboardMessage::Binary(b) = msg {
@@ -153,31 +391,156 @@ async fn dashboard_send(
         {
             Ok(_) => {}
             Err(err) => {
-                println!("failed to send message: {}", err);
+                debug!("failed to send message: {}", err);
                 break;
             }
         }
     }
 }
 
+/// Checks that the message is about a client in a watched room. Messages not
+/// related to a specific client are always forwarded.
+async fn is_watched(
+    msg: &DashboardMessage,
+    server: &Server,
+    filter: &watch::Receiver<RoomFilter>,
+) -> bool {
+    let filter = filter.borrow().clone();
+    if let RoomFilter::All = filter {
+        return true;
+    }
+    let Some(id) = msg.client_id() else { return true; };
+    match server.get_client(id).await {
+        Some(client) => filter.matches(client.room().name()),
+        None => true,
+    }
+}
+
+/// Gets all clients in watched rooms
+async fn watched_clients(server: &Server, filter: &RoomFilter) -> Vec<(ClientId, Client)> {
+    let mut clients = vec![];
+    for room in server.rooms(filter).await {
+        clients.extend(room.get_clients().await);
+    }
+    clients
+}
+
+/// Finds room in which calibration of given pair of clients should run
+async fn calibration_room(
+    server: &Server,
+    target_id: ClientId,
+    reference_id: ClientId,
+) -> std::result::Result<Room, String> {
+    let Some(target) = server.get_client(target_id).await else {
+        return Err(format!("Target client {} not found", target_id));
+    };
+    let Some(reference) = server.get_client(reference_id).await else {
+        return Err(format!("Reference client {} not found", reference_id));
+    };
+    let room = target.room();
+    if room.name() != reference.room().name() {
+        return Err("Calibrated clients must be in the same room".to_owned());
+    }
+    Ok(room)
+}
+
+/// Finds room of the reference, in which all targets calibrated against it at
+/// once must be
+async fn shared_calibration_room(
+    server: &Server,
+    reference_id: ClientId,
+    target_ids: &[ClientId],
+) -> std::result::Result<Room, String> {
+    let Some(reference) = server.get_client(reference_id).await else {
+        return Err(format!("Reference client {} not found", reference_id));
+    };
+    for &target_id in target_ids {
+        calibration_room(server, target_id, reference_id).await?;
+    }
+    Ok(reference.room())
+}
+
+/// Sends a stored calibration to the client and records that it was rolled
+/// back to it
+async fn apply_stored_calibration(
+    server: &Server,
+    client_id: ClientId,
+    record_id: u64,
+) -> Result<()> {
+    let client = server
+        .get_client(client_id)
+        .await
+        .ok_or_else(|| anyhow!("Client {} not found", client_id))?;
+    let store = server.calibration_store();
+    let record = store
+        .get(record_id)
+        .ok_or_else(|| anyhow!("Calibration {} not found", record_id))?;
+    if !apply_calibration(&client, record.pose.clone()) {
+        return Err(anyhow!(
+            "Failed to send calibration to client {}",
+            client_id
+        ));
+    }
+    store
+        .add(
+            client.name(),
+            record.reference_name,
+            CalibrationSource::Rollback,
+            record.pose,
+            None,
+            None,
+        )
+        .await;
+    Ok(())
+}
+
+/// Describes rooms matching the filter
+async fn rooms_message(server: &Server, filter: &RoomFilter) -> DashboardMessage {
+    let mut rooms = vec![];
+    for room in server.rooms(filter).await {
+        let mut clients: Vec<ClientId> = room
+            .get_clients()
+            .await
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        clients.sort();
+        rooms.push(RoomInfo {
+            name: room.name().to_owned(),
+            clients,
+            state_rate: room.state_rate(),
+        });
+    }
+    rooms.sort_by(|a, b| a.name.cmp(&b.name));
+    DashboardMessage::Rooms {
+        rooms,
+        watching: match filter {
+            RoomFilter::All => None,
+            RoomFilter::Only(name) => Some(name.clone()),
+        },
+    }
+}
+
 /// Handle messages from dashboard
 async fn dashboard_receive(
     mut ws: SplitStream<WebSocket>,
     server: Server,
     reply: mpsc::UnboundedSender<DashboardMessage>,
-    calibration_sender: CalibrationSender,
+    auth: Auth,
+    filter: watch::Sender<RoomFilter>,
+    replay: Option<ReplaySender>,
 ) {
     loop {
         let Some(val) = ws.next().await else { break; };
         let val = match val {
             Ok(val) => val,
             Err(error) => {
-                println!("Error receiving message from websocket: {}", error);
+                warn!("Error receiving message from websocket: {}", error);
                 break;
             }
         };
         let Ok(val_str) = val.to_str() else {
-            println!("TODO: binary messages");
+            warn!("TODO: binary messages");
             continue;
         };
 
@@ -185,14 +548,15 @@ async fn dashboard_receive(
         let val = match val {
             Ok(val) => val,
             Err(error) => {
-                println!("Failed to parse message \"{}\": {:?}", val_str, error);
+                warn!("Failed to parse message \"{}\": {:?}", val_str, error);
                 continue;
             }
         };
-        println!("Received message: {:?}", val);
+        debug!("Received message: {:?}", val);
+        let watched = filter.borrow().clone();
         match val {
             DashboardMessageRecv::MoveSomeClients => {
-                if let Some(client) = server.get_first_client().await {
+                if let Some((_, client)) = watched_clients(&server, &watched).await.first() {
                     if let Err(err) = client.send_configuration_down(
                         ConfigurationDown::SetServerSpacePose(netvr_data::Pose {
                             position: netvr_data::Vec3 {
@@ -203,31 +567,35 @@ async fn dashboard_receive(
                             orientation: netvr_data::Quaternion::default(),
                         }),
                     ) {
-                        println!("Failed to send configuration down: {}", err);
+                        warn!("Failed to send configuration down: {}", err);
                     }
                 } else {
-                    println!("... no clients");
+                    warn!("... no clients");
                 }
             }
             DashboardMessageRecv::ResetAllCalibrations => {
-                let clients = server.get_clients().await;
+                let clients = watched_clients(&server, &watched).await;
                 for (_client_id, client) in clients {
                     if let Err(err) = client.send_configuration_down(
                         ConfigurationDown::SetServerSpacePose(Default::default()),
                     ) {
-                        println!("Failed to reset calibration: {}", err);
+                        warn!("Failed to reset calibration: {}", err);
                     }
                 }
             }
             DashboardMessageRecv::KeepAlive => {}
             DashboardMessageRecv::Init => {
                 let Ok(_) = reply.send(DashboardMessage::ConfigurationSnapshotChanged {
-                    value: {
-                        let watch = server.latest_configuration().await;
-                        let value = watch.borrow().to_owned();
-                        value
-                    },
+                    value: server.latest_configuration(&watched).await,
                 }) else { return; };
+                let Ok(_) = reply.send(rooms_message(&server, &watched).await) else { return; };
+                let Ok(_) = reply.send(DashboardMessage::CalibrationHistory {
+                    records: server.calibration_store().history(),
+                }) else { return; };
+                let names = DashboardMessage::RevokedClients {
+                    names: auth.revoked(),
+                };
+                let Ok(_) = reply.send(names) else { return; };
             }
             DashboardMessageRecv::ReapplyCalibration {
                 target_id,
@@ -236,12 +604,20 @@ async fn dashboard_receive(
                 reference_subaction_path,
                 data,
             } => {
-                if let Err(err) = calibration_sender.send(Reapply {
+                let room = match calibration_room(&server, target_id, reference_id).await {
+                    Ok(room) => room,
+                    Err(message) => {
+                        warn!("{}", message);
+                        let _ = reply.send(DashboardMessage::Info { message });
+                        continue;
+                    }
+                };
+                if let Err(err) = room.calibration_sender().send(Reapply {
                     client_target: (target_id, target_subaction_path),
                     client_reference: (reference_id, reference_subaction_path),
                     data,
                 }) {
-                    println!("Failed to send reapply calibration request: {}", err);
+                    warn!("Failed to send reapply calibration request: {}", err);
                 }
             }
             DashboardMessageRecv::StartCalibration {
@@ -251,12 +627,20 @@ async fn dashboard_receive(
                 reference_subaction_path,
                 conf,
             } => {
-                if let Err(err) = calibration_sender.send(Begin {
+                let room = match calibration_room(&server, target_id, reference_id).await {
+                    Ok(room) => room,
+                    Err(message) => {
+                        warn!("{}", message);
+                        let _ = reply.send(DashboardMessage::Info { message });
+                        continue;
+                    }
+                };
+                if let Err(err) = room.calibration_sender().send(Begin {
                     client_target: (target_id, target_subaction_path),
                     client_reference: (reference_id, reference_subaction_path),
                     conf,
                 }) {
-                    println!("Failed to send calibration request: {}", err);
+                    warn!("Failed to send calibration request: {}", err);
                 }
             }
             DashboardMessageRecv::StartHijack {
@@ -265,21 +649,127 @@ async fn dashboard_receive(
                 reference_id,
                 reference_subaction_path,
             } => {
-                if let Err(err) = calibration_sender.send(Hijack {
+                let room = match calibration_room(&server, target_id, reference_id).await {
+                    Ok(room) => room,
+                    Err(message) => {
+                        warn!("{}", message);
+                        let _ = reply.send(DashboardMessage::Info { message });
+                        continue;
+                    }
+                };
+                if let Err(err) = room.calibration_sender().send(Hijack {
+                    client_target: (target_id, target_subaction_path),
+                    client_reference: (reference_id, reference_subaction_path),
+                }) {
+                    warn!("Failed to send calibration request: {}", err);
+                }
+            }
+            DashboardMessageRecv::StartContinuousCalibration {
+                target_id,
+                target_subaction_path,
+                reference_id,
+                reference_subaction_path,
+                conf,
+            } => {
+                let room = match calibration_room(&server, target_id, reference_id).await {
+                    Ok(room) => room,
+                    Err(message) => {
+                        warn!("{}", message);
+                        let _ = reply.send(DashboardMessage::Info { message });
+                        continue;
+                    }
+                };
+                if let Err(err) = room.calibration_sender().send(Continuous {
                     client_target: (target_id, target_subaction_path),
                     client_reference: (reference_id, reference_subaction_path),
+                    conf,
                 }) {
-                    println!("Failed to send calibration request: {}", err);
+                    warn!("Failed to send calibration request: {}", err);
+                }
+            }
+            DashboardMessageRecv::StartBatchCalibration {
+                reference_id,
+                reference_subaction_path,
+                targets,
+                conf,
+            } => {
+                let target_ids: Vec<_> = targets.iter().map(|target| target.client_id).collect();
+                let room = match shared_calibration_room(&server, reference_id, &target_ids).await {
+                    Ok(room) => room,
+                    Err(message) => {
+                        warn!("{}", message);
+                        let _ = reply.send(DashboardMessage::Info { message });
+                        continue;
+                    }
+                };
+                if let Err(err) = room.calibration_sender().send(Batch {
+                    client_reference: (reference_id, reference_subaction_path),
+                    targets,
+                    conf,
+                }) {
+                    warn!("Failed to send calibration request: {}", err);
+                }
+            }
+            DashboardMessageRecv::ApplyStoredCalibration {
+                client_id,
+                record_id,
+            } => {
+                if let Err(err) = apply_stored_calibration(&server, client_id, record_id).await {
+                    warn!("Failed to apply stored calibration: {:?}", err);
+                    let _ = reply.send(DashboardMessage::Info {
+                        message: err.to_string(),
+                    });
                 }
             }
             DashboardMessageRecv::FinishCalibration => {
-                if let Err(err) = calibration_sender.send(FinishCalibration) {
-                    println!("Failed to send calibration request: {}", err);
+                for room in server.rooms(&watched).await {
+                    if let Err(err) = room.calibration_sender().send(FinishCalibration) {
+                        warn!("Failed to send calibration request: {}", err);
+                    }
+                }
+            }
+            DashboardMessageRecv::StartMarkerCalibration {
+                reference_id,
+                reference_subaction_path,
+                targets,
+                point_count,
+            } => {
+                let target_ids: Vec<_> = targets.iter().map(|target| target.client_id).collect();
+                let room = match shared_calibration_room(&server, reference_id, &target_ids).await {
+                    Ok(room) => room,
+                    Err(message) => {
+                        warn!("{}", message);
+                        let _ = reply.send(DashboardMessage::Info { message });
+                        continue;
+                    }
+                };
+                if let Err(err) = room.calibration_sender().send(Markers {
+                    client_reference: (reference_id, reference_subaction_path),
+                    targets,
+                    point_count,
+                }) {
+                    warn!("Failed to send calibration request: {}", err);
+                }
+            }
+            DashboardMessageRecv::CaptureMarker => {
+                for room in server.rooms(&watched).await {
+                    if let Err(err) = room.calibration_sender().send(CaptureMarker) {
+                        warn!("Failed to send calibration request: {}", err);
+                    }
+                }
+            }
+            DashboardMessageRecv::CancelCalibration => {
+                for room in server.rooms(&watched).await {
+                    if let Err(err) = room.calibration_sender().send(Cancel) {
+                        warn!("Failed to send calibration request: {}", err);
+                    }
                 }
             }
             DashboardMessageRecv::CalibrateByHeadsetPosition => {
-                if let Err(err) = calibration_sender.send(ByHeadset) {
-                    println!("Failed to send by headset calibration request: {}", err);
+                for room in server.rooms(&watched).await {
+                    if let Err(err) = room.calibration_sender().send(ByHeadset) {
+                        warn!("Failed to send by headset calibration request: {}", err);
+                    }
                 }
             }
             DashboardMessageRecv::ResetCalibration { client_id } => {
@@ -290,7 +780,7 @@ async fn dashboard_receive(
                             orientation: netvr_data::Quaternion::default(),
                         }),
                     ) {
-                        println!("Failed to send configuration down: {}", err);
+                        warn!("Failed to send configuration down: {}", err);
                     }
                 } else {
                     let Ok(_) = reply.send(DashboardMessage::Info {
@@ -302,7 +792,7 @@ async fn dashboard_receive(
                 client_id,
                 subaction_path,
             } => {
-                println!(
+                warn!(
                     "TODO: trigger haptic impulse for {} {}",
                     client_id, subaction_path
                 )
@@ -312,7 +802,7 @@ async fn dashboard_receive(
                     if let Err(err) =
                         client.send_configuration_down(ConfigurationDown::ChangeName(name.clone()))
                     {
-                        println!("Failed to send configuration down: {}", err);
+                        warn!("Failed to send configuration down: {}", err);
                     }
                 } else {
                     let Ok(_) = reply.send(DashboardMessage::Info {
@@ -321,7 +811,7 @@ async fn dashboard_receive(
                 }
             }
             DashboardMessageRecv::ForceDisconnectAll => {
-                let clients = server.get_clients().await;
+                let clients = watched_clients(&server, &watched).await;
                 for (client_id, client) in clients {
                     client.cancel()
                 }
@@ -329,26 +819,129 @@ async fn dashboard_receive(
             DashboardMessageRecv::ResetObjects => {
                 // TODO: forward to app_channel server.
             }
+            DashboardMessageRecv::ListClients => {
+                let clients = watched_clients(&server, &watched)
+                    .await
+                    .into_iter()
+                    .map(|(id, client)| AdmittedClient {
+                        id,
+                        addr: client.remote_address(),
+                        room: client.room().name().to_owned(),
+                    })
+                    .collect();
+                let message = DashboardMessage::AdmittedClients { clients };
+                let Ok(_) = reply.send(message) else { return; };
+            }
+            DashboardMessageRecv::RevokeClient { client_id } => {
+                let message = if let Some(client) = server.get_client(client_id).await {
+                    let name = client.name();
+                    client.close(CloseCode::Revoked, "removed by operator");
+                    if name.is_empty() {
+                        format!(
+                            "Disconnected client {}, it has no name so it can rejoin",
+                            client_id
+                        )
+                    } else {
+                        auth.revoke(name.clone());
+                        format!("Revoked client {} named {}", client_id, name)
+                    }
+                } else {
+                    "Revoke client: Client not found".to_owned()
+                };
+                info!("{}", message);
+                let Ok(_) = reply.send(DashboardMessage::Info { message }) else { return; };
+                let names = DashboardMessage::RevokedClients {
+                    names: auth.revoked(),
+                };
+                let Ok(_) = reply.send(names) else { return; };
+            }
+            DashboardMessageRecv::UnrevokeClient { name } => {
+                let message = if auth.unrevoke(&name) {
+                    format!("Clients named {} can join again", name)
+                } else {
+                    format!("Unrevoke client: {} was not revoked", name)
+                };
+                info!("{}", message);
+                let Ok(_) = reply.send(DashboardMessage::Info { message }) else { return; };
+                let names = DashboardMessage::RevokedClients {
+                    names: auth.revoked(),
+                };
+                let Ok(_) = reply.send(names) else { return; };
+            }
+            DashboardMessageRecv::WatchRoom { room } => {
+                filter.send_replace(match room {
+                    Some(name) => RoomFilter::Only(name),
+                    None => RoomFilter::All,
+                });
+            }
+            DashboardMessageRecv::MoveClient { client_id, room } => {
+                if let Err(err) = server.move_client(client_id, &room).await {
+                    let Ok(_) = reply.send(DashboardMessage::Info {
+                        message: format!("Move client: {}", err),
+                    }) else { return; };
+                }
+            }
+            DashboardMessageRecv::SetStateRate { room, rate } => {
+                if let Err(err) = server.set_state_rate(&room, rate).await {
+                    let Ok(_) = reply.send(DashboardMessage::Info {
+                        message: format!("Set state rate: {}", err),
+                    }) else { return; };
+                }
+            }
+            DashboardMessageRecv::ReplayPlay
+            | DashboardMessageRecv::ReplayPause
+            | DashboardMessageRecv::ReplaySeek { .. }
+            | DashboardMessageRecv::ReplaySetSpeed { .. } => {
+                let command = match val {
+                    DashboardMessageRecv::ReplayPause => ReplayCommand::Pause,
+                    DashboardMessageRecv::ReplaySeek { position_nanos } => {
+                        ReplayCommand::Seek(position_nanos)
+                    }
+                    DashboardMessageRecv::ReplaySetSpeed { speed } => {
+                        ReplayCommand::SetSpeed(speed)
+                    }
+                    _ => ReplayCommand::Play,
+                };
+                let sent = match &replay {
+                    Some(replay) => replay.send(command).is_ok(),
+                    None => false,
+                };
+                if !sent {
+                    let Ok(_) = reply.send(DashboardMessage::Info {
+                        message: "Replay: server is not replaying a session".to_owned(),
+                    }) else { return; };
+                }
+            }
         }
     }
 }
 
-/// Forward configuration changes to the dashboard.
+/// Forward configuration and room changes to the dashboard.
 async fn dashboard_send_configuration(
     server: Server,
     reply: mpsc::UnboundedSender<DashboardMessage>,
+    mut filter: watch::Receiver<RoomFilter>,
 ) {
-    let mut conf = server.latest_configuration().await;
+    let mut changes = server.subscribe_changes();
     loop {
-        if let Err(error) = conf.changed().await {
-            println!("Failed to wait for configuration change: {}", error);
+        let result = tokio::select! {
+            res = changes.changed() => res,
+            res = filter.changed() => res,
+        };
+        if let Err(error) = result {
+            debug!("Failed to wait for configuration change: {}", error);
             return;
         }
 
+        let watched = filter.borrow().clone();
         if let Err(error) = reply.send(DashboardMessage::ConfigurationSnapshotChanged {
-            value: conf.borrow().clone(),
+            value: server.latest_configuration(&watched).await,
         }) {
-            println!("Failed to send configuration snapshot: {}", error);
+            debug!("Failed to send configuration snapshot: {}", error);
+            return;
+        }
+        if let Err(error) = reply.send(rooms_message(&server, &watched).await) {
+            debug!("Failed to send rooms: {}", error);
             return;
         }
     }
@@ -359,39 +952,28 @@ async fn dashboard_connected(
     ws: WebSocket,
     broadcast_receiver: broadcast::Receiver<DashboardMessage>,
     server: Server,
-    calibration_sender: CalibrationSender,
+    auth: Auth,
+    replay: Option<ReplaySender>,
 ) {
     let (sender, receiver) = mpsc::unbounded_channel();
+    let (filter, filter_receiver) = watch::channel(RoomFilter::All);
     let split = ws.split();
     tokio::select! {
-        _ = dashboard_send(split.0, broadcast_receiver, receiver) => {},
-        _ = dashboard_send_configuration(server.clone(), sender.clone()) => {},
-        _ = dashboard_receive(split.1, server, sender, calibration_sender) => {},
+        _ = dashboard_send(
+            split.0,
+            broadcast_receiver,
+            receiver,
+            server.clone(),
+            filter_receiver.clone(),
+        ) => {},
+        _ = dashboard_send_configuration(server.clone(), sender.clone(), filter_receiver) => {},
+        _ = dashboard_receive(split.1, server, sender, auth, filter, replay) => {},
     }
 }
 
-fn get_upload_dir() -> std::path::PathBuf {
-    let mut upload_dir = env::current_dir().unwrap();
-    upload_dir.push("upload");
-    upload_dir
-}
-
-/// Main entry point for the dashboard subsystem. Serves dashboard on TCP port 13161
-pub(crate) async fn serve_dashboard(
-    tx: broadcast::Sender<DashboardMessage>,
-    server: Server,
-    calibration_sender: CalibrationSender,
-) -> anyhow::Result<()> {
-    match tokio::fs::create_dir(&get_upload_dir()).await {
-        Ok(_) => {}
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::AlreadyExists => {}
-            _ => {
-                return Err(anyhow!("Failed to create upload dir: {e}"));
-            }
-        },
-    };
-
+/// Finds the built dashboard next to the executable, or in the source tree
+/// when running from cargo
+fn find_dashboard_assets() -> std::path::PathBuf {
     let exe_parent: Result<_> = match std::env::current_exe() {
         Ok(p) => match p.parent() {
             Some(p) => anyhow::Result::Ok(p.to_owned()),
@@ -402,7 +984,7 @@ pub(crate) async fn serve_dashboard(
     let path = match exe_parent {
         Ok(p) => p,
         Err(e) => {
-            println!("failed to get current exe path, falling back to cwd: {}", e);
+            warn!("failed to get current exe path, falling back to cwd: {}", e);
             match env::current_dir() {
                 Ok(p) => p.as_path().to_owned(),
                 Err(e) => {
@@ -420,17 +1002,52 @@ pub(crate) async fn serve_dashboard(
         dashboard.pop();
         dashboard.push("netvr-dashboard");
         dashboard.push("dist");
-        if !dashboard.exists() {
-            panic!("failed to find dashboard");
-        }
     }
+    dashboard
+}
+
+/// Main entry point for the dashboard subsystem. Binds the dashboard to the
+/// configured TCP address and returns the actual address and future serving it.
+pub(crate) async fn serve_dashboard(
+    tx: broadcast::Sender<DashboardMessage>,
+    server: Server,
+    auth: Auth,
+    replay: Option<ReplaySender>,
+    options: &ServerOptions,
+) -> anyhow::Result<(SocketAddr, impl Future<Output = ()>)> {
+    let upload_dir = if options.enable_uploads {
+        let upload_dir = options.upload_directory.clone();
+        match tokio::fs::create_dir_all(&upload_dir).await {
+            Ok(_) => {}
+            Err(e) => {
+                return Err(anyhow!("Failed to create upload dir {:?}: {e}", upload_dir));
+            }
+        };
+        Some(upload_dir)
+    } else {
+        None
+    };
+
+    let dashboard = match &options.dashboard_assets {
+        Some(path) => path.clone(),
+        None => find_dashboard_assets(),
+    };
+    if !dashboard.exists() {
+        warn!("failed to find dashboard, serving only the websocket");
+    }
+    let metrics_server = server.clone();
     let ws = warp::path("ws")
         .and(warp::ws())
-        .map(move |ws: warp::ws::Ws| {
+        .and(warp::addr::remote())
+        .map(move |ws: warp::ws::Ws, addr: Option<SocketAddr>| {
             let rx = tx.subscribe();
             let server = server.clone();
-            let calibration_sender = calibration_sender.clone();
-            ws.on_upgrade(move |socket| dashboard_connected(socket, rx, server, calibration_sender))
+            let auth = auth.clone();
+            let replay = replay.clone();
+            let span = info_span!("dashboard", addr = ?addr);
+            ws.on_upgrade(move |socket| {
+                dashboard_connected(socket, rx, server, auth, replay).instrument(span)
+            })
         });
     let files = warp::filters::fs::dir(dashboard.clone());
     let mut index = dashboard.clone();
@@ -439,21 +1056,38 @@ pub(crate) async fn serve_dashboard(
         .and(warp::path("upload"))
         .and(warp::path::param())
         .and(warp::body::bytes())
+        .and(warp::any().map(move || upload_dir.clone()))
         .and_then(handle_upload);
 
-    let routes = ws.or(files).or(upload_route).or(warp::fs::file(index));
-    warp::serve(routes).run(([0, 0, 0, 0], 13161)).await;
-    println!("serving dashboard from {:?}", dashboard);
-    Ok(())
+    let metrics = warp::path("metrics")
+        .and(warp::get())
+        .and(warp::any().map(move || metrics_server.clone()))
+        .then(|server: Server| async move { server.render_metrics().await });
+
+    let routes = ws
+        .or(metrics)
+        .or(files)
+        .or(upload_route)
+        .or(warp::fs::file(index));
+    let (addr, serve) = warp::serve(routes).try_bind_ephemeral(options.dashboard_addr)?;
+    info!("serving dashboard from {:?} on {:?}", dashboard, addr);
+    Ok((addr, serve))
 }
 
 /// Handles when a file is uploaded to the dashboard (used by logger.cs)
 async fn handle_upload(
     filename: String,
     bytes: Bytes,
+    upload_dir: Option<std::path::PathBuf>,
 ) -> std::result::Result<impl warp::Reply, warp::Rejection> {
-    let fname = get_upload_dir().join(filename);
-    println!("Uploading {:?}...", fname);
+    let Some(upload_dir) = upload_dir else {
+        return Ok(warp::reply::with_status(
+            "Uploads are disabled\n".to_owned(),
+            http::StatusCode::FORBIDDEN,
+        ));
+    };
+    let fname = upload_dir.join(filename);
+    info!("Uploading {:?}...", fname);
     if let Err(err) = tokio::fs::write(fname.clone(), bytes).await {
         return Ok(warp::reply::with_status(
             format!("Failed to write file {:?}\n", err),
@@ -1,20 +1,29 @@
-use std::{net::Ipv4Addr, sync::Arc};
+use std::sync::Arc;
 
 use anyhow::Result;
 use netvr_data::{bincode, net};
 use tokio::net::UdpSocket;
+use tracing::{debug, info, trace, warn};
+
+use crate::ServerOptions;
 
 /// Initializes the discovery server and returns the data needed to run it.
-pub(crate) async fn init_discovery_server() -> Result<(UdpSocket, Vec<u8>)> {
-    let discovery_socket = UdpSocket::bind("0.0.0.0:13161").await?;
-    println!(
-        "[discovery] Discovery address: {:?}",
-        discovery_socket.local_addr()?
-    );
-    let multi_addr = Ipv4Addr::new(234, 2, 2, 2);
-    let inter = Ipv4Addr::new(0, 0, 0, 0);
-    discovery_socket.join_multicast_v4(multi_addr, inter)?;
-    let discovery_response = bincode::serialize(&net::DiscoveryResponse::default())?;
+/// Responses advertise the fingerprint so that clients can pin it.
+pub(crate) async fn init_discovery_server(
+    options: &ServerOptions,
+    fingerprint: net::Fingerprint,
+) -> Result<(UdpSocket, Vec<u8>)> {
+    let discovery_socket = UdpSocket::bind(options.discovery_addr).await?;
+    info!("Discovery address: {:?}", discovery_socket.local_addr()?);
+    if options.enable_multicast {
+        if let Err(err) =
+            discovery_socket.join_multicast_v4(options.multicast_group, options.multicast_interface)
+        {
+            // broadcast still works, multicast is not available on every network
+            warn!("Failed to join multicast group: {:?}", err);
+        }
+    }
+    let discovery_response = bincode::serialize(&net::DiscoveryResponse::new(fingerprint))?;
     Ok((discovery_socket, discovery_response))
 }
 
@@ -25,19 +34,18 @@ pub(crate) async fn run_discovery_server(server_udp: Arc<UdpSocket>, data: (UdpS
     loop {
         match discovery_socket.recv_from(&mut buf).await {
             Ok((amt, src)) => {
-                println!("[discovery] received {} bytes from {:?}", amt, src);
                 let eq = buf[0..amt].eq("netvr".as_bytes());
-                println!("[discovery] eq: {}", eq);
+                trace!("received {} bytes from {:?}, eq: {}", amt, src, eq);
                 if eq {
-                    println!("[discovery] Sending response");
+                    debug!("Sending discovery response to {:?}", src);
 
                     if let Err(e) = server_udp.send_to(&discovery_response, src).await {
-                        println!("[discovery] send_to err = {:?}", e);
+                        warn!("send_to err = {:?}", e);
                     }
                 }
             }
             Err(e) => {
-                println!("[discovery] recv_from err = {:?}", e);
+                warn!("recv_from err = {:?}", e);
             }
         };
     }
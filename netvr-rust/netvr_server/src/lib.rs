@@ -0,0 +1,299 @@
+//! NetVR server. Discovers and connects headsets, synchronizes their state and
+//! serves the dashboard. The binary is a thin wrapper around [start], which is
+//! also used by integration tests to run the server in-process.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use futures_util::future::select_all;
+use netvr_data::net::{self, Fingerprint};
+use serde::Deserialize;
+use tokio::{net::UdpSocket, spawn, sync::broadcast, task::JoinHandle};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{
+    accept_connection::accept_connection,
+    auth::Auth,
+    calibration_store::CalibrationStore,
+    dashboard::{serve_dashboard, DashboardMessage},
+    discovery_server::{init_discovery_server, run_discovery_server},
+    my_socket::MySocket,
+    quinn_server::{make_server_endpoint, ServerIdentity},
+    recorder::Recorder,
+    replay::Replay,
+    server::Server,
+};
+
+pub use logging::{init_logging, LogFormat, LogOptions};
+
+mod accept_connection;
+mod app;
+mod auth;
+mod batch_calibration;
+mod calibration_protocol;
+mod calibration_state;
+mod calibration_store;
+mod client;
+mod clock;
+mod continuous_calibration;
+mod dashboard;
+mod discovery_server;
+mod logging;
+mod marker_calibration;
+mod metrics;
+mod my_socket;
+mod quinn_server;
+mod recorder;
+mod replay;
+mod room;
+mod server;
+
+/// How to run the server. Ports set to 0 are chosen by the OS. Can be loaded
+/// from a TOML config file, missing keys keep their default values.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerOptions {
+    /// UDP address for QUIC connections. Random port is used if it is taken.
+    pub server_addr: SocketAddr,
+    /// UDP address on which discovery requests are answered
+    pub discovery_addr: SocketAddr,
+    /// Multicast group the discovery socket joins
+    pub multicast_group: Ipv4Addr,
+    /// Interface used for joining the multicast group
+    pub multicast_interface: Ipv4Addr,
+    /// TCP address of the dashboard
+    pub dashboard_addr: SocketAddr,
+    /// Built dashboard. Looked up next to the executable if not set.
+    pub dashboard_assets: Option<PathBuf>,
+    /// Where files uploaded through the dashboard are stored
+    pub upload_directory: PathBuf,
+    /// Where data of each finished calibration is written
+    pub calibration_directory: PathBuf,
+    /// Where the server keypair is persisted between restarts
+    pub identity_directory: PathBuf,
+    /// Where applied calibrations are kept by client name
+    pub calibration_store_directory: PathBuf,
+    /// Answer discovery requests so that clients can find the server
+    pub enable_discovery: bool,
+    /// Also answer discovery requests sent to the multicast group
+    pub enable_multicast: bool,
+    /// Serve the dashboard and its websocket
+    pub enable_dashboard: bool,
+    /// Accept file uploads through the dashboard
+    pub enable_uploads: bool,
+    /// Write data of each finished calibration to a file
+    pub enable_calibration_files: bool,
+    /// Keep history of applied calibrations on disk, otherwise only in memory
+    pub enable_calibration_store: bool,
+    /// Send clients their last good calibration when they connect instead of
+    /// only offering it on the dashboard
+    pub auto_apply_calibration: bool,
+    /// Only admit clients which present this token
+    pub join_token: Option<String>,
+    /// Record everything clients send into this session file
+    pub record: Option<PathBuf>,
+    /// Play back session file as virtual clients, controlled from dashboard
+    pub replay: Option<PathBuf>,
+    /// How many times per second each client is sent state of the others.
+    /// Can be changed per room from the dashboard.
+    pub state_rate: f64,
+    /// Used by the binary to set up logging, see [init_logging]
+    pub log: LogOptions,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        let any = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        Self {
+            server_addr: SocketAddr::new(any, net::DEFAULT_SERVER_PORT),
+            discovery_addr: SocketAddr::new(any, net::DEFAULT_DISCOVERY_PORT),
+            multicast_group: Ipv4Addr::new(234, 2, 2, 2),
+            multicast_interface: Ipv4Addr::UNSPECIFIED,
+            dashboard_addr: SocketAddr::new(any, net::DEFAULT_DASHBOARD_PORT),
+            dashboard_assets: None,
+            upload_directory: PathBuf::from("upload"),
+            calibration_directory: PathBuf::from("."),
+            identity_directory: PathBuf::from("server_identity"),
+            calibration_store_directory: PathBuf::from("calibration_store"),
+            enable_discovery: true,
+            enable_multicast: true,
+            enable_dashboard: true,
+            enable_uploads: true,
+            enable_calibration_files: true,
+            enable_calibration_store: true,
+            auto_apply_calibration: false,
+            join_token: None,
+            record: None,
+            replay: None,
+            state_rate: 60.,
+            log: LogOptions::default(),
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Reads options from a TOML file
+    pub async fn load(path: &Path) -> Result<Self> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config file {:?}", path))
+    }
+}
+
+/// Server started by [start]. All its tasks are stopped when dropped.
+pub struct RunningServer {
+    /// Where clients connect to
+    pub server_addr: SocketAddr,
+    /// None if discovery is disabled
+    pub discovery_addr: Option<SocketAddr>,
+    /// None if dashboard is disabled
+    pub dashboard_addr: Option<SocketAddr>,
+    /// Fingerprint of the certificate clients see
+    pub fingerprint: Fingerprint,
+    tasks: Vec<(&'static str, JoinHandle<()>)>,
+}
+
+impl RunningServer {
+    /// Runs until one of the subsystems stops, which should not happen
+    pub async fn wait(mut self) {
+        let (names, tasks): (Vec<_>, Vec<_>) = std::mem::take(&mut self.tasks).into_iter().unzip();
+        let (result, index, _) = select_all(tasks).await;
+        error!("{} finished: {:?}", names[index], result);
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        for (_, task) in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Binds all sockets and starts serving. Returns once everything is ready to
+/// accept connections.
+pub async fn start(options: ServerOptions) -> Result<RunningServer> {
+    let auth = Auth::new(options.join_token.clone());
+    if auth.requires_token() {
+        info!("Clients are required to present join token");
+    }
+
+    let server_udp = match UdpSocket::bind(options.server_addr).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            warn!(
+                "Failed to bind {}, using random port: {:?}",
+                options.server_addr, err
+            );
+            UdpSocket::bind(SocketAddr::new(options.server_addr.ip(), 0)).await?
+        }
+    };
+    let server_udp = Arc::new(server_udp);
+    let identity = ServerIdentity::load_or_generate(&options.identity_directory).await?;
+    let endpoint = make_server_endpoint(MySocket::new(server_udp.clone()), &identity)?;
+    let server_addr = endpoint.local_addr()?;
+    let (dashboard_tx, mut rx) = broadcast::channel::<DashboardMessage>(16);
+    let mut tasks = vec![];
+
+    info!("Server port: {:?}", server_addr.port());
+    info!("Server fingerprint: {}", identity.fingerprint_hex());
+
+    let discovery_addr = if options.enable_discovery {
+        let discovery_server = init_discovery_server(&options, identity.fingerprint()).await?;
+        let discovery_addr = discovery_server.0.local_addr()?;
+        tasks.push((
+            "discovery",
+            spawn(run_discovery_server(server_udp.clone(), discovery_server)),
+        ));
+        Some(discovery_addr)
+    } else {
+        info!("Discovery is disabled");
+        None
+    };
+    let recorder = match &options.record {
+        Some(path) => Recorder::start(path.clone()).await?,
+        None => Recorder::disabled(),
+    };
+    let calibration_directory = if options.enable_calibration_files {
+        Some(options.calibration_directory.clone())
+    } else {
+        None
+    };
+    let calibration_store = CalibrationStore::load(
+        options
+            .enable_calibration_store
+            .then(|| options.calibration_store_directory.clone()),
+        options.auto_apply_calibration,
+        dashboard_tx.clone(),
+    )
+    .await?;
+    room::check_state_rate(options.state_rate)?;
+    let server = Server::start(
+        dashboard_tx.clone(),
+        recorder,
+        calibration_directory,
+        calibration_store,
+        options.state_rate,
+    )
+    .await;
+    let replay = match &options.replay {
+        Some(path) => {
+            let (replay, sender) = Replay::load(path, server.clone(), dashboard_tx.clone()).await?;
+            spawn(replay.run());
+            Some(sender)
+        }
+        None => None,
+    };
+    let dashboard_addr = if options.enable_dashboard {
+        let (dashboard_addr, dashboard) = serve_dashboard(
+            dashboard_tx.clone(),
+            server.clone(),
+            auth.clone(),
+            replay,
+            &options,
+        )
+        .await?;
+        tasks.push(("dashboard", spawn(dashboard)));
+        Some(dashboard_addr)
+    } else {
+        info!("Dashboard is disabled");
+        None
+    };
+
+    let connections = spawn(async move {
+        loop {
+            if let Some(connecting) = endpoint.accept().await {
+                let dashboard_tx = dashboard_tx.clone();
+                let server = server.clone();
+                let auth = auth.clone();
+                let id = server.next_client_id();
+                let span = info_span!("client", id, addr = %connecting.remote_address());
+                spawn(
+                    accept_connection(connecting, server, dashboard_tx, id, auth).instrument(span),
+                );
+            }
+        }
+    });
+    tasks.push(("connections", connections));
+
+    // black-hole all the messages so that channel does not get closed
+    let sink = spawn(async move {
+        loop {
+            let _ = rx.recv().await;
+        }
+    });
+    tasks.push(("dashboard sink", sink));
+
+    Ok(RunningServer {
+        server_addr,
+        discovery_addr,
+        dashboard_addr,
+        fingerprint: identity.fingerprint(),
+        tasks,
+    })
+}
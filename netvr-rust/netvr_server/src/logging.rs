@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
+
+/// How log lines are formatted
+#[derive(Clone, Copy, Debug, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, one line per event
+    #[default]
+    Text,
+    /// One JSON object per line including the spans, for log collectors
+    Json,
+}
+
+/// Where and what the server logs
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogOptions {
+    /// Which events are logged, eg. "info,netvr_server::app=debug". RUST_LOG
+    /// environment variable takes precedence.
+    pub filter: String,
+    pub format: LogFormat,
+    /// Append to this file instead of writing to stdout
+    pub file: Option<PathBuf>,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_owned(),
+            format: LogFormat::Text,
+            file: None,
+        }
+    }
+}
+
+/// Installs global tracing subscriber. Returned guard has to be kept alive
+/// for as long as logs are written to a file, otherwise they get lost.
+pub fn init_logging(options: &LogOptions) -> Result<Option<WorkerGuard>> {
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(env) => EnvFilter::try_new(env)?,
+        Err(_) => EnvFilter::try_new(&options.filter)?,
+    };
+    let (writer, guard) = match &options.file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| anyhow!("Failed to open log file {:?}: {}", path, err))?;
+            let (writer, guard) = tracing_appender::non_blocking(file);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(options.file.is_none());
+    match options.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).try_init(),
+    }
+    .map_err(|err| anyhow!("Failed to initialize logging: {}", err))?;
+    Ok(guard)
+}
@@ -0,0 +1,257 @@
+//! Calibration by touching shared physical markers, for example corners of a
+//! table. Every client places its device on the same marker and the dashboard
+//! asks for a capture, which requests one sample of each device. After two or
+//! more markers yaw and translation of every target are computed from the
+//! captured points, so the devices never need to be held together.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use netvr_calibrate::{CalibrationInput, SamplePairing, MIN_MARKERS};
+use netvr_data::net::{
+    BaseSpace, CalibrationSample, CalibrationSolver, ClientId, ConfigurationDown::RequestSample,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+};
+use tracing::{info, warn};
+
+use crate::{
+    calibration_protocol::{
+        any_disconnected, apply_result, check_result, server_space_pose, supports_calibration,
+        CalibrationProtocolMessage::{self, *},
+    },
+    calibration_state::{CalibrationState, CalibrationStatus},
+    calibration_store::CalibrationSource,
+    client::Client,
+    clock,
+    dashboard::DashboardMessage,
+    room::Room,
+};
+
+/// How long to wait for samples of one capture
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How one capture ended
+enum Capture {
+    /// Valid sample of every device
+    Captured(HashMap<ClientId, CalibrationSample>),
+    /// Cancel arrived while waiting for samples
+    Cancelled,
+    /// FinishCalibration arrived while waiting for samples
+    Finished,
+}
+
+/// Client calibrated by markers
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MarkerTarget {
+    pub client_id: ClientId,
+    pub subaction_path: String,
+}
+
+/// Device which is placed on the markers
+struct Device {
+    client: Client,
+    path: String,
+    space: BaseSpace,
+}
+
+/// Captures marker points whenever CaptureMarker is received, then calibrates
+/// all targets against the reference. FinishCalibration solves early if at
+/// least two points were captured. Returns whether all targets were applied.
+pub(crate) async fn run_markers(
+    recv: &mut mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
+    client_reference: (ClientId, String),
+    targets: Vec<MarkerTarget>,
+    point_count: usize,
+    room: Room,
+    tx: broadcast::Sender<DashboardMessage>,
+) -> bool {
+    let reference_id = client_reference.0;
+    let ids: Vec<_> = std::iter::once(reference_id)
+        .chain(targets.iter().map(|target| target.client_id))
+        .collect();
+    let status = CalibrationStatus::new(tx.clone(), &room, "markers", ids.clone());
+    if targets.is_empty() {
+        return status.fail("Marker calibration has no targets");
+    }
+    if ids.iter().collect::<HashSet<_>>().len() != ids.len() {
+        return status.fail("Every client can only be in marker calibration once");
+    }
+    if point_count < MIN_MARKERS {
+        return status.fail(format!("At least {} markers are needed", MIN_MARKERS));
+    }
+
+    let paths = std::iter::once(client_reference.1)
+        .chain(targets.into_iter().map(|target| target.subaction_path));
+    let mut devices = vec![];
+    for (&id, path) in ids.iter().zip(paths) {
+        let Some(client) = room.get_client(id).await else {
+            return status.fail(format!("Client {} not found", id));
+        };
+        let space = if id == reference_id {
+            BaseSpace::Server
+        } else {
+            BaseSpace::Stage
+        };
+        devices.push(Device {
+            client,
+            path,
+            space,
+        });
+    }
+    if !supports_calibration(&devices.iter().map(|d| &d.client).collect::<Vec<_>>()) {
+        return status.fail("Not all clients support calibration");
+    }
+
+    status.set(CalibrationState::Collecting);
+    let mut points: HashMap<ClientId, Vec<CalibrationSample>> = HashMap::new();
+    let mut captured = 0;
+    while captured < point_count {
+        let message = select! {
+            message = recv.recv() => message,
+            id = any_disconnected(devices.iter().map(|d| &d.client)) => {
+                return status.fail(format!("Client {} disconnected", id));
+            }
+        };
+        match message {
+            None | Some(FinishCalibration) => break,
+            Some(Cancel) => return status.fail("Cancelled"),
+            Some(CaptureMarker) => match capture(&devices, recv).await {
+                Ok(Capture::Cancelled) => return status.fail("Cancelled"),
+                Ok(Capture::Finished) => break,
+                Ok(Capture::Captured(samples)) => {
+                    for (id, sample) in samples {
+                        points.entry(id).or_default().push(sample);
+                    }
+                    captured += 1;
+                    info!("Captured marker {} of {}", captured, point_count);
+                    let _ = tx.send(DashboardMessage::MarkerCaptured {
+                        captured,
+                        point_count,
+                    });
+                }
+                Err(err) => {
+                    warn!("Failed to capture marker: {:?}", err);
+                    let _ = tx.send(DashboardMessage::Info {
+                        message: format!("Marker was not captured: {}", err),
+                    });
+                }
+            },
+            // late answers to a failed capture
+            Some(Sample { .. }) => continue,
+            Some(message) => warn!("Ignoring {:?} during marker calibration", message),
+        }
+    }
+    if captured < MIN_MARKERS {
+        return status.fail(format!(
+            "Only {} of at least {} markers were captured",
+            captured, MIN_MARKERS
+        ));
+    }
+
+    status.set(CalibrationState::Solving);
+    let reference = &devices[0].client;
+    let positions = |id| -> Vec<_> {
+        points[&id]
+            .iter()
+            .map(|sample| sample.pose.position.clone())
+            .collect()
+    };
+    let mut solved = vec![];
+    for target in &devices[1..] {
+        let id = target.client.id();
+        let result = netvr_calibrate::calibrate_markers(&positions(id), &positions(reference_id));
+        match check_result(&tx, result, id, reference_id) {
+            Ok(data) => solved.push((&target.client, data)),
+            Err(err) => warn!("Marker calibration of {} failed: {}", id, err),
+        }
+    }
+
+    status.set(CalibrationState::Applying);
+    let source = CalibrationSource::Markers;
+    let mut applied = 0;
+    for (target, data) in solved {
+        let pose = server_space_pose(data.translation.clone(), data.rotation.clone());
+        let input = CalibrationInput {
+            target: points[&target.id()].clone(),
+            target_name: target.name(),
+            reference: points[&reference_id].clone(),
+            reference_name: reference.name(),
+            pairing: SamplePairing::Order,
+            target_clock: target.clock(),
+            reference_clock: reference.clock(),
+            solver: CalibrationSolver::GravityAligned,
+        };
+        if apply_result(target, reference, source, pose, data, input).await {
+            applied += 1;
+        }
+    }
+    let targets = devices.len() - 1;
+    if applied < targets {
+        return status.fail(format!("Applied to {} of {} targets", applied, targets));
+    }
+    status.set(CalibrationState::Idle);
+    true
+}
+
+/// Requests one sample of every device and waits for all of them. Fails if
+/// some device does not answer with a valid pose in time. Stops waiting on
+/// Cancel or FinishCalibration.
+async fn capture(
+    devices: &[Device],
+    recv: &mut mpsc::UnboundedReceiver<CalibrationProtocolMessage>,
+) -> Result<Capture> {
+    let requested = clock::now_nanos();
+    for device in devices {
+        device
+            .client
+            .send_configuration_down(RequestSample(device.path.clone(), device.space))?;
+    }
+    let mut samples = HashMap::new();
+    let timeout = tokio::time::sleep(CAPTURE_TIMEOUT);
+    tokio::pin!(timeout);
+    while samples.len() < devices.len() {
+        select! {
+            message = recv.recv() => match message {
+                Some(Sample { client, sample }) => {
+                    let device = devices.iter().find(|device| device.client.id() == client);
+                    let Some(device) = device else { continue; };
+                    if netvr_calibrate::is_valid(&sample)
+                        && !is_stale(&device.client, &sample, requested)
+                    {
+                        samples.insert(client, sample);
+                    }
+                }
+                Some(Cancel) => return Ok(Capture::Cancelled),
+                Some(FinishCalibration) => return Ok(Capture::Finished),
+                Some(message) => warn!("Ignoring {:?} while capturing marker", message),
+                None => break,
+            },
+            _ = &mut timeout => break,
+        }
+    }
+    let missing: Vec<_> = devices
+        .iter()
+        .map(|device| device.client.id())
+        .filter(|id| !samples.contains_key(id))
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!("No valid pose from clients {:?}", missing));
+    }
+    Ok(Capture::Captured(samples))
+}
+
+/// Whether the sample was taken before it was requested, so it is a late
+/// answer to an earlier capture which timed out. Samples of clients whose
+/// clock is not known yet can not be checked.
+fn is_stale(client: &Client, sample: &CalibrationSample, requested: i64) -> bool {
+    let Some(clock) = client.clock() else { return false; };
+    clock.to_server(sample.nanos) + clock.uncertainty_nanos < requested
+}
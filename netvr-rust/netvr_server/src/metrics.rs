@@ -0,0 +1,257 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use crate::client::Client;
+
+/// Server-wide counters exposed on /metrics
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// Messages dropped because the dashboard could not keep up
+    pub dashboard_lagged: AtomicU64,
+    /// Finished calibrations by kind and whether they succeeded
+    calibrations: Mutex<BTreeMap<(&'static str, bool), u64>>,
+}
+
+impl Metrics {
+    /// Call when a calibration of given kind ends, successfully or not
+    pub fn calibration_finished(&self, kind: &'static str, ok: bool) {
+        let mut calibrations = self.calibrations.lock().unwrap();
+        *calibrations.entry((kind, ok)).or_default() += 1;
+    }
+}
+
+/// Counters of one client exposed on /metrics
+#[derive(Default)]
+pub(crate) struct ClientMetrics {
+    pub datagrams_up: AtomicU64,
+    pub datagrams_down: AtomicU64,
+    /// Datagrams which could not be deserialized
+    pub datagram_decode_failures: AtomicU64,
+    /// Messages waiting to be written to configuration down stream
+    pub configuration_down_queue: AtomicI64,
+    /// Messages waiting to be written to app down stream
+    pub app_down_queue: AtomicI64,
+    /// Outgoing datagrams dropped because the send buffer was full
+    pub datagram_drops: AtomicU64,
+    snapshot_timing: Mutex<SnapshotTiming>,
+}
+
+/// Smoothed interval between state snapshots and its variation, computed
+/// the same way RTP computes interarrival jitter.
+#[derive(Default, Clone, Copy)]
+struct SnapshotTiming {
+    last: Option<Instant>,
+    interval: f64,
+    jitter: f64,
+}
+
+impl ClientMetrics {
+    /// Call when state snapshot arrives from the client
+    pub fn snapshot_arrived(&self) {
+        let now = Instant::now();
+        let mut timing = self.snapshot_timing.lock().unwrap();
+        if let Some(last) = timing.last {
+            let interval = (now - last).as_secs_f64();
+            if timing.interval == 0. {
+                timing.interval = interval;
+            }
+            let deviation = (interval - timing.interval).abs();
+            timing.jitter += (deviation - timing.jitter) / 16.;
+            timing.interval += (interval - timing.interval) / 16.;
+        }
+        timing.last = Some(now);
+    }
+
+    /// Smoothed snapshot interval and jitter in seconds
+    pub fn snapshot_timing(&self) -> (f64, f64) {
+        let timing = self.snapshot_timing.lock().unwrap();
+        (timing.interval, timing.jitter)
+    }
+}
+
+/// Increments a counter
+pub(crate) fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Escapes a label value. The text format only knows escapes of backslash,
+/// double quote and newline, everything else is written as is.
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders metrics in Prometheus text exposition format
+pub(crate) fn render(metrics: &Metrics, clients: &[Client], rooms: usize) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, values: &[(String, f64)]| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in values {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    };
+    let per_client = |f: &dyn Fn(&Client) -> Option<f64>| -> Vec<(String, f64)> {
+        clients
+            .iter()
+            .filter_map(|client| {
+                let labels = format!(
+                    "{{client=\"{}\",room=\"{}\"}}",
+                    client.id(),
+                    escape_label(client.room().name())
+                );
+                f(client).map(|value| (labels, value))
+            })
+            .collect()
+    };
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as f64;
+    let load_gauge = |gauge: &AtomicI64| gauge.load(Ordering::Relaxed) as f64;
+
+    metric(
+        "netvr_connected_clients",
+        "gauge",
+        "Clients currently connected, including replayed ones",
+        &[(String::new(), clients.len() as f64)],
+    );
+    metric(
+        "netvr_rooms",
+        "gauge",
+        "Rooms which currently exist",
+        &[(String::new(), rooms as f64)],
+    );
+    metric(
+        "netvr_client_datagrams_up_total",
+        "counter",
+        "Datagrams received from the client",
+        &per_client(&|c| Some(load(&c.metrics().datagrams_up))),
+    );
+    metric(
+        "netvr_client_datagrams_down_total",
+        "counter",
+        "Datagrams sent to the client",
+        &per_client(&|c| Some(load(&c.metrics().datagrams_down))),
+    );
+    metric(
+        "netvr_client_datagram_decode_failures_total",
+        "counter",
+        "Datagrams from the client which failed to decode",
+        &per_client(&|c| Some(load(&c.metrics().datagram_decode_failures))),
+    );
+    metric(
+        "netvr_client_configuration_down_queue",
+        "gauge",
+        "Configuration messages waiting to be sent to the client",
+        &per_client(&|c| Some(load_gauge(&c.metrics().configuration_down_queue))),
+    );
+    metric(
+        "netvr_client_app_down_queue",
+        "gauge",
+        "Synchronized object messages waiting to be sent to the client",
+        &per_client(&|c| Some(load_gauge(&c.metrics().app_down_queue))),
+    );
+    metric(
+        "netvr_client_datagram_drops_total",
+        "counter",
+        "Datagrams to the client dropped because the send buffer was full",
+        &per_client(&|c| Some(load(&c.metrics().datagram_drops))),
+    );
+    metric(
+        "netvr_client_snapshot_jitter_seconds",
+        "gauge",
+        "Variation of time between state snapshots from the client",
+        &per_client(&|c| Some(c.metrics().snapshot_timing().1)),
+    );
+    metric(
+        "netvr_client_rtt_seconds",
+        "gauge",
+        "QUIC estimate of the round trip time",
+        &per_client(&|c| c.connection_stats().map(|s| s.path.rtt.as_secs_f64())),
+    );
+    metric(
+        "netvr_client_sent_packets_total",
+        "counter",
+        "QUIC packets sent to the client",
+        &per_client(&|c| c.connection_stats().map(|s| s.path.sent_packets as f64)),
+    );
+    metric(
+        "netvr_client_lost_packets_total",
+        "counter",
+        "QUIC packets sent to the client which were lost",
+        &per_client(&|c| c.connection_stats().map(|s| s.path.lost_packets as f64)),
+    );
+    metric(
+        "netvr_dashboard_lagged_messages_total",
+        "counter",
+        "Messages not forwarded because a dashboard could not keep up",
+        &[(String::new(), load(&metrics.dashboard_lagged))],
+    );
+    let calibrations: Vec<_> = metrics
+        .calibrations
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((kind, ok), count)| {
+            let outcome = if *ok { "ok" } else { "failed" };
+            (
+                format!("{{kind=\"{}\",outcome=\"{}\"}}", kind, outcome),
+                *count as f64,
+            )
+        })
+        .collect();
+    metric(
+        "netvr_calibrations_total",
+        "counter",
+        "Finished calibrations by kind and outcome",
+        &calibrations,
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use netvr_data::net::Capabilities;
+    use tokio::sync::{broadcast, watch};
+
+    use super::*;
+    use crate::{calibration_store::CalibrationStore, room::Room};
+
+    #[tokio::test]
+    async fn room_label_uses_only_prometheus_escapes() {
+        let ws = broadcast::channel(16).0;
+        let metrics = Arc::new(Metrics::default());
+        let store = CalibrationStore::load(None, false, ws.clone())
+            .await
+            .unwrap();
+        let name = "Bob's café \"corner\"\\\n2".to_owned();
+        let changed = watch::channel(()).0;
+        let room = Room::start(name, ws.clone(), changed, None, store, metrics.clone(), 30.).await;
+        let client = Client::new_virtual(ws, room.clone(), 7, Capabilities::all());
+
+        let text = render(&metrics, &[client], 1);
+        room.close();
+
+        let line = text
+            .lines()
+            .find(|line| line.starts_with("netvr_client_datagrams_up_total{"))
+            .expect("client should have a sample");
+        let expected = r#"{client="7",room="Bob's café \"corner\"\\\n2"} 0"#;
+        assert_eq!(line, format!("netvr_client_datagrams_up_total{}", expected));
+    }
+}
@@ -1,22 +1,86 @@
-use anyhow::Result;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use netvr_data::net::{self, Fingerprint};
 use quinn::{AsyncUdpSocket, Endpoint, EndpointConfig, ServerConfig, TokioRuntime};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+/// Certificate and private key the server uses to identify itself to clients.
+/// Stays the same across restarts so that clients can pin it.
+pub(crate) struct ServerIdentity {
+    cert_der: Vec<u8>,
+    key_der: Vec<u8>,
+}
+
+impl ServerIdentity {
+    /// Loads the identity from given directory or generates and stores a new
+    /// one if there is none yet.
+    pub(crate) async fn load_or_generate(dir: &Path) -> Result<Self> {
+        let cert_path = dir.join("cert.der");
+        let key_path = dir.join("key.der");
+
+        if cert_path.exists() && key_path.exists() {
+            let cert_der = tokio::fs::read(&cert_path)
+                .await
+                .with_context(|| format!("Failed to read {:?}", cert_path))?;
+            let key_der = tokio::fs::read(&key_path)
+                .await
+                .with_context(|| format!("Failed to read {:?}", key_path))?;
+            return Ok(Self { cert_der, key_der });
+        }
+
+        info!("Generating new server identity in {:?}", dir);
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+        let identity = Self {
+            cert_der: cert.serialize_der()?,
+            key_der: cert.serialize_private_key_der(),
+        };
+        tokio::fs::create_dir_all(dir).await?;
+        write_private(&key_path, &identity.key_der).await?;
+        tokio::fs::write(&cert_path, &identity.cert_der).await?;
+        Ok(identity)
+    }
+
+    /// SHA-256 of the certificate, advertised in discovery responses.
+    pub(crate) fn fingerprint(&self) -> Fingerprint {
+        net::fingerprint_of(&self.cert_der)
+    }
+
+    /// Hex encoded fingerprint for displaying to the user.
+    pub(crate) fn fingerprint_hex(&self) -> String {
+        net::fingerprint_to_hex(&self.fingerprint())
+    }
+}
+
+/// Writes file readable only by the owner, other users must not be able to
+/// impersonate the server
+async fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(contents).await?;
+    file.flush().await
+}
 
-/// Returns default server configuration along with its certificate.
-fn configure_server() -> Result<(ServerConfig, Vec<u8>)> {
-    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-    let cert_der = cert.serialize_der().unwrap();
-    let priv_key = cert.serialize_private_key_der();
-    let priv_key = rustls::PrivateKey(priv_key);
-    let cert_chain = vec![rustls::Certificate(cert_der.clone())];
+/// Returns server configuration using given identity.
+fn configure_server(identity: &ServerIdentity) -> Result<ServerConfig> {
+    let priv_key = rustls::PrivateKey(identity.key_der.clone());
+    let cert_chain = vec![rustls::Certificate(identity.cert_der.clone())];
 
     let server_config = ServerConfig::with_single_cert(cert_chain, priv_key)?;
 
-    Ok((server_config, cert_der))
+    Ok(server_config)
 }
 
-/// Returns a new server endpoint and its certificate.
-pub(crate) fn make_server_endpoint(socket: impl AsyncUdpSocket) -> Result<Endpoint> {
-    let (server_config, _server_cert) = configure_server()?;
+/// Returns a new server endpoint presenting given identity.
+pub(crate) fn make_server_endpoint(
+    socket: impl AsyncUdpSocket,
+    identity: &ServerIdentity,
+) -> Result<Endpoint> {
+    let server_config = configure_server(identity)?;
     let endpoint = Endpoint::new_with_abstract_socket(
         EndpointConfig::default(),
         Some(server_config),
@@ -0,0 +1,104 @@
+use std::{path::PathBuf, time::Instant};
+
+use anyhow::Result;
+use netvr_data::{
+    app::{AppDatagramUp, AppUp},
+    net::{CalibrationSample, Capabilities, ClientId, ConfigurationUp, StateSnapshot},
+    serde::{Deserialize, Serialize},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+use tracing::{error, info};
+
+/// One line of the session file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RecordedEvent {
+    /// Time since the recording started
+    pub nanos: u64,
+    pub client: ClientId,
+    /// Room the client was in when the event happened
+    pub room: String,
+    pub kind: RecordedEventKind,
+}
+
+/// Everything that is recorded about a client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum RecordedEventKind {
+    Connected(Capabilities),
+    Disconnected,
+    State(StateSnapshot),
+    ConfigurationUp(ConfigurationUp),
+    AppUp(AppUp),
+    AppDatagramUp(AppDatagramUp),
+    CalibrationSample(CalibrationSample),
+}
+
+/// Writes events received from clients into a session file. Cheap to clone,
+/// does nothing if recording is disabled.
+#[derive(Clone)]
+pub(crate) struct Recorder {
+    sender: Option<mpsc::UnboundedSender<RecordedEvent>>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Recorder which drops all events
+    pub(crate) fn disabled() -> Self {
+        Self {
+            sender: None,
+            start: Instant::now(),
+        }
+    }
+
+    /// Creates the session file and starts writing events into it
+    pub(crate) async fn start(path: PathBuf) -> Result<Self> {
+        let file = File::create(&path).await?;
+        info!("Recording session to {:?}", path);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Err(err) = write_events(BufWriter::new(file), receiver).await {
+                error!("Failed to write session file {:?}: {:?}", path, err);
+            }
+        });
+        Ok(Self {
+            sender: Some(sender),
+            start: Instant::now(),
+        })
+    }
+
+    /// Records an event, timestamped with current time
+    pub(crate) fn record(&self, client: ClientId, room: &str, kind: RecordedEventKind) {
+        let Some(sender) = &self.sender else { return; };
+        let _ = sender.send(RecordedEvent {
+            nanos: self.start.elapsed().as_nanos() as u64,
+            client,
+            room: room.to_owned(),
+            kind,
+        });
+    }
+}
+
+/// Writes events as JSON lines, flushing whenever there is nothing else to do
+async fn write_events(
+    mut file: BufWriter<File>,
+    mut receiver: mpsc::UnboundedReceiver<RecordedEvent>,
+) -> Result<()> {
+    while let Some(event) = receiver.recv().await {
+        write_event(&mut file, &event).await?;
+        while let Ok(event) = receiver.try_recv() {
+            write_event(&mut file, &event).await?;
+        }
+        file.flush().await?;
+    }
+    Ok(())
+}
+
+async fn write_event(file: &mut BufWriter<File>, event: &RecordedEvent) -> Result<()> {
+    let mut line = serde_json::to_string(event)?;
+    line.push('\n');
+    file.write_all(line.as_bytes()).await?;
+    Ok(())
+}
@@ -0,0 +1,329 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use netvr_data::net::{Capabilities, ClientId};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    app::AppServerMessage,
+    client::Client,
+    dashboard::DashboardMessage,
+    recorder::{RecordedEvent, RecordedEventKind},
+    server::{RoomFilter, Server},
+};
+
+/// Controls sent from the dashboard
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ReplayCommand {
+    Play,
+    Pause,
+    /// Jump to given time since the start of the recording
+    Seek(u64),
+    /// Multiplier of the playback speed, 1.0 is real time
+    SetSpeed(f64),
+}
+
+/// Channel for controlling the replay
+pub(crate) type ReplaySender = mpsc::UnboundedSender<ReplayCommand>;
+
+/// Position in the recording which advances with real time while playing
+struct ReplayClock {
+    position: u64,
+    since: Instant,
+    speed: f64,
+    playing: bool,
+}
+
+impl ReplayClock {
+    fn now(&self) -> u64 {
+        if !self.playing {
+            return self.position;
+        }
+        self.position + (self.since.elapsed().as_nanos() as f64 * self.speed) as u64
+    }
+
+    fn play(&mut self) {
+        if !self.playing {
+            self.since = Instant::now();
+            self.playing = true;
+        }
+    }
+
+    fn pause(&mut self) {
+        self.position = self.now();
+        self.playing = false;
+    }
+
+    fn seek(&mut self, position: u64) {
+        self.position = position;
+        self.since = Instant::now();
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.seek(self.now());
+        self.speed = speed;
+    }
+
+    /// How long to wait until the clock reaches given position. None if the
+    /// clock is paused.
+    fn until(&self, position: u64) -> Option<Duration> {
+        if !self.playing {
+            return None;
+        }
+        let remaining = position.saturating_sub(self.now()) as f64 / self.speed;
+        Some(Duration::from_nanos(remaining as u64))
+    }
+}
+
+/// Feeds recorded session back into the server as virtual clients
+pub(crate) struct Replay {
+    events: Vec<RecordedEvent>,
+    receiver: mpsc::UnboundedReceiver<ReplayCommand>,
+    server: Server,
+    ws: broadcast::Sender<DashboardMessage>,
+    /// Recorded client id to virtual client
+    clients: HashMap<ClientId, Client>,
+    next: usize,
+    clock: ReplayClock,
+}
+
+impl Replay {
+    /// Reads the session file written by Recorder
+    pub(crate) async fn load(
+        path: &Path,
+        server: Server,
+        ws: broadcast::Sender<DashboardMessage>,
+    ) -> Result<(Self, ReplaySender)> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read session file {:?}", path))?;
+        let mut events = vec![];
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event: RecordedEvent = serde_json::from_str(line)
+                .map_err(|err| anyhow!("Invalid event on line {}: {}", number + 1, err))?;
+            events.push(event);
+        }
+        events.sort_by_key(|event| event.nanos);
+        info!("Loaded {} recorded events from {:?}", events.len(), path);
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Ok((
+            Self {
+                events,
+                receiver,
+                server,
+                ws,
+                clients: HashMap::new(),
+                next: 0,
+                clock: ReplayClock {
+                    position: 0,
+                    since: Instant::now(),
+                    speed: 1.0,
+                    playing: true,
+                },
+            },
+            sender,
+        ))
+    }
+
+    fn duration(&self) -> u64 {
+        self.events.last().map(|event| event.nanos).unwrap_or(0)
+    }
+
+    /// Plays the recording, reacting to commands until all senders are gone
+    pub(crate) async fn run(mut self) {
+        let mut status = tokio::time::interval(Duration::from_millis(500));
+        loop {
+            let wait = self
+                .events
+                .get(self.next)
+                .and_then(|event| self.clock.until(event.nanos));
+            select! {
+                command = self.receiver.recv() => {
+                    let Some(command) = command else { break; };
+                    self.handle_command(command).await;
+                    self.send_status();
+                },
+                _ = tokio::time::sleep(wait.unwrap_or_default()), if wait.is_some() => {
+                    self.apply_due().await;
+                },
+                _ = status.tick(), if self.clock.playing => self.send_status(),
+            }
+        }
+        info!("Replay stopped");
+    }
+
+    async fn handle_command(&mut self, command: ReplayCommand) {
+        debug!("Replay command: {:?}", command);
+        match command {
+            ReplayCommand::Play => {
+                if self.next >= self.events.len() {
+                    self.seek(0).await;
+                }
+                self.clock.play();
+            }
+            ReplayCommand::Pause => self.clock.pause(),
+            ReplayCommand::Seek(position) => self.seek(position).await,
+            ReplayCommand::SetSpeed(speed) => {
+                if speed.is_finite() && speed > 0.0 {
+                    self.clock.set_speed(speed);
+                } else {
+                    warn!("Ignoring invalid replay speed {}", speed);
+                }
+            }
+        }
+    }
+
+    /// Applies all events up to the current time
+    async fn apply_due(&mut self) {
+        let now = self.clock.now();
+        while let Some(event) = self.events.get(self.next) {
+            if event.nanos > now {
+                break;
+            }
+            let event = event.clone();
+            self.next += 1;
+            self.apply(event).await;
+        }
+        if self.next >= self.events.len() {
+            self.clock.pause();
+            self.send_status();
+        }
+    }
+
+    /// Moves to given position. Events before it are applied immediately,
+    /// except for state snapshots of which only the last one per client is.
+    async fn seek(&mut self, position: u64) {
+        if position < self.clock.now() {
+            self.reset().await;
+        }
+        let mut states = HashMap::new();
+        while let Some(event) = self.events.get(self.next) {
+            if event.nanos > position {
+                break;
+            }
+            let event = event.clone();
+            self.next += 1;
+            match event.kind {
+                RecordedEventKind::State(_) => {
+                    states.insert(event.client, event);
+                }
+                RecordedEventKind::Disconnected => {
+                    states.remove(&event.client);
+                    self.apply(event).await;
+                }
+                _ => self.apply(event).await,
+            }
+        }
+        for (_, event) in states {
+            self.apply(event).await;
+        }
+        self.clock.seek(position);
+    }
+
+    /// Removes all virtual clients and resets synchronized objects
+    async fn reset(&mut self) {
+        for (_, client) in self.clients.drain() {
+            self.server.remove_client(client.id()).await;
+            client.cancel();
+            let _ = self
+                .ws
+                .send(DashboardMessage::ConnectionClosed { id: client.id() });
+        }
+        for room in self.server.rooms(&RoomFilter::All).await {
+            let _ = room.app_channel().send(AppServerMessage::ResetObjects);
+        }
+        self.next = 0;
+    }
+
+    async fn apply(&mut self, event: RecordedEvent) {
+        if let Err(err) = self.try_apply(event).await {
+            warn!("Failed to replay event: {:?}", err);
+        }
+    }
+
+    async fn try_apply(&mut self, event: RecordedEvent) -> Result<()> {
+        let client = match (self.clients.get(&event.client).cloned(), &event.kind) {
+            (Some(client), RecordedEventKind::Connected(_)) => {
+                // connected twice, should not happen but start over
+                self.server.remove_client(client.id()).await;
+                self.add_client(&event).await?
+            }
+            (Some(client), _) => client,
+            // never seen in this replay, so there is nothing to disconnect
+            (None, RecordedEventKind::Disconnected) => return Ok(()),
+            // recording might have started while client was connected
+            (None, _) => self.add_client(&event).await?,
+        };
+        if client.room().name() != event.room {
+            self.server.move_client(client.id(), &event.room).await?;
+        }
+        match event.kind {
+            RecordedEventKind::Connected(_) => {}
+            RecordedEventKind::Disconnected => {
+                self.clients.remove(&event.client);
+                self.server.remove_client(client.id()).await;
+                client.cancel();
+                let _ = self
+                    .ws
+                    .send(DashboardMessage::ConnectionClosed { id: client.id() });
+            }
+            RecordedEventKind::State(snapshot) => client.handle_recv_snapshot(snapshot, 0).await?,
+            RecordedEventKind::ConfigurationUp(message) => {
+                client.handle_configuration_up(message).await
+            }
+            RecordedEventKind::AppUp(message) => client.handle_app_up(message)?,
+            RecordedEventKind::AppDatagramUp(message) => client.handle_app_datagram(message)?,
+            RecordedEventKind::CalibrationSample(sample) => {
+                client.handle_calibration_sample(sample)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates virtual client for a recorded one
+    async fn add_client(&mut self, event: &RecordedEvent) -> Result<Client> {
+        let capabilities = match event.kind {
+            RecordedEventKind::Connected(capabilities) => capabilities,
+            _ => Capabilities::all(),
+        };
+        let room = self.server.room(&event.room).await;
+        let client = Client::new_virtual(
+            self.ws.clone(),
+            room,
+            self.server.next_client_id(),
+            capabilities,
+        );
+        self.server.add_client(client.clone()).await?;
+        self.clients.insert(event.client, client.clone());
+        let _ = self
+            .ws
+            .send(DashboardMessage::FullyConnected { id: client.id() });
+        info!(
+            "Replaying recorded client {} as {}",
+            event.client,
+            client.id()
+        );
+        Ok(client)
+    }
+
+    fn send_status(&self) {
+        let _ = self.ws.send(DashboardMessage::ReplayStatus {
+            playing: self.clock.playing,
+            position_nanos: self.clock.now().min(self.duration()),
+            duration_nanos: self.duration(),
+            speed: self.clock.speed,
+        });
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    collections::{hash_map::Entry::Occupied, HashMap},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use netvr_data::net::{
+    ClientId, ConfigurationSnapshotSet, RemoteConfigurationSnapshot, RemoteStateSnapshotSet,
+    StateSnapshot,
+};
+use tokio::{
+    select, spawn,
+    sync::{broadcast, mpsc, watch, Mutex, RwLock},
+    time::{self, Interval, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+
+use crate::{
+    app::{AppChannel, AppServer},
+    calibration_protocol::{CalibrationProtocol, CalibrationSender},
+    calibration_store::CalibrationStore,
+    client::Client,
+    dashboard::DashboardMessage,
+    metrics::Metrics,
+};
+
+#[derive(Debug)]
+enum ServerChange {
+    AddClient(ClientId),
+    SetSnapshot(ClientId, StateSnapshot),
+    SetConfiguration(ClientId, RemoteConfigurationSnapshot),
+    RemoveClient(ClientId),
+}
+
+type ServerChannel = tokio::sync::mpsc::Sender<ServerChange>;
+type LatestSnaphots = Arc<RwLock<RemoteStateSnapshotSet>>;
+type LatestConfigurations = Arc<RwLock<watch::Sender<ConfigurationSnapshotSet>>>;
+
+/// Allowed values of state rate in Hz
+const STATE_RATES: std::ops::RangeInclusive<f64> = 1.0..=240.0;
+
+/// Fails if state snapshots cannot be sent at this rate
+pub(crate) fn check_state_rate(rate: f64) -> Result<()> {
+    if !STATE_RATES.contains(&rate) {
+        return Err(anyhow!(
+            "State rate must be between {} and {} Hz, got {}",
+            STATE_RATES.start(),
+            STATE_RATES.end(),
+            rate
+        ));
+    }
+    Ok(())
+}
+
+/// State of one isolated shared world. Clients only see state snapshots,
+/// configurations and synchronized objects of clients in the same room.
+#[derive(Clone)]
+pub(crate) struct Room {
+    name: Arc<str>,
+    clients: Arc<Mutex<HashMap<ClientId, Client>>>,
+    latest_snapshots: LatestSnaphots,
+    latest_configurations: LatestConfigurations,
+    channel: ServerChannel,
+    app_channel: AppChannel,
+    calibration_sender: CalibrationSender,
+    calibration_store: CalibrationStore,
+    /// How many times per second state snapshots are sent to clients
+    state_rate: watch::Sender<f64>,
+    /// Stops all tasks of the room once it is removed
+    token: CancellationToken,
+}
+
+impl Room {
+    /// Creates the room and starts its synchronized object and calibration
+    /// subsystems. `changed` is notified whenever membership or configuration
+    /// of the room changes. Calibration data is written to
+    /// `calibration_output` and applied calibrations are recorded in
+    /// `calibration_store`. State is sent to clients `state_rate` times per
+    /// second.
+    pub async fn start(
+        name: String,
+        ws: broadcast::Sender<DashboardMessage>,
+        changed: watch::Sender<()>,
+        calibration_output: Option<PathBuf>,
+        calibration_store: CalibrationStore,
+        metrics: Arc<Metrics>,
+        state_rate: f64,
+    ) -> Self {
+        let token = CancellationToken::new();
+        let latest_snapshots: LatestSnaphots = Arc::default();
+        let latest_configurations: LatestConfigurations =
+            Arc::new(RwLock::new(watch::channel(Default::default()).0));
+        let channel = Self::receive(
+            latest_snapshots.clone(),
+            latest_configurations.clone(),
+            changed,
+            token.clone(),
+        )
+        .await;
+        let (app_channel, app_receiver) = mpsc::unbounded_channel();
+        let (calibration, calibration_sender) =
+            CalibrationProtocol::new(calibration_output, metrics);
+        let room = Self {
+            name: name.into(),
+            clients: Arc::default(),
+            latest_snapshots,
+            latest_configurations,
+            channel,
+            app_channel,
+            calibration_sender,
+            calibration_store,
+            state_rate: watch::channel(state_rate).0,
+            token: token.clone(),
+        };
+
+        let mut app = AppServer::new(app_receiver, room.clone());
+        let name = room.name.clone();
+        let cancelled = token.clone();
+        spawn(async move {
+            select! {
+                res = app.run() => if let Err(e) = res {
+                    error!("AppServer of room {} error: {:?}", name, e);
+                },
+                _ = cancelled.cancelled() => {},
+            }
+        });
+        let name = room.name.clone();
+        let calibration = calibration.run(room.clone(), ws);
+        let cancelled = token.clone();
+        spawn(async move {
+            select! {
+                res = calibration => if let Err(e) = res {
+                    error!("Calibration of room {} error: {:?}", name, e);
+                },
+                _ = cancelled.cancelled() => {},
+            }
+        });
+        let broadcast = room.clone().run_state_broadcast();
+        spawn(async move {
+            select! {
+                _ = broadcast => {},
+                _ = token.cancelled() => {},
+            }
+        });
+
+        room
+    }
+
+    async fn receive(
+        latest_snapshots: LatestSnaphots,
+        latest_configurations: LatestConfigurations,
+        changed: watch::Sender<()>,
+        token: CancellationToken,
+    ) -> ServerChannel {
+        let (snapshot_channel, mut receiver) = tokio::sync::mpsc::channel::<ServerChange>(100);
+        spawn(async move {
+            loop {
+                let change = select! {
+                    change = receiver.recv() => change,
+                    _ = token.cancelled() => None,
+                };
+                let Some(change) = change else { break };
+                match change {
+                    ServerChange::AddClient(id) => {
+                        let mut latest_snaphots = latest_snapshots.write().await;
+                        let latest_configurations = latest_configurations.write().await;
+                        latest_snaphots.order += 1;
+                        latest_snaphots.clients.insert(id, Default::default());
+                        latest_configurations.send_modify(|confs| {
+                            confs.clients.insert(id, Default::default());
+                        });
+                        changed.send_replace(());
+                    }
+                    ServerChange::SetSnapshot(id, snapshot) => {
+                        let mut latest_snaphots = latest_snapshots.write().await;
+                        if latest_snaphots.clients.contains_key(&id) {
+                            latest_snaphots.order += 1;
+                            latest_snaphots.clients.insert(id, snapshot);
+                        }
+                    }
+                    ServerChange::SetConfiguration(id, config) => {
+                        let latest_configurations = latest_configurations.write().await;
+                        let modified = latest_configurations.send_if_modified(|confs| {
+                            if let Occupied(mut e) = confs.clients.entry(id) {
+                                e.insert(config);
+                                true
+                            } else {
+                                debug!("Configuration ignored {:?}", config);
+                                false
+                            }
+                        });
+                        if modified {
+                            changed.send_replace(());
+                        }
+                    }
+                    ServerChange::RemoveClient(id) => {
+                        let mut latest_snaphots = latest_snapshots.write().await;
+                        let latest_configurations = latest_configurations.write().await;
+                        latest_snaphots.order += 1;
+                        latest_snaphots.clients.remove(&id);
+                        latest_configurations.send_modify(|confs| {
+                            confs.clients.remove(&id);
+                        });
+                        changed.send_replace(());
+                    }
+                }
+            }
+        });
+        snapshot_channel
+    }
+
+    /// Sends latest state of other clients to every client in the room on
+    /// a fixed interval, regardless of how often they send their own
+    async fn run_state_broadcast(self) {
+        let mut rate = self.state_rate.subscribe();
+        let mut interval = state_interval(*rate.borrow());
+        loop {
+            select! {
+                _ = interval.tick() => {
+                    let snapshots = self.read_latest_snapshots().await;
+                    for (id, client) in self.get_clients().await {
+                        if let Err(err) = client.send_state(&self, snapshots.clone()) {
+                            debug!("Failed to send state to client {}: {:?}", id, err);
+                        }
+                    }
+                }
+                changed = rate.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    interval = state_interval(*rate.borrow());
+                }
+            }
+        }
+    }
+
+    /// Name chosen by clients or the dashboard
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Channel for synchronized objects of this room
+    pub fn app_channel(&self) -> &AppChannel {
+        &self.app_channel
+    }
+
+    /// Channel for calibration of clients in this room
+    pub fn calibration_sender(&self) -> &CalibrationSender {
+        &self.calibration_sender
+    }
+
+    /// History of calibrations applied to clients, shared by all rooms
+    pub fn calibration_store(&self) -> &CalibrationStore {
+        &self.calibration_store
+    }
+
+    /// How many times per second state snapshots are sent to clients
+    pub fn state_rate(&self) -> f64 {
+        *self.state_rate.borrow()
+    }
+
+    /// Changes how many times per second state snapshots are sent to clients
+    pub fn set_state_rate(&self, rate: f64) -> Result<()> {
+        check_state_rate(rate)?;
+        self.state_rate.send_replace(rate);
+        Ok(())
+    }
+
+    /// Get the latest configuration snapshots
+    pub async fn latest_configuration(&self) -> watch::Receiver<ConfigurationSnapshotSet> {
+        self.latest_configurations.read().await.subscribe()
+    }
+
+    /// Set the latest state snapshot to a client
+    pub async fn apply_snapshot(&self, id: ClientId, snapshot: StateSnapshot) {
+        if let Err(err) = self
+            .channel
+            .send(ServerChange::SetSnapshot(id, snapshot))
+            .await
+        {
+            warn!("Failed to send snapshot to be applied {:?}", err);
+        }
+    }
+
+    /// Stops all tasks of the room, call once it is removed from the server
+    pub fn close(&self) {
+        self.token.cancel();
+    }
+
+    /// Whether both are handles of the same room
+    pub fn ptr_eq(&self, other: &Room) -> bool {
+        Arc::ptr_eq(&self.clients, &other.clients)
+    }
+
+    /// Whether no client is in the room
+    pub async fn is_empty(&self) -> bool {
+        self.clients.lock().await.is_empty()
+    }
+
+    /// called when a client enters the room
+    pub async fn add_client(&self, client: Client) -> Result<()> {
+        let mut clients = self.clients.lock().await;
+        if self.token.is_cancelled() {
+            return Err(anyhow!("Room {:?} was removed", self.name));
+        }
+        self.channel
+            .send(ServerChange::AddClient(client.id()))
+            .await?;
+        clients.insert(client.id(), client);
+        Ok(())
+    }
+
+    /// Gets all the clients
+    pub async fn get_clients(&self) -> Vec<(u32, Client)> {
+        self.clients
+            .lock()
+            .await
+            .iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect()
+    }
+
+    // Gets a client by id
+    #[allow(dead_code)]
+    pub async fn get_client(&self, id: ClientId) -> Option<Client> {
+        self.clients.lock().await.get(&id).cloned()
+    }
+
+    /// Called when a client leaves the room
+    pub async fn remove_client(&self, id: ClientId) {
+        let mut clients = self.clients.lock().await;
+        if let Err(err) = self.channel.send(ServerChange::RemoveClient(id)).await {
+            warn!("Failed to remove client: {:?}", err);
+        }
+        clients.remove(&id);
+    }
+
+    /// Applies a configuration to a client
+    pub async fn apply_configuration(&self, id: ClientId, config: RemoteConfigurationSnapshot) {
+        if let Err(err) = self
+            .channel
+            .send(ServerChange::SetConfiguration(id, config))
+            .await
+        {
+            warn!("Failed to send configuration to be applied {:?}", err);
+        }
+    }
+
+    /// Gets all the latest state snapshots
+    pub async fn read_latest_snapshots(&self) -> RemoteStateSnapshotSet {
+        self.latest_snapshots.read().await.clone()
+    }
+}
+
+fn state_interval(rate: f64) -> Interval {
+    let mut interval = time::interval(Duration::from_secs_f64(1. / rate));
+    // late ticks would only send the same state again
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    interval
+}
@@ -1,159 +1,224 @@
 use std::{
-    collections::{hash_map::Entry::Occupied, HashMap},
-    sync::Arc,
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
 };
 
-use anyhow::Result;
-use netvr_data::net::{
-    ClientId, ConfigurationSnapshotSet, RemoteConfigurationSnapshot, RemoteStateSnapshotSet,
-    StateSnapshot,
-};
-use tokio::{
-    spawn,
-    sync::{watch, Mutex, RwLock},
-};
+use anyhow::{anyhow, Result};
+use netvr_data::net::{self, ClientId, ConfigurationSnapshotSet};
+use tokio::sync::{broadcast, watch, Mutex};
+use tracing::info;
 
-use crate::client::Client;
+use crate::{
+    calibration_store::CalibrationStore,
+    client::Client,
+    dashboard::DashboardMessage,
+    metrics::{self, Metrics},
+    recorder::Recorder,
+    room::Room,
+};
 
-#[derive(Debug)]
-enum ServerChange {
-    AddClient(ClientId),
-    SetSnapshot(ClientId, StateSnapshot),
-    SetConfiguration(ClientId, RemoteConfigurationSnapshot),
-    RemoveClient(ClientId),
+/// Which rooms an observer (usually dashboard) is interested in
+#[derive(Clone, Debug, Default)]
+pub(crate) enum RoomFilter {
+    #[default]
+    All,
+    Only(String),
 }
 
-type ServerChannel = tokio::sync::mpsc::Sender<ServerChange>;
-type LatestSnaphots = Arc<RwLock<RemoteStateSnapshotSet>>;
-type LatestConfigurations = Arc<RwLock<watch::Sender<ConfigurationSnapshotSet>>>;
+impl RoomFilter {
+    pub fn matches(&self, room: &str) -> bool {
+        match self {
+            RoomFilter::All => true,
+            RoomFilter::Only(name) => name == room,
+        }
+    }
+}
 
-/// Ful server state
+/// Full server state. Holds all rooms and index of all connected clients.
 #[derive(Clone)]
 pub(crate) struct Server {
+    rooms: Arc<Mutex<HashMap<String, Room>>>,
     clients: Arc<Mutex<HashMap<ClientId, Client>>>,
-    latest_snapshots: LatestSnaphots,
-    latest_configurations: LatestConfigurations,
-    channel: ServerChannel,
+    ws: broadcast::Sender<DashboardMessage>,
+    changed: watch::Sender<()>,
+    next_client_id: Arc<AtomicU32>,
+    recorder: Recorder,
+    calibration_output: Option<PathBuf>,
+    calibration_store: CalibrationStore,
+    metrics: Arc<Metrics>,
+    /// State rate of newly created rooms
+    state_rate: f64,
 }
 
 impl Server {
     /// Prepare the server to be run
-    pub async fn start() -> Self {
-        let latest_snapshots: LatestSnaphots = Arc::default();
-        let latest_configurations: LatestConfigurations =
-            Arc::new(RwLock::new(watch::channel(Default::default()).0));
-        let channel = Self::receive(latest_snapshots.clone(), latest_configurations.clone()).await;
-        Self {
+    pub async fn start(
+        ws: broadcast::Sender<DashboardMessage>,
+        recorder: Recorder,
+        calibration_output: Option<PathBuf>,
+        calibration_store: CalibrationStore,
+        state_rate: f64,
+    ) -> Self {
+        let server = Self {
+            rooms: Arc::default(),
             clients: Arc::default(),
-            latest_snapshots,
-            latest_configurations,
-            channel,
-        }
+            ws,
+            changed: watch::channel(()).0,
+            next_client_id: Arc::new(AtomicU32::new(1)),
+            recorder,
+            calibration_output,
+            calibration_store,
+            metrics: Arc::default(),
+            state_rate,
+        };
+        server.room(net::DEFAULT_ROOM).await;
+        server
     }
 
-    async fn receive(
-        latest_snapshots: LatestSnaphots,
-        latest_configurations: LatestConfigurations,
-    ) -> ServerChannel {
-        let (snapshot_channel, mut receiver) = tokio::sync::mpsc::channel::<ServerChange>(100);
-        spawn(async move {
-            loop {
-                let Some(change) = receiver.recv().await else { break };
-                match change {
-                    ServerChange::AddClient(id) => {
-                        let mut latest_snaphots = latest_snapshots.write().await;
-                        let latest_configurations = latest_configurations.write().await;
-                        latest_snaphots.order += 1;
-                        latest_snaphots.clients.insert(id, Default::default());
-                        latest_configurations.send_modify(|confs| {
-                            confs.clients.insert(id, Default::default());
-                        });
-                    }
-                    ServerChange::SetSnapshot(id, snapshot) => {
-                        let mut latest_snaphots = latest_snapshots.write().await;
-                        if latest_snaphots.clients.contains_key(&id) {
-                            latest_snaphots.order += 1;
-                            latest_snaphots.clients.insert(id, snapshot);
-                        }
-                    }
-                    ServerChange::SetConfiguration(id, config) => {
-                        let latest_configurations = latest_configurations.write().await;
-                        latest_configurations.send_if_modified(|confs| {
-                            if let Occupied(mut e) = confs.clients.entry(id) {
-                                e.insert(config);
-                                true
-                            } else {
-                                println!("Configuration ignored {:?}", config);
-                                false
-                            }
-                        });
-                    }
-                    ServerChange::RemoveClient(id) => {
-                        let mut latest_snaphots = latest_snapshots.write().await;
-                        let latest_configurations = latest_configurations.write().await;
-                        latest_snaphots.order += 1;
-                        latest_snaphots.clients.remove(&id);
-                        latest_configurations.send_modify(|confs| {
-                            confs.clients.remove(&id);
-                        });
-                    }
-                }
-            }
-        });
-        snapshot_channel
+    /// Allocates id for a new client, both real and virtual
+    pub fn next_client_id(&self) -> ClientId {
+        self.next_client_id.fetch_add(1, Ordering::Relaxed)
     }
 
-    /// Get the latest configuration snapshots
-    pub async fn latest_configuration(&self) -> watch::Receiver<ConfigurationSnapshotSet> {
-        self.latest_configurations.read().await.subscribe()
+    /// Records events of all clients, if enabled
+    pub fn recorder(&self) -> &Recorder {
+        &self.recorder
     }
 
-    /// Set the latest state snapshot to a client
-    pub async fn apply_snapshot(&self, id: ClientId, snapshot: StateSnapshot) {
-        if let Err(err) = self
-            .channel
-            .send(ServerChange::SetSnapshot(id, snapshot))
-            .await
-        {
-            println!("Failed to send snapshot to be applied {:?}", err);
+    /// History of calibrations applied to clients
+    pub fn calibration_store(&self) -> &CalibrationStore {
+        &self.calibration_store
+    }
+
+    /// Server-wide counters exposed on /metrics
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Renders all metrics in Prometheus text format
+    pub async fn render_metrics(&self) -> String {
+        let clients: Vec<Client> = self.clients.lock().await.values().cloned().collect();
+        let rooms = self.rooms.lock().await.len();
+        metrics::render(&self.metrics, &clients, rooms)
+    }
+
+    /// Gets room by name, creating it if it does not exist yet
+    pub async fn room(&self, name: &str) -> Room {
+        let mut rooms = self.rooms.lock().await;
+        self.room_in(&mut rooms, name).await
+    }
+
+    async fn room_in(&self, rooms: &mut HashMap<String, Room>, name: &str) -> Room {
+        if let Some(room) = rooms.get(name) {
+            return room.clone();
         }
+        info!("Creating room {:?}", name);
+        let room = Room::start(
+            name.to_owned(),
+            self.ws.clone(),
+            self.changed.clone(),
+            self.calibration_output.clone(),
+            self.calibration_store.clone(),
+            self.metrics.clone(),
+            self.state_rate,
+        )
+        .await;
+        rooms.insert(name.to_owned(), room.clone());
+        self.changed.send_replace(());
+        room
     }
 
-    /// called when a new client connects
-    pub async fn add_client(&self, client: Client) -> Result<()> {
-        let mut clients = self.clients.lock().await;
-        self.channel
-            .send(ServerChange::AddClient(client.id()))
-            .await?;
-        clients.insert(client.id(), client);
-        Ok(())
+    /// Puts the client to the room of given name, creating it again if it was
+    /// removed after the client was given it. Joining happens under the rooms
+    /// lock, so that a room is never removed while a client enters it.
+    async fn join_room(&self, client: &Client, name: &str) -> Result<Room> {
+        let mut rooms = self.rooms.lock().await;
+        let room = self.room_in(&mut rooms, name).await;
+        room.add_client(client.clone()).await?;
+        Ok(room)
     }
 
-    /// Gets a random client (not really random, but only used for some debug stuff)
-    pub async fn get_first_client(&self) -> Option<Client> {
-        self.clients
+    /// Removes the room and stops its tasks once the last client left it.
+    /// Default room is kept even when empty.
+    async fn remove_room_if_empty(&self, room: &Room) {
+        if room.name() == net::DEFAULT_ROOM {
+            return;
+        }
+        let mut rooms = self.rooms.lock().await;
+        if !room.is_empty().await {
+            return;
+        }
+        // a room of the same name might have been created since
+        if !matches!(rooms.get(room.name()), Some(current) if current.ptr_eq(room)) {
+            return;
+        }
+        info!("Removing empty room {:?}", room.name());
+        rooms.remove(room.name());
+        room.close();
+        self.changed.send_replace(());
+    }
+
+    /// Changes how often clients in the room receive state snapshots
+    pub async fn set_state_rate(&self, name: &str, rate: f64) -> Result<()> {
+        let room = self
+            .rooms
             .lock()
             .await
-            .iter()
-            .take(1)
-            .collect::<Vec<_>>()
-            .get(0)
-            .map(|v| v.1)
+            .get(name)
             .cloned()
+            .ok_or_else(|| anyhow!("Room {:?} does not exist", name))?;
+        room.set_state_rate(rate)?;
+        self.changed.send_replace(());
+        Ok(())
     }
 
-    /// Gets all the clients
-    pub async fn get_clients(&self) -> Vec<(u32, Client)> {
-        self.clients
+    /// Gets all the rooms matching the filter
+    pub async fn rooms(&self, filter: &RoomFilter) -> Vec<Room> {
+        self.rooms
             .lock()
             .await
-            .iter()
-            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .values()
+            .filter(|room| filter.matches(room.name()))
+            .cloned()
             .collect()
     }
 
+    /// Notified when rooms, their members or their configurations change
+    pub fn subscribe_changes(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    /// Merged configuration snapshots of all clients in matching rooms
+    pub async fn latest_configuration(&self, filter: &RoomFilter) -> ConfigurationSnapshotSet {
+        let mut result = ConfigurationSnapshotSet::default();
+        for room in self.rooms(filter).await {
+            let conf = room.latest_configuration().await;
+            let conf = conf.borrow();
+            for (id, value) in conf.clients.iter() {
+                result.clients.insert(*id, value.clone());
+            }
+        }
+        result
+    }
+
+    /// called when a new client connects, puts it to its room
+    pub async fn add_client(&self, client: Client) -> Result<()> {
+        let mut clients = self.clients.lock().await;
+        let name = client.room().name().to_owned();
+        let room = self.join_room(&client, &name).await?;
+        if !room.ptr_eq(&client.room()) {
+            client.set_room(room);
+        }
+        client.handle_connected();
+        clients.insert(client.id(), client);
+        Ok(())
+    }
+
     // Gets a client by id
-    #[allow(dead_code)]
     pub async fn get_client(&self, id: ClientId) -> Option<Client> {
         self.clients.lock().await.get(&id).cloned()
     }
@@ -161,25 +226,34 @@ impl Server {
     /// Deletes a client
     pub async fn remove_client(&self, id: ClientId) {
         let mut clients = self.clients.lock().await;
-        if let Err(err) = self.channel.send(ServerChange::RemoveClient(id)).await {
-            println!("Failed to remove client: {:?}", err);
+        if let Some(client) = clients.remove(&id) {
+            client.handle_disconnected();
+            let room = client.room();
+            room.remove_client(id).await;
+            self.remove_room_if_empty(&room).await;
         }
-        clients.remove(&id);
     }
 
-    /// Applies a configuration to a client
-    pub async fn apply_configuration(&self, id: ClientId, config: RemoteConfigurationSnapshot) {
-        if let Err(err) = self
-            .channel
-            .send(ServerChange::SetConfiguration(id, config))
-            .await
-        {
-            println!("Failed to send configuration to be applied {:?}", err);
+    /// Moves client to a different room
+    pub async fn move_client(&self, id: ClientId, room: &str) -> Result<()> {
+        let clients = self.clients.lock().await;
+        let client = clients
+            .get(&id)
+            .ok_or_else(|| anyhow!("Client {} not found", id))?;
+        let old_room = client.room();
+        if old_room.name() == room {
+            return Ok(());
         }
-    }
-
-    /// Gets all the latest state snapshots
-    pub async fn read_latest_snapshots(&self) -> RemoteStateSnapshotSet {
-        self.latest_snapshots.read().await.clone()
+        old_room.remove_client(id).await;
+        self.remove_room_if_empty(&old_room).await;
+        let new_room = self.join_room(client, room).await?;
+        client.set_room(new_room);
+        info!(
+            "Moved client {} from {:?} to {:?}",
+            id,
+            old_room.name(),
+            room
+        );
+        Ok(())
     }
 }
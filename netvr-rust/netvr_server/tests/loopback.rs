@@ -0,0 +1,1006 @@
+//! Runs the server in-process and talks to it over loopback the same way
+//! headsets and the dashboard do.
+
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Deref,
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::{SinkExt, StreamExt};
+use nalgebra::{Isometry3, Translation3, UnitQuaternion};
+use netvr_client::{ConnectOptions, Error, NetVRConnection, RetryPolicy};
+use netvr_data::{
+    app::{AppDatagramUp, AppDown, AppUp, Snapshot},
+    bincode,
+    compact::StateDecoder,
+    net::{
+        BaseSpace, CalibrationSample, ClientId, ClockEstimate, CloseCode, ConfigurationDown,
+        ConfigurationUp, DatagramDown, DatagramUp, RemoteConfigurationSnapshot,
+        RemoteStateSnapshotSet, StateSnapshot,
+    },
+    Pose, Vec3,
+};
+use netvr_server::{RunningServer, ServerOptions};
+use netvr_simulated_client::{from_isometry, to_isometry, Motion};
+use serde_json::json;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+type Dashboard = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Running server which deletes its identity and calibrations when dropped
+struct TestServer {
+    server: RunningServer,
+    directory: PathBuf,
+}
+
+impl Deref for TestServer {
+    type Target = RunningServer;
+
+    fn deref(&self) -> &RunningServer {
+        &self.server
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+/// Starts a server on random loopback ports with its own identity
+async fn start_server() -> TestServer {
+    start_server_with(|_| {}).await
+}
+
+/// Like start_server, but options can be changed before starting
+async fn start_server_with(configure: impl FnOnce(&mut ServerOptions)) -> TestServer {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    let directory = std::env::temp_dir().join(format!(
+        "netvr-test-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let loopback = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let mut options = ServerOptions {
+        server_addr: loopback,
+        discovery_addr: loopback,
+        dashboard_addr: loopback,
+        enable_uploads: false,
+        enable_calibration_files: false,
+        identity_directory: directory.clone(),
+        calibration_store_directory: directory.join("calibrations"),
+        ..Default::default()
+    };
+    configure(&mut options);
+    let server = netvr_server::start(options)
+        .await
+        .expect("server should start");
+    TestServer { server, directory }
+}
+
+async fn connect(server: &RunningServer) -> NetVRConnection {
+    connect_with(server, None)
+        .await
+        .expect("client should connect")
+}
+
+/// Connects announcing the name in the hello, which may get the client rejected
+async fn connect_with(
+    server: &RunningServer,
+    name: Option<&str>,
+) -> Result<NetVRConnection, Error> {
+    let options = ConnectOptions {
+        server_address: Some(server.server_addr),
+        retry: RetryPolicy {
+            max_attempts: Some(3),
+            ..Default::default()
+        },
+        name: name.map(str::to_owned),
+        ..Default::default()
+    };
+    netvr_client::connect_with_options(|text| println!("[client] {}", text), &options).await
+}
+
+async fn connect_dashboard(server: &RunningServer) -> Dashboard {
+    let url = format!("ws://{}/ws", server.dashboard_addr.unwrap());
+    tokio_tungstenite::connect_async(url)
+        .await
+        .expect("dashboard should connect")
+        .0
+}
+
+async fn send_dashboard(dashboard: &mut Dashboard, message: serde_json::Value) {
+    dashboard
+        .send(Message::Text(message.to_string()))
+        .await
+        .expect("dashboard message should be sent");
+}
+
+/// Fails the test if the future takes too long
+async fn within<T>(future: impl Future<Output = T>) -> T {
+    tokio::time::timeout(Duration::from_secs(10), future)
+        .await
+        .expect("timed out")
+}
+
+fn send_datagram(connection: &NetVRConnection, datagram: &DatagramUp) {
+    let bytes = bincode::serialize(datagram).unwrap();
+    connection.connection.send_datagram(bytes.into()).unwrap();
+}
+
+async fn read_datagram(connection: &NetVRConnection) -> DatagramDown {
+    let bytes = connection.connection.read_datagram().await.unwrap();
+    bincode::deserialize(&bytes).unwrap()
+}
+
+/// Reads configuration messages until one matches
+async fn read_configuration<T>(
+    connection: &mut NetVRConnection,
+    mut matches: impl FnMut(ConfigurationDown) -> Option<T>,
+) -> T {
+    loop {
+        let message = connection.configuration_down.read().await.unwrap();
+        if let Some(value) = matches(message) {
+            return value;
+        }
+    }
+}
+
+fn state_at(x: f32) -> StateSnapshot {
+    StateSnapshot {
+        view: Pose {
+            position: Vec3 { x, y: 1.6, z: 0. },
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn state_is_fanned_out_to_other_clients() {
+    let server = start_server().await;
+    let mut clients = vec![];
+    for _ in 0..3 {
+        clients.push(connect(&server).await);
+    }
+    let ids: Vec<ClientId> = clients.iter().map(|client| client.client_id).collect();
+
+    let mut decoders: Vec<_> = ids.iter().map(|_| StateDecoder::default()).collect();
+    let mut acknowledged = vec![0; ids.len()];
+
+    within(async {
+        loop {
+            // server answers each snapshot with the latest ones of others
+            for (i, client) in clients.iter().enumerate() {
+                let state = DatagramUp::State(state_at(i as f32), acknowledged[i]);
+                send_datagram(client, &state);
+            }
+            let mut done = true;
+            for (i, client) in clients.iter().enumerate() {
+                let DatagramDown::State(set) = read_datagram(client).await else { continue; };
+                let set = decoders[i].decode(set).expect("baseline should be known");
+                acknowledged[i] = acknowledged[i].max(set.order);
+                done &= has_others(&set, &ids, i);
+            }
+            if done {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+
+    fn has_others(set: &RemoteStateSnapshotSet, ids: &[ClientId], me: usize) -> bool {
+        assert!(!set.clients.contains_key(&ids[me]), "own state echoed");
+        ids.iter().enumerate().filter(|(i, _)| *i != me).all(|(i, id)| {
+            matches!(set.clients.get(id), Some(state) if state.view.position.x == i as f32)
+        })
+    }
+}
+
+#[tokio::test]
+async fn state_is_sent_to_clients_which_do_not_send() {
+    let server = start_server().await;
+    let talker = connect(&server).await;
+    let listener = connect(&server).await;
+    let mut decoder = StateDecoder::default();
+
+    send_datagram(&talker, &DatagramUp::State(state_at(3.), 0));
+    within(async {
+        loop {
+            let DatagramDown::State(set) = read_datagram(&listener).await else { continue; };
+            let Some(set) = decoder.decode(set) else { continue; };
+            let talker_state = set.clients.get(&talker.client_id);
+            if matches!(talker_state, Some(state) if state.view.position.x == 3.) {
+                break;
+            }
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn state_is_encoded_from_scratch_after_room_change() {
+    let server = start_server().await;
+    let mut dashboard = connect_dashboard(&server).await;
+    let mut mover = connect(&server).await;
+    let stayer = connect(&server).await;
+    let mut other = connect(&server).await;
+    let to_side = |id: ClientId| json!({ "type": "MoveClient", "clientId": id, "room": "side" });
+    send_dashboard(&mut dashboard, to_side(other.client_id)).await;
+    within(joined_room(&mut other, "side")).await;
+    send_datagram(&other, &DatagramUp::State(state_at(7.), 0));
+    // default room gets far ahead of the side room
+    for i in 0..50 {
+        send_datagram(&stayer, &DatagramUp::State(state_at(i as f32), 0));
+    }
+    let (stayer, other) = (stayer.client_id, other.client_id);
+
+    let (mut decoder, mut acknowledged) = (StateDecoder::default(), 0);
+    let has_stayer = |_, set: &RemoteStateSnapshotSet| has_state(set, stayer, 49.);
+    let received = receive_state(&mover, &mut decoder, &mut acknowledged, has_stayer);
+    within(received).await;
+    let stale = acknowledged;
+    assert!(stale >= 50);
+
+    send_dashboard(&mut dashboard, to_side(mover.client_id)).await;
+    within(joined_room(&mut mover, "side")).await;
+    // sets of the new room are not relative to sets of the old one, even if
+    // the client still acknowledges those
+    let mut decoder = StateDecoder::default();
+    within(async {
+        loop {
+            send_datagram(&mover, &DatagramUp::State(state_at(1.), stale));
+            let DatagramDown::State(set) = read_datagram(&mover).await else { continue; };
+            if set.order >= stale {
+                continue;
+            }
+            assert_eq!(set.baseline, 0);
+            let set = decoder.decode(set).expect("set should be complete");
+            if has_state(&set, other, 7.) && !set.clients.contains_key(&stayer) {
+                break;
+            }
+        }
+    })
+    .await;
+
+    let mut acknowledged = 0;
+    let delta = |baseline, set: &RemoteStateSnapshotSet| baseline != 0 && has_state(set, other, 7.);
+    let received = receive_state(&mover, &mut decoder, &mut acknowledged, delta);
+    within(received).await;
+}
+
+fn has_state(set: &RemoteStateSnapshotSet, id: ClientId, x: f32) -> bool {
+    matches!(set.clients.get(&id), Some(state) if state.view.position.x == x)
+}
+
+/// Waits until the client is told it is in the room
+async fn joined_room(client: &mut NetVRConnection, room: &str) {
+    read_configuration(client, |message| match message {
+        ConfigurationDown::JoinedRoom(joined) if joined == room => Some(()),
+        _ => None,
+    })
+    .await
+}
+
+/// Decodes state sets acknowledging each applied one, until done returns true
+/// for a set and the baseline it was encoded against
+async fn receive_state(
+    client: &NetVRConnection,
+    decoder: &mut StateDecoder,
+    acknowledged: &mut usize,
+    done: impl Fn(usize, &RemoteStateSnapshotSet) -> bool,
+) {
+    loop {
+        send_datagram(client, &DatagramUp::State(state_at(1.), *acknowledged));
+        let DatagramDown::State(set) = read_datagram(client).await else { continue; };
+        let baseline = set.baseline;
+        let Some(set) = decoder.decode(set) else { continue; };
+        *acknowledged = set.order;
+        if done(baseline, &set) {
+            return;
+        }
+    }
+}
+
+#[tokio::test]
+async fn client_clock_is_estimated_from_heartbeats() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    // pretend that the client clock is five seconds ahead
+    let offset = 5_000_000_000;
+
+    let clock = within(answer_heartbeats(&mut client, offset)).await;
+    assert!(clock.uncertainty_nanos >= 0);
+    assert!((clock.offset_nanos - offset).abs() <= clock.uncertainty_nanos + 1_000_000);
+}
+
+/// Answers heartbeats with client clock offset from the system one until the
+/// server estimates it
+async fn answer_heartbeats(client: &mut NetVRConnection, offset: i64) -> ClockEstimate {
+    loop {
+        let heartbeat = client.heartbeat.read().await.unwrap();
+        if let Some(clock) = heartbeat.clock {
+            return clock;
+        }
+        let pong = DatagramUp::Pong {
+            server_nanos: heartbeat.server_nanos,
+            client_nanos: now_nanos() + offset,
+        };
+        send_datagram(client, &pong);
+    }
+}
+
+fn now_nanos() -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_nanos() as i64
+}
+
+#[tokio::test]
+async fn configuration_is_propagated() {
+    let server = start_server().await;
+    let mut alpha = connect(&server).await;
+    let mut beta = connect(&server).await;
+    let alpha_id = alpha.client_id;
+
+    alpha
+        .configuration_up
+        .write(&ConfigurationUp::ConfigurationSnapshot(
+            RemoteConfigurationSnapshot {
+                version: 1,
+                name: "alpha".to_owned(),
+                ..Default::default()
+            },
+        ))
+        .await
+        .unwrap();
+    within(read_configuration(&mut beta, |message| match message {
+        ConfigurationDown::Snapshot(set) => set
+            .clients
+            .get(&alpha_id)
+            .filter(|conf| conf.name == "alpha")
+            .map(|_| ()),
+        _ => None,
+    }))
+    .await;
+
+    let mut dashboard = connect_dashboard(&server).await;
+    send_dashboard(
+        &mut dashboard,
+        json!({ "type": "SetName", "name": "renamed", "clientId": alpha_id }),
+    )
+    .await;
+    let name = within(read_configuration(&mut alpha, |message| match message {
+        ConfigurationDown::ChangeName(name) => Some(name),
+        _ => None,
+    }))
+    .await;
+    assert_eq!(name, "renamed");
+}
+
+#[tokio::test]
+async fn object_ownership_is_transferred() {
+    let server = start_server().await;
+    let mut alpha = connect(&server).await;
+    let mut beta = connect(&server).await;
+    let mut alpha_app = alpha.app_up_stream.take().expect("app objects negotiated");
+    let mut alpha_app_down = alpha.app_down_stream.take().unwrap();
+    let mut beta_app = beta.app_up_stream.take().unwrap();
+
+    let pose = |x| Pose {
+        position: Vec3 { x, y: 1., z: 0. },
+        ..Default::default()
+    };
+    alpha_app
+        .write(&AppUp::Init(Snapshot {
+            objects: vec![pose(0.), pose(1.)],
+        }))
+        .await
+        .unwrap();
+    alpha_app.write(&AppUp::Grab(0)).await.unwrap();
+
+    // wait until alpha owns the object and can move it
+    within(wait_for_object(&alpha, &pose(5.), || {
+        send_datagram(
+            &alpha,
+            &DatagramUp::App(AppDatagramUp::SetPose(0, pose(5.))),
+        )
+    }))
+    .await;
+
+    beta_app.write(&AppUp::Grab(0)).await.unwrap();
+    let released = within(alpha_app_down.read()).await.unwrap();
+    assert!(matches!(released, AppDown::Release(0)));
+
+    // now only beta can move it
+    send_datagram(
+        &alpha,
+        &DatagramUp::App(AppDatagramUp::SetPose(0, pose(6.))),
+    );
+    within(wait_for_object(&alpha, &pose(7.), || {
+        send_datagram(&beta, &DatagramUp::App(AppDatagramUp::SetPose(0, pose(7.))))
+    }))
+    .await;
+
+    async fn wait_for_object(client: &NetVRConnection, expected: &Pose, mut tick: impl FnMut()) {
+        loop {
+            tick();
+            // snapshots sent before Init was processed are empty
+            let DatagramDown::App(snapshot) = read_datagram(client).await else { continue; };
+            let first = snapshot.objects.first();
+            if matches!(first, Some(object) if object.position.x == expected.position.x) {
+                return;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn calibration_recovers_known_transform() {
+    let server = start_server().await;
+    let mut reference = connect(&server).await;
+    let mut target = connect(&server).await;
+    // where target's stage is in the world, calibration should undo this
+    let stage = Isometry3::from_parts(
+        Translation3::new(1., 0., -2.),
+        UnitQuaternion::from_euler_angles(0., 0.5, 0.),
+    );
+
+    let server_space = to_isometry(&calibrate(&server, &mut reference, &mut target, stage).await);
+
+    let error = stage * server_space;
+    assert!(
+        error.translation.vector.norm() < 0.01 && error.rotation.angle() < 0.01,
+        "calibration is off by {:?}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn stored_calibration_is_applied_on_reconnect() {
+    let server = start_server_with(|options| options.auto_apply_calibration = true).await;
+    let mut reference = connect(&server).await;
+    let mut target = connect(&server).await;
+    set_name(&mut reference, "reference").await;
+    set_name(&mut target, "target").await;
+    let stage = Isometry3::from_parts(
+        Translation3::new(0., 0., 1.),
+        UnitQuaternion::from_euler_angles(0., -1., 0.),
+    );
+    let applied = calibrate(&server, &mut reference, &mut target, stage).await;
+    drop(target);
+
+    let mut target = connect(&server).await;
+    set_name(&mut target, "target").await;
+    let reapplied = within(read_server_space_pose(&mut target)).await;
+    assert_eq!(reapplied, applied);
+}
+
+#[tokio::test]
+async fn batch_calibration_chains_transforms() {
+    let server = start_server().await;
+    let mut reference = connect(&server).await;
+    let mut first = connect(&server).await;
+    let mut second = connect(&server).await;
+    let first_stage = Isometry3::from_parts(
+        Translation3::new(1., 0., -2.),
+        UnitQuaternion::from_euler_angles(0., 0.5, 0.),
+    );
+    let second_stage = Isometry3::from_parts(
+        Translation3::new(-0.5, 0., 3.),
+        UnitQuaternion::from_euler_angles(0., -1.2, 0.),
+    );
+
+    let mut dashboard = connect_dashboard(&server).await;
+    send_dashboard(
+        &mut dashboard,
+        json!({
+            "type": "StartBatchCalibration",
+            "referenceId": reference.client_id,
+            "referenceSubactionPath": "/user/hand/right",
+            "targets": [
+                // solved against first, listed before it on purpose
+                {
+                    "clientId": second.client_id,
+                    "subactionPath": "/user/hand/right",
+                    "parentId": first.client_id,
+                },
+                {
+                    "clientId": first.client_id,
+                    "subactionPath": "/user/hand/right",
+                    "parentId": null,
+                },
+            ],
+            "conf": { "sample_count": 40, "sample_interval_nanos": 200_000_000 },
+        }),
+    )
+    .await;
+    tokio::join!(
+        within(answer_calibration(&mut reference, Isometry3::identity())),
+        within(answer_calibration(&mut first, first_stage)),
+        within(answer_calibration(&mut second, second_stage)),
+    );
+
+    for (client, stage) in [(&mut first, first_stage), (&mut second, second_stage)] {
+        let error = stage * to_isometry(&within(read_server_space_pose(client)).await);
+        assert!(
+            error.translation.vector.norm() < 0.01 && error.rotation.angle() < 0.01,
+            "calibration of {} is off by {:?}",
+            client.client_id,
+            error
+        );
+    }
+}
+
+#[tokio::test]
+async fn marker_calibration_recovers_yaw_and_translation() {
+    let server = start_server().await;
+    let mut reference = connect(&server).await;
+    let mut target = connect(&server).await;
+    let stage = Isometry3::from_parts(
+        Translation3::new(2., 0., 0.5),
+        UnitQuaternion::from_euler_angles(0., 0.8, 0.),
+    );
+    let markers = [
+        Translation3::new(0., 0.8, 0.),
+        Translation3::new(1.2, 0.8, 0.3),
+        Translation3::new(0.2, 0.8, 1.5),
+    ];
+
+    let mut dashboard = connect_dashboard(&server).await;
+    start_markers(&mut dashboard, &reference, &target, markers.len()).await;
+    for (i, marker) in markers.into_iter().enumerate() {
+        send_dashboard(&mut dashboard, json!({ "type": "CaptureMarker" })).await;
+        tokio::join!(
+            within(answer_marker(&mut reference, Isometry3::identity(), marker)),
+            within(answer_marker(&mut target, stage, marker)),
+        );
+        let captured = within(wait_for_dashboard(&mut dashboard, "MarkerCaptured")).await;
+        assert_eq!(captured["captured"], i + 1);
+    }
+
+    let error = stage * to_isometry(&within(read_server_space_pose(&mut target)).await);
+    assert!(
+        error.translation.vector.norm() < 0.01 && error.rotation.angle() < 0.01,
+        "calibration is off by {:?}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn late_marker_sample_is_not_used_for_next_marker() {
+    let server = start_server().await;
+    let mut reference = connect(&server).await;
+    let mut target = connect(&server).await;
+    // samples can only be told apart in time once clocks are known
+    within(answer_heartbeats(&mut reference, 0)).await;
+    within(answer_heartbeats(&mut target, 0)).await;
+    let stage = Isometry3::from_parts(
+        Translation3::new(2., 0., 0.5),
+        UnitQuaternion::from_euler_angles(0., 0.8, 0.),
+    );
+    let markers = [
+        Translation3::new(0., 0.8, 0.),
+        Translation3::new(1.2, 0.8, 0.3),
+        Translation3::new(0.2, 0.8, 1.5),
+    ];
+
+    let mut dashboard = connect_dashboard(&server).await;
+    start_markers(&mut dashboard, &reference, &target, 2).await;
+    send_dashboard(&mut dashboard, json!({ "type": "CaptureMarker" })).await;
+    let identity = Isometry3::identity();
+    within(answer_marker(&mut reference, identity, markers[0])).await;
+    within(read_sample_request(&mut target)).await;
+    let late = marker_sample(stage, markers[0]);
+    within(wait_for_dashboard(&mut dashboard, "Info")).await;
+
+    for marker in &markers[1..] {
+        send_dashboard(&mut dashboard, json!({ "type": "CaptureMarker" })).await;
+        within(read_sample_request(&mut target)).await;
+        write_sample(&mut target, &late).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let sample = marker_sample(stage, *marker);
+        tokio::join!(
+            within(answer_marker(&mut reference, identity, *marker)),
+            within(write_sample(&mut target, &sample)),
+        );
+        within(wait_for_dashboard(&mut dashboard, "MarkerCaptured")).await;
+    }
+
+    let error = stage * to_isometry(&within(read_server_space_pose(&mut target)).await);
+    assert!(
+        error.translation.vector.norm() < 0.01 && error.rotation.angle() < 0.01,
+        "calibration is off by {:?}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn cancel_stops_marker_capture() {
+    let server = start_server().await;
+    let mut reference = connect(&server).await;
+    let mut target = connect(&server).await;
+    let mut dashboard = connect_dashboard(&server).await;
+    start_markers(&mut dashboard, &reference, &target, 2).await;
+    send_dashboard(&mut dashboard, json!({ "type": "CaptureMarker" })).await;
+    within(read_sample_request(&mut reference)).await;
+    within(read_sample_request(&mut target)).await;
+
+    send_dashboard(&mut dashboard, json!({ "type": "CancelCalibration" })).await;
+    let state = within(async {
+        loop {
+            let message = wait_for_dashboard(&mut dashboard, "CalibrationState").await;
+            if message["state"]["type"] == "Failed" {
+                break message["state"].clone();
+            }
+        }
+    })
+    .await;
+    assert_eq!(state["reason"], "Cancelled");
+}
+
+#[tokio::test]
+async fn calibration_fails_when_client_disconnects() {
+    let server = start_server().await;
+    let mut reference = connect(&server).await;
+    let mut target = connect(&server).await;
+    let mut dashboard = connect_dashboard(&server).await;
+    send_dashboard(
+        &mut dashboard,
+        json!({
+            "type": "StartCalibration",
+            "targetId": target.client_id,
+            "targetSubactionPath": "/user/hand/right",
+            "referenceId": reference.client_id,
+            "referenceSubactionPath": "/user/hand/right",
+            "conf": { "sample_count": 40, "sample_interval_nanos": 200_000_000 },
+        }),
+    )
+    .await;
+    for client in [&mut reference, &mut target] {
+        within(read_configuration(client, |message| match message {
+            ConfigurationDown::TriggerCalibration(..) => Some(()),
+            _ => None,
+        }))
+        .await;
+    }
+    let target_id = target.client_id;
+    drop(target);
+
+    let mut states = vec![];
+    within(async {
+        loop {
+            let message = read_dashboard(&mut dashboard).await;
+            if message["type"] != "CalibrationState" {
+                continue;
+            }
+            assert_eq!(message["clients"], json!([reference.client_id, target_id]));
+            states.push(message["state"].clone());
+            if message["state"]["type"] == "Failed" {
+                break;
+            }
+        }
+    })
+    .await;
+    let types: Vec<_> = states.iter().map(|state| &state["type"]).collect();
+    assert_eq!(types, ["Triggering", "Collecting", "Failed"]);
+    let reason = states[2]["reason"].as_str().unwrap();
+    assert!(reason.contains("disconnected"), "failed because {}", reason);
+}
+
+#[tokio::test]
+async fn headset_calibration_reports_states() {
+    let server = start_server().await;
+    let mut first = connect(&server).await;
+    let mut second = connect(&server).await;
+    let mut dashboard = connect_dashboard(&server).await;
+    let calibrate = json!({ "type": "CalibrateByHeadsetPosition" });
+    send_dashboard(&mut dashboard, calibrate).await;
+
+    for (client, x) in [(&mut first, 1.), (&mut second, -1.)] {
+        let path = within(read_configuration(client, |message| match message {
+            ConfigurationDown::RequestSample(path, BaseSpace::Stage) => Some(path),
+            _ => None,
+        }))
+        .await;
+        assert_eq!(path, "/user/head");
+        let head = Translation3::new(x, 1.7, 2.);
+        write_sample(client, &marker_sample(Isometry3::identity(), head)).await;
+    }
+    for (client, x) in [(&mut first, 1.), (&mut second, -1.)] {
+        let pose = within(read_server_space_pose(client)).await;
+        assert_eq!(pose.position, Vec3 { x, y: 0., z: 2. });
+    }
+
+    let mut states = vec![];
+    within(async {
+        loop {
+            let message = wait_for_dashboard(&mut dashboard, "CalibrationState").await;
+            assert_eq!(message["kind"], "headset");
+            states.push(message["state"]["type"].clone());
+            if message["state"]["type"] == "Idle" {
+                break;
+            }
+        }
+    })
+    .await;
+    assert_eq!(states, ["Triggering", "Collecting", "Applying", "Idle"]);
+}
+
+#[tokio::test]
+async fn revoking_refuses_only_clients_with_the_name() {
+    let server = start_server().await;
+    let mut dashboard = connect_dashboard(&server).await;
+    let mut alice = connect(&server).await;
+    let mut bob = connect(&server).await;
+    set_name(&mut alice, "alice").await;
+    set_name(&mut bob, "bob").await;
+    let names = [(alice.client_id, "alice"), (bob.client_id, "bob")];
+    within(wait_for_names(&mut dashboard, &names)).await;
+
+    let revoke = json!({ "type": "RevokeClient", "clientId": alice.client_id });
+    send_dashboard(&mut dashboard, revoke).await;
+    let revoked = Some(CloseCode::Revoked.code());
+    assert_eq!(within(close_code(&alice)).await, revoked);
+    // same address, different name
+    assert!(bob.connection.close_reason().is_none());
+
+    // refused before admission if the name is known from the hello
+    let refused = within(connect_with(&server, Some("alice"))).await;
+    assert!(matches!(refused, Err(Error::Rejected(_))));
+    // and disconnected once the name is reported later
+    let mut again = connect(&server).await;
+    set_name(&mut again, "alice").await;
+    assert_eq!(within(close_code(&again)).await, revoked);
+
+    let unrevoke = json!({ "type": "UnrevokeClient", "name": "alice" });
+    send_dashboard(&mut dashboard, unrevoke).await;
+    within(async { while read_dashboard(&mut dashboard).await["type"] != "RevokedClients" {} })
+        .await;
+    let mut allowed = connect(&server).await;
+    set_name(&mut allowed, "alice").await;
+    let names = [(allowed.client_id, "alice")];
+    within(wait_for_names(&mut dashboard, &names)).await;
+    assert!(allowed.connection.close_reason().is_none());
+}
+
+#[tokio::test]
+async fn empty_rooms_are_removed() {
+    let server = start_server().await;
+    let mut dashboard = connect_dashboard(&server).await;
+    let client = connect(&server).await;
+    let id = client.client_id;
+    let in_side = json!([["default", []], ["side", [id]]]);
+
+    let room = json!({ "type": "MoveClient", "clientId": id, "room": "side" });
+    send_dashboard(&mut dashboard, room).await;
+    within(wait_for_rooms(&mut dashboard, in_side.clone())).await;
+    let room = json!({ "type": "MoveClient", "clientId": id, "room": "default" });
+    send_dashboard(&mut dashboard, room).await;
+    within(wait_for_rooms(&mut dashboard, json!([["default", [id]]]))).await;
+
+    let room = json!({ "type": "MoveClient", "clientId": id, "room": "side" });
+    send_dashboard(&mut dashboard, room).await;
+    within(wait_for_rooms(&mut dashboard, in_side.clone())).await;
+    drop(client);
+    within(wait_for_rooms(&mut dashboard, json!([["default", []]]))).await;
+}
+
+/// Waits until the dashboard lists exactly the given [name, clients] rooms
+async fn wait_for_rooms(dashboard: &mut Dashboard, expected: serde_json::Value) {
+    loop {
+        let message = read_dashboard(dashboard).await;
+        if message["type"] != "Rooms" {
+            continue;
+        }
+        let rooms: Vec<_> = message["rooms"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|room| json!([room["name"], room["clients"]]))
+            .collect();
+        if serde_json::Value::from(rooms) == expected {
+            return;
+        }
+    }
+}
+
+/// Starts calibrating target against reference by markers, both by their
+/// right hands
+async fn start_markers(
+    dashboard: &mut Dashboard,
+    reference: &NetVRConnection,
+    target: &NetVRConnection,
+    point_count: usize,
+) {
+    let start = json!({
+        "type": "StartMarkerCalibration",
+        "referenceId": reference.client_id,
+        "referenceSubactionPath": "/user/hand/right",
+        "targets": [{ "clientId": target.client_id, "subactionPath": "/user/hand/right" }],
+        "pointCount": point_count,
+    });
+    send_dashboard(dashboard, start).await;
+}
+
+/// Reads dashboard messages until one of the type arrives
+async fn wait_for_dashboard(dashboard: &mut Dashboard, message_type: &str) -> serde_json::Value {
+    loop {
+        let message = read_dashboard(dashboard).await;
+        if message["type"] == message_type {
+            return message;
+        }
+    }
+}
+
+/// Waits until the dashboard sees all the clients with given names
+async fn wait_for_names(dashboard: &mut Dashboard, names: &[(ClientId, &str)]) {
+    loop {
+        let message = read_dashboard(dashboard).await;
+        let clients = &message["value"]["clients"];
+        if message["type"] == "ConfigurationSnapshotChanged"
+            && names
+                .iter()
+                .all(|(id, name)| clients[id.to_string()]["name"] == *name)
+        {
+            return;
+        }
+    }
+}
+
+/// Application close code of the client's connection once it is closed
+async fn close_code(client: &NetVRConnection) -> Option<u32> {
+    match client.connection.closed().await {
+        quinn::ConnectionError::ApplicationClosed(close) => {
+            Some(close.error_code.into_inner() as u32)
+        }
+        _ => None,
+    }
+}
+
+async fn read_dashboard(dashboard: &mut Dashboard) -> serde_json::Value {
+    loop {
+        let message = dashboard.next().await.unwrap().unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+async fn read_server_space_pose(client: &mut NetVRConnection) -> Pose {
+    read_configuration(client, |message| match message {
+        ConfigurationDown::SetServerSpacePose(pose) => Some(pose),
+        _ => None,
+    })
+    .await
+}
+
+async fn set_name(client: &mut NetVRConnection, name: &str) {
+    client
+        .configuration_up
+        .write(&ConfigurationUp::ConfigurationSnapshot(
+            RemoteConfigurationSnapshot {
+                version: 1,
+                name: name.to_owned(),
+                ..Default::default()
+            },
+        ))
+        .await
+        .unwrap();
+}
+
+/// Calibrates right hands of the clients from the dashboard and returns the
+/// server space pose the target receives
+async fn calibrate(
+    server: &RunningServer,
+    reference: &mut NetVRConnection,
+    target: &mut NetVRConnection,
+    stage: Isometry3<f32>,
+) -> Pose {
+    let mut dashboard = connect_dashboard(server).await;
+    send_dashboard(
+        &mut dashboard,
+        json!({
+            "type": "StartCalibration",
+            "targetId": target.client_id,
+            "targetSubactionPath": "/user/hand/right",
+            "referenceId": reference.client_id,
+            "referenceSubactionPath": "/user/hand/right",
+            "conf": { "sample_count": 40, "sample_interval_nanos": 200_000_000 },
+        }),
+    )
+    .await;
+
+    tokio::join!(
+        within(answer_calibration(reference, Isometry3::identity())),
+        within(answer_calibration(target, stage)),
+    );
+    within(read_server_space_pose(target)).await
+}
+
+/// Answers sample request with device placed on the marker
+async fn answer_marker(
+    client: &mut NetVRConnection,
+    stage: Isometry3<f32>,
+    marker: Translation3<f32>,
+) {
+    let base_space = read_sample_request(client).await;
+    assert!(match base_space {
+        BaseSpace::Server => stage == Isometry3::identity(),
+        BaseSpace::Stage => stage != Isometry3::identity(),
+    });
+    write_sample(client, &marker_sample(stage, marker)).await;
+}
+
+/// Waits until the client is asked for a sample of its right hand
+async fn read_sample_request(client: &mut NetVRConnection) -> BaseSpace {
+    let (path, base_space) = read_configuration(client, |message| match message {
+        ConfigurationDown::RequestSample(path, base_space) => Some((path, base_space)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(path, "/user/hand/right");
+    base_space
+}
+
+/// Sample of a device lying on the marker taken now
+fn marker_sample(stage: Isometry3<f32>, marker: Translation3<f32>) -> CalibrationSample {
+    let world = Isometry3::from_parts(marker, UnitQuaternion::identity());
+    CalibrationSample {
+        flags: 0b1111,
+        pose: from_isometry(&(stage.inverse() * world)),
+        prev_flags: None,
+        prev_pose: None,
+        nanos: now_nanos(),
+        now_nanos: now_nanos(),
+    }
+}
+
+async fn write_sample(client: &mut NetVRConnection, sample: &CalibrationSample) {
+    client
+        .calibration_up
+        .as_mut()
+        .unwrap()
+        .write(sample)
+        .await
+        .unwrap();
+}
+
+/// Answers calibration trigger with samples of devices held together
+async fn answer_calibration(client: &mut NetVRConnection, stage: Isometry3<f32>) {
+    let (path, conf, base_space) = read_configuration(client, |message| match message {
+        ConfigurationDown::TriggerCalibration(path, conf, base_space) => {
+            Some((path, conf, base_space))
+        }
+        _ => None,
+    })
+    .await;
+    assert_eq!(path, "/user/hand/right");
+    // reference already is in server space, target is being calibrated
+    assert!(match base_space {
+        BaseSpace::Server => stage == Isometry3::identity(),
+        BaseSpace::Stage => stage != Isometry3::identity(),
+    });
+    let motion = Motion::scripted();
+    let calibration_up = client.calibration_up.as_mut().unwrap();
+    for i in 0..conf.sample_count as i64 {
+        let nanos = i * conf.sample_interval_nanos;
+        let world = motion.rig(nanos as f64 / 1e9).right;
+        calibration_up
+            .write(&CalibrationSample {
+                flags: 0b1111,
+                pose: from_isometry(&(stage.inverse() * world)),
+                prev_flags: None,
+                prev_pose: None,
+                nanos,
+                now_nanos: nanos,
+            })
+            .await
+            .unwrap();
+    }
+}
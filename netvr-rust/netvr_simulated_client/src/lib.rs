@@ -0,0 +1,11 @@
+//! Headless client which behaves like a headset running the netvr plugin.
+//! Used for load testing the server and checking calibration without any
+//! OpenXR runtime.
+
+mod motion;
+mod pose;
+mod simulated_client;
+
+pub use motion::{Motion, Rig};
+pub use pose::{from_isometry, to_isometry};
+pub use simulated_client::{run, SimulationOptions};
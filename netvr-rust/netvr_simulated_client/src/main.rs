@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::{Parser, ValueEnum};
+use nalgebra::{Isometry3, Translation3, UnitQuaternion};
+use netvr_client::ConnectOptions;
+use netvr_simulated_client::{run, Motion, SimulationOptions};
+use tokio::task::JoinSet;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum MotionKind {
+    /// Same path for every client, use this for checking calibration
+    Scripted,
+    /// Different random path for every client
+    Random,
+}
+
+/// Simulated headset with two controllers. Joins netvr server as a full
+/// participant without needing an OpenXR runtime.
+#[derive(Parser, Debug)]
+#[command(about)]
+struct Args {
+    /// Server address, discovery is used if not specified
+    #[arg(long)]
+    server: Option<String>,
+
+    /// Number of clients to simulate
+    #[arg(long, default_value_t = 1)]
+    count: usize,
+
+    /// Name shown in the dashboard, suffixed by index if count is more than 1
+    #[arg(long, default_value = "simulated")]
+    name: String,
+
+    #[arg(long)]
+    room: Option<String>,
+
+    #[arg(long)]
+    join_token: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = MotionKind::Scripted)]
+    motion: MotionKind,
+
+    /// Seed of the random motion, incremented for each client
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Position of the simulated stage space in the world, in meters
+    #[arg(long, num_args = 3, value_names = ["X", "Y", "Z"], allow_negative_numbers = true)]
+    stage_position: Option<Vec<f32>>,
+
+    /// Rotation of the simulated stage space around the up axis, in degrees
+    #[arg(long, default_value_t = 0., allow_negative_numbers = true)]
+    stage_yaw: f32,
+
+    /// State snapshots sent per second
+    #[arg(long, default_value_t = 90.)]
+    rate: f64,
+
+    /// Grab a random synchronized object every this many seconds
+    #[arg(long)]
+    grab_every: Option<f64>,
+
+    /// Number of synchronized objects to announce
+    #[arg(long, default_value_t = 3)]
+    objects: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let server_address = match &args.server {
+        Some(address) => Some(netvr_client::resolve_server_address(address).await?),
+        None => None,
+    };
+    let position = args.stage_position.clone().unwrap_or(vec![0., 0., 0.]);
+    let stage = Isometry3::from_parts(
+        Translation3::new(position[0], position[1], position[2]),
+        UnitQuaternion::from_euler_angles(0., args.stage_yaw.to_radians(), 0.),
+    );
+
+    let mut clients = JoinSet::new();
+    for i in 0..args.count {
+        let name = if args.count > 1 {
+            format!("{} {}", args.name, i + 1)
+        } else {
+            args.name.clone()
+        };
+        let options = SimulationOptions {
+            connect: ConnectOptions {
+                server_address,
+                join_token: args.join_token.clone(),
+                room: args.room.clone(),
+                name: Some(name.clone()),
+                ..Default::default()
+            },
+            name,
+            motion: match args.motion {
+                MotionKind::Scripted => Motion::scripted(),
+                MotionKind::Random => Motion::random(args.seed + i as u64),
+            },
+            stage,
+            snapshot_interval: Duration::from_secs_f64(1. / args.rate),
+            grab_interval: args.grab_every.map(Duration::from_secs_f64),
+            object_count: args.objects,
+        };
+        clients.spawn(run(options));
+    }
+    while let Some(result) = clients.join_next().await {
+        match result {
+            Ok(Ok(())) => println!("Client finished"),
+            Ok(Err(err)) => println!("Client failed: {:?}", err),
+            Err(err) => println!("Client panicked: {:?}", err),
+        }
+    }
+    Ok(())
+}
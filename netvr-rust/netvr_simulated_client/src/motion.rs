@@ -0,0 +1,88 @@
+use nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Poses of all simulated devices in world space
+#[derive(Clone, Copy, Debug)]
+pub struct Rig {
+    pub head: Isometry3<f32>,
+    pub left: Isometry3<f32>,
+    pub right: Isometry3<f32>,
+}
+
+/// Frequency and phase of one sine wave the motion is composed of
+#[derive(Clone, Copy, Debug)]
+struct Wave {
+    frequency: f64,
+    phase: f64,
+}
+
+impl Wave {
+    fn at(&self, seconds: f64) -> f32 {
+        (self.frequency * seconds + self.phase).sin() as f32
+    }
+}
+
+/// How the simulated user moves. Motion is a function of wall-clock time so
+/// that separate processes with the same motion move in lockstep, which is
+/// what calibration expects from devices held together.
+#[derive(Clone, Debug)]
+pub struct Motion {
+    /// x, y, z of the body, yaw, pitch, roll of the head, swing of the hands.
+    /// Y is up as in OpenXR.
+    waves: [Wave; 7],
+}
+
+impl Motion {
+    /// Deterministic path, same for every client
+    pub fn scripted() -> Self {
+        let frequencies = [0.5, 0.8, 0.5, 0.9, 0.7, 1.1, 1.3];
+        let mut waves = [Wave {
+            frequency: 0.,
+            phase: 0.,
+        }; 7];
+        for (i, wave) in waves.iter_mut().enumerate() {
+            wave.frequency = frequencies[i];
+            // cosine for z so that the body walks in a circle
+            wave.phase = if i == 2 {
+                std::f64::consts::FRAC_PI_2
+            } else {
+                0.
+            };
+        }
+        Self { waves }
+    }
+
+    /// Smooth random path, different for every seed
+    pub fn random(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut waves = [Wave {
+            frequency: 0.,
+            phase: 0.,
+        }; 7];
+        for wave in waves.iter_mut() {
+            wave.frequency = rng.gen_range(0.2..1.5);
+            wave.phase = rng.gen_range(0.0..std::f64::consts::TAU);
+        }
+        Self { waves }
+    }
+
+    /// Where the devices are at given time
+    pub fn rig(&self, seconds: f64) -> Rig {
+        let w = |i: usize| self.waves[i].at(seconds);
+        let head = Isometry3::from_parts(
+            Translation3::new(0.5 * w(0), 1.6 + 0.1 * w(1), 0.5 * w(2)),
+            UnitQuaternion::from_euler_angles(0.5 * w(4), 1.2 * w(3), 0.4 * w(5)),
+        );
+        let hand = |side: f32| {
+            head * Isometry3::from_parts(
+                Translation3::new(0.2 * side, -0.3, -0.3),
+                UnitQuaternion::from_axis_angle(&Vector3::x_axis(), 0.6 * w(6) * side),
+            )
+        };
+        Rig {
+            head,
+            left: hand(-1.),
+            right: hand(1.),
+        }
+    }
+}
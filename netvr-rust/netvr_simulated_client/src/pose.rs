@@ -0,0 +1,34 @@
+use nalgebra::{Isometry3, Quaternion, Translation3, UnitQuaternion};
+use netvr_data::Pose;
+
+/// Converts network pose to nalgebra for doing math with it
+pub fn to_isometry(pose: &Pose) -> Isometry3<f32> {
+    Isometry3::from_parts(
+        Translation3::new(pose.position.x, pose.position.y, pose.position.z),
+        UnitQuaternion::from_quaternion(Quaternion::new(
+            pose.orientation.w,
+            pose.orientation.x,
+            pose.orientation.y,
+            pose.orientation.z,
+        )),
+    )
+}
+
+/// Converts nalgebra pose back to what is sent over the network
+pub fn from_isometry(isometry: &Isometry3<f32>) -> Pose {
+    let translation = isometry.translation;
+    let rotation = isometry.rotation;
+    Pose {
+        position: netvr_data::Vec3 {
+            x: translation.x,
+            y: translation.y,
+            z: translation.z,
+        },
+        orientation: netvr_data::Quaternion {
+            x: rotation.i,
+            y: rotation.j,
+            z: rotation.k,
+            w: rotation.w,
+        },
+    }
+}
@@ -0,0 +1,478 @@
+use std::{
+    future::{pending, Future},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use nalgebra::{Isometry3, Translation3, UnitQuaternion};
+use netvr_client::{ConnectOptions, RecvFrames, SendFrames};
+use netvr_data::{
+    app::{AppDatagramUp, AppDown, AppUp, Snapshot},
+    bincode,
+    compact::StateDecoder,
+    net::{
+        ActionType, BaseSpace, CalibrationConfiguration, CalibrationSample, ClientId,
+        ConfigurationDown, ConfigurationUp, Controller, DatagramDown, DatagramUp, Heartbeat,
+        RemoteAction, RemoteConfigurationSnapshot, RemoteInteractionProfile, StateSnapshot,
+    },
+    Pose,
+};
+use quinn::Connection;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    select,
+    sync::{mpsc, watch},
+    time::{self, Interval},
+};
+
+use crate::{
+    motion::Motion,
+    pose::{from_isometry, to_isometry},
+};
+
+/// XR_SPACE_LOCATION_{ORIENTATION,POSITION}_{VALID,TRACKED}_BIT
+const TRACKED_FLAGS: u64 = 0b1111;
+const INTERACTION_PROFILE: &str = "/interaction_profiles/khr/simple_controller";
+const USER_PATHS: [&str; 2] = ["/user/hand/left", "/user/hand/right"];
+
+/// Everything needed to run one simulated client
+#[derive(Clone, Debug)]
+pub struct SimulationOptions {
+    pub connect: ConnectOptions,
+    pub name: String,
+    pub motion: Motion,
+    /// Where the client's stage space is in the world. Successful calibration
+    /// against a client with identity stage sets server space to its inverse.
+    pub stage: Isometry3<f32>,
+    /// How often state snapshots are sent
+    pub snapshot_interval: Duration,
+    /// How often to grab a random synchronized object, None to never grab
+    pub grab_interval: Option<Duration>,
+    /// Number of synchronized objects announced to the server. It only uses
+    /// them if no other client announced objects before.
+    pub object_count: usize,
+}
+
+enum CalibrationTrigger {
+    Start(String, CalibrationConfiguration, BaseSpace),
+    RequestSample(String, BaseSpace),
+    Stop,
+}
+
+/// State shared between the tasks of one client
+struct SimulatedClient {
+    options: SimulationOptions,
+    client_id: ClientId,
+    configuration: watch::Sender<RemoteConfigurationSnapshot>,
+    /// Pose of server space in stage space, set by calibration
+    server_space: Mutex<Isometry3<f32>>,
+    /// Latest poses of synchronized objects received from the server
+    objects: Mutex<Vec<Pose>>,
+    /// Object currently held by the right hand
+    holding: Mutex<Option<u32>>,
+    /// Order of the latest state set received from the server
+    state_order: AtomicUsize,
+    /// Decodes state sets of the current room, reset when the room changes
+    state_decoder: Mutex<StateDecoder>,
+}
+
+/// Connects to the server and behaves like a headset until the connection
+/// fails.
+pub async fn run(options: SimulationOptions) -> Result<()> {
+    let connection =
+        netvr_client::connect_with_options(|text| println!("[connect] {}", text), &options.connect)
+            .await?;
+    let client = Arc::new(SimulatedClient {
+        client_id: connection.client_id,
+        configuration: watch::channel(configuration_snapshot(options.name.clone())).0,
+        server_space: Mutex::new(Isometry3::identity()),
+        objects: Mutex::new(vec![]),
+        holding: Mutex::new(None),
+        state_order: AtomicUsize::new(0),
+        state_decoder: Mutex::default(),
+        options,
+    });
+    client.log(format!(
+        "Connected to {:?} with capabilities {:?}",
+        connection.connection.remote_address(),
+        connection.capabilities
+    ));
+    let calibration_trigger = mpsc::channel(1);
+
+    select! {
+        value = run_heartbeat(
+            connection.heartbeat,
+            connection.connection.clone(),
+            client.clone(),
+        ) => value,
+        value = run_transmit_configuration(connection.configuration_up, client.clone()) => value,
+        value = run_transmit_snapshots(connection.connection.clone(), client.clone()) => value,
+        value = run_receive_datagrams(connection.connection.clone(), client.clone()) => value,
+        value = run_receive_configuration(
+            connection.configuration_down,
+            client.clone(),
+            calibration_trigger.0,
+        ) => value,
+        value = optional(connection.calibration_up.map(|calibration_up| {
+            run_calibration_sender(calibration_up, client.clone(), calibration_trigger.1)
+        })) => value,
+        value = optional(connection.app_up_stream.map(|app_up| {
+            run_send_app(app_up, connection.connection.clone(), client.clone())
+        })) => value,
+        value = optional(connection.app_down_stream.map(|app_down| {
+            run_recv_app(app_down, client.clone())
+        })) => value,
+    }
+}
+
+/// Runs the future if the stream it needs was negotiated, otherwise never
+/// finishes.
+async fn optional<F: Future>(future: Option<F>) -> F::Output {
+    match future {
+        Some(future) => future.await,
+        None => pending().await,
+    }
+}
+
+/// Waits for the next tick, never finishes if there is no interval
+async fn optional_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+/// Time as reported by simulated OpenXR runtime. Wall-clock based so that
+/// clients in different processes agree on it.
+fn now_nanos() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as i64)
+        .unwrap_or_default()
+}
+
+fn configuration_snapshot(name: String) -> RemoteConfigurationSnapshot {
+    RemoteConfigurationSnapshot {
+        version: 1,
+        user_paths: USER_PATHS.iter().map(|path| path.to_string()).collect(),
+        interaction_profiles: vec![RemoteInteractionProfile {
+            path: INTERACTION_PROFILE.to_owned(),
+            bindings: USER_PATHS
+                .iter()
+                .map(|path| RemoteAction {
+                    ty: ActionType::Pose,
+                    name: "grip_pose".to_owned(),
+                    localized_name: "Grip Pose".to_owned(),
+                    binding: format!("{}/input/grip/pose", path),
+                })
+                .collect(),
+        }],
+        name,
+    }
+}
+
+impl SimulatedClient {
+    fn log(&self, text: String) {
+        println!("[{} {}] {}", self.client_id, self.options.name, text);
+    }
+
+    /// Pose of a device at given time relative to the base space, None if
+    /// the device is not simulated
+    fn locate(&self, path: &str, nanos: i64, base_space: BaseSpace) -> Option<Isometry3<f32>> {
+        let rig = self.options.motion.rig(nanos as f64 / 1e9);
+        let world = match path {
+            "/user/head" => rig.head,
+            "/user/hand/left" => rig.left,
+            "/user/hand/right" => rig.right,
+            _ => return None,
+        };
+        let stage = self.options.stage.inverse() * world;
+        Some(match base_space {
+            BaseSpace::Stage => stage,
+            BaseSpace::Server => self.server_space().inverse() * stage,
+        })
+    }
+
+    fn server_space(&self) -> Isometry3<f32> {
+        *self.server_space.lock().unwrap()
+    }
+
+    fn collect_calibration_sample(
+        &self,
+        path: &str,
+        nanos: i64,
+        prev_nanos: Option<i64>,
+        base_space: BaseSpace,
+    ) -> Result<CalibrationSample> {
+        let pose = self
+            .locate(path, nanos, base_space)
+            .ok_or_else(|| anyhow!("Device {:?} is not simulated", path))?;
+        let prev_pose = prev_nanos.and_then(|nanos| self.locate(path, nanos, base_space));
+        Ok(CalibrationSample {
+            flags: TRACKED_FLAGS,
+            pose: from_isometry(&pose),
+            prev_flags: prev_pose.map(|_| TRACKED_FLAGS),
+            prev_pose: prev_pose.map(|pose| from_isometry(&pose)),
+            nanos,
+            now_nanos: now_nanos(),
+        })
+    }
+}
+
+/// Answers heartbeats so that the server can estimate our clock and reports
+/// whenever its estimate changes
+async fn run_heartbeat(
+    mut heartbeat: RecvFrames<Heartbeat>,
+    connection: Connection,
+    client: Arc<SimulatedClient>,
+) -> Result<()> {
+    let mut clock = None;
+    loop {
+        let message = heartbeat.read().await?;
+        let datagram = DatagramUp::Pong {
+            server_nanos: message.server_nanos,
+            client_nanos: now_nanos(),
+        };
+        connection.send_datagram(bincode::serialize(&datagram)?.into())?;
+        if message.clock != clock {
+            clock = message.clock;
+            client.log(format!("Clock estimated by server: {:?}", clock));
+        }
+    }
+}
+
+async fn run_transmit_configuration(
+    mut configuration_up: SendFrames<ConfigurationUp>,
+    client: Arc<SimulatedClient>,
+) -> Result<()> {
+    let mut configuration = client.configuration.subscribe();
+    loop {
+        let value = configuration.borrow().clone();
+        configuration_up
+            .write(&ConfigurationUp::ConfigurationSnapshot(value))
+            .await?;
+        configuration.changed().await?;
+    }
+}
+
+async fn run_transmit_snapshots(
+    connection: Connection,
+    client: Arc<SimulatedClient>,
+) -> Result<()> {
+    let mut interval = time::interval(client.options.snapshot_interval);
+    loop {
+        interval.tick().await;
+        let nanos = now_nanos();
+        let locate = |path| {
+            client
+                .locate(path, nanos, BaseSpace::Server)
+                .map(|pose| from_isometry(&pose))
+                .unwrap_or_default()
+        };
+        let controllers = USER_PATHS
+            .iter()
+            .enumerate()
+            .map(|(i, path)| Controller {
+                interaction_profile: 1,
+                user_path: i as u8 + 1,
+                pose: locate(path),
+            })
+            .collect();
+        let snapshot = StateSnapshot {
+            controllers,
+            view: locate("/user/head"),
+            required_configuration: client.configuration.borrow().version,
+            nanos,
+        };
+        let acknowledged = client.state_order.load(Ordering::Relaxed);
+        let datagram = DatagramUp::State(snapshot, acknowledged);
+        connection.send_datagram(bincode::serialize(&datagram)?.into())?;
+    }
+}
+
+async fn run_receive_datagrams(connection: Connection, client: Arc<SimulatedClient>) -> Result<()> {
+    loop {
+        let datagram = connection.read_datagram().await?;
+        match bincode::deserialize(&datagram)? {
+            DatagramDown::App(snapshot) => {
+                if !snapshot.objects.is_empty() {
+                    *client.objects.lock().unwrap() = snapshot.objects;
+                }
+            }
+            DatagramDown::State(set) => {
+                if let Some(set) = client.state_decoder.lock().unwrap().decode(set) {
+                    client.state_order.fetch_max(set.order, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+async fn run_receive_configuration(
+    mut configuration_down: RecvFrames<ConfigurationDown>,
+    client: Arc<SimulatedClient>,
+    calibration_trigger: mpsc::Sender<CalibrationTrigger>,
+) -> Result<()> {
+    loop {
+        let message = configuration_down.read().await?;
+        match message {
+            ConfigurationDown::Snapshot(_) => {}
+            ConfigurationDown::SetServerSpacePose(pose) => {
+                let server_space = to_isometry(&pose);
+                *client.server_space.lock().unwrap() = server_space;
+                // how far is the server space from where calibration against
+                // a client with identity stage should put it
+                let error = client.options.stage * server_space;
+                client.log(format!(
+                    "Server space set, it is {:.4} m and {:.4} rad away from world origin",
+                    error.translation.vector.norm(),
+                    error.rotation.angle()
+                ));
+            }
+            ConfigurationDown::TriggerCalibration(path, conf, base_space) => {
+                calibration_trigger
+                    .send(CalibrationTrigger::Start(path, conf, base_space))
+                    .await?;
+            }
+            ConfigurationDown::RequestSample(path, base_space) => {
+                calibration_trigger
+                    .send(CalibrationTrigger::RequestSample(path, base_space))
+                    .await?;
+            }
+            ConfigurationDown::StopCalibration => {
+                calibration_trigger.send(CalibrationTrigger::Stop).await?;
+            }
+            ConfigurationDown::ChangeName(name) => {
+                client.log(format!("Renamed to {:?}", name));
+                client.configuration.send_modify(|configuration| {
+                    configuration.name = name;
+                    configuration.version += 1;
+                });
+            }
+            ConfigurationDown::JoinedRoom(room) => {
+                client.log(format!("Joined room {:?}", room));
+                // orders and baselines of the previous room mean nothing here
+                *client.state_decoder.lock().unwrap() = StateDecoder::default();
+                client.state_order.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+async fn run_calibration_sender(
+    mut calibration_up: SendFrames<CalibrationSample>,
+    client: Arc<SimulatedClient>,
+    mut calibration_trigger: mpsc::Receiver<CalibrationTrigger>,
+) -> Result<()> {
+    loop {
+        let Some(trigger) = calibration_trigger.recv().await else { break; };
+        let (path, conf, base_space) = match trigger {
+            CalibrationTrigger::RequestSample(path, base_space) => {
+                let sample =
+                    client.collect_calibration_sample(&path, now_nanos(), None, base_space)?;
+                calibration_up.write(&sample).await?;
+                continue;
+            }
+            CalibrationTrigger::Start(path, conf, base_space) => (path, conf, base_space),
+            CalibrationTrigger::Stop => continue,
+        };
+        client.log(format!("Calibrating {:?} in {:?}", path, base_space));
+
+        // Align sample times to multiples of the interval so that clients
+        // triggered at about the same time sample the exact same poses.
+        let interval_nanos = conf.sample_interval_nanos.max(1);
+        let mut interval = time::interval(Duration::from_nanos(interval_nanos as u64));
+        let mut sample_time = now_nanos() / interval_nanos * interval_nanos;
+        let mut prev_time = None;
+        loop {
+            let sample =
+                client.collect_calibration_sample(&path, sample_time, prev_time, base_space)?;
+            calibration_up.write(&sample).await?;
+            prev_time = Some(sample_time);
+            sample_time += interval_nanos;
+
+            select! {
+                trigger = calibration_trigger.recv() => match trigger {
+                    Some(CalibrationTrigger::Stop) | None => break,
+                    Some(_) => client.log(
+                        "Calibration trigger received while already calibrating".to_owned(),
+                    ),
+                },
+                _ = interval.tick() => {},
+            }
+        }
+        client.log("Calibration stopped".to_owned());
+    }
+    Ok(())
+}
+
+async fn run_send_app(
+    mut app_up: SendFrames<AppUp>,
+    connection: Connection,
+    client: Arc<SimulatedClient>,
+) -> Result<()> {
+    // objects in a row in front of the world origin
+    let objects: Vec<Pose> = (0..client.options.object_count)
+        .map(|i| {
+            from_isometry(&Isometry3::from_parts(
+                Translation3::new(i as f32 * 0.2, 1.0, -0.5),
+                UnitQuaternion::identity(),
+            ))
+        })
+        .collect();
+    *client.objects.lock().unwrap() = objects.clone();
+    app_up.write(&AppUp::Init(Snapshot { objects })).await?;
+
+    let mut rng = StdRng::from_entropy();
+    let mut grab = client.options.grab_interval.map(time::interval);
+    let mut interval = time::interval(Duration::from_millis(20));
+    loop {
+        select! {
+            _ = optional_tick(&mut grab) => {
+                let count = client.objects.lock().unwrap().len();
+                if count == 0 {
+                    continue;
+                }
+                let object_id = rng.gen_range(0..count) as u32;
+                client.log(format!("Grabbing object {}", object_id));
+                app_up.write(&AppUp::Grab(object_id)).await?;
+                *client.holding.lock().unwrap() = Some(object_id);
+            },
+            _ = interval.tick() => {
+                let Some(object_id) = *client.holding.lock().unwrap() else { continue; };
+                let now = now_nanos();
+                let Some(pose) = client.locate("/user/hand/right", now, BaseSpace::Server) else {
+                    continue;
+                };
+                let datagram = DatagramUp::App(AppDatagramUp::SetPose(
+                    object_id as usize,
+                    from_isometry(&pose),
+                ));
+                connection.send_datagram(bincode::serialize(&datagram)?.into())?;
+            },
+        }
+    }
+}
+
+async fn run_recv_app(
+    mut app_down: RecvFrames<AppDown>,
+    client: Arc<SimulatedClient>,
+) -> Result<()> {
+    loop {
+        match app_down.read().await? {
+            AppDown::Release(object_id) => {
+                let mut holding = client.holding.lock().unwrap();
+                if *holding == Some(object_id) {
+                    client.log(format!("Object {} taken by someone else", object_id));
+                    *holding = None;
+                }
+            }
+        }
+    }
+}